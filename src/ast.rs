@@ -1,37 +1,197 @@
+use super::lexer::Span;
 use super::lexer::Token;
-use std::any::*;
 use std::fmt::*;
+use std::rc::Rc;
 
-pub trait AsAny {
-    fn as_any(&self) -> &dyn Any;
-}
-
-pub trait Node: Debug + Any + AsAny {
+pub trait Node: Debug {
     fn token_literal(&self) -> &str;
     fn string(&self) -> String;
 }
-impl<T: Node> AsAny for T {
-    fn as_any(&self) -> &dyn Any {
-        self
+
+#[derive(Debug, Clone)]
+pub enum Statement {
+    Let(LetStatement),
+    LetPattern(LetPatternStatement),
+    Return(ReturnStatement),
+    Throw(ThrowStatement),
+    Import(ImportStatement),
+    Export(ExportStatement),
+    Expression(ExpressionStmt),
+    Block(BlockStatement),
+}
+impl Statement {
+    pub fn span(&self) -> Span {
+        match self {
+            Statement::Let(stmt) => stmt.token.span,
+            Statement::LetPattern(stmt) => stmt.token.span,
+            Statement::Return(stmt) => stmt.token.span,
+            Statement::Throw(stmt) => stmt.token.span,
+            Statement::Import(stmt) => stmt.token.span,
+            Statement::Export(stmt) => stmt.token.span,
+            Statement::Expression(stmt) => stmt.token.span,
+            Statement::Block(stmt) => stmt.token.span,
+        }
+    }
+}
+impl Node for Statement {
+    fn token_literal(&self) -> &str {
+        match self {
+            Statement::Let(stmt) => stmt.token_literal(),
+            Statement::LetPattern(stmt) => stmt.token_literal(),
+            Statement::Return(stmt) => stmt.token_literal(),
+            Statement::Throw(stmt) => stmt.token_literal(),
+            Statement::Import(stmt) => stmt.token_literal(),
+            Statement::Export(stmt) => stmt.token_literal(),
+            Statement::Expression(stmt) => stmt.token_literal(),
+            Statement::Block(stmt) => stmt.token_literal(),
+        }
+    }
+    fn string(&self) -> String {
+        match self {
+            Statement::Let(stmt) => stmt.string(),
+            Statement::LetPattern(stmt) => stmt.string(),
+            Statement::Return(stmt) => stmt.string(),
+            Statement::Throw(stmt) => stmt.string(),
+            Statement::Import(stmt) => stmt.string(),
+            Statement::Export(stmt) => stmt.string(),
+            Statement::Expression(stmt) => stmt.string(),
+            Statement::Block(stmt) => stmt.string(),
+        }
+    }
+}
+
+// Function and macro literals sit behind an `Rc` so that creating a closure
+// shares the literal instead of copying its body. `if` and `try` are boxed to
+// keep the enum small.
+#[derive(Debug, Clone)]
+pub enum Expression {
+    Identifier(Identifier),
+    Integer(IntegerLiteral),
+    String(StringLiteral),
+    Boolean(Boolean),
+    Prefix(PrefixExpression),
+    Infix(InfixExpression),
+    If(Box<IfExpression>),
+    Try(Box<TryExpression>),
+    Function(Rc<FunctionLiteral>),
+    Macro(Rc<MacroLiteral>),
+    Call(CallExpression),
+    Array(ArrayLiteral),
+    Hash(HashLiteral),
+    Index(IndexExpression),
+    Match(MatchExpression),
+}
+impl Expression {
+    fn node(&self) -> &dyn Node {
+        match self {
+            Expression::Identifier(exp) => exp,
+            Expression::Integer(exp) => exp,
+            Expression::String(exp) => exp,
+            Expression::Boolean(exp) => exp,
+            Expression::Prefix(exp) => exp,
+            Expression::Infix(exp) => exp,
+            Expression::If(exp) => exp.as_ref(),
+            Expression::Try(exp) => exp.as_ref(),
+            Expression::Function(exp) => exp.as_ref(),
+            Expression::Macro(exp) => exp.as_ref(),
+            Expression::Call(exp) => exp,
+            Expression::Array(exp) => exp,
+            Expression::Hash(exp) => exp,
+            Expression::Index(exp) => exp,
+            Expression::Match(exp) => exp,
+        }
+    }
+
+    // Position of the token that starts the expression, or of the operator
+    // for infix, call and index expressions.
+    pub fn span(&self) -> Span {
+        match self {
+            Expression::Identifier(exp) => exp.token.span,
+            Expression::Integer(exp) => exp.token.span,
+            Expression::String(exp) => exp.token.span,
+            Expression::Boolean(exp) => exp.token.span,
+            Expression::Prefix(exp) => exp.token.span,
+            Expression::Infix(exp) => exp.token.span,
+            Expression::If(exp) => exp.token.span,
+            Expression::Try(exp) => exp.token.span,
+            Expression::Function(exp) => exp.token.span,
+            Expression::Macro(exp) => exp.token.span,
+            Expression::Call(exp) => exp.token.span,
+            Expression::Array(exp) => exp.token.span,
+            Expression::Hash(exp) => exp.token.span,
+            Expression::Index(exp) => exp.token.span,
+            Expression::Match(exp) => exp.token.span,
+        }
+    }
+}
+impl Node for Expression {
+    fn token_literal(&self) -> &str {
+        self.node().token_literal()
+    }
+    fn string(&self) -> String {
+        self.node().string()
     }
 }
 
-pub trait Statement: Node {
-    fn statement_node(&self);
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    Literal(LiteralPattern),
+    Wildcard(WildcardPattern),
+    Binding(BindingPattern),
+    Array(ArrayPattern),
+    Hash(HashPattern),
+}
+impl Pattern {
+    fn node(&self) -> &dyn Node {
+        match self {
+            Pattern::Literal(pattern) => pattern,
+            Pattern::Wildcard(pattern) => pattern,
+            Pattern::Binding(pattern) => pattern,
+            Pattern::Array(pattern) => pattern,
+            Pattern::Hash(pattern) => pattern,
+        }
+    }
+}
+impl Node for Pattern {
+    fn token_literal(&self) -> &str {
+        self.node().token_literal()
+    }
+    fn string(&self) -> String {
+        self.node().string()
+    }
 }
 
-pub trait Expression: Node {
-    fn expression_node(&self);
+// Optional type written after a name, e.g. `int`, `[string]`,
+// `{string: int}` or `fn(int, int) -> bool`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TypeAnnotation {
+    Named(String),
+    Array(Box<TypeAnnotation>),
+    Hash(Box<TypeAnnotation>, Box<TypeAnnotation>),
+    Function(Vec<TypeAnnotation>, Box<TypeAnnotation>),
+}
+impl TypeAnnotation {
+    pub fn string(&self) -> String {
+        match self {
+            TypeAnnotation::Named(name) => name.clone(),
+            TypeAnnotation::Array(element) => format!("[{}]", element.string()),
+            TypeAnnotation::Hash(key, value) => format!("{{{}: {}}}", key.string(), value.string()),
+            TypeAnnotation::Function(params, ret) => {
+                let params: Vec<String> = params.iter().map(|p| p.string()).collect();
+                format!("fn({}) -> {}", params.join(", "), ret.string())
+            }
+        }
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Program {
-    pub statements: Vec<Box<dyn Statement>>,
+    pub statements: Vec<Statement>,
 }
 impl Node for Program {
     fn token_literal(&self) -> &str {
         let statements = &self.statements;
-        if statements.len() > 0 {
+        if !statements.is_empty() {
             statements[0].token_literal()
         } else {
             ""
@@ -46,14 +206,12 @@ impl Node for Program {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct LetStatement {
     pub token: Token,
     pub name: Identifier,
-    pub value: Box<dyn Expression>,
-}
-impl Statement for LetStatement {
-    fn statement_node(&self) {}
+    pub type_annotation: Option<TypeAnnotation>,
+    pub value: Expression,
 }
 impl Node for LetStatement {
     fn token_literal(&self) -> &str {
@@ -62,22 +220,32 @@ impl Node for LetStatement {
     fn string(&self) -> String {
         let mut out = String::new();
         out.push_str(self.token_literal());
-        out.push_str(" ");
+        out.push(' ');
         out.push_str(&self.name.string());
+        if let Some(type_annotation) = &self.type_annotation {
+            out.push_str(": ");
+            out.push_str(&type_annotation.string());
+        }
         out.push_str(" = ");
         out.push_str(&self.value.string());
-        out.push_str(";");
+        out.push(';');
         out
     }
 }
 
+// Where a local variable lives: `depth` environments up from the current
+// one, at index `slot` of that environment's slots.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LexicalAddress {
+    pub depth: usize,
+    pub slot: usize,
+}
+
 #[derive(Debug, Clone)]
 pub struct Identifier {
     pub token: Token,
     pub value: String,
-}
-impl Expression for Identifier {
-    fn expression_node(&self) {}
+    pub address: Option<LexicalAddress>, // None for globals and unresolved code
 }
 impl Node for Identifier {
     fn token_literal(&self) -> &str {
@@ -90,13 +258,10 @@ impl Node for Identifier {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ReturnStatement {
     pub token: Token,
-    pub return_value: Box<dyn Expression>,
-}
-impl Statement for ReturnStatement {
-    fn statement_node(&self) {}
+    pub return_value: Expression,
 }
 impl Node for ReturnStatement {
     fn token_literal(&self) -> &str {
@@ -105,20 +270,74 @@ impl Node for ReturnStatement {
     fn string(&self) -> String {
         let mut out = String::new();
         out.push_str(self.token_literal());
-        out.push_str(" ");
+        out.push(' ');
         out.push_str(&self.return_value.string());
-        out.push_str(";");
+        out.push(';');
         out
     }
 }
 
-#[derive(Debug)]
-pub struct ExpressionStmt {
+#[derive(Debug, Clone)]
+pub struct ThrowStatement {
+    pub token: Token,
+    pub value: Expression,
+}
+impl Node for ThrowStatement {
+    fn token_literal(&self) -> &str {
+        &self.token.literal
+    }
+    fn string(&self) -> String {
+        let mut out = String::new();
+        out.push_str(self.token_literal());
+        out.push(' ');
+        out.push_str(&self.value.string());
+        out.push(';');
+        out
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ImportStatement {
+    pub token: Token,
+    pub path: String,
+    pub alias: Identifier,
+}
+impl Node for ImportStatement {
+    fn token_literal(&self) -> &str {
+        &self.token.literal
+    }
+    fn string(&self) -> String {
+        format!(
+            "{} \"{}\" as {};",
+            self.token_literal(),
+            self.path,
+            self.alias.string()
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ExportStatement {
     pub token: Token,
-    pub expression: Box<dyn Expression>,
+    pub statement: LetStatement,
 }
-impl Statement for ExpressionStmt {
-    fn statement_node(&self) {}
+impl Node for ExportStatement {
+    fn token_literal(&self) -> &str {
+        &self.token.literal
+    }
+    fn string(&self) -> String {
+        let mut out = String::new();
+        out.push_str(self.token_literal());
+        out.push(' ');
+        out.push_str(&self.statement.string());
+        out
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ExpressionStmt {
+    pub token: Token,
+    pub expression: Expression,
 }
 impl Node for ExpressionStmt {
     fn token_literal(&self) -> &str {
@@ -129,14 +348,11 @@ impl Node for ExpressionStmt {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct IntegerLiteral {
     pub token: Token,
     pub value: i64,
 }
-impl Expression for IntegerLiteral {
-    fn expression_node(&self) {}
-}
 impl Node for IntegerLiteral {
     fn token_literal(&self) -> &str {
         &self.token.literal
@@ -146,14 +362,11 @@ impl Node for IntegerLiteral {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct PrefixExpression {
     pub token: Token,
     pub operator: String,
-    pub right: Box<dyn Expression>,
-}
-impl Expression for PrefixExpression {
-    fn expression_node(&self) {}
+    pub right: Box<Expression>,
 }
 impl Node for PrefixExpression {
     fn token_literal(&self) -> &str {
@@ -161,23 +374,20 @@ impl Node for PrefixExpression {
     }
     fn string(&self) -> String {
         let mut out = String::new();
-        out.push_str("(");
+        out.push('(');
         out.push_str(&self.operator);
         out.push_str(&self.right.string());
-        out.push_str(")");
+        out.push(')');
         out
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct InfixExpression {
     pub token: Token,
-    pub left: Box<dyn Expression>,
+    pub left: Box<Expression>,
     pub operator: String,
-    pub right: Box<dyn Expression>,
-}
-impl Expression for InfixExpression {
-    fn expression_node(&self) {}
+    pub right: Box<Expression>,
 }
 impl Node for InfixExpression {
     fn token_literal(&self) -> &str {
@@ -185,25 +395,22 @@ impl Node for InfixExpression {
     }
     fn string(&self) -> String {
         let mut out = String::new();
-        out.push_str("(");
+        out.push('(');
         out.push_str(&self.left.string());
-        out.push_str(" ");
+        out.push(' ');
         out.push_str(&self.operator);
-        out.push_str(" ");
+        out.push(' ');
         out.push_str(&self.right.string());
-        out.push_str(")");
+        out.push(')');
         out
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Boolean {
     pub token: Token,
     pub value: bool,
 }
-impl Expression for Boolean {
-    fn expression_node(&self) {}
-}
 impl Node for Boolean {
     fn token_literal(&self) -> &str {
         &self.token.literal
@@ -213,16 +420,13 @@ impl Node for Boolean {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct IfExpression {
     pub token: Token,
-    pub condition: Box<dyn Expression>,
+    pub condition: Box<Expression>,
     pub consequence: BlockStatement,
     pub alternative: Option<BlockStatement>,
 }
-impl Expression for IfExpression {
-    fn expression_node(&self) {}
-}
 impl Node for IfExpression {
     fn token_literal(&self) -> &str {
         &self.token.literal
@@ -231,26 +435,54 @@ impl Node for IfExpression {
         let mut out = String::new();
         out.push_str("if");
         out.push_str(&self.condition.string());
-        out.push_str(" ");
+        out.push(' ');
         out.push_str(&self.consequence.string());
-        match &self.alternative {
-            Some(alternative) => {
-                out.push_str(" else ");
-                out.push_str(&alternative.string());
+        if let Some(alternative) = &self.alternative {
+            out.push_str(" else ");
+            out.push_str(&alternative.string());
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TryExpression {
+    pub token: Token,
+    pub block: BlockStatement,
+    pub catch_parameter: Option<Identifier>,
+    pub catch_block: Option<BlockStatement>,
+    pub finally_block: Option<BlockStatement>,
+}
+impl Node for TryExpression {
+    fn token_literal(&self) -> &str {
+        &self.token.literal
+    }
+    fn string(&self) -> String {
+        let mut out = String::new();
+        out.push_str("try ");
+        out.push_str(&self.block.string());
+        if let Some(catch_block) = &self.catch_block {
+            out.push_str(" catch");
+            if let Some(param) = &self.catch_parameter {
+                out.push('(');
+                out.push_str(&param.string());
+                out.push(')');
             }
-            _ => {}
+            out.push(' ');
+            out.push_str(&catch_block.string());
+        }
+        if let Some(finally_block) = &self.finally_block {
+            out.push_str(" finally ");
+            out.push_str(&finally_block.string());
         }
         out
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct BlockStatement {
     pub token: Token,
-    pub statements: Vec<Box<dyn Statement>>,
-}
-impl Statement for BlockStatement {
-    fn statement_node(&self) {}
+    pub statements: Vec<Statement>,
 }
 impl Node for BlockStatement {
     fn token_literal(&self) -> &str {
@@ -265,16 +497,66 @@ impl Node for BlockStatement {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct FunctionLiteral {
     pub token: Token,
     pub parameters: Vec<Identifier>,
+    pub defaults: Vec<Option<Expression>>, // one entry per parameter
+    pub patterns: Vec<Option<Pattern>>,    // one entry per parameter
+    pub rest: Option<Identifier>,
+    pub parameter_types: Vec<Option<TypeAnnotation>>, // one entry per parameter
+    pub return_type: Option<TypeAnnotation>,
     pub body: BlockStatement,
+    pub name: Option<String>, // of the `let` binding it, for stack traces
 }
-impl Expression for FunctionLiteral {
-    fn expression_node(&self) {}
+impl FunctionLiteral {
+    pub fn parameters_string(&self) -> String {
+        let mut params: Vec<String> = Vec::new();
+        for (idx, p) in self.parameters.iter().enumerate() {
+            let mut param = p.string();
+            if let Some(Some(parameter_type)) = self.parameter_types.get(idx) {
+                param.push_str(": ");
+                param.push_str(&parameter_type.string());
+            }
+            if let Some(Some(default)) = self.defaults.get(idx) {
+                param.push_str(" = ");
+                param.push_str(&default.string());
+            }
+            params.push(param);
+        }
+        if let Some(rest) = &self.rest {
+            params.push(format!("...{}", rest.string()));
+        }
+        params.join(", ")
+    }
 }
 impl Node for FunctionLiteral {
+    fn token_literal(&self) -> &str {
+        &self.token.literal
+    }
+    fn string(&self) -> String {
+        let mut out = String::new();
+        out.push_str(self.token_literal());
+        out.push('(');
+        out.push_str(&self.parameters_string());
+        out.push(')');
+        if let Some(return_type) = &self.return_type {
+            out.push_str(" -> ");
+            out.push_str(&return_type.string());
+            out.push(' ');
+        }
+        out.push_str(&self.body.string());
+        out
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MacroLiteral {
+    pub token: Token,
+    pub parameters: Vec<Identifier>,
+    pub body: BlockStatement,
+}
+impl Node for MacroLiteral {
     fn token_literal(&self) -> &str {
         &self.token.literal
     }
@@ -285,22 +567,20 @@ impl Node for FunctionLiteral {
             params.push(p.string());
         }
         out.push_str(self.token_literal());
-        out.push_str("(");
+        out.push('(');
         out.push_str(&params.join(", "));
-        out.push_str(")");
+        out.push(')');
         out.push_str(&self.body.string());
         out
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct CallExpression {
     pub token: Token,
-    pub function: Box<dyn Expression>,
-    pub arguments: Vec<Box<dyn Expression>>,
-}
-impl Expression for CallExpression {
-    fn expression_node(&self) {}
+    pub function: Box<Expression>,
+    pub arguments: Vec<Expression>,
+    pub named_arguments: Vec<(Identifier, Expression)>,
 }
 impl Node for CallExpression {
     fn token_literal(&self) -> &str {
@@ -312,10 +592,391 @@ impl Node for CallExpression {
         for a in self.arguments.iter() {
             args.push(a.string());
         }
+        for (name, a) in self.named_arguments.iter() {
+            args.push(format!("{}: {}", name.string(), a.string()));
+        }
         out.push_str(&self.function.string());
-        out.push_str("(");
+        out.push('(');
         out.push_str(&args.join(", "));
-        out.push_str(")");
+        out.push(')');
         out
     }
 }
+
+#[derive(Debug, Clone)]
+pub struct StringLiteral {
+    pub token: Token,
+    pub value: String,
+}
+impl Node for StringLiteral {
+    fn token_literal(&self) -> &str {
+        &self.token.literal
+    }
+    fn string(&self) -> String {
+        format!("\"{}\"", self.value)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ArrayLiteral {
+    pub token: Token,
+    pub elements: Vec<Expression>,
+}
+impl Node for ArrayLiteral {
+    fn token_literal(&self) -> &str {
+        &self.token.literal
+    }
+    fn string(&self) -> String {
+        let mut out = String::new();
+        let mut elements: Vec<String> = Vec::new();
+        for e in self.elements.iter() {
+            elements.push(e.string());
+        }
+        out.push('[');
+        out.push_str(&elements.join(", "));
+        out.push(']');
+        out
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HashLiteral {
+    pub token: Token,
+    pub pairs: Vec<(Expression, Expression)>,
+}
+impl Node for HashLiteral {
+    fn token_literal(&self) -> &str {
+        &self.token.literal
+    }
+    fn string(&self) -> String {
+        let mut out = String::new();
+        let mut pairs: Vec<String> = Vec::new();
+        for (key, value) in self.pairs.iter() {
+            pairs.push(format!("{}: {}", key.string(), value.string()));
+        }
+        out.push('{');
+        out.push_str(&pairs.join(", "));
+        out.push('}');
+        out
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IndexExpression {
+    pub token: Token,
+    pub left: Box<Expression>,
+    pub index: Box<Expression>,
+}
+impl Node for IndexExpression {
+    fn token_literal(&self) -> &str {
+        &self.token.literal
+    }
+    fn string(&self) -> String {
+        let mut out = String::new();
+        out.push('(');
+        out.push_str(&self.left.string());
+        out.push('[');
+        out.push_str(&self.index.string());
+        out.push_str("])");
+        out
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MatchExpression {
+    pub token: Token,
+    pub value: Box<Expression>,
+    pub arms: Vec<MatchArm>,
+}
+impl Node for MatchExpression {
+    fn token_literal(&self) -> &str {
+        &self.token.literal
+    }
+    fn string(&self) -> String {
+        let mut out = String::new();
+        let mut arms: Vec<String> = Vec::new();
+        for arm in self.arms.iter() {
+            arms.push(arm.string());
+        }
+        out.push_str("match");
+        out.push_str(&self.value.string());
+        out.push_str(" { ");
+        out.push_str(&arms.join(", "));
+        out.push_str(" }");
+        out
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MatchArm {
+    pub token: Token,
+    pub pattern: Pattern,
+    pub guard: Option<Expression>,
+    pub body: BlockStatement,
+}
+impl Node for MatchArm {
+    fn token_literal(&self) -> &str {
+        &self.token.literal
+    }
+    fn string(&self) -> String {
+        let mut out = String::new();
+        out.push_str(&self.pattern.string());
+        if let Some(guard) = &self.guard {
+            out.push_str(" if ");
+            out.push_str(&guard.string());
+        }
+        out.push_str(" => ");
+        out.push_str(&self.body.string());
+        out
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LetPatternStatement {
+    pub token: Token,
+    pub pattern: Pattern,
+    pub value: Expression,
+}
+impl Node for LetPatternStatement {
+    fn token_literal(&self) -> &str {
+        &self.token.literal
+    }
+    fn string(&self) -> String {
+        let mut out = String::new();
+        out.push_str(self.token_literal());
+        out.push(' ');
+        out.push_str(&self.pattern.string());
+        out.push_str(" = ");
+        out.push_str(&self.value.string());
+        out.push(';');
+        out
+    }
+}
+
+// 0, -1, "text", true
+#[derive(Debug, Clone)]
+pub struct LiteralPattern {
+    pub token: Token,
+    pub value: Expression,
+}
+impl Node for LiteralPattern {
+    fn token_literal(&self) -> &str {
+        &self.token.literal
+    }
+    fn string(&self) -> String {
+        self.value.string()
+    }
+}
+
+// _
+#[derive(Debug, Clone)]
+pub struct WildcardPattern {
+    pub token: Token,
+}
+impl Node for WildcardPattern {
+    fn token_literal(&self) -> &str {
+        &self.token.literal
+    }
+    fn string(&self) -> String {
+        String::from("_")
+    }
+}
+
+// x
+#[derive(Debug, Clone)]
+pub struct BindingPattern {
+    pub token: Token,
+    pub name: Identifier,
+}
+impl Node for BindingPattern {
+    fn token_literal(&self) -> &str {
+        &self.token.literal
+    }
+    fn string(&self) -> String {
+        self.name.string()
+    }
+}
+
+// [first, second, ...rest]
+#[derive(Debug, Clone)]
+pub struct ArrayPattern {
+    pub token: Token,
+    pub elements: Vec<Pattern>,
+    pub rest: Option<Identifier>,
+}
+impl Node for ArrayPattern {
+    fn token_literal(&self) -> &str {
+        &self.token.literal
+    }
+    fn string(&self) -> String {
+        let mut out = String::new();
+        let mut elements: Vec<String> = Vec::new();
+        for e in self.elements.iter() {
+            elements.push(e.string());
+        }
+        if let Some(rest) = &self.rest {
+            elements.push(format!("...{}", rest.string()));
+        }
+        out.push('[');
+        out.push_str(&elements.join(", "));
+        out.push(']');
+        out
+    }
+}
+
+// {"kind": k}
+#[derive(Debug, Clone)]
+pub struct HashPattern {
+    pub token: Token,
+    pub pairs: Vec<(Expression, Pattern)>,
+}
+impl Node for HashPattern {
+    fn token_literal(&self) -> &str {
+        &self.token.literal
+    }
+    fn string(&self) -> String {
+        let mut out = String::new();
+        let mut pairs: Vec<String> = Vec::new();
+        for (key, value) in self.pairs.iter() {
+            pairs.push(format!("{}: {}", key.string(), value.string()));
+        }
+        out.push('{');
+        out.push_str(&pairs.join(", "));
+        out.push('}');
+        out
+    }
+}
+
+pub type ModifierFunc<'a> = dyn FnMut(&mut Expression) + 'a;
+
+// Walks every node of the program, children first, and hands each expression
+// to `modifier`, which may replace it in place.
+pub fn modify_program(program: &mut Program, modifier: &mut ModifierFunc) {
+    for stmt in program.statements.iter_mut() {
+        modify_statement(stmt, modifier);
+    }
+}
+
+pub fn modify_statement(stmt: &mut Statement, modifier: &mut ModifierFunc) {
+    match stmt {
+        Statement::Let(let_stmt) => modify_expression(&mut let_stmt.value, modifier),
+        Statement::LetPattern(let_stmt) => {
+            modify_pattern(&mut let_stmt.pattern, modifier);
+            modify_expression(&mut let_stmt.value, modifier);
+        }
+        Statement::Export(export_stmt) => {
+            modify_expression(&mut export_stmt.statement.value, modifier)
+        }
+        Statement::Return(return_stmt) => {
+            modify_expression(&mut return_stmt.return_value, modifier)
+        }
+        Statement::Throw(throw_stmt) => modify_expression(&mut throw_stmt.value, modifier),
+        Statement::Expression(expression_stmt) => {
+            modify_expression(&mut expression_stmt.expression, modifier)
+        }
+        Statement::Block(block) => modify_block_statement(block, modifier),
+        Statement::Import(_) => {}
+    }
+}
+
+pub fn modify_block_statement(block: &mut BlockStatement, modifier: &mut ModifierFunc) {
+    for stmt in block.statements.iter_mut() {
+        modify_statement(stmt, modifier);
+    }
+}
+
+pub fn modify_expression(exp: &mut Expression, modifier: &mut ModifierFunc) {
+    match exp {
+        Expression::Prefix(prefix_exp) => modify_expression(&mut prefix_exp.right, modifier),
+        Expression::Infix(infix_exp) => {
+            modify_expression(&mut infix_exp.left, modifier);
+            modify_expression(&mut infix_exp.right, modifier);
+        }
+        Expression::If(if_exp) => {
+            modify_expression(&mut if_exp.condition, modifier);
+            modify_block_statement(&mut if_exp.consequence, modifier);
+            if let Some(alternative) = &mut if_exp.alternative {
+                modify_block_statement(alternative, modifier);
+            }
+        }
+        Expression::Try(try_exp) => {
+            modify_block_statement(&mut try_exp.block, modifier);
+            if let Some(catch_block) = &mut try_exp.catch_block {
+                modify_block_statement(catch_block, modifier);
+            }
+            if let Some(finally_block) = &mut try_exp.finally_block {
+                modify_block_statement(finally_block, modifier);
+            }
+        }
+        Expression::Function(function_literal) => {
+            let function_literal = Rc::make_mut(function_literal);
+            for default in function_literal.defaults.iter_mut().flatten() {
+                modify_expression(default, modifier);
+            }
+            for pattern in function_literal.patterns.iter_mut().flatten() {
+                modify_pattern(pattern, modifier);
+            }
+            modify_block_statement(&mut function_literal.body, modifier);
+        }
+        Expression::Macro(macro_literal) => {
+            modify_block_statement(&mut Rc::make_mut(macro_literal).body, modifier)
+        }
+        Expression::Call(call_exp) => {
+            modify_expression(&mut call_exp.function, modifier);
+            for arg in call_exp.arguments.iter_mut() {
+                modify_expression(arg, modifier);
+            }
+            for (_, arg) in call_exp.named_arguments.iter_mut() {
+                modify_expression(arg, modifier);
+            }
+        }
+        Expression::Array(array_literal) => {
+            for element in array_literal.elements.iter_mut() {
+                modify_expression(element, modifier);
+            }
+        }
+        Expression::Hash(hash_literal) => {
+            for (key, value) in hash_literal.pairs.iter_mut() {
+                modify_expression(key, modifier);
+                modify_expression(value, modifier);
+            }
+        }
+        Expression::Index(index_exp) => {
+            modify_expression(&mut index_exp.left, modifier);
+            modify_expression(&mut index_exp.index, modifier);
+        }
+        Expression::Match(match_exp) => {
+            modify_expression(&mut match_exp.value, modifier);
+            for arm in match_exp.arms.iter_mut() {
+                modify_pattern(&mut arm.pattern, modifier);
+                if let Some(guard) = &mut arm.guard {
+                    modify_expression(guard, modifier);
+                }
+                modify_block_statement(&mut arm.body, modifier);
+            }
+        }
+        Expression::Identifier(_)
+        | Expression::Integer(_)
+        | Expression::String(_)
+        | Expression::Boolean(_) => {}
+    }
+    modifier(exp);
+}
+
+pub fn modify_pattern(pattern: &mut Pattern, modifier: &mut ModifierFunc) {
+    match pattern {
+        Pattern::Literal(literal) => modify_expression(&mut literal.value, modifier),
+        Pattern::Array(array_pattern) => {
+            for element in array_pattern.elements.iter_mut() {
+                modify_pattern(element, modifier);
+            }
+        }
+        Pattern::Hash(hash_pattern) => {
+            for (key, value) in hash_pattern.pairs.iter_mut() {
+                modify_expression(key, modifier);
+                modify_pattern(value, modifier);
+            }
+        }
+        Pattern::Wildcard(_) | Pattern::Binding(_) => {}
+    }
+}
@@ -0,0 +1,58 @@
+use super::evaluator::*;
+use super::object::*;
+use std::collections::HashMap;
+
+// Every name `lookup` knows, for suggestions.
+pub const NAMES: &[&str] = &["len", "puts"];
+
+pub fn lookup(name: &str) -> Option<Builtin> {
+    match name {
+        "len" => Some(Builtin::new("len", len)),
+        "puts" => Some(Builtin::new("puts", puts)),
+        _ => None,
+    }
+}
+
+fn len(args: Vec<Value>, named: HashMap<String, Value>) -> Value {
+    if let Some(err) = no_named_arguments("len", &named) {
+        return err;
+    }
+    if args.len() != 1 {
+        return Value::from(new_error(
+            ARGUMENT_ERROR,
+            format_args!("wrong number of arguments: want=1, got={}", args.len()),
+        ));
+    }
+    match &args[0] {
+        Value::Str(string) => Value::Integer(string.len() as i64),
+        Value::Array(elements) => Value::Integer(elements.len() as i64),
+        _ => Value::from(new_error(
+            TYPE_ERROR,
+            format_args!(
+                "argument to `len` not supported, got {}",
+                args[0].get_type()
+            ),
+        )),
+    }
+}
+
+fn puts(args: Vec<Value>, named: HashMap<String, Value>) -> Value {
+    if let Some(err) = no_named_arguments("puts", &named) {
+        return err;
+    }
+    for arg in args.iter() {
+        println!("{}", arg.inspect());
+    }
+    NULL
+}
+
+fn no_named_arguments(name: &str, named: &HashMap<String, Value>) -> Option<Value> {
+    let mut names: Vec<&String> = named.keys().collect();
+    names.sort();
+    names.first().map(|n| {
+        Value::from(new_error(
+            ARGUMENT_ERROR,
+            format_args!("unknown named argument for {}: {}", name, n),
+        ))
+    })
+}
@@ -0,0 +1,831 @@
+use super::ast::*;
+use super::builtins::*;
+use super::code::Opcode;
+use super::diagnostics::did_you_mean;
+use super::environment::*;
+use super::evaluator::*;
+use super::lexer::Span;
+use super::module::*;
+use super::object::*;
+use super::vm::{binary_operation, index_value, is_truthy, negate, splice_unquoted};
+use std::cell::*;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::rc::*;
+
+// Turns the AST into nested Rust closures once, so running a program does
+// not dispatch on nodes or format them again. Values and environments are
+// the evaluator's, and so are the semantics.
+
+// Calls recurse on the Rust stack. When a call finds less than `RED_ZONE`
+// left, it runs on a new `STACK_SIZE` segment, so only `max_depth` bounds
+// how deep calls go, whatever thread the engine runs on.
+const RED_ZONE: usize = 1 << 20;
+pub const STACK_SIZE: usize = 16 << 20;
+
+// `depth` counts calls against the `max_depth` of the limits.
+pub struct Frame {
+    pub env: Rc<RefCell<Environment>>,
+    pub depth: usize,
+    pub limits: Rc<ExecutionLimits>,
+}
+
+// Why a closure stopped early: an error on its way to a `catch`, or a
+// `return` on its way out of the function.
+pub enum Unwind {
+    Error(Rc<Error>),
+    Return(Value),
+}
+impl From<Rc<Error>> for Unwind {
+    fn from(err: Rc<Error>) -> Unwind {
+        Unwind::Error(err)
+    }
+}
+
+pub type Code = Box<dyn Fn(&mut Frame) -> Result<Value, Unwind>>;
+// `None` for statements that only bind names
+type StatementCode = Box<dyn Fn(&mut Frame) -> Result<Option<Value>, Unwind>>;
+type Block = Rc<Vec<StatementCode>>;
+
+enum PatternCode {
+    Wildcard,
+    Binding(Identifier),
+    Literal(Code),
+    Array(Vec<PatternCode>, Option<Identifier>),
+    Hash(Vec<(Code, PatternCode)>),
+}
+
+// A pattern with its source, for the error when it does not match.
+struct Destructuring {
+    pattern: PatternCode,
+    display: String,
+}
+
+pub struct FunctionCode {
+    defaults: Vec<Option<Code>>,
+    patterns: Vec<Option<Destructuring>>,
+    body: Vec<StatementCode>,
+}
+
+pub struct ProgramCode {
+    statements: Vec<StatementCode>,
+}
+impl ProgramCode {
+    // Same result as `evaluator::eval` on the program this was compiled from.
+    pub fn run(&self, env: &Rc<RefCell<Environment>>) -> Option<Value> {
+        self.run_with_limits(env, &ExecutionLimits::default())
+    }
+
+    pub fn run_with_limits(
+        &self,
+        env: &Rc<RefCell<Environment>>,
+        limits: &ExecutionLimits,
+    ) -> Option<Value> {
+        let mut frame = Frame {
+            env: Rc::clone(env),
+            depth: 0,
+            limits: Rc::new(limits.clone()),
+        };
+        let mut result = None;
+        for stmt in self.statements.iter() {
+            match stmt(&mut frame) {
+                Ok(val) => result = val,
+                Err(Unwind::Return(val)) => return Some(val),
+                Err(Unwind::Error(err)) => return Some(Value::Error(err)),
+            }
+        }
+        result
+    }
+}
+
+pub fn compile(program: &Program) -> ProgramCode {
+    ProgramCode {
+        statements: program.statements.iter().map(compile_statement).collect(),
+    }
+}
+
+pub fn eval(program: &Program, env: &Rc<RefCell<Environment>>) -> Option<Value> {
+    compile(program).run(env)
+}
+
+pub fn eval_with_limits(
+    program: &Program,
+    env: &Rc<RefCell<Environment>>,
+    limits: &ExecutionLimits,
+) -> Option<Value> {
+    compile(program).run_with_limits(env, limits)
+}
+
+fn compile_statement(stmt: &Statement) -> StatementCode {
+    match stmt {
+        Statement::Expression(expression_stmt) => {
+            let exp = compile_expression(&expression_stmt.expression);
+            Box::new(move |frame| exp(frame).map(Some))
+        }
+        Statement::Block(block) => {
+            let block = compile_block(block);
+            Box::new(move |frame| run_block(&block, frame))
+        }
+        Statement::Let(let_stmt) => compile_let(let_stmt),
+        Statement::Export(export_stmt) => compile_let(&export_stmt.statement),
+        Statement::LetPattern(let_stmt) => {
+            let value = compile_expression(&let_stmt.value);
+            let destructuring = compile_destructuring(&let_stmt.pattern);
+            Box::new(move |frame| {
+                let val = value(frame)?;
+                bind_pattern(&destructuring, &val, frame)?;
+                Ok(None)
+            })
+        }
+        Statement::Return(return_stmt) => {
+            let value = compile_expression(&return_stmt.return_value);
+            Box::new(move |frame| Err(Unwind::Return(value(frame)?)))
+        }
+        Statement::Throw(throw_stmt) => {
+            let value = compile_expression(&throw_stmt.value);
+            let span = throw_stmt.token.span;
+            Box::new(move |frame| {
+                let val = value(frame)?;
+                Err(Unwind::Error(raised_at(
+                    Rc::new(Error::from_value(&val)),
+                    span,
+                )))
+            })
+        }
+        Statement::Import(import_stmt) => {
+            let import_stmt = import_stmt.clone();
+            let span = import_stmt.token.span;
+            Box::new(move |frame| {
+                let (loader, file) = {
+                    let env = frame.env.borrow();
+                    (env.loader(), env.file())
+                };
+                let loader = loader.ok_or_else(|| {
+                    let err = new_error(
+                        IMPORT_ERROR,
+                        format_args!("no module loader available for: {}", import_stmt.path),
+                    );
+                    raised_at(Rc::new(err), span)
+                })?;
+                match import_module(&loader, &import_stmt.path, file.as_deref()) {
+                    Ok(module) => {
+                        define(
+                            &frame.env,
+                            &import_stmt.alias,
+                            Value::Module(Rc::new(module)),
+                        );
+                        Ok(None)
+                    }
+                    Err(Value::Error(err)) => Err(Unwind::Error(raised_at(err, span))),
+                    Err(val) => Err(Unwind::Error(raised_at(
+                        Rc::new(Error::from_value(&val)),
+                        span,
+                    ))),
+                }
+            })
+        }
+    }
+}
+
+fn compile_let(let_stmt: &LetStatement) -> StatementCode {
+    let value = compile_expression(&let_stmt.value);
+    let name = let_stmt.name.clone();
+    Box::new(move |frame| {
+        let val = value(frame)?;
+        define(&frame.env, &name, val);
+        Ok(None)
+    })
+}
+
+fn compile_block(block: &BlockStatement) -> Block {
+    Rc::new(block.statements.iter().map(compile_statement).collect())
+}
+
+fn run_block(block: &[StatementCode], frame: &mut Frame) -> Result<Option<Value>, Unwind> {
+    let mut result = None;
+    for stmt in block.iter() {
+        result = stmt(frame)?;
+    }
+    Ok(result)
+}
+
+fn compile_expression(exp: &Expression) -> Code {
+    match exp {
+        Expression::Integer(integer_literal) => {
+            let val = Value::Integer(integer_literal.value);
+            Box::new(move |_| Ok(val.clone()))
+        }
+        Expression::String(string_literal) => {
+            let val = Value::Str(Rc::from(string_literal.value.as_str()));
+            Box::new(move |_| Ok(val.clone()))
+        }
+        Expression::Boolean(boolean) => {
+            let val = Value::Boolean(boolean.value);
+            Box::new(move |_| Ok(val.clone()))
+        }
+        Expression::Identifier(ident) => compile_identifier(ident),
+        Expression::Prefix(prefix_exp) => {
+            let right = compile_expression(&prefix_exp.right);
+            let span = prefix_exp.token.span;
+            match prefix_exp.operator.as_str() {
+                "!" => Box::new(move |frame| Ok(Value::Boolean(!is_truthy(&right(frame)?)))),
+                "-" => Box::new(move |frame| match right(frame)? {
+                    Value::Integer(value) => Ok(negate(value).map_err(|err| raised_at(err, span))?),
+                    val => Err(type_error(
+                        span,
+                        format_args!("unknown operator: -{}", val.get_type()),
+                    )),
+                }),
+                _ => {
+                    let operator = prefix_exp.operator.clone();
+                    Box::new(move |frame| {
+                        let val = right(frame)?;
+                        Err(type_error(
+                            span,
+                            format_args!("unknown operator: {}{}", operator, val.get_type()),
+                        ))
+                    })
+                }
+            }
+        }
+        Expression::Infix(infix_exp) => {
+            let left = compile_expression(&infix_exp.left);
+            let right = compile_expression(&infix_exp.right);
+            let span = infix_exp.token.span;
+            match infix_opcode(&infix_exp.operator) {
+                Some(op) => Box::new(move |frame| {
+                    let left = left(frame)?;
+                    let right = right(frame)?;
+                    Ok(binary_operation(op, &left, &right).map_err(|err| raised_at(err, span))?)
+                }),
+                _ => {
+                    let operator = infix_exp.operator.clone();
+                    Box::new(move |frame| {
+                        let left = left(frame)?;
+                        let right = right(frame)?;
+                        let verb = if left.get_type() != right.get_type() {
+                            "type mismatch"
+                        } else {
+                            "unknown operator"
+                        };
+                        Err(type_error(
+                            span,
+                            format_args!(
+                                "{}: {} {} {}",
+                                verb,
+                                left.get_type(),
+                                operator,
+                                right.get_type()
+                            ),
+                        ))
+                    })
+                }
+            }
+        }
+        Expression::If(if_exp) => {
+            let condition = compile_expression(&if_exp.condition);
+            let consequence = compile_block(&if_exp.consequence);
+            let alternative = if_exp.alternative.as_ref().map(compile_block);
+            Box::new(move |frame| {
+                let result = if is_truthy(&condition(frame)?) {
+                    run_block(&consequence, frame)?
+                } else if let Some(alternative) = &alternative {
+                    run_block(alternative, frame)?
+                } else {
+                    None
+                };
+                Ok(result.unwrap_or(Value::Null))
+            })
+        }
+        Expression::Function(function_literal) => {
+            let function_literal = Rc::clone(function_literal);
+            let code = Rc::new(compile_function(&function_literal));
+            Box::new(move |frame| {
+                Ok(Value::Function(Rc::new(Function {
+                    function_literal: Rc::clone(&function_literal),
+                    env: Rc::clone(&frame.env),
+                    compiled: Some(Rc::clone(&code)),
+                    body: None,
+                })))
+            })
+        }
+        Expression::Macro(macro_literal) => {
+            let macro_literal = Rc::clone(macro_literal);
+            Box::new(move |frame| {
+                Ok(Value::Macro(Rc::new(Macro {
+                    macro_literal: Rc::clone(&macro_literal),
+                    env: Rc::clone(&frame.env),
+                })))
+            })
+        }
+        Expression::Call(call_exp) => compile_call(call_exp),
+        Expression::Array(array_literal) => {
+            let elements: Vec<Code> = array_literal
+                .elements
+                .iter()
+                .map(compile_expression)
+                .collect();
+            Box::new(move |frame| {
+                let mut values = Vec::with_capacity(elements.len());
+                for element in elements.iter() {
+                    values.push(element(frame)?);
+                }
+                Ok(Value::Array(Rc::new(values)))
+            })
+        }
+        Expression::Hash(hash_literal) => {
+            let pairs: Vec<(Code, Code)> = hash_literal
+                .pairs
+                .iter()
+                .map(|(key, value)| (compile_expression(key), compile_expression(value)))
+                .collect();
+            let span = hash_literal.token.span;
+            Box::new(move |frame| {
+                let mut hash = BTreeMap::new();
+                for (key_code, value_code) in pairs.iter() {
+                    let key = key_code(frame)?;
+                    let hash_key = key.hash_key().ok_or_else(|| {
+                        type_error(
+                            span,
+                            format_args!("unusable as hash key: {}", key.get_type()),
+                        )
+                    })?;
+                    let value = value_code(frame)?;
+                    hash.insert(hash_key, HashPair { key, value });
+                }
+                Ok(Value::Hash(Rc::new(hash)))
+            })
+        }
+        Expression::Index(index_exp) => {
+            let left = compile_expression(&index_exp.left);
+            let index = compile_expression(&index_exp.index);
+            let span = index_exp.token.span;
+            Box::new(move |frame| {
+                let left = left(frame)?;
+                let index = index(frame)?;
+                Ok(index_value(&left, &index).map_err(|err| raised_at(err, span))?)
+            })
+        }
+        Expression::Match(match_exp) => compile_match(match_exp),
+        Expression::Try(try_exp) => compile_try(try_exp),
+    }
+}
+
+fn compile_identifier(ident: &Identifier) -> Code {
+    let ident = ident.clone();
+    let builtin = lookup(&ident.value).map(|builtin| Value::Builtin(Rc::new(builtin)));
+    Box::new(move |frame| {
+        let env = frame.env.borrow();
+        // an empty slot means the local is not bound yet, look for an outer one by name
+        if let Some(val) = ident.address.and_then(|address| env.get_at(address)) {
+            return Ok(val);
+        }
+        if let Some(val) = env.get(&ident.value) {
+            return Ok(val);
+        }
+        match &builtin {
+            Some(builtin) => Ok(builtin.clone()),
+            _ => {
+                let names = env.names();
+                let candidates = names
+                    .iter()
+                    .map(String::as_str)
+                    .chain(NAMES.iter().copied());
+                let err = new_error(
+                    NAME_ERROR,
+                    format_args!(
+                        "identifier not found: {}{}",
+                        ident.value,
+                        did_you_mean(&ident.value, candidates)
+                    ),
+                );
+                Err(Unwind::Error(raised_at(Rc::new(err), ident.token.span)))
+            }
+        }
+    })
+}
+
+fn compile_call(call_exp: &CallExpression) -> Code {
+    if call_exp.function.token_literal() == "quote" && call_exp.arguments.len() == 1 {
+        return compile_quote(&call_exp.arguments[0]);
+    }
+    let function = compile_expression(&call_exp.function);
+    let arguments: Vec<Code> = call_exp.arguments.iter().map(compile_expression).collect();
+    let named_arguments: Vec<(String, Code)> = call_exp
+        .named_arguments
+        .iter()
+        .map(|(name, arg)| (name.value.clone(), compile_expression(arg)))
+        .collect();
+    let name = call_exp.function.string();
+    let span = call_exp.token.span;
+    let argc = arguments.len() + named_arguments.len();
+    Box::new(move |frame| {
+        let function = function(frame)?;
+        let mut args = Vec::with_capacity(arguments.len());
+        for arg in arguments.iter() {
+            args.push(arg(frame)?);
+        }
+        let mut named = Vec::with_capacity(named_arguments.len());
+        for (arg_name, arg) in named_arguments.iter() {
+            named.push((arg_name.clone(), arg(frame)?));
+        }
+        apply_function(function.clone(), args, named, frame).map_err(|err| {
+            let mut err = raised_at(err, span);
+            let name = function.function_name().unwrap_or(&name);
+            Rc::make_mut(&mut err)
+                .stack
+                .push(StackFrame::new(name, span, argc));
+            Unwind::Error(err)
+        })
+    })
+}
+
+// The `unquote` arguments are compiled in the order `modify_expression`
+// visits them, which is the order `splice_unquoted` fills them back in.
+fn compile_quote(node: &Expression) -> Code {
+    let mut template = node.clone();
+    let mut unquoted: Vec<Code> = Vec::new();
+    modify_expression(&mut template, &mut |exp: &mut Expression| {
+        if let Expression::Call(call_exp) = exp {
+            if call_exp.function.token_literal() == "unquote" && call_exp.arguments.len() == 1 {
+                unquoted.push(compile_expression(&call_exp.arguments[0]));
+            }
+        }
+    });
+    Box::new(move |frame| {
+        let mut values = Vec::with_capacity(unquoted.len());
+        for code in unquoted.iter() {
+            values.push(code(frame)?);
+        }
+        let node = splice_unquoted(template.clone(), values)?;
+        Ok(Value::Quote(Rc::new(node)))
+    })
+}
+
+fn compile_function(function_literal: &FunctionLiteral) -> FunctionCode {
+    FunctionCode {
+        defaults: function_literal
+            .defaults
+            .iter()
+            .map(|default| default.as_ref().map(compile_expression))
+            .collect(),
+        patterns: function_literal
+            .patterns
+            .iter()
+            .map(|pattern| pattern.as_ref().map(compile_destructuring))
+            .collect(),
+        body: function_literal
+            .body
+            .statements
+            .iter()
+            .map(compile_statement)
+            .collect(),
+    }
+}
+
+fn apply_function(
+    func: Value,
+    args: Vec<Value>,
+    named: Vec<(String, Value)>,
+    caller: &Frame,
+) -> Result<Value, Rc<Error>> {
+    let function = match func {
+        Value::Builtin(builtin) => {
+            let mut named_map: HashMap<String, Value> = HashMap::new();
+            for (name, val) in named {
+                if named_map.contains_key(&name) {
+                    return Err(Rc::new(new_error(
+                        ARGUMENT_ERROR,
+                        format_args!("duplicate argument: {}", name),
+                    )));
+                }
+                named_map.insert(name, val);
+            }
+            return match (builtin.function)(args, named_map) {
+                Value::Error(err) => Err(err),
+                val => Ok(val),
+            };
+        }
+        Value::Function(function) => function,
+        _ => {
+            return Err(type_error_value(format_args!(
+                "not a function: {}",
+                func.get_type()
+            )))
+        }
+    };
+    caller.limits.check_interrupt()?;
+    if caller.depth >= caller.limits.max_depth {
+        return Err(Rc::new(new_error(
+            RECURSION_ERROR,
+            format_args!("maximum recursion depth exceeded"),
+        )));
+    }
+    // functions the evaluator made have no code yet
+    let code = match &function.compiled {
+        Some(code) => Rc::clone(code),
+        _ => Rc::new(compile_function(&function.function_literal)),
+    };
+
+    let mut frame = extend_function_frame(&function, &code, args, named, caller)?;
+    stacker::maybe_grow(RED_ZONE, STACK_SIZE, || {
+        match run_block(&code.body, &mut frame) {
+            Ok(val) => Ok(val.unwrap_or(Value::Null)),
+            Err(Unwind::Return(val)) => Ok(val),
+            Err(Unwind::Error(err)) => Err(err),
+        }
+    })
+}
+
+fn extend_function_frame(
+    function: &Function,
+    code: &FunctionCode,
+    args: Vec<Value>,
+    named: Vec<(String, Value)>,
+    caller: &Frame,
+) -> Result<Frame, Rc<Error>> {
+    let function_literal = &function.function_literal;
+    let got = args.len() + named.len();
+    if function_literal.rest.is_none() && args.len() > function_literal.parameters.len() {
+        return Err(Rc::new(wrong_number_of_arguments(function_literal, got)));
+    }
+
+    let mut args = args.into_iter();
+    let mut slots: Vec<Option<Value>> = function_literal
+        .parameters
+        .iter()
+        .map(|_| args.next())
+        .collect();
+    for (name, val) in named {
+        match function_literal
+            .parameters
+            .iter()
+            .position(|p| p.value == name)
+        {
+            Some(idx) if slots[idx].is_some() => {
+                return Err(Rc::new(new_error(
+                    ARGUMENT_ERROR,
+                    format_args!("duplicate argument: {}", name),
+                )));
+            }
+            Some(idx) => slots[idx] = Some(val),
+            _ => {
+                return Err(Rc::new(new_error(
+                    ARGUMENT_ERROR,
+                    format_args!("unknown named argument: {}", name),
+                )));
+            }
+        }
+    }
+    if slots
+        .iter()
+        .zip(code.defaults.iter())
+        .any(|(slot, default)| slot.is_none() && default.is_none())
+    {
+        return Err(Rc::new(wrong_number_of_arguments(function_literal, got)));
+    }
+
+    let mut frame = Frame {
+        env: Rc::new(RefCell::new(new_enclosed_environment(&function.env))),
+        depth: caller.depth + 1,
+        limits: Rc::clone(&caller.limits),
+    };
+    for (((param, default), pattern), slot) in function_literal
+        .parameters
+        .iter()
+        .zip(code.defaults.iter())
+        .zip(code.patterns.iter())
+        .zip(slots)
+    {
+        let val = match (slot, default) {
+            (Some(val), _) => val,
+            (None, Some(default)) => match default(&mut frame) {
+                Ok(val) | Err(Unwind::Return(val)) => val,
+                Err(Unwind::Error(err)) => return Err(err),
+            },
+            (None, None) => Value::Null,
+        };
+        match pattern {
+            Some(destructuring) => match bind_pattern(destructuring, &val, &mut frame) {
+                Ok(()) | Err(Unwind::Return(_)) => {}
+                Err(Unwind::Error(err)) => return Err(err),
+            },
+            _ => define(&frame.env, param, val),
+        }
+    }
+    if let Some(rest) = &function_literal.rest {
+        define(&frame.env, rest, Value::Array(Rc::new(args.collect())));
+    }
+    Ok(frame)
+}
+
+fn compile_match(match_exp: &MatchExpression) -> Code {
+    let value = compile_expression(&match_exp.value);
+    let arms: Vec<(PatternCode, Option<Code>, Block)> = match_exp
+        .arms
+        .iter()
+        .map(|arm| {
+            (
+                compile_pattern(&arm.pattern),
+                arm.guard.as_ref().map(compile_expression),
+                compile_block(&arm.body),
+            )
+        })
+        .collect();
+    let span = match_exp.token.span;
+    Box::new(move |frame| {
+        let value = value(frame)?;
+        for (pattern, guard, body) in arms.iter() {
+            let mut bindings: Vec<(&Identifier, Value)> = Vec::new();
+            match match_pattern(pattern, &value, frame, &mut bindings) {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(Unwind::Error(err)) => return Err(Unwind::Error(raised_at(err, span))),
+                Err(unwind) => return Err(unwind),
+            }
+            let mut arm_frame = Frame {
+                env: Rc::new(RefCell::new(new_enclosed_environment(&frame.env))),
+                depth: frame.depth,
+                limits: Rc::clone(&frame.limits),
+            };
+            for (ident, val) in bindings {
+                define(&arm_frame.env, ident, val);
+            }
+            if let Some(guard) = guard {
+                if !is_truthy(&guard(&mut arm_frame)?) {
+                    continue;
+                }
+            }
+            return Ok(run_block(body, &mut arm_frame)?.unwrap_or(Value::Null));
+        }
+        let err = new_error(
+            MATCH_ERROR,
+            format_args!("no pattern matched value: {}", value.inspect()),
+        );
+        Err(Unwind::Error(raised_at(Rc::new(err), span)))
+    })
+}
+
+fn compile_try(try_exp: &TryExpression) -> Code {
+    let block = compile_block(&try_exp.block);
+    let catch_parameter = try_exp.catch_parameter.clone();
+    let catch_block = try_exp.catch_block.as_ref().map(compile_block);
+    let finally_block = try_exp.finally_block.as_ref().map(compile_block);
+    Box::new(move |frame| {
+        let mut result = run_block(&block, frame);
+        if let Some(catch_block) = &catch_block {
+            match &result {
+                Err(Unwind::Error(err)) if !is_interrupt(err) => {
+                    let err = Rc::clone(err);
+                    let mut catch_frame = Frame {
+                        env: Rc::new(RefCell::new(new_enclosed_environment(&frame.env))),
+                        depth: frame.depth,
+                        limits: Rc::clone(&frame.limits),
+                    };
+                    if let Some(param) = &catch_parameter {
+                        define(&catch_frame.env, param, err.to_value());
+                    }
+                    result = run_block(catch_block, &mut catch_frame);
+                }
+                _ => {}
+            }
+        }
+        if let Some(finally_block) = &finally_block {
+            if !matches!(&result, Err(Unwind::Error(err)) if is_interrupt(err)) {
+                // an error or return inside `finally` replaces the pending result
+                run_block(finally_block, frame)?;
+            }
+        }
+        Ok(result?.unwrap_or(Value::Null))
+    })
+}
+
+// An interrupt stops the script rather than report to it, so neither `catch`
+// nor `finally` runs.
+fn is_interrupt(err: &Error) -> bool {
+    err.kind == INTERRUPT_ERROR
+}
+
+fn compile_destructuring(pattern: &Pattern) -> Destructuring {
+    Destructuring {
+        pattern: compile_pattern(pattern),
+        display: pattern.string(),
+    }
+}
+
+fn compile_pattern(pattern: &Pattern) -> PatternCode {
+    match pattern {
+        Pattern::Wildcard(_) => PatternCode::Wildcard,
+        Pattern::Binding(binding) => PatternCode::Binding(binding.name.clone()),
+        Pattern::Literal(literal) => PatternCode::Literal(compile_expression(&literal.value)),
+        Pattern::Array(array_pattern) => PatternCode::Array(
+            array_pattern.elements.iter().map(compile_pattern).collect(),
+            array_pattern.rest.clone(),
+        ),
+        Pattern::Hash(hash_pattern) => PatternCode::Hash(
+            hash_pattern
+                .pairs
+                .iter()
+                .map(|(key, p)| (compile_expression(key), compile_pattern(p)))
+                .collect(),
+        ),
+    }
+}
+
+fn bind_pattern(
+    destructuring: &Destructuring,
+    value: &Value,
+    frame: &mut Frame,
+) -> Result<(), Unwind> {
+    let mut bindings: Vec<(&Identifier, Value)> = Vec::new();
+    if !match_pattern(&destructuring.pattern, value, frame, &mut bindings)? {
+        return Err(Unwind::Error(Rc::new(new_error(
+            MATCH_ERROR,
+            format_args!(
+                "pattern {} does not match value: {}",
+                destructuring.display,
+                value.inspect()
+            ),
+        ))));
+    }
+    for (ident, val) in bindings {
+        define(&frame.env, ident, val);
+    }
+    Ok(())
+}
+
+fn match_pattern<'a>(
+    pattern: &'a PatternCode,
+    value: &Value,
+    frame: &mut Frame,
+    bindings: &mut Vec<(&'a Identifier, Value)>,
+) -> Result<bool, Unwind> {
+    match pattern {
+        PatternCode::Wildcard => Ok(true),
+        PatternCode::Binding(name) => {
+            bindings.push((name, value.clone()));
+            Ok(true)
+        }
+        PatternCode::Literal(literal) => Ok(objects_equal(&literal(frame)?, value)),
+        PatternCode::Array(patterns, rest) => {
+            let elements = match value {
+                Value::Array(elements) => elements,
+                _ => return Ok(false),
+            };
+            let count = patterns.len();
+            if elements.len() < count || (rest.is_none() && elements.len() != count) {
+                return Ok(false);
+            }
+            for (p, v) in patterns.iter().zip(elements.iter()) {
+                if !match_pattern(p, v, frame, bindings)? {
+                    return Ok(false);
+                }
+            }
+            if let Some(rest) = rest {
+                bindings.push((rest, Value::Array(Rc::new(elements[count..].to_vec()))));
+            }
+            Ok(true)
+        }
+        PatternCode::Hash(pairs) => {
+            let hash = match value {
+                Value::Hash(hash) => hash,
+                _ => return Ok(false),
+            };
+            for (key, p) in pairs.iter() {
+                let key = key(frame)?;
+                let pair = key.hash_key().and_then(|hash_key| hash.get(&hash_key));
+                match pair {
+                    Some(pair) => {
+                        if !match_pattern(p, &pair.value, frame, bindings)? {
+                            return Ok(false);
+                        }
+                    }
+                    _ => return Ok(false),
+                }
+            }
+            Ok(true)
+        }
+    }
+}
+
+fn infix_opcode(operator: &str) -> Option<Opcode> {
+    match operator {
+        "+" => Some(Opcode::Add),
+        "-" => Some(Opcode::Sub),
+        "*" => Some(Opcode::Mul),
+        "/" => Some(Opcode::Div),
+        "==" => Some(Opcode::Equal),
+        "!=" => Some(Opcode::NotEqual),
+        ">" => Some(Opcode::GreaterThan),
+        "<" => Some(Opcode::LessThan),
+        _ => None,
+    }
+}
+
+fn type_error_value(args: std::fmt::Arguments<'_>) -> Rc<Error> {
+    Rc::new(new_error(TYPE_ERROR, args))
+}
+
+fn type_error(span: Span, args: std::fmt::Arguments<'_>) -> Unwind {
+    Unwind::Error(raised_at(type_error_value(args), span))
+}
@@ -0,0 +1,203 @@
+// Bytecode shared by the compiler and the virtual machine. An instruction is
+// a one byte opcode followed by big-endian operands.
+pub type Instructions = Vec<u8>;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Opcode {
+    Constant,
+    Pop,
+    Null,
+    True,
+    False,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Equal,
+    NotEqual,
+    GreaterThan,
+    LessThan,
+    Minus,
+    Bang,
+    Jump,
+    JumpNotTruthy,
+    JumpIfBound,
+    GetGlobal,
+    SetGlobal,
+    GetLocal,
+    SetLocal,
+    GetUpvalue,
+    SetUpvalue,
+    GetFree,
+    Array,
+    Hash,
+    CheckHashKey,
+    Index,
+    Call,
+    ReturnValue,
+    Return,
+    Closure,
+    Throw,
+    SetupCatch,
+    SetupFinally,
+    PopHandler,
+    EndFinally,
+    ErrorValue,
+    MatchArray,
+    MatchHash,
+    MatchKey,
+    MatchEqual,
+    Element,
+    RestOf,
+    NoMatch,
+    PatternMismatch,
+    Quote,
+    Import,
+}
+
+const OPCODES: [Opcode; 49] = [
+    Opcode::Constant,
+    Opcode::Pop,
+    Opcode::Null,
+    Opcode::True,
+    Opcode::False,
+    Opcode::Add,
+    Opcode::Sub,
+    Opcode::Mul,
+    Opcode::Div,
+    Opcode::Equal,
+    Opcode::NotEqual,
+    Opcode::GreaterThan,
+    Opcode::LessThan,
+    Opcode::Minus,
+    Opcode::Bang,
+    Opcode::Jump,
+    Opcode::JumpNotTruthy,
+    Opcode::JumpIfBound,
+    Opcode::GetGlobal,
+    Opcode::SetGlobal,
+    Opcode::GetLocal,
+    Opcode::SetLocal,
+    Opcode::GetUpvalue,
+    Opcode::SetUpvalue,
+    Opcode::GetFree,
+    Opcode::Array,
+    Opcode::Hash,
+    Opcode::CheckHashKey,
+    Opcode::Index,
+    Opcode::Call,
+    Opcode::ReturnValue,
+    Opcode::Return,
+    Opcode::Closure,
+    Opcode::Throw,
+    Opcode::SetupCatch,
+    Opcode::SetupFinally,
+    Opcode::PopHandler,
+    Opcode::EndFinally,
+    Opcode::ErrorValue,
+    Opcode::MatchArray,
+    Opcode::MatchHash,
+    Opcode::MatchKey,
+    Opcode::MatchEqual,
+    Opcode::Element,
+    Opcode::RestOf,
+    Opcode::NoMatch,
+    Opcode::PatternMismatch,
+    Opcode::Quote,
+    Opcode::Import,
+];
+
+impl Opcode {
+    pub fn from_byte(byte: u8) -> Option<Opcode> {
+        OPCODES.get(byte as usize).copied()
+    }
+
+    // Widths in bytes of the operands that follow the opcode.
+    pub fn operand_widths(&self) -> &'static [usize] {
+        match self {
+            Opcode::Constant
+            | Opcode::Jump
+            | Opcode::JumpNotTruthy
+            | Opcode::GetGlobal
+            | Opcode::SetGlobal
+            | Opcode::GetLocal
+            | Opcode::SetLocal
+            | Opcode::GetUpvalue
+            | Opcode::SetUpvalue
+            | Opcode::GetFree
+            | Opcode::Array
+            | Opcode::Hash
+            | Opcode::Closure
+            | Opcode::SetupCatch
+            | Opcode::SetupFinally
+            | Opcode::MatchHash
+            | Opcode::MatchKey
+            | Opcode::MatchEqual
+            | Opcode::Element
+            | Opcode::RestOf
+            | Opcode::PatternMismatch
+            | Opcode::Import => &[2],
+            Opcode::JumpIfBound | Opcode::Quote => &[2, 2],
+            Opcode::Call => &[1, 1, 2],
+            Opcode::MatchArray => &[2, 1, 2],
+            _ => &[],
+        }
+    }
+}
+
+pub fn make(op: Opcode, operands: &[usize]) -> Vec<u8> {
+    let widths = op.operand_widths();
+    let mut instruction = vec![op as u8];
+    for (operand, width) in operands.iter().zip(widths.iter()) {
+        match width {
+            2 => instruction.extend_from_slice(&(*operand as u16).to_be_bytes()),
+            _ => instruction.push(*operand as u8),
+        }
+    }
+    instruction
+}
+
+pub fn read_u16(ins: &[u8], offset: usize) -> usize {
+    u16::from_be_bytes([ins[offset], ins[offset + 1]]) as usize
+}
+
+// Decodes the operands of `op` starting at `ins[0]`. Returns them with the
+// number of bytes read.
+pub fn read_operands(op: Opcode, ins: &[u8]) -> (Vec<usize>, usize) {
+    let mut operands = Vec::new();
+    let mut offset = 0;
+    for width in op.operand_widths() {
+        match width {
+            2 => operands.push(read_u16(ins, offset)),
+            _ => operands.push(ins[offset] as usize),
+        }
+        offset += width;
+    }
+    (operands, offset)
+}
+
+// One instruction per line, e.g. `0003 Call 2 0 1`.
+pub fn disassemble(ins: &[u8]) -> String {
+    let mut out = String::new();
+    let mut ip = 0;
+    while ip < ins.len() {
+        let op = match Opcode::from_byte(ins[ip]) {
+            Some(op) => op,
+            _ => {
+                out.push_str(&format!("{:04} ERROR: unknown opcode {}\n", ip, ins[ip]));
+                ip += 1;
+                continue;
+            }
+        };
+        let (operands, read) = read_operands(op, &ins[ip + 1..]);
+        let mut line = format!("{:04} {:?}", ip, op);
+        for operand in operands {
+            line.push_str(&format!(" {}", operand));
+        }
+        out.push_str(&line);
+        out.push('\n');
+        ip += 1 + read;
+    }
+    out
+}
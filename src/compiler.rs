@@ -0,0 +1,1059 @@
+use super::ast::*;
+use super::code::*;
+use super::environment::*;
+use super::lexer::Span;
+use super::object::*;
+use std::cell::*;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::rc::*;
+
+// Where a name lives at runtime. Locals captured by a nested function are
+// kept in upvalues so the frame and its closures share them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Storage {
+    Global(usize),
+    Local(usize),
+    Upvalue(usize),
+    Free(usize),
+}
+
+#[derive(Debug)]
+pub struct Bytecode {
+    pub main: Rc<CompiledFunction>,
+    pub globals: Vec<String>, // global names by slot
+}
+
+// Compile state of the program or of one function literal in it.
+struct FunctionState {
+    instructions: Instructions,
+    constants: Vec<Value>,
+    constant_index: HashMap<HashKey, usize>,
+    functions: Vec<Rc<CompiledFunction>>,
+    // innermost last; the program's own scope is the globals, so it has none
+    scopes: Vec<HashMap<String, Storage>>,
+    captured: HashSet<String>,
+    local_names: Vec<String>,
+    upvalue_names: Vec<String>,
+    free: Vec<(String, Capture)>,
+    span: Span, // of the statement or expression being compiled
+    spans: Vec<(usize, Span)>,
+}
+impl FunctionState {
+    fn new(captured: HashSet<String>, span: Span) -> FunctionState {
+        FunctionState {
+            instructions: Vec::new(),
+            constants: Vec::new(),
+            constant_index: HashMap::new(),
+            functions: Vec::new(),
+            scopes: Vec::new(),
+            captured,
+            local_names: Vec::new(),
+            upvalue_names: Vec::new(),
+            free: Vec::new(),
+            span,
+            spans: Vec::new(),
+        }
+    }
+    fn new_local(&mut self, name: &str) -> usize {
+        self.local_names.push(String::from(name));
+        self.local_names.len() - 1
+    }
+    fn new_upvalue(&mut self, name: &str) -> usize {
+        self.upvalue_names.push(String::from(name));
+        self.upvalue_names.len() - 1
+    }
+}
+
+// Lowers a program to bytecode for the `vm`. Global slots are kept between
+// calls to `compile`, so a REPL can compile one line at a time.
+#[derive(Default)]
+pub struct Compiler {
+    globals: Vec<String>,
+    global_index: HashMap<String, usize>,
+    functions: Vec<FunctionState>,
+}
+impl Compiler {
+    pub fn new() -> Compiler {
+        Compiler::default()
+    }
+
+    // Returns the slot of the global `name`, adding it if needed.
+    pub fn define_global(&mut self, name: &str) -> usize {
+        if let Some(idx) = self.global_index.get(name) {
+            return *idx;
+        }
+        self.globals.push(String::from(name));
+        self.global_index
+            .insert(String::from(name), self.globals.len() - 1);
+        self.globals.len() - 1
+    }
+
+    pub fn compile(&mut self, program: &Program) -> Result<Bytecode, String> {
+        let mut names = NameCollector::default();
+        names.statements(&program.statements);
+        self.functions = vec![FunctionState::new(names.captured, Span::default())];
+        let result = self.compile_program(program);
+        let state = self.functions.pop().unwrap();
+        self.functions.clear();
+        result?;
+        let main = finish_function(state, Vec::new(), Vec::new(), false, String::from("<main>"));
+        Ok(Bytecode {
+            main: Rc::new(main),
+            globals: self.globals.clone(),
+        })
+    }
+
+    fn compile_program(&mut self, program: &Program) -> Result<(), String> {
+        let count = program.statements.len();
+        if count == 0 {
+            self.emit(Opcode::Return, &[])?;
+        }
+        for (idx, stmt) in program.statements.iter().enumerate() {
+            let has_value = self.compile_statement(stmt)?;
+            if idx + 1 < count {
+                if has_value {
+                    self.emit(Opcode::Pop, &[])?;
+                }
+            } else if has_value {
+                self.emit(Opcode::ReturnValue, &[])?;
+            } else {
+                // a program ending in `let` has no value
+                self.emit(Opcode::Return, &[])?;
+            }
+        }
+        Ok(())
+    }
+
+    // Returns whether the statement left a value on the stack.
+    fn compile_statement(&mut self, stmt: &Statement) -> Result<bool, String> {
+        let outer = self.set_span(stmt.span());
+        let has_value = self.compile_statement_node(stmt)?;
+        self.set_span(outer);
+        Ok(has_value)
+    }
+
+    fn compile_statement_node(&mut self, stmt: &Statement) -> Result<bool, String> {
+        match stmt {
+            Statement::Expression(expression_stmt) => {
+                self.compile_expression(&expression_stmt.expression)?;
+                Ok(true)
+            }
+            Statement::Block(block) => {
+                self.compile_block(block)?;
+                Ok(true)
+            }
+            Statement::Let(let_stmt) => {
+                self.compile_let(let_stmt)?;
+                Ok(false)
+            }
+            Statement::Export(export_stmt) => {
+                self.compile_let(&export_stmt.statement)?;
+                Ok(false)
+            }
+            Statement::LetPattern(let_stmt) => {
+                self.compile_expression(&let_stmt.value)?;
+                let slot = self.new_temporary();
+                self.emit(Opcode::SetLocal, &[slot])?;
+                self.compile_destructuring(&let_stmt.pattern, slot)?;
+                Ok(false)
+            }
+            Statement::Return(return_stmt) => {
+                self.compile_expression(&return_stmt.return_value)?;
+                self.emit(Opcode::ReturnValue, &[])?;
+                Ok(false)
+            }
+            Statement::Throw(throw_stmt) => {
+                self.compile_expression(&throw_stmt.value)?;
+                self.emit(Opcode::Throw, &[])?;
+                Ok(false)
+            }
+            Statement::Import(import_stmt) => {
+                let path = self.add_constant(Value::Str(Rc::from(import_stmt.path.as_str())))?;
+                self.emit(Opcode::Import, &[path])?;
+                self.store(&import_stmt.alias.value)?;
+                Ok(false)
+            }
+        }
+    }
+
+    fn compile_let(&mut self, let_stmt: &LetStatement) -> Result<(), String> {
+        self.compile_expression(&let_stmt.value)?;
+        self.store(&let_stmt.name.value)
+    }
+
+    // Leaves the value of the block's last expression, or null.
+    fn compile_block(&mut self, block: &BlockStatement) -> Result<(), String> {
+        let count = block.statements.len();
+        if count == 0 {
+            self.emit(Opcode::Null, &[])?;
+        }
+        for (idx, stmt) in block.statements.iter().enumerate() {
+            let has_value = self.compile_statement(stmt)?;
+            if idx + 1 < count {
+                if has_value {
+                    self.emit(Opcode::Pop, &[])?;
+                }
+            } else if !has_value {
+                self.emit(Opcode::Null, &[])?;
+            }
+        }
+        Ok(())
+    }
+
+    fn compile_expression(&mut self, exp: &Expression) -> Result<(), String> {
+        let outer = self.set_span(exp.span());
+        self.compile_expression_node(exp)?;
+        self.set_span(outer);
+        Ok(())
+    }
+
+    fn compile_expression_node(&mut self, exp: &Expression) -> Result<(), String> {
+        match exp {
+            Expression::Integer(integer_literal) => {
+                let idx = self.add_constant(Value::Integer(integer_literal.value))?;
+                self.emit(Opcode::Constant, &[idx])?;
+            }
+            Expression::String(string_literal) => {
+                let idx = self.add_constant(Value::Str(Rc::from(string_literal.value.as_str())))?;
+                self.emit(Opcode::Constant, &[idx])?;
+            }
+            Expression::Boolean(boolean) => {
+                let op = if boolean.value {
+                    Opcode::True
+                } else {
+                    Opcode::False
+                };
+                self.emit(op, &[])?;
+            }
+            Expression::Identifier(ident) => self.load(&ident.value)?,
+            Expression::Prefix(prefix_exp) => {
+                self.compile_expression(&prefix_exp.right)?;
+                let op = match prefix_exp.operator.as_str() {
+                    "-" => Opcode::Minus,
+                    "!" => Opcode::Bang,
+                    operator => return Err(format!("unknown operator: {}", operator)),
+                };
+                self.emit(op, &[])?;
+            }
+            Expression::Infix(infix_exp) => {
+                self.compile_expression(&infix_exp.left)?;
+                self.compile_expression(&infix_exp.right)?;
+                let op = match infix_exp.operator.as_str() {
+                    "+" => Opcode::Add,
+                    "-" => Opcode::Sub,
+                    "*" => Opcode::Mul,
+                    "/" => Opcode::Div,
+                    "==" => Opcode::Equal,
+                    "!=" => Opcode::NotEqual,
+                    ">" => Opcode::GreaterThan,
+                    "<" => Opcode::LessThan,
+                    operator => return Err(format!("unknown operator: {}", operator)),
+                };
+                self.emit(op, &[])?;
+            }
+            Expression::If(if_exp) => {
+                self.compile_expression(&if_exp.condition)?;
+                let to_alternative = self.emit_jump(Opcode::JumpNotTruthy)?;
+                self.compile_block(&if_exp.consequence)?;
+                let to_end = self.emit_jump(Opcode::Jump)?;
+                self.patch_jump(to_alternative)?;
+                match &if_exp.alternative {
+                    Some(alternative) => self.compile_block(alternative)?,
+                    _ => {
+                        self.emit(Opcode::Null, &[])?;
+                    }
+                }
+                self.patch_jump(to_end)?;
+            }
+            Expression::Function(function_literal) => self.compile_function(function_literal)?,
+            Expression::Macro(macro_literal) => {
+                let idx = self.add_constant(Value::Macro(Rc::new(Macro {
+                    macro_literal: Rc::clone(macro_literal),
+                    env: Rc::new(RefCell::new(new_environment())),
+                })))?;
+                self.emit(Opcode::Constant, &[idx])?;
+            }
+            Expression::Call(call_exp) => self.compile_call(call_exp)?,
+            Expression::Array(array_literal) => {
+                for element in array_literal.elements.iter() {
+                    self.compile_expression(element)?;
+                }
+                self.emit(Opcode::Array, &[array_literal.elements.len()])?;
+            }
+            Expression::Hash(hash_literal) => {
+                for (key, value) in hash_literal.pairs.iter() {
+                    self.compile_expression(key)?;
+                    self.emit(Opcode::CheckHashKey, &[])?;
+                    self.compile_expression(value)?;
+                }
+                self.emit(Opcode::Hash, &[hash_literal.pairs.len()])?;
+            }
+            Expression::Index(index_exp) => {
+                self.compile_expression(&index_exp.left)?;
+                self.compile_expression(&index_exp.index)?;
+                self.emit(Opcode::Index, &[])?;
+            }
+            Expression::Match(match_exp) => self.compile_match(match_exp)?,
+            Expression::Try(try_exp) => self.compile_try(try_exp)?,
+        }
+        Ok(())
+    }
+
+    fn compile_call(&mut self, call_exp: &CallExpression) -> Result<(), String> {
+        if call_exp.function.token_literal() == "quote" && call_exp.arguments.len() == 1 {
+            return self.compile_quote(&call_exp.arguments[0]);
+        }
+        if call_exp.arguments.len() > u8::MAX as usize
+            || call_exp.named_arguments.len() > u8::MAX as usize
+        {
+            return Err(format!(
+                "too many arguments in call to {}",
+                call_exp.function.string()
+            ));
+        }
+        self.compile_expression(&call_exp.function)?;
+        for arg in call_exp.arguments.iter() {
+            self.compile_expression(arg)?;
+        }
+        for (name, arg) in call_exp.named_arguments.iter() {
+            let idx = self.add_constant(Value::Str(Rc::from(name.value.as_str())))?;
+            self.emit(Opcode::Constant, &[idx])?;
+            self.compile_expression(arg)?;
+        }
+        // names the callee in error stacks
+        let name = self.add_constant(Value::Str(Rc::from(call_exp.function.string().as_str())))?;
+        self.emit(
+            Opcode::Call,
+            &[
+                call_exp.arguments.len(),
+                call_exp.named_arguments.len(),
+                name,
+            ],
+        )?;
+        Ok(())
+    }
+
+    // The unquoted expressions are evaluated here, in order, and spliced into
+    // a copy of the quoted node by the VM.
+    fn compile_quote(&mut self, node: &Expression) -> Result<(), String> {
+        let mut template = node.clone();
+        let mut unquoted: Vec<Expression> = Vec::new();
+        modify_expression(&mut template, &mut |exp: &mut Expression| {
+            if let Expression::Call(call_exp) = exp {
+                if call_exp.function.token_literal() == "unquote" && call_exp.arguments.len() == 1 {
+                    unquoted.push(call_exp.arguments[0].clone());
+                }
+            }
+        });
+        for exp in unquoted.iter() {
+            self.compile_expression(exp)?;
+        }
+        let idx = self.add_constant(Value::Quote(Rc::new(template)))?;
+        self.emit(Opcode::Quote, &[idx, unquoted.len()])?;
+        Ok(())
+    }
+
+    fn compile_function(&mut self, function_literal: &FunctionLiteral) -> Result<(), String> {
+        let mut names = NameCollector::default();
+        names.function(function_literal);
+        self.functions.push(FunctionState::new(
+            names.captured,
+            function_literal.token.span,
+        ));
+        let result = self.compile_function_body(function_literal);
+        let state = self.functions.pop().unwrap();
+        result?;
+
+        let function = finish_function(
+            state,
+            function_literal
+                .parameters
+                .iter()
+                .map(|p| p.value.clone())
+                .collect(),
+            function_literal
+                .defaults
+                .iter()
+                .map(|d| d.is_some())
+                .collect(),
+            function_literal.rest.is_some(),
+            format!(
+                "fn({}) {{\n{}\n}}",
+                function_literal.parameters_string(),
+                function_literal.body.string()
+            ),
+        );
+        let state = self.functions.last_mut().unwrap();
+        state.functions.push(Rc::new(function));
+        let idx = state.functions.len() - 1;
+        self.emit(Opcode::Closure, &[idx])?;
+        Ok(())
+    }
+
+    fn compile_function_body(&mut self, function_literal: &FunctionLiteral) -> Result<(), String> {
+        let count = function_literal.parameters.len();
+        // arguments arrive in the first local slots
+        let mut slots: Vec<(&str, usize)> = Vec::new();
+        {
+            let state = self.functions.last_mut().unwrap();
+            for (param, pattern) in function_literal
+                .parameters
+                .iter()
+                .zip(function_literal.patterns.iter())
+            {
+                let slot = state.new_local(&param.value);
+                if pattern.is_none() {
+                    slots.push((&param.value, slot));
+                }
+            }
+            if let Some(rest) = &function_literal.rest {
+                slots.push((&rest.value, state.new_local(&rest.value)));
+            }
+        }
+
+        self.functions
+            .last_mut()
+            .unwrap()
+            .scopes
+            .push(HashMap::new());
+        let mut moves: Vec<(usize, usize)> = Vec::new();
+        for (name, slot) in slots {
+            let state = self.functions.last_mut().unwrap();
+            let storage = if state.captured.contains(name) {
+                let upvalue = state.new_upvalue(name);
+                moves.push((slot, upvalue));
+                Storage::Upvalue(upvalue)
+            } else {
+                Storage::Local(slot)
+            };
+            state
+                .scopes
+                .last_mut()
+                .unwrap()
+                .insert(String::from(name), storage);
+        }
+        let mut names: Vec<String> = Vec::new();
+        for pattern in function_literal.patterns.iter().flatten() {
+            pattern_names(pattern, &mut names);
+        }
+        declared_names(&function_literal.body.statements, &mut names);
+        for name in names.iter() {
+            self.declare(name)?;
+        }
+
+        for (idx, (default, pattern)) in function_literal
+            .defaults
+            .iter()
+            .zip(function_literal.patterns.iter())
+            .enumerate()
+        {
+            if let Some(default) = default {
+                let pos = self.emit(Opcode::JumpIfBound, &[idx, 0])?;
+                self.compile_expression(default)?;
+                self.emit(Opcode::SetLocal, &[idx])?;
+                self.patch_jump(pos + 3)?;
+            }
+            if let Some(pattern) = pattern {
+                self.compile_destructuring(pattern, idx)?;
+            }
+            if let Some((_, upvalue)) = moves.iter().find(|(slot, _)| *slot == idx) {
+                self.emit(Opcode::GetLocal, &[idx])?;
+                self.emit(Opcode::SetUpvalue, &[*upvalue])?;
+            }
+        }
+        if let Some((_, upvalue)) = moves.iter().find(|(slot, _)| *slot == count) {
+            self.emit(Opcode::GetLocal, &[count])?;
+            self.emit(Opcode::SetUpvalue, &[*upvalue])?;
+        }
+
+        self.compile_block(&function_literal.body)?;
+        self.emit(Opcode::ReturnValue, &[])?;
+        Ok(())
+    }
+
+    fn compile_match(&mut self, match_exp: &MatchExpression) -> Result<(), String> {
+        self.compile_expression(&match_exp.value)?;
+        let slot = self.new_temporary();
+        self.emit(Opcode::SetLocal, &[slot])?;
+
+        let mut to_end: Vec<usize> = Vec::new();
+        for arm in match_exp.arms.iter() {
+            self.functions
+                .last_mut()
+                .unwrap()
+                .scopes
+                .push(HashMap::new());
+            let mut names: Vec<String> = Vec::new();
+            pattern_names(&arm.pattern, &mut names);
+            if let Some(guard) = &arm.guard {
+                expression_declarations(guard, &mut names);
+            }
+            declared_names(&arm.body.statements, &mut names);
+            for name in names.iter() {
+                self.declare(name)?;
+            }
+
+            let mut to_next: Vec<usize> = Vec::new();
+            let mut bindings: Vec<(String, usize)> = Vec::new();
+            self.compile_pattern(&arm.pattern, slot, &mut to_next, &mut bindings)?;
+            self.bind(bindings)?;
+            if let Some(guard) = &arm.guard {
+                self.compile_expression(guard)?;
+                to_next.push(self.emit_jump(Opcode::JumpNotTruthy)?);
+            }
+            self.compile_block(&arm.body)?;
+            to_end.push(self.emit_jump(Opcode::Jump)?);
+            self.functions.last_mut().unwrap().scopes.pop();
+            for pos in to_next {
+                self.patch_jump(pos)?;
+            }
+        }
+        self.emit(Opcode::GetLocal, &[slot])?;
+        self.emit(Opcode::NoMatch, &[])?;
+        for pos in to_end {
+            self.patch_jump(pos)?;
+        }
+        Ok(())
+    }
+
+    // Binds the names of `pattern` to the value in local `slot`, or raises a
+    // match error.
+    fn compile_destructuring(&mut self, pattern: &Pattern, slot: usize) -> Result<(), String> {
+        let mut to_error: Vec<usize> = Vec::new();
+        let mut bindings: Vec<(String, usize)> = Vec::new();
+        self.compile_pattern(pattern, slot, &mut to_error, &mut bindings)?;
+        self.bind(bindings)?;
+        let to_end = self.emit_jump(Opcode::Jump)?;
+        for pos in to_error {
+            self.patch_jump(pos)?;
+        }
+        let idx = self.add_constant(Value::Str(Rc::from(pattern.string().as_str())))?;
+        self.emit(Opcode::GetLocal, &[slot])?;
+        self.emit(Opcode::PatternMismatch, &[idx])?;
+        self.patch_jump(to_end)
+    }
+
+    // Tests the value in local `slot` against `pattern`, jumping through one
+    // of `fails` when it does not match. Nothing is bound yet; `bindings`
+    // collects the temporaries holding each name's value.
+    fn compile_pattern(
+        &mut self,
+        pattern: &Pattern,
+        slot: usize,
+        fails: &mut Vec<usize>,
+        bindings: &mut Vec<(String, usize)>,
+    ) -> Result<(), String> {
+        match pattern {
+            Pattern::Wildcard(_) => {}
+            Pattern::Binding(binding) => bindings.push((binding.name.value.clone(), slot)),
+            Pattern::Literal(literal) => {
+                self.emit(Opcode::GetLocal, &[slot])?;
+                self.compile_expression(&literal.value)?;
+                fails.push(self.emit_jump(Opcode::MatchEqual)?);
+            }
+            Pattern::Array(array_pattern) => {
+                let count = array_pattern.elements.len();
+                self.emit(Opcode::GetLocal, &[slot])?;
+                let pos = self.emit(
+                    Opcode::MatchArray,
+                    &[count, array_pattern.rest.is_some() as usize, 0],
+                )?;
+                fails.push(pos + 4);
+                for (idx, element) in array_pattern.elements.iter().enumerate() {
+                    if let Pattern::Wildcard(_) = element {
+                        continue;
+                    }
+                    let element_slot = self.new_temporary();
+                    self.emit(Opcode::GetLocal, &[slot])?;
+                    self.emit(Opcode::Element, &[idx])?;
+                    self.emit(Opcode::SetLocal, &[element_slot])?;
+                    self.compile_pattern(element, element_slot, fails, bindings)?;
+                }
+                if let Some(rest) = &array_pattern.rest {
+                    let rest_slot = self.new_temporary();
+                    self.emit(Opcode::GetLocal, &[slot])?;
+                    self.emit(Opcode::RestOf, &[count])?;
+                    self.emit(Opcode::SetLocal, &[rest_slot])?;
+                    bindings.push((rest.value.clone(), rest_slot));
+                }
+            }
+            Pattern::Hash(hash_pattern) => {
+                self.emit(Opcode::GetLocal, &[slot])?;
+                fails.push(self.emit_jump(Opcode::MatchHash)?);
+                for (key, value) in hash_pattern.pairs.iter() {
+                    self.emit(Opcode::GetLocal, &[slot])?;
+                    self.compile_expression(key)?;
+                    fails.push(self.emit_jump(Opcode::MatchKey)?);
+                    let value_slot = self.new_temporary();
+                    self.emit(Opcode::SetLocal, &[value_slot])?;
+                    self.compile_pattern(value, value_slot, fails, bindings)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn bind(&mut self, bindings: Vec<(String, usize)>) -> Result<(), String> {
+        for (name, slot) in bindings {
+            self.emit(Opcode::GetLocal, &[slot])?;
+            self.store(&name)?;
+        }
+        Ok(())
+    }
+
+    fn compile_try(&mut self, try_exp: &TryExpression) -> Result<(), String> {
+        let to_finally = match &try_exp.finally_block {
+            Some(_) => Some(self.emit_jump(Opcode::SetupFinally)?),
+            _ => None,
+        };
+        let to_catch = match &try_exp.catch_block {
+            Some(_) => Some(self.emit_jump(Opcode::SetupCatch)?),
+            _ => None,
+        };
+        self.compile_block(&try_exp.block)?;
+
+        if let (Some(to_catch), Some(catch_block)) = (to_catch, &try_exp.catch_block) {
+            self.emit(Opcode::PopHandler, &[])?;
+            let to_end = self.emit_jump(Opcode::Jump)?;
+            self.patch_jump(to_catch)?;
+            self.functions
+                .last_mut()
+                .unwrap()
+                .scopes
+                .push(HashMap::new());
+            let mut names: Vec<String> = Vec::new();
+            if let Some(param) = &try_exp.catch_parameter {
+                names.push(param.value.clone());
+            }
+            declared_names(&catch_block.statements, &mut names);
+            for name in names.iter() {
+                self.declare(name)?;
+            }
+            match &try_exp.catch_parameter {
+                Some(param) => {
+                    self.emit(Opcode::ErrorValue, &[])?;
+                    self.store(&param.value)?;
+                }
+                _ => {
+                    self.emit(Opcode::Pop, &[])?;
+                }
+            }
+            self.compile_block(catch_block)?;
+            self.functions.last_mut().unwrap().scopes.pop();
+            self.patch_jump(to_end)?;
+        }
+
+        if let (Some(to_finally), Some(finally_block)) = (to_finally, &try_exp.finally_block) {
+            // the result of the try so far stays on the stack while the
+            // finally block runs
+            self.emit(Opcode::PopHandler, &[])?;
+            self.patch_jump(to_finally)?;
+            self.compile_block(finally_block)?;
+            self.emit(Opcode::EndFinally, &[])?;
+        }
+        Ok(())
+    }
+
+    fn new_temporary(&mut self) -> usize {
+        self.functions.last_mut().unwrap().new_local("")
+    }
+
+    // Adds `name` to the innermost scope, or to the globals at the top level.
+    fn declare(&mut self, name: &str) -> Result<Storage, String> {
+        let top_level = self.functions.len() == 1;
+        let state = self.functions.last_mut().unwrap();
+        let storage = match state.scopes.last() {
+            Some(scope) => match scope.get(name) {
+                Some(storage) => *storage,
+                _ => {
+                    let storage = if state.captured.contains(name) {
+                        Storage::Upvalue(state.new_upvalue(name))
+                    } else {
+                        Storage::Local(state.new_local(name))
+                    };
+                    state
+                        .scopes
+                        .last_mut()
+                        .unwrap()
+                        .insert(String::from(name), storage);
+                    storage
+                }
+            },
+            _ if top_level => Storage::Global(self.define_global(name)),
+            _ => return Err(format!("no scope to declare {} in", name)),
+        };
+        Ok(storage)
+    }
+
+    // Pops the top of the stack into `name` in the innermost scope.
+    fn store(&mut self, name: &str) -> Result<(), String> {
+        match self.declare(name)? {
+            Storage::Global(idx) => self.emit(Opcode::SetGlobal, &[idx])?,
+            Storage::Local(idx) => self.emit(Opcode::SetLocal, &[idx])?,
+            Storage::Upvalue(idx) => self.emit(Opcode::SetUpvalue, &[idx])?,
+            Storage::Free(_) => return Err(format!("cannot assign free variable {}", name)),
+        };
+        Ok(())
+    }
+
+    fn load(&mut self, name: &str) -> Result<(), String> {
+        let level = self.functions.len() - 1;
+        let storage = match self.resolve(level, name) {
+            Some(storage) => storage,
+            _ => Storage::Global(self.define_global(name)),
+        };
+        match storage {
+            Storage::Global(idx) => self.emit(Opcode::GetGlobal, &[idx])?,
+            Storage::Local(idx) => self.emit(Opcode::GetLocal, &[idx])?,
+            Storage::Upvalue(idx) => self.emit(Opcode::GetUpvalue, &[idx])?,
+            Storage::Free(idx) => self.emit(Opcode::GetFree, &[idx])?,
+        };
+        Ok(())
+    }
+
+    // Looks `name` up from the function at `level` outwards, threading it
+    // through the free variables of every function in between. `None` means
+    // a global.
+    fn resolve(&mut self, level: usize, name: &str) -> Option<Storage> {
+        let state = &self.functions[level];
+        for scope in state.scopes.iter().rev() {
+            if let Some(storage) = scope.get(name) {
+                return Some(*storage);
+            }
+        }
+        if let Some(idx) = state.free.iter().position(|(n, _)| n == name) {
+            return Some(Storage::Free(idx));
+        }
+        if level == 0 {
+            return None;
+        }
+        let capture = match self.resolve(level - 1, name)? {
+            Storage::Upvalue(idx) => Capture::Upvalue(idx),
+            Storage::Free(idx) => Capture::Free(idx),
+            _ => return None,
+        };
+        let state = &mut self.functions[level];
+        state.free.push((String::from(name), capture));
+        Some(Storage::Free(state.free.len() - 1))
+    }
+
+    fn add_constant(&mut self, value: Value) -> Result<usize, String> {
+        let state = self.functions.last_mut().unwrap();
+        let key = match &value {
+            Value::Integer(_) | Value::Str(_) => value.hash_key(),
+            _ => None,
+        };
+        if let Some(idx) = key.as_ref().and_then(|key| state.constant_index.get(key)) {
+            return Ok(*idx);
+        }
+        if state.constants.len() > u16::MAX as usize {
+            return Err(String::from("too many constants in one function"));
+        }
+        state.constants.push(value);
+        let idx = state.constants.len() - 1;
+        if let Some(key) = key {
+            state.constant_index.insert(key, idx);
+        }
+        Ok(idx)
+    }
+
+    // Sets the source position of the instructions emitted next and returns
+    // the previous one.
+    fn set_span(&mut self, span: Span) -> Span {
+        std::mem::replace(&mut self.functions.last_mut().unwrap().span, span)
+    }
+
+    // Appends an instruction and returns its position.
+    fn emit(&mut self, op: Opcode, operands: &[usize]) -> Result<usize, String> {
+        for (operand, width) in operands.iter().zip(op.operand_widths().iter()) {
+            if *operand >= 1 << (8 * width) {
+                return Err(format!("operand {} of {:?} out of range", operand, op));
+            }
+        }
+        let state = self.functions.last_mut().unwrap();
+        let pos = state.instructions.len();
+        if state.spans.last().map(|(_, span)| *span) != Some(state.span) {
+            state.spans.push((pos, state.span));
+        }
+        state.instructions.extend(make(op, operands));
+        Ok(pos)
+    }
+
+    // Emits a jump whose only operand is its target and returns the
+    // position of that operand for `patch_jump`.
+    fn emit_jump(&mut self, op: Opcode) -> Result<usize, String> {
+        Ok(self.emit(op, &[0])? + 1)
+    }
+
+    // Points the jump operand at `pos` to the next instruction.
+    fn patch_jump(&mut self, pos: usize) -> Result<(), String> {
+        let state = self.functions.last_mut().unwrap();
+        let target = state.instructions.len();
+        if target > u16::MAX as usize {
+            return Err(String::from("function body too large"));
+        }
+        state.instructions[pos..pos + 2].copy_from_slice(&(target as u16).to_be_bytes());
+        Ok(())
+    }
+}
+
+fn finish_function(
+    state: FunctionState,
+    parameters: Vec<String>,
+    defaults: Vec<bool>,
+    rest: bool,
+    display: String,
+) -> CompiledFunction {
+    CompiledFunction {
+        instructions: state.instructions,
+        constants: state.constants,
+        functions: state.functions,
+        parameters,
+        defaults,
+        rest,
+        local_names: state.local_names,
+        upvalue_names: state.upvalue_names,
+        free: state.free,
+        spans: state.spans,
+        display,
+    }
+}
+
+// Names bound by `let` in `statements`, including the ones inside `if`,
+// `try` and `finally` blocks, which share the enclosing scope.
+fn declared_names(statements: &[Statement], names: &mut Vec<String>) {
+    for stmt in statements.iter() {
+        match stmt {
+            Statement::Let(let_stmt) => {
+                names.push(let_stmt.name.value.clone());
+                expression_declarations(&let_stmt.value, names);
+            }
+            Statement::Export(export_stmt) => {
+                names.push(export_stmt.statement.name.value.clone());
+                expression_declarations(&export_stmt.statement.value, names);
+            }
+            Statement::LetPattern(let_stmt) => {
+                pattern_names(&let_stmt.pattern, names);
+                expression_declarations(&let_stmt.value, names);
+            }
+            Statement::Import(import_stmt) => names.push(import_stmt.alias.value.clone()),
+            Statement::Return(return_stmt) => {
+                expression_declarations(&return_stmt.return_value, names)
+            }
+            Statement::Throw(throw_stmt) => expression_declarations(&throw_stmt.value, names),
+            Statement::Expression(expression_stmt) => {
+                expression_declarations(&expression_stmt.expression, names)
+            }
+            Statement::Block(block) => declared_names(&block.statements, names),
+        }
+    }
+}
+
+fn expression_declarations(exp: &Expression, names: &mut Vec<String>) {
+    match exp {
+        Expression::If(if_exp) => {
+            expression_declarations(&if_exp.condition, names);
+            declared_names(&if_exp.consequence.statements, names);
+            if let Some(alternative) = &if_exp.alternative {
+                declared_names(&alternative.statements, names);
+            }
+        }
+        Expression::Try(try_exp) => {
+            declared_names(&try_exp.block.statements, names);
+            if let Some(finally_block) = &try_exp.finally_block {
+                declared_names(&finally_block.statements, names);
+            }
+        }
+        Expression::Prefix(prefix_exp) => expression_declarations(&prefix_exp.right, names),
+        Expression::Infix(infix_exp) => {
+            expression_declarations(&infix_exp.left, names);
+            expression_declarations(&infix_exp.right, names);
+        }
+        Expression::Call(call_exp) => {
+            expression_declarations(&call_exp.function, names);
+            for arg in call_exp.arguments.iter() {
+                expression_declarations(arg, names);
+            }
+            for (_, arg) in call_exp.named_arguments.iter() {
+                expression_declarations(arg, names);
+            }
+        }
+        Expression::Array(array_literal) => {
+            for element in array_literal.elements.iter() {
+                expression_declarations(element, names);
+            }
+        }
+        Expression::Hash(hash_literal) => {
+            for (key, value) in hash_literal.pairs.iter() {
+                expression_declarations(key, names);
+                expression_declarations(value, names);
+            }
+        }
+        Expression::Index(index_exp) => {
+            expression_declarations(&index_exp.left, names);
+            expression_declarations(&index_exp.index, names);
+        }
+        Expression::Match(match_exp) => expression_declarations(&match_exp.value, names),
+        Expression::Identifier(_)
+        | Expression::Integer(_)
+        | Expression::String(_)
+        | Expression::Boolean(_)
+        | Expression::Function(_)
+        | Expression::Macro(_) => {}
+    }
+}
+
+fn pattern_names(pattern: &Pattern, names: &mut Vec<String>) {
+    match pattern {
+        Pattern::Binding(binding) => names.push(binding.name.value.clone()),
+        Pattern::Array(array_pattern) => {
+            for element in array_pattern.elements.iter() {
+                pattern_names(element, names);
+            }
+            if let Some(rest) = &array_pattern.rest {
+                names.push(rest.value.clone());
+            }
+        }
+        Pattern::Hash(hash_pattern) => {
+            for (_, value) in hash_pattern.pairs.iter() {
+                pattern_names(value, names);
+            }
+        }
+        Pattern::Literal(_) | Pattern::Wildcard(_) => {}
+    }
+}
+
+// Collects every name referenced inside a nested function literal. Any local
+// with one of these names is given an upvalue, which over-approximates the
+// locals closures really capture.
+#[derive(Default)]
+struct NameCollector {
+    depth: usize,
+    captured: HashSet<String>,
+}
+impl NameCollector {
+    fn function(&mut self, function_literal: &FunctionLiteral) {
+        for default in function_literal.defaults.iter().flatten() {
+            self.expression(default);
+        }
+        for pattern in function_literal.patterns.iter().flatten() {
+            self.pattern(pattern);
+        }
+        self.statements(&function_literal.body.statements);
+    }
+
+    fn statements(&mut self, statements: &[Statement]) {
+        for stmt in statements.iter() {
+            match stmt {
+                Statement::Let(let_stmt) => self.expression(&let_stmt.value),
+                Statement::Export(export_stmt) => self.expression(&export_stmt.statement.value),
+                Statement::LetPattern(let_stmt) => {
+                    self.pattern(&let_stmt.pattern);
+                    self.expression(&let_stmt.value);
+                }
+                Statement::Return(return_stmt) => self.expression(&return_stmt.return_value),
+                Statement::Throw(throw_stmt) => self.expression(&throw_stmt.value),
+                Statement::Expression(expression_stmt) => {
+                    self.expression(&expression_stmt.expression)
+                }
+                Statement::Block(block) => self.statements(&block.statements),
+                Statement::Import(_) => {}
+            }
+        }
+    }
+
+    fn expression(&mut self, exp: &Expression) {
+        match exp {
+            Expression::Identifier(ident) => {
+                if self.depth > 0 {
+                    self.captured.insert(ident.value.clone());
+                }
+            }
+            Expression::Function(function_literal) => {
+                self.depth += 1;
+                self.function(function_literal);
+                self.depth -= 1;
+            }
+            Expression::Macro(macro_literal) => {
+                self.depth += 1;
+                self.statements(&macro_literal.body.statements);
+                self.depth -= 1;
+            }
+            Expression::Prefix(prefix_exp) => self.expression(&prefix_exp.right),
+            Expression::Infix(infix_exp) => {
+                self.expression(&infix_exp.left);
+                self.expression(&infix_exp.right);
+            }
+            Expression::If(if_exp) => {
+                self.expression(&if_exp.condition);
+                self.statements(&if_exp.consequence.statements);
+                if let Some(alternative) = &if_exp.alternative {
+                    self.statements(&alternative.statements);
+                }
+            }
+            Expression::Try(try_exp) => {
+                self.statements(&try_exp.block.statements);
+                if let Some(catch_block) = &try_exp.catch_block {
+                    self.statements(&catch_block.statements);
+                }
+                if let Some(finally_block) = &try_exp.finally_block {
+                    self.statements(&finally_block.statements);
+                }
+            }
+            Expression::Call(call_exp) => {
+                self.expression(&call_exp.function);
+                for arg in call_exp.arguments.iter() {
+                    self.expression(arg);
+                }
+                for (_, arg) in call_exp.named_arguments.iter() {
+                    self.expression(arg);
+                }
+            }
+            Expression::Array(array_literal) => {
+                for element in array_literal.elements.iter() {
+                    self.expression(element);
+                }
+            }
+            Expression::Hash(hash_literal) => {
+                for (key, value) in hash_literal.pairs.iter() {
+                    self.expression(key);
+                    self.expression(value);
+                }
+            }
+            Expression::Index(index_exp) => {
+                self.expression(&index_exp.left);
+                self.expression(&index_exp.index);
+            }
+            Expression::Match(match_exp) => {
+                self.expression(&match_exp.value);
+                for arm in match_exp.arms.iter() {
+                    self.pattern(&arm.pattern);
+                    if let Some(guard) = &arm.guard {
+                        self.expression(guard);
+                    }
+                    self.statements(&arm.body.statements);
+                }
+            }
+            Expression::Integer(_) | Expression::String(_) | Expression::Boolean(_) => {}
+        }
+    }
+
+    fn pattern(&mut self, pattern: &Pattern) {
+        match pattern {
+            Pattern::Literal(literal) => self.expression(&literal.value),
+            Pattern::Array(array_pattern) => {
+                for element in array_pattern.elements.iter() {
+                    self.pattern(element);
+                }
+            }
+            Pattern::Hash(hash_pattern) => {
+                for (key, value) in hash_pattern.pairs.iter() {
+                    self.expression(key);
+                    self.pattern(value);
+                }
+            }
+            Pattern::Wildcard(_) | Pattern::Binding(_) => {}
+        }
+    }
+}
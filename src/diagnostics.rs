@@ -0,0 +1,383 @@
+use super::lexer::Span;
+use super::object::Error;
+use super::resolver;
+use super::typechecker::TypeError;
+use std::fmt::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+impl Display for Severity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+// A problem found in the source, with what it takes to point at it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub span: Span,
+    pub width: usize, // characters underlined, 0 for the word at `span`
+    pub notes: Vec<String>,
+    pub help: Vec<String>,
+}
+impl Diagnostic {
+    pub fn error(span: Span, message: impl Into<String>) -> Diagnostic {
+        Diagnostic::new(Severity::Error, span, message.into())
+    }
+    pub fn warning(span: Span, message: impl Into<String>) -> Diagnostic {
+        Diagnostic::new(Severity::Warning, span, message.into())
+    }
+    fn new(severity: Severity, span: Span, message: String) -> Diagnostic {
+        Diagnostic {
+            severity,
+            message,
+            span,
+            width: 0,
+            notes: Vec::new(),
+            help: Vec::new(),
+        }
+    }
+    pub fn with_width(mut self, width: usize) -> Diagnostic {
+        self.width = width;
+        self
+    }
+    pub fn with_note(mut self, note: impl Into<String>) -> Diagnostic {
+        self.notes.push(note.into());
+        self
+    }
+    pub fn with_help(mut self, help: impl Into<String>) -> Diagnostic {
+        self.help.push(help.into());
+        self
+    }
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "{}: {}", self.span, self.message)
+    }
+}
+impl From<&resolver::Diagnostic> for Diagnostic {
+    fn from(diagnostic: &resolver::Diagnostic) -> Diagnostic {
+        let severity = if diagnostic.is_error() {
+            Severity::Error
+        } else {
+            Severity::Warning
+        };
+        let report = Diagnostic::new(severity, diagnostic.span, diagnostic.message.clone());
+        match diagnostic.kind {
+            resolver::DiagnosticKind::UnusedBinding => {
+                report.with_help("start the name with `_` if it is meant to be unused")
+            }
+            _ => report,
+        }
+    }
+}
+impl From<&TypeError> for Diagnostic {
+    fn from(err: &TypeError) -> Diagnostic {
+        Diagnostic::error(err.span, err.message.clone())
+    }
+}
+
+// An error a program raised, at the expression that raised it with the
+// calls it went through as notes. Without that position it is at the
+// innermost call; errors raised outside any call then have none.
+impl From<&Error> for Diagnostic {
+    fn from(err: &Error) -> Diagnostic {
+        let span = match err.stack.first() {
+            Some(frame) if err.span == Span::default() => frame.span,
+            _ => err.span,
+        };
+        let mut diagnostic = Diagnostic::error(span, format!("{}: {}", err.kind, err.message));
+        diagnostic.notes = err.trace();
+        diagnostic
+    }
+}
+
+// How many single character edits, swaps of neighbours included, turn one
+// string into the other.
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    // distances from the prefixes of `a` to those of `b`, two rows back
+    let mut before: Vec<usize> = Vec::new();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        let mut current = vec![i; b.len() + 1];
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            current[j] = (previous[j] + 1)
+                .min(current[j - 1] + 1)
+                .min(previous[j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                current[j] = current[j].min(before[j - 2] + 1);
+            }
+        }
+        before = std::mem::replace(&mut previous, current);
+    }
+    previous[b.len()]
+}
+
+// The candidates nearest to `name`, best first, at most three. Names of one
+// or two characters are too short to guess from.
+pub fn closest_names<'a>(
+    name: &str,
+    candidates: impl IntoIterator<Item = &'a str>,
+) -> Vec<&'a str> {
+    let limit = name.chars().count() / 3;
+    let mut close: Vec<(usize, &str)> = candidates
+        .into_iter()
+        .filter(|candidate| *candidate != name)
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= limit)
+        .collect();
+    close.sort();
+    close.dedup();
+    close
+        .into_iter()
+        .take(3)
+        .map(|(_, candidate)| candidate)
+        .collect()
+}
+
+// " — did you mean `x`?" for the closest candidates, or nothing.
+pub fn did_you_mean<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> String {
+    let names: Vec<String> = closest_names(name, candidates)
+        .iter()
+        .map(|candidate| format!("`{}`", candidate))
+        .collect();
+    match names.split_last() {
+        None => String::new(),
+        Some((last, [])) => format!(" \u{2014} did you mean {}?", last),
+        Some((last, rest)) => format!(" \u{2014} did you mean {} or {}?", rest.join(", "), last),
+    }
+}
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const BLUE: &str = "\x1b[1;34m";
+const CYAN: &str = "\x1b[1;36m";
+
+// How diagnostics are written out, as set by `--color` and `--error-format`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DiagnosticFormat {
+    pub colour: bool,
+    pub json: bool, // one JSON array per call of `show`, colour or not
+}
+impl DiagnosticFormat {
+    // The diagnostics found in `source`, which `file` names, ready to write.
+    pub fn show(&self, file: &str, source: &str, diagnostics: &[Diagnostic]) -> String {
+        let renderer = Renderer::new(file, source).with_colour(self.colour);
+        if self.json {
+            return format!("{}\n", renderer.json(diagnostics));
+        }
+        diagnostics.iter().map(|d| renderer.render(d)).collect()
+    }
+}
+
+// Shows diagnostics the way compilers do, e.g.
+//
+//     error: expected next token to be ')', got ';' instead
+//      --> main.mk:1:11
+//       |
+//     1 | let x = (1;
+//       |           ^
+pub struct Renderer<'a> {
+    file: &'a str,
+    lines: Vec<&'a str>,
+    colour: bool,
+}
+impl<'a> Renderer<'a> {
+    pub fn new(file: &'a str, source: &'a str) -> Renderer<'a> {
+        Renderer {
+            file,
+            lines: source.lines().collect(),
+            colour: false,
+        }
+    }
+    pub fn with_colour(mut self, colour: bool) -> Renderer<'a> {
+        self.colour = colour;
+        self
+    }
+
+    pub fn render(&self, diagnostic: &Diagnostic) -> String {
+        let severity = match diagnostic.severity {
+            Severity::Error => RED,
+            Severity::Warning => YELLOW,
+        };
+        let mut out = format!(
+            "{}{}: {}{}\n",
+            self.paint(severity, &diagnostic.severity.to_string()),
+            self.paint(BOLD, ""),
+            diagnostic.message,
+            self.paint(RESET, "")
+        );
+        let line = match self.line(diagnostic) {
+            Some(line) => line,
+            // unknown positions, or the end of input after a last newline
+            _ if diagnostic.span.line == 0 => {
+                out.push_str(&format!("{} {}\n", self.paint(BLUE, "-->"), self.file));
+                return out + &self.footer(diagnostic, 0);
+            }
+            // no source to show, as for a precompiled program
+            _ if self.lines.is_empty() => {
+                out.push_str(&format!(
+                    "{} {}:{}\n",
+                    self.paint(BLUE, "-->"),
+                    self.file,
+                    diagnostic.span
+                ));
+                return out + &self.footer(diagnostic, 0);
+            }
+            _ => "",
+        };
+        let gutter = diagnostic.span.line.to_string().len();
+        let margin = " ".repeat(gutter);
+        out.push_str(&format!(
+            "{}{} {}:{}\n",
+            margin,
+            self.paint(BLUE, "-->"),
+            self.file,
+            diagnostic.span
+        ));
+        out.push_str(&format!("{} {}\n", margin, self.paint(BLUE, "|")));
+        out.push_str(&format!(
+            "{} {} {}\n",
+            self.paint(BLUE, &diagnostic.span.line.to_string()),
+            self.paint(BLUE, "|"),
+            line
+        ));
+        // tabs stay tabs, so the underline lines up however they are shown
+        let column = diagnostic.span.column.max(1) - 1;
+        let indent: String = line
+            .chars()
+            .chain(std::iter::repeat(' '))
+            .take(column)
+            .map(|ch| if ch == '\t' { '\t' } else { ' ' })
+            .collect();
+        let width = self.width(diagnostic);
+        out.push_str(&format!(
+            "{} {} {}{}\n",
+            margin,
+            self.paint(BLUE, "|"),
+            indent,
+            self.paint(severity, &"^".repeat(width))
+        ));
+        out + &self.footer(diagnostic, gutter)
+    }
+
+    fn footer(&self, diagnostic: &Diagnostic, gutter: usize) -> String {
+        let mut out = String::new();
+        let margin = " ".repeat(gutter);
+        for note in diagnostic.notes.iter() {
+            out.push_str(&format!(
+                "{} {} {}: {}\n",
+                margin,
+                self.paint(BLUE, "="),
+                self.paint(BOLD, "note"),
+                note
+            ));
+        }
+        for help in diagnostic.help.iter() {
+            out.push_str(&format!(
+                "{} {} {}: {}\n",
+                margin,
+                self.paint(BLUE, "="),
+                self.paint(CYAN, "help"),
+                help
+            ));
+        }
+        out
+    }
+
+    // The diagnostics as a JSON array, one object per diagnostic, for editors.
+    pub fn json(&self, diagnostics: &[Diagnostic]) -> String {
+        let objects: Vec<String> = diagnostics
+            .iter()
+            .map(|diagnostic| {
+                let fields = [
+                    ("file", json_string(self.file)),
+                    ("severity", json_string(&diagnostic.severity.to_string())),
+                    ("message", json_string(&diagnostic.message)),
+                    ("line", diagnostic.span.line.to_string()),
+                    ("column", diagnostic.span.column.to_string()),
+                    ("width", self.width(diagnostic).to_string()),
+                    ("notes", json_strings(&diagnostic.notes)),
+                    ("help", json_strings(&diagnostic.help)),
+                ];
+                let fields: Vec<String> = fields
+                    .iter()
+                    .map(|(key, value)| format!("\"{}\":{}", key, value))
+                    .collect();
+                format!("{{{}}}", fields.join(","))
+            })
+            .collect();
+        format!("[{}]", objects.join(","))
+    }
+
+    fn width(&self, diagnostic: &Diagnostic) -> usize {
+        match (diagnostic.width, self.line(diagnostic)) {
+            (0, Some(line)) => word_width(line, diagnostic.span.column.max(1) - 1),
+            (0, _) => 1,
+            (width, _) => width,
+        }
+    }
+
+    fn line(&self, diagnostic: &Diagnostic) -> Option<&'a str> {
+        self.lines
+            .get(diagnostic.span.line.wrapping_sub(1))
+            .copied()
+    }
+
+    // `text` in `colour`, or `text` alone without colours; an empty `text`
+    // is only the colour code.
+    fn paint(&self, colour: &str, text: &str) -> String {
+        match (self.colour, text.is_empty()) {
+            (false, _) => String::from(text),
+            (true, true) => String::from(colour),
+            (true, false) => format!("{}{}{}", colour, text, RESET),
+        }
+    }
+}
+
+// The length of the name or number at `column`, one for anything else.
+fn word_width(line: &str, column: usize) -> usize {
+    line.chars()
+        .skip(column)
+        .take_while(|ch| ch.is_alphanumeric() || *ch == '_')
+        .count()
+        .max(1)
+}
+
+fn json_strings(strings: &[String]) -> String {
+    let strings: Vec<String> = strings.iter().map(|s| json_string(s)).collect();
+    format!("[{}]", strings.join(","))
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for ch in s.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            ch if (ch as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", ch as u32)),
+            ch => out.push(ch),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -1,46 +1,122 @@
+use super::ast::LexicalAddress;
+use super::module::*;
 use super::object::*;
 use std::cell::*;
 use std::collections::HashMap;
+use std::path::*;
 use std::rc::*;
 
 #[derive(Debug)]
 pub struct Environment {
-    store: HashMap<String, Box<dyn Object>>,
-    outer: RefCell<Weak<RefCell<Environment>>>,
+    store: HashMap<String, Value>,
+    slots: Vec<Option<Value>>, // locals resolved to lexical addresses
+    outer: Option<Rc<RefCell<Environment>>>,
+    loader: Option<Rc<RefCell<ModuleLoader>>>, // only set on top-level environments
+    file: Option<PathBuf>,                     // the module file this environment belongs to
 }
 impl Environment {
-    pub fn get(&self, name: &String) -> Option<Box<dyn Object>> {
-        println!("env.get: {}", name);
+    pub fn get(&self, name: &String) -> Option<Value> {
         match self.store.get(name) {
-            Some(obj) => {
-                let v: Box<dyn Object> = obj.duplicate();
-                Some(v)
-            }
-            _ => match self.outer.borrow().upgrade() {
+            Some(obj) => Some(obj.clone()),
+            _ => match &self.outer {
                 Some(outer) => outer.borrow().get(name),
                 _ => None,
             },
         }
     }
-    pub fn set(&mut self, name: String, val: Box<dyn Object>) -> Option<Box<dyn Object>> {
-        println!("env.set: {}, {:?}", name, val);
+    pub fn set(&mut self, name: String, val: Value) -> Option<Value> {
         match self.store.insert(name.clone(), val) {
             Some(_) => None,
             _ => self.get(&name),
         }
     }
+    // The names bound here and in the environments around this one. Locals
+    // resolved to slots have no names to give.
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.store.keys().cloned().collect();
+        if let Some(outer) = &self.outer {
+            names.extend(outer.borrow().names());
+        }
+        names
+    }
+    // The names bound in this environment alone and their values, by name.
+    pub fn bindings(&self) -> Vec<(String, Value)> {
+        let mut bindings: Vec<(String, Value)> = self
+            .store
+            .iter()
+            .map(|(name, val)| (name.clone(), val.clone()))
+            .collect();
+        bindings.sort_by(|a, b| a.0.cmp(&b.0));
+        bindings
+    }
+    pub fn get_at(&self, address: LexicalAddress) -> Option<Value> {
+        if address.depth > 0 {
+            let outer = self.outer.as_ref()?.borrow();
+            return outer.get_at(LexicalAddress {
+                depth: address.depth - 1,
+                slot: address.slot,
+            });
+        }
+        match self.slots.get(address.slot) {
+            Some(Some(obj)) => Some(obj.clone()),
+            _ => None,
+        }
+    }
+    pub fn set_at(&mut self, slot: usize, val: Value) {
+        if self.slots.len() <= slot {
+            self.slots.resize_with(slot + 1, || None);
+        }
+        self.slots[slot] = Some(val);
+    }
+    pub fn loader(&self) -> Option<Rc<RefCell<ModuleLoader>>> {
+        match &self.loader {
+            Some(loader) => Some(Rc::clone(loader)),
+            _ => match &self.outer {
+                Some(outer) => outer.borrow().loader(),
+                _ => None,
+            },
+        }
+    }
+    pub fn set_loader(&mut self, loader: Rc<RefCell<ModuleLoader>>) {
+        self.loader = Some(loader);
+    }
+    pub fn file(&self) -> Option<PathBuf> {
+        match &self.file {
+            Some(file) => Some(file.clone()),
+            _ => match &self.outer {
+                Some(outer) => outer.borrow().file(),
+                _ => None,
+            },
+        }
+    }
+    pub fn set_file(&mut self, file: Option<PathBuf>) {
+        self.file = file;
+    }
 }
 
 pub fn new_environment() -> Environment {
+    new_module_environment(&Rc::new(RefCell::new(ModuleLoader::new())), None)
+}
+
+pub fn new_module_environment(
+    loader: &Rc<RefCell<ModuleLoader>>,
+    file: Option<PathBuf>,
+) -> Environment {
     Environment {
         store: HashMap::new(),
-        outer: RefCell::new(Weak::new()),
+        slots: Vec::new(),
+        outer: None,
+        loader: Some(Rc::clone(loader)),
+        file,
     }
 }
 
 pub fn new_enclosed_environment(outer: &Rc<RefCell<Environment>>) -> Environment {
-    let env = new_environment();
-    let rc = Rc::clone(outer);
-    *env.outer.borrow_mut() = Rc::downgrade(&rc);
-    env
+    Environment {
+        store: HashMap::new(),
+        slots: Vec::new(),
+        outer: Some(Rc::clone(outer)),
+        loader: None,
+        file: None,
+    }
 }
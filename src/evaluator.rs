@@ -1,416 +1,1631 @@
 use super::ast::*;
+use super::builtins::*;
+use super::diagnostics::did_you_mean;
 use super::environment::*;
+use super::lexer::*;
+use super::module::*;
 use super::object::*;
-use std::any::*;
+use super::vm::splice_unquoted;
 use std::cell::*;
-use std::ops::*;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
 use std::rc::*;
+use std::sync::atomic::*;
+use std::sync::Arc;
+use std::time::*;
 
-pub const TRUE: super::object::Boolean = super::object::Boolean { value: true };
-pub const FALSE: super::object::Boolean = super::object::Boolean { value: false };
-pub const NULL: super::object::Null = super::object::Null {};
+pub const TRUE: Value = Value::Boolean(true);
+pub const FALSE: Value = Value::Boolean(false);
+pub const NULL: Value = Value::Null;
 
-fn eval_statement(stmt: &dyn Statement, env: &Rc<RefCell<Environment>>) -> Option<Box<dyn Object>> {
-    println!("eval_statement: {:?}", stmt.string());
-    if let Some(expression_stmt) = stmt.as_any().downcast_ref::<ExpressionStmt>() {
-        return eval_expression(&*expression_stmt.expression, env);
+// How deep calls may nest before evaluation fails with a RecursionError.
+pub const MAX_DEPTH: usize = 10_000;
+
+// Budgets for one evaluation, for scripts that cannot be trusted to end.
+// Running out of one fails evaluation with a LimitError, which scripts cannot
+// catch.
+#[derive(Debug, Clone)]
+pub struct ExecutionLimits {
+    pub fuel: Option<u64>, // expressions evaluated
+    pub timeout: Option<Duration>,
+    pub max_depth: usize, // calls nested, past it is a RecursionError
+    pub max_allocations: Option<usize>, // strings, arrays, hashes, functions and quotes made
+    pub max_allocated_bytes: Option<usize>,
+    pub interrupt: Option<InterruptHandle>, // stops evaluation with an InterruptError
+}
+impl ExecutionLimits {
+    // An InterruptError once the interrupt handle is raised. Engines check
+    // at least once per call.
+    pub fn check_interrupt(&self) -> Result<(), Rc<Error>> {
+        match &self.interrupt {
+            Some(interrupt) if interrupt.is_interrupted() => Err(Rc::new(new_error(
+                INTERRUPT_ERROR,
+                format_args!("interrupted"),
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+impl Default for ExecutionLimits {
+    fn default() -> ExecutionLimits {
+        ExecutionLimits {
+            fuel: None,
+            timeout: None,
+            max_depth: MAX_DEPTH,
+            max_allocations: None,
+            max_allocated_bytes: None,
+            interrupt: None,
+        }
     }
-    if let Some(block_stmt) = stmt.as_any().downcast_ref::<BlockStatement>() {
-        return eval_block_statement(block_stmt, env);
+}
+
+// A flag another thread, or a signal handler, raises to stop an evaluation.
+// Clones share the flag, which stays raised until `reset`.
+#[derive(Debug, Clone, Default)]
+pub struct InterruptHandle {
+    interrupted: Arc<AtomicBool>,
+}
+impl InterruptHandle {
+    pub fn new() -> InterruptHandle {
+        InterruptHandle::default()
     }
-    if let Some(return_stmt) = stmt.as_any().downcast_ref::<ReturnStatement>() {
-        if let Some(val) = eval_expression(&*return_stmt.return_value, env) {
-            if is_error(&val) {
-                return Some(val);
-            }
-            return Some(Box::new(ReturnValue { value: val }));
+    pub fn interrupt(&self) {
+        self.interrupted.store(true, Ordering::SeqCst);
+    }
+    pub fn is_interrupted(&self) -> bool {
+        self.interrupted.load(Ordering::SeqCst)
+    }
+    pub fn reset(&self) {
+        self.interrupted.store(false, Ordering::SeqCst);
+    }
+}
+
+// The clock is only read every so many steps.
+const STEPS_PER_CLOCK_CHECK: u64 = 256;
+
+type Env = Rc<RefCell<Environment>>;
+type NamedArguments = Vec<(String, Value)>;
+
+// The evaluator runs a copy of the AST whose parts are shared through `Rc`,
+// so that work put off for later can hold on to them.
+enum Code {
+    Literal(Value),
+    Identifier(Identifier),
+    Prefix(Rc<str>, Rc<Code>, Span),
+    Infix(Rc<str>, Rc<Code>, Rc<Code>, Span),
+    If(Rc<Code>, Block, Option<Block>),
+    Try(Rc<TryCode>),
+    Function(Rc<FunctionLiteral>, Rc<FunctionBody>),
+    Macro(Rc<MacroLiteral>),
+    Call(Rc<CallCode>),
+    Quote(Expression, Vec<Rc<Code>>),
+    Array(Vec<Rc<Code>>),
+    Hash(Vec<(Rc<Code>, Rc<Code>)>),
+    Index(Rc<Code>, Rc<Code>, Span),
+    Match(Rc<Code>, Vec<MatchArmCode>, Span),
+}
+
+struct CallCode {
+    name: String,
+    span: Span,
+    function: Rc<Code>,
+    arguments: Vec<Rc<Code>>,
+    named_arguments: Vec<(String, Rc<Code>)>,
+}
+
+impl CallCode {
+    fn argument_count(&self) -> usize {
+        self.arguments.len() + self.named_arguments.len()
+    }
+}
+
+struct TryCode {
+    block: Block,
+    catch_parameter: Option<Identifier>,
+    catch_block: Option<Block>,
+    finally_block: Option<Block>,
+}
+
+struct MatchArmCode {
+    pattern: Pattern,
+    guard: Option<Rc<Code>>,
+    body: Block,
+}
+
+enum Stmt {
+    Expression(Rc<Code>),
+    Let(Rc<Identifier>, Rc<Code>),
+    LetPattern(Rc<Pattern>, Rc<Code>),
+    Return(Rc<Code>),
+    Throw(Rc<Code>, Span),
+    Import(Rc<ImportStatement>),
+    Block(Block),
+}
+
+type Block = Rc<[Stmt]>;
+
+// The body of a function literal, converted along with the code around it.
+pub struct FunctionBody {
+    defaults: Vec<Option<Rc<Code>>>,
+    body: Block,
+}
+
+// The calls a frame was made by, the first one and then those in tail
+// position, with the function called and repeats counted.
+type Calls = Vec<(Rc<CallCode>, Rc<FunctionLiteral>, usize)>;
+
+// What is left to do, next last. An expression leaves its value on the value
+// stack, a statement leaves `None` or the value of its expression.
+enum Work {
+    Expression(Rc<Code>, Env),
+    Statements(Block, usize, Env),
+    Discard,
+    ToValue,
+    // operators and indexes with the span of their expression, for errors
+    Prefix(Rc<str>, Span),
+    Infix(Rc<str>, Span),
+    Branch(Rc<Code>, Env),
+    Call(Rc<CallCode>),
+    Parameters(Box<Parameters>),
+    Array(usize),
+    HashKey,
+    Hash(usize),
+    Index(Span),
+    Quote(Rc<Code>),
+    Match(Rc<Code>, Env),
+    Guard {
+        code: Rc<Code>,
+        env: Env,
+        value: Value,
+        arm: usize,
+        arm_env: Env,
+    },
+    Define(Rc<Identifier>, Env),
+    Destructure(Rc<Pattern>, Env),
+    Return,
+    Throw(Span),
+    Catch(Rc<TryCode>, Env, usize),
+    Finally(Rc<TryCode>, Env, usize),
+    Resume(Unwind),
+    // the end of a call
+    Frame(Calls, usize),
+}
+
+// Leaving code early, until a `catch`, `finally` or the end of the function.
+enum Unwind {
+    Error(Rc<Error>),
+    Return(Value),
+}
+
+// An argument for each parameter, `None` where its default is needed.
+type Slots = Vec<Option<Value>>;
+
+// A call whose parameters are being bound, waiting for a default value.
+struct Parameters {
+    function: Rc<Function>,
+    body: Rc<FunctionBody>,
+    env: Env,
+    slots: Slots,
+    rest: Vec<Value>,
+    next: usize,
+}
+
+// Evaluates with its work and values on the heap, so that deep recursion in
+// a script runs into `max_depth` rather than the end of the Rust stack.
+struct Machine {
+    work: Vec<Work>,
+    values: Vec<Option<Value>>,
+    depth: usize,
+    limits: ExecutionLimits,
+    deadline: Option<Instant>,
+    steps: u64,
+    allocations: usize,
+    allocated_bytes: usize,
+}
+
+impl Machine {
+    fn new(limits: &ExecutionLimits) -> Machine {
+        Machine {
+            work: Vec::new(),
+            values: Vec::new(),
+            depth: 0,
+            limits: limits.clone(),
+            deadline: limits.timeout.map(|timeout| Instant::now() + timeout),
+            steps: 0,
+            allocations: 0,
+            allocated_bytes: 0,
+        }
+    }
+
+    fn run(mut self, work: Work) -> Option<Value> {
+        self.work.push(work);
+        while let Some(work) = self.work.pop() {
+            self.step(work);
+        }
+        self.values.pop().flatten()
+    }
+
+    fn push(&mut self, val: Value) {
+        self.values.push(Some(val));
+    }
+
+    fn pop(&mut self) -> Value {
+        self.values.pop().flatten().unwrap_or(NULL)
+    }
+
+    fn pop_values(&mut self, count: usize) -> Vec<Value> {
+        let at = self.values.len() - count;
+        self.values
+            .drain(at..)
+            .map(|val| val.unwrap_or(NULL))
+            .collect()
+    }
+
+    // Pushes the result of the expression at `span`, or raises its error there.
+    fn push_result(&mut self, result: Option<Value>, span: Span) {
+        match result {
+            Some(Value::Error(err)) => self.throw(raised_at(err, span)),
+            result => self.push(result.unwrap_or(NULL)),
         }
     }
-    if let Some(let_stmt) = stmt.as_any().downcast_ref::<LetStatement>() {
-        if let Some(val) = eval_expression(&*let_stmt.value, env) {
-            if is_error(&val) {
-                return Some(val);
+
+    // Pushes a value that may have just been made, counting it against the
+    // allocation limits.
+    fn push_allocated(&mut self, val: Value) {
+        if let Some(size) = allocation_size(&val) {
+            self.allocations += 1;
+            self.allocated_bytes += size;
+            if self
+                .limits
+                .max_allocations
+                .is_some_and(|max| self.allocations > max)
+            {
+                return self.throw(limit_error("allocation limit exceeded"));
+            }
+            if self
+                .limits
+                .max_allocated_bytes
+                .is_some_and(|max| self.allocated_bytes > max)
+            {
+                return self.throw(limit_error("memory limit exceeded"));
             }
-            env.borrow_mut().set(let_stmt.name.value.clone(), val);
         }
+        self.push(val);
     }
-    None
-}
 
-fn eval_expression(
-    exp: &dyn Expression,
-    env: &Rc<RefCell<Environment>>,
-) -> Option<Box<dyn Object>> {
-    println!("eval_expression: {:?}", exp.string());
-    if let Some(integer_literal) = exp.as_any().downcast_ref::<IntegerLiteral>() {
-        return Some(Box::new(Integer {
-            value: integer_literal.value,
-        }));
+    // Takes a step of fuel, failing once the fuel or the time is used up or
+    // evaluation is interrupted.
+    fn use_fuel(&mut self) -> Result<(), Rc<Error>> {
+        self.limits.check_interrupt()?;
+        self.steps += 1;
+        if self.limits.fuel.is_some_and(|fuel| self.steps > fuel) {
+            return Err(limit_error("evaluation step limit exceeded"));
+        }
+        match self.deadline {
+            Some(deadline)
+                if self.steps.is_multiple_of(STEPS_PER_CLOCK_CHECK)
+                    && Instant::now() >= deadline =>
+            {
+                Err(limit_error("time limit exceeded"))
+            }
+            _ => Ok(()),
+        }
     }
-    if let Some(boolean) = exp.as_any().downcast_ref::<super::ast::Boolean>() {
-        return native_bool_to_boolean_object(boolean.value);
+
+    fn step(&mut self, work: Work) {
+        match work {
+            Work::Expression(code, env) => self.expression(code, env),
+            Work::Statements(block, idx, env) => {
+                let stmt = match block.get(idx) {
+                    Some(stmt) => stmt,
+                    _ => return self.values.push(None),
+                };
+                if idx + 1 < block.len() {
+                    self.work.push(Work::Statements(
+                        Rc::clone(&block),
+                        idx + 1,
+                        Rc::clone(&env),
+                    ));
+                    self.work.push(Work::Discard);
+                }
+                self.statement(stmt, env);
+            }
+            Work::Discard => {
+                self.values.pop();
+            }
+            Work::ToValue => {
+                if let Some(top @ None) = self.values.last_mut() {
+                    *top = Some(NULL);
+                }
+            }
+            Work::Prefix(operator, span) => {
+                let right = self.pop();
+                let result = eval_prefix_expression(&operator, right);
+                self.push_result(result, span);
+            }
+            Work::Infix(operator, span) => {
+                let right = self.pop();
+                let left = self.pop();
+                // strings are joined into a new one
+                match eval_infix_expression(&operator, left, right) {
+                    Some(Value::Error(err)) => self.throw(raised_at(err, span)),
+                    result => self.push_allocated(result.unwrap_or(NULL)),
+                }
+            }
+            Work::Branch(code, env) => {
+                let condition = self.pop();
+                if let Code::If(_, consequence, alternative) = &*code {
+                    let block = if is_truthy(Some(condition)) {
+                        Some(consequence)
+                    } else {
+                        alternative.as_ref()
+                    };
+                    match block {
+                        Some(block) => self.block(block, env),
+                        _ => self.push(NULL),
+                    }
+                }
+            }
+            Work::Call(call) => {
+                let named_values = self.pop_values(call.named_arguments.len());
+                let args = self.pop_values(call.arguments.len());
+                let function = self.pop();
+                let named = call
+                    .named_arguments
+                    .iter()
+                    .map(|(name, _)| name.clone())
+                    .zip(named_values)
+                    .collect();
+                self.apply(call, function, args, named);
+            }
+            Work::Parameters(parameters) => {
+                let val = self.pop();
+                match bind_parameter(&parameters, parameters.next - 1, val) {
+                    Ok(()) => self.bind_parameters(parameters),
+                    Err(err) => self.throw(err),
+                }
+            }
+            Work::Array(count) => {
+                let elements = self.pop_values(count);
+                self.push_allocated(Value::Array(Rc::new(elements)));
+            }
+            Work::HashKey => {
+                let key = self.values.last().cloned().flatten().unwrap_or(NULL);
+                if key.hash_key().is_none() {
+                    self.throw(Rc::new(new_error(
+                        TYPE_ERROR,
+                        format_args!("unusable as hash key: {}", key.get_type()),
+                    )));
+                }
+            }
+            Work::Hash(count) => {
+                let mut values = self.pop_values(2 * count).into_iter();
+                let mut pairs = BTreeMap::new();
+                while let (Some(key), Some(value)) = (values.next(), values.next()) {
+                    if let Some(hash_key) = key.hash_key() {
+                        pairs.insert(hash_key, HashPair { key, value });
+                    }
+                }
+                self.push_allocated(Value::Hash(Rc::new(pairs)));
+            }
+            Work::Index(span) => {
+                let index = self.pop();
+                let left = self.pop();
+                let result = eval_index_expression(left, index);
+                self.push_result(result, span);
+            }
+            Work::Quote(code) => {
+                if let Code::Quote(template, unquoted) = &*code {
+                    let values = self.pop_values(unquoted.len());
+                    match splice_unquoted(template.clone(), values) {
+                        Ok(node) => self.push_allocated(Value::Quote(Rc::new(node))),
+                        Err(err) => self.throw(err),
+                    }
+                }
+            }
+            Work::Match(code, env) => {
+                let value = self.pop();
+                self.match_arms(code, env, value, 0);
+            }
+            Work::Guard {
+                code,
+                env,
+                value,
+                arm,
+                arm_env,
+            } => {
+                let condition = self.pop();
+                match &*code {
+                    Code::Match(_, arms, _) if is_truthy(Some(condition)) => {
+                        self.block(&arms[arm].body, arm_env)
+                    }
+                    _ => self.match_arms(code, env, value, arm + 1),
+                }
+            }
+            Work::Define(ident, env) => {
+                let val = self.pop();
+                define(&env, &ident, val);
+                self.values.push(None);
+            }
+            Work::Destructure(pattern, env) => {
+                let val = self.pop();
+                match bind_pattern(&pattern, &val, &env) {
+                    Ok(()) => self.values.push(None),
+                    Err(err) => self.throw(err),
+                }
+            }
+            Work::Return => {
+                let val = self.pop();
+                self.unwind(Unwind::Return(val));
+            }
+            Work::Throw(span) => {
+                let val = self.pop();
+                self.throw(raised_at(Rc::new(Error::from_value(&val)), span));
+            }
+            // the `try` block ran without error
+            Work::Catch(..) => {}
+            Work::Finally(try_code, env, _) => {
+                if let Some(finally_block) = &try_code.finally_block {
+                    self.work.push(Work::Discard);
+                    self.work
+                        .push(Work::Statements(Rc::clone(finally_block), 0, env));
+                }
+            }
+            Work::Resume(unwind) => self.unwind(unwind),
+            Work::Frame(..) => {
+                let val = self.pop();
+                self.push(val);
+                self.depth -= 1;
+            }
+        }
     }
-    if let Some(prefix_exp) = exp.as_any().downcast_ref::<PrefixExpression>() {
-        if let Some(right) = eval_expression(&*prefix_exp.right, env) {
-            if is_error(&right) {
-                return Some(right);
+
+    fn statement(&mut self, stmt: &Stmt, env: Env) {
+        match stmt {
+            Stmt::Expression(code) => self.work.push(Work::Expression(Rc::clone(code), env)),
+            Stmt::Let(ident, code) => {
+                self.work
+                    .push(Work::Define(Rc::clone(ident), Rc::clone(&env)));
+                self.work.push(Work::Expression(Rc::clone(code), env));
             }
-            return eval_prefix_expression(&prefix_exp.operator, right);
+            Stmt::LetPattern(pattern, code) => {
+                self.work
+                    .push(Work::Destructure(Rc::clone(pattern), Rc::clone(&env)));
+                self.work.push(Work::Expression(Rc::clone(code), env));
+            }
+            Stmt::Return(code) => {
+                self.work.push(Work::Return);
+                self.work.push(Work::Expression(Rc::clone(code), env));
+            }
+            Stmt::Throw(code, span) => {
+                self.work.push(Work::Throw(*span));
+                self.work.push(Work::Expression(Rc::clone(code), env));
+            }
+            Stmt::Import(import_stmt) => match eval_import_statement(import_stmt, &env) {
+                Ok(()) => self.values.push(None),
+                Err(err) => self.throw(raised_at(err, import_stmt.token.span)),
+            },
+            Stmt::Block(block) => self.work.push(Work::Statements(Rc::clone(block), 0, env)),
         }
     }
-    if let Some(infix_exp) = exp.as_any().downcast_ref::<InfixExpression>() {
-        if let Some(left) = eval_expression(&*infix_exp.left, env) {
-            if is_error(&left) {
-                return Some(left);
+
+    fn expression(&mut self, code: Rc<Code>, env: Env) {
+        if let Err(err) = self.use_fuel() {
+            return self.throw(err);
+        }
+        match &*code {
+            Code::Literal(val) => self.push(val.clone()),
+            Code::Identifier(ident) => {
+                let result = eval_identifier(ident, &env);
+                self.push_result(result, ident.token.span);
+            }
+            Code::Prefix(operator, right, span) => {
+                self.work.push(Work::Prefix(Rc::clone(operator), *span));
+                self.work.push(Work::Expression(Rc::clone(right), env));
+            }
+            Code::Infix(operator, left, right, span) => {
+                self.work.push(Work::Infix(Rc::clone(operator), *span));
+                self.work
+                    .push(Work::Expression(Rc::clone(right), Rc::clone(&env)));
+                self.work.push(Work::Expression(Rc::clone(left), env));
+            }
+            Code::If(condition, ..) => {
+                self.work
+                    .push(Work::Branch(Rc::clone(&code), Rc::clone(&env)));
+                self.work.push(Work::Expression(Rc::clone(condition), env));
+            }
+            Code::Try(try_code) => {
+                let height = self.values.len();
+                self.work.push(Work::ToValue);
+                if try_code.finally_block.is_some() {
+                    self.work
+                        .push(Work::Finally(Rc::clone(try_code), Rc::clone(&env), height));
+                }
+                if try_code.catch_block.is_some() {
+                    self.work
+                        .push(Work::Catch(Rc::clone(try_code), Rc::clone(&env), height));
+                }
+                self.work
+                    .push(Work::Statements(Rc::clone(&try_code.block), 0, env));
+            }
+            Code::Function(function_literal, body) => {
+                self.push_allocated(Value::Function(Rc::new(Function {
+                    function_literal: Rc::clone(function_literal),
+                    env,
+                    compiled: None,
+                    body: Some(Rc::clone(body)),
+                })))
+            }
+            Code::Macro(macro_literal) => self.push_allocated(Value::Macro(Rc::new(Macro {
+                macro_literal: Rc::clone(macro_literal),
+                env,
+            }))),
+            Code::Call(call) => {
+                self.work.push(Work::Call(Rc::clone(call)));
+                for (_, arg) in call.named_arguments.iter().rev() {
+                    self.work
+                        .push(Work::Expression(Rc::clone(arg), Rc::clone(&env)));
+                }
+                for arg in call.arguments.iter().rev() {
+                    self.work
+                        .push(Work::Expression(Rc::clone(arg), Rc::clone(&env)));
+                }
+                self.work
+                    .push(Work::Expression(Rc::clone(&call.function), env));
+            }
+            Code::Quote(_, unquoted) => {
+                self.work.push(Work::Quote(Rc::clone(&code)));
+                for arg in unquoted.iter().rev() {
+                    self.work
+                        .push(Work::Expression(Rc::clone(arg), Rc::clone(&env)));
+                }
+            }
+            Code::Array(elements) => {
+                self.work.push(Work::Array(elements.len()));
+                for element in elements.iter().rev() {
+                    self.work
+                        .push(Work::Expression(Rc::clone(element), Rc::clone(&env)));
+                }
             }
-            if let Some(right) = eval_expression(&*infix_exp.right, env) {
-                if is_error(&right) {
-                    return Some(right);
+            Code::Hash(pairs) => {
+                self.work.push(Work::Hash(pairs.len()));
+                for (key, value) in pairs.iter().rev() {
+                    self.work
+                        .push(Work::Expression(Rc::clone(value), Rc::clone(&env)));
+                    self.work.push(Work::HashKey);
+                    self.work
+                        .push(Work::Expression(Rc::clone(key), Rc::clone(&env)));
                 }
-                return eval_infix_expression(&infix_exp.operator, left, right);
             }
+            Code::Index(left, index, span) => {
+                self.work.push(Work::Index(*span));
+                self.work
+                    .push(Work::Expression(Rc::clone(index), Rc::clone(&env)));
+                self.work.push(Work::Expression(Rc::clone(left), env));
+            }
+            Code::Match(value, ..) => {
+                self.work
+                    .push(Work::Match(Rc::clone(&code), Rc::clone(&env)));
+                self.work.push(Work::Expression(Rc::clone(value), env));
+            }
+        }
+    }
+
+    // Runs the block as the value of an expression.
+    fn block(&mut self, block: &Block, env: Env) {
+        if !matches!(block.last(), Some(Stmt::Expression(_))) {
+            self.work.push(Work::ToValue);
         }
+        self.work.push(Work::Statements(Rc::clone(block), 0, env));
     }
-    if let Some(if_exp) = exp.as_any().downcast_ref::<IfExpression>() {
-        return eval_if_expression(&*if_exp, env);
+
+    // An error takes the stack it is raised in, one rethrown keeps its own.
+    fn throw(&mut self, mut err: Rc<Error>) {
+        if err.stack.is_empty() {
+            Rc::make_mut(&mut err).stack = self.stack_trace();
+        }
+        self.unwind(Unwind::Error(err));
     }
-    if let Some(ident) = exp.as_any().downcast_ref::<Identifier>() {
-        return eval_identifier(&*ident, env);
+
+    // The calls running, innermost first.
+    fn stack_trace(&self) -> Vec<StackFrame> {
+        let mut stack = Vec::new();
+        for work in self.work.iter().rev() {
+            if let Work::Frame(calls, _) = work {
+                for (call, function_literal, count) in calls.iter().rev() {
+                    let name = function_literal.name.as_deref().unwrap_or(&call.name);
+                    let frame = StackFrame::new(name, call.span, call.argument_count());
+                    stack.extend(std::iter::repeat_n(frame, *count));
+                }
+            }
+        }
+        stack
+    }
+
+    fn unwind(&mut self, unwind: Unwind) {
+        while let Some(work) = self.work.pop() {
+            match work {
+                Work::Catch(try_code, env, height) => {
+                    let err = match &unwind {
+                        Unwind::Error(err) if !is_uncatchable(&unwind) => err,
+                        _ => continue,
+                    };
+                    self.values.truncate(height);
+                    let catch_env = Rc::new(RefCell::new(new_enclosed_environment(&env)));
+                    if let Some(param) = &try_code.catch_parameter {
+                        define(&catch_env, param, err.to_value());
+                    }
+                    if let Some(catch_block) = &try_code.catch_block {
+                        self.work
+                            .push(Work::Statements(Rc::clone(catch_block), 0, catch_env));
+                    }
+                    return;
+                }
+                // a stopped script gets to run nothing more
+                Work::Finally(..) if is_uncatchable(&unwind) => {}
+                Work::Finally(try_code, env, height) => {
+                    self.values.truncate(height);
+                    self.work.push(Work::Resume(unwind));
+                    self.work.push(Work::Finally(try_code, env, height));
+                    return;
+                }
+                Work::Frame(_, height) => {
+                    self.values.truncate(height);
+                    self.depth -= 1;
+                    if let Unwind::Return(val) = unwind {
+                        return self.push(val);
+                    }
+                }
+                _ => {}
+            }
+        }
+        // nothing left to unwind to, evaluation ends here
+        self.values.clear();
+        match unwind {
+            Unwind::Error(err) => self.push(Value::Error(err)),
+            Unwind::Return(val) => self.push(val),
+        }
     }
-    if let Some(function_literal) = exp.as_any().downcast_ref::<FunctionLiteral>() {
-        return Some(Box::new(Function {
-            function_literal: Rc::new(RefCell::new(function_literal)),
-            env: Rc::clone(env),
+
+    fn apply(&mut self, call: Rc<CallCode>, func: Value, args: Vec<Value>, named: NamedArguments) {
+        let span = call.span;
+        let function = match func {
+            Value::Function(function) => function,
+            Value::Builtin(builtin) => {
+                return match apply_builtin(&builtin, args, named) {
+                    Value::Error(mut err) => {
+                        if err.stack.is_empty() {
+                            let frame =
+                                StackFrame::new(&builtin.name, call.span, call.argument_count());
+                            let mut stack = vec![frame];
+                            stack.extend(self.stack_trace());
+                            Rc::make_mut(&mut err).stack = stack;
+                        }
+                        self.throw(raised_at(err, span))
+                    }
+                    // builtins are taken to make what they return
+                    val => self.push_allocated(val),
+                };
+            }
+            _ => {
+                let mut err = new_error(
+                    TYPE_ERROR,
+                    format_args!("not a function: {}", func.get_type()),
+                );
+                err.stack.push(StackFrame::new(
+                    &call.name,
+                    call.span,
+                    call.argument_count(),
+                ));
+                err.stack.extend(self.stack_trace());
+                return self.throw(raised_at(Rc::new(err), span));
+            }
+        };
+
+        let function_literal = Rc::clone(&function.function_literal);
+        let calls = match self.take_tail_frame() {
+            Some(mut calls) => {
+                match calls.last_mut() {
+                    Some((last, last_literal, count))
+                        if Rc::ptr_eq(last, &call)
+                            && Rc::ptr_eq(last_literal, &function_literal) =>
+                    {
+                        *count += 1
+                    }
+                    _ => calls.push((call, function_literal, 1)),
+                }
+                calls
+            }
+            _ => {
+                self.depth += 1;
+                vec![(call, function_literal, 1)]
+            }
+        };
+        self.work.push(Work::Frame(calls, self.values.len()));
+        if self.depth > self.limits.max_depth {
+            let err = new_error(
+                RECURSION_ERROR,
+                format_args!("maximum recursion depth exceeded"),
+            );
+            return self.throw(raised_at(Rc::new(err), span));
+        }
+
+        let (slots, rest) = match fill_parameter_slots(&function.function_literal, args, named) {
+            Ok(filled) => filled,
+            Err(err) => return self.throw(raised_at(err, span)),
+        };
+        let body = match &function.body {
+            Some(body) => Rc::clone(body),
+            _ => Rc::new(convert_function(&function.function_literal)),
+        };
+        let env = Rc::new(RefCell::new(new_enclosed_environment(&function.env)));
+        self.work
+            .push(Work::Statements(Rc::clone(&body.body), 0, Rc::clone(&env)));
+        self.bind_parameters(Box::new(Parameters {
+            function,
+            body,
+            env,
+            slots,
+            rest,
+            next: 0,
         }));
     }
-    if let Some(call_exp) = exp.as_any().downcast_ref::<CallExpression>() {
-        if let Some(function) = eval_expression(&*call_exp.function, env) {
-            if is_error(&function) {
-                return Some(function);
+
+    // Binds parameters in order, so a default can use the ones before it.
+    fn bind_parameters(&mut self, mut parameters: Box<Parameters>) {
+        while parameters.next < parameters.slots.len() {
+            let idx = parameters.next;
+            parameters.next += 1;
+            let val = match parameters.slots[idx].take() {
+                Some(val) => val,
+                _ => match parameters.body.defaults[idx].clone() {
+                    Some(default) => {
+                        let env = Rc::clone(&parameters.env);
+                        self.work.push(Work::Parameters(parameters));
+                        self.work.push(Work::Expression(default, env));
+                        return;
+                    }
+                    _ => NULL,
+                },
+            };
+            if let Err(err) = bind_parameter(&parameters, idx, val) {
+                return self.throw(err);
             }
+        }
+        if let Some(rest) = &parameters.function.function_literal.rest {
+            let elements = std::mem::take(&mut parameters.rest);
+            define(&parameters.env, rest, Value::Array(Rc::new(elements)));
+        }
+    }
 
-            let args = eval_expressions(&call_exp.arguments, env);
-            if args.len() == 1 && args[0].is_some() && is_error(&args[0].as_ref().unwrap()) {
-                return Some(args[0].as_ref().unwrap().duplicate());
+    // A call takes over the frame of the function it is made from when
+    // nothing is left to do there but return its value. Hands back the calls
+    // of the frame taken over.
+    fn take_tail_frame(&mut self) -> Option<Calls> {
+        let returning = matches!(self.work.last(), Some(Work::Return));
+        let mut idx = self.work.len();
+        while idx > 0 {
+            match self.work[idx - 1] {
+                Work::Frame(..) => break,
+                // a `return` skips the rest of the function, but not a handler
+                Work::Catch(..) | Work::Finally(..) => return None,
+                _ if returning => idx -= 1,
+                _ => return None,
             }
+        }
+        if idx == 0 || !matches!(self.work[idx - 1], Work::Frame(..)) {
+            return None;
+        }
+        self.work.truncate(idx);
+        match self.work.pop() {
+            Some(Work::Frame(calls, height)) => {
+                self.values.truncate(height);
+                Some(calls)
+            }
+            _ => None,
+        }
+    }
 
-            return apply_function(function, args);
+    fn match_arms(&mut self, code: Rc<Code>, env: Env, value: Value, from: usize) {
+        let (arms, span) = match &*code {
+            Code::Match(_, arms, span) => (arms, *span),
+            _ => return,
+        };
+        for (idx, arm) in arms.iter().enumerate().skip(from) {
+            let mut bindings: Vec<(&Identifier, Value)> = Vec::new();
+            match match_pattern(&arm.pattern, &value, &env, &mut bindings) {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(err) => return self.throw(raised_at(err, span)),
+            }
+            let arm_env = Rc::new(RefCell::new(new_enclosed_environment(&env)));
+            for (ident, val) in bindings {
+                define(&arm_env, ident, val);
+            }
+            match &arm.guard {
+                Some(guard) => {
+                    let guard = Rc::clone(guard);
+                    self.work.push(Work::Guard {
+                        code: Rc::clone(&code),
+                        env,
+                        value,
+                        arm: idx,
+                        arm_env: Rc::clone(&arm_env),
+                    });
+                    self.work.push(Work::Expression(guard, arm_env));
+                }
+                _ => self.block(&arm.body, arm_env),
+            }
+            return;
         }
+        let err = new_error(
+            MATCH_ERROR,
+            format_args!("no pattern matched value: {}", value.inspect()),
+        );
+        self.throw(raised_at(Rc::new(err), span));
     }
-    None
 }
-pub fn eval(node: &dyn Node, env: &Rc<RefCell<Environment>>) -> Option<Box<dyn Object>> {
-    println!("eval: {:?}", node.string());
-    if let Some(program) = node.as_any().downcast_ref::<Program>() {
-        return eval_program(program, env);
-    }
-    None
+
+pub fn eval(program: &Program, env: &Rc<RefCell<Environment>>) -> Option<Value> {
+    eval_with_limits(program, env, &ExecutionLimits::default())
 }
 
-fn apply_function(
-    func: Box<dyn Object>,
-    args: Vec<Option<Box<dyn Object>>>,
-) -> Option<Box<dyn Object>> {
-    let function = func.as_any().downcast_ref::<Function>();
-    if function.is_none() {
-        return Some(Box::new(new_error(format_args!(
-            "not a function: {}",
-            func.get_type()
-        ))));
-    }
+pub fn eval_with_max_depth(
+    program: &Program,
+    env: &Rc<RefCell<Environment>>,
+    max_depth: usize,
+) -> Option<Value> {
+    let limits = ExecutionLimits {
+        max_depth,
+        ..ExecutionLimits::default()
+    };
+    eval_with_limits(program, env, &limits)
+}
+
+pub fn eval_with_limits(
+    program: &Program,
+    env: &Rc<RefCell<Environment>>,
+    limits: &ExecutionLimits,
+) -> Option<Value> {
+    let block = convert_block(&program.statements);
+    Machine::new(limits).run(Work::Statements(block, 0, Rc::clone(env)))
+}
 
-    let extended_env = Rc::new(RefCell::new(extend_function_env(function.unwrap(), args)));
-    let evaluated = eval_statement(
-        &function.unwrap().function_literal.borrow().body,
-        &extended_env,
-    );
-    return unwrap_return_value(evaluated);
+pub fn eval_block_statement(
+    block: &BlockStatement,
+    env: &Rc<RefCell<Environment>>,
+) -> Option<Value> {
+    let block = convert_block(&block.statements);
+    Machine::new(&ExecutionLimits::default()).run(Work::Statements(block, 0, Rc::clone(env)))
 }
 
-fn unwrap_return_value(obj: Option<Box<dyn Object>>) -> Option<Box<dyn Object>> {
-    if obj.is_none() {
-        return obj;
+// Patterns only hold literals, which get a machine of their own.
+fn eval_nested(exp: &Expression, env: &Rc<RefCell<Environment>>) -> Result<Value, Rc<Error>> {
+    let code = convert_expression(exp);
+    match Machine::new(&ExecutionLimits::default()).run(Work::Expression(code, Rc::clone(env))) {
+        Some(Value::Error(err)) => Err(err),
+        val => Ok(val.unwrap_or(NULL)),
     }
-    match obj.as_ref().unwrap().as_any().downcast_ref::<ReturnValue>() {
-        Some(return_value) => Some(return_value.value.duplicate()),
-        _ => obj,
+}
+
+fn convert_block(statements: &[Statement]) -> Block {
+    statements.iter().map(convert_statement).collect()
+}
+
+fn convert_statement(stmt: &Statement) -> Stmt {
+    match stmt {
+        Statement::Expression(expression_stmt) => {
+            Stmt::Expression(convert_expression(&expression_stmt.expression))
+        }
+        Statement::Block(block_stmt) => Stmt::Block(convert_block(&block_stmt.statements)),
+        Statement::Return(return_stmt) => {
+            Stmt::Return(convert_expression(&return_stmt.return_value))
+        }
+        Statement::Throw(throw_stmt) => {
+            Stmt::Throw(convert_expression(&throw_stmt.value), throw_stmt.token.span)
+        }
+        Statement::Import(import_stmt) => Stmt::Import(Rc::new(import_stmt.clone())),
+        Statement::Export(ExportStatement {
+            statement: let_stmt,
+            ..
+        })
+        | Statement::Let(let_stmt) => Stmt::Let(
+            Rc::new(let_stmt.name.clone()),
+            convert_expression(&let_stmt.value),
+        ),
+        Statement::LetPattern(let_stmt) => Stmt::LetPattern(
+            Rc::new(let_stmt.pattern.clone()),
+            convert_expression(&let_stmt.value),
+        ),
     }
 }
 
-fn extend_function_env(func: &Function, args: Vec<Option<Box<dyn Object>>>) -> Environment {
-    let mut env = new_enclosed_environment(&func.env);
-    for (param_idx, param) in func.function_literal.borrow().parameters.iter().enumerate() {
-        env.set(
-            param.value.clone(),
-            args[param_idx].as_ref().unwrap().duplicate(),
-        );
+fn convert_expression(exp: &Expression) -> Rc<Code> {
+    Rc::new(match exp {
+        Expression::Integer(integer_literal) => {
+            Code::Literal(Value::Integer(integer_literal.value))
+        }
+        Expression::Boolean(boolean) => Code::Literal(Value::Boolean(boolean.value)),
+        Expression::String(string_literal) => {
+            Code::Literal(Value::Str(Rc::from(string_literal.value.as_str())))
+        }
+        Expression::Identifier(ident) => Code::Identifier(ident.clone()),
+        Expression::Prefix(prefix_exp) => Code::Prefix(
+            Rc::from(prefix_exp.operator.as_str()),
+            convert_expression(&prefix_exp.right),
+            prefix_exp.token.span,
+        ),
+        Expression::Infix(infix_exp) => Code::Infix(
+            Rc::from(infix_exp.operator.as_str()),
+            convert_expression(&infix_exp.left),
+            convert_expression(&infix_exp.right),
+            infix_exp.token.span,
+        ),
+        Expression::If(if_exp) => Code::If(
+            convert_expression(&if_exp.condition),
+            convert_block(&if_exp.consequence.statements),
+            if_exp
+                .alternative
+                .as_ref()
+                .map(|alternative| convert_block(&alternative.statements)),
+        ),
+        Expression::Try(try_exp) => Code::Try(Rc::new(TryCode {
+            block: convert_block(&try_exp.block.statements),
+            catch_parameter: try_exp.catch_parameter.clone(),
+            catch_block: try_exp
+                .catch_block
+                .as_ref()
+                .map(|catch_block| convert_block(&catch_block.statements)),
+            finally_block: try_exp
+                .finally_block
+                .as_ref()
+                .map(|finally_block| convert_block(&finally_block.statements)),
+        })),
+        Expression::Function(function_literal) => Code::Function(
+            Rc::clone(function_literal),
+            Rc::new(convert_function(function_literal)),
+        ),
+        Expression::Macro(macro_literal) => Code::Macro(Rc::clone(macro_literal)),
+        Expression::Call(call_exp) if is_quote(call_exp) => convert_quote(&call_exp.arguments[0]),
+        Expression::Call(call_exp) => Code::Call(Rc::new(CallCode {
+            name: call_exp.function.string(),
+            span: call_exp.token.span,
+            function: convert_expression(&call_exp.function),
+            arguments: call_exp.arguments.iter().map(convert_expression).collect(),
+            named_arguments: call_exp
+                .named_arguments
+                .iter()
+                .map(|(name, arg)| (name.value.clone(), convert_expression(arg)))
+                .collect(),
+        })),
+        Expression::Array(array_literal) => Code::Array(
+            array_literal
+                .elements
+                .iter()
+                .map(convert_expression)
+                .collect(),
+        ),
+        Expression::Hash(hash_literal) => Code::Hash(
+            hash_literal
+                .pairs
+                .iter()
+                .map(|(key, value)| (convert_expression(key), convert_expression(value)))
+                .collect(),
+        ),
+        Expression::Index(index_exp) => Code::Index(
+            convert_expression(&index_exp.left),
+            convert_expression(&index_exp.index),
+            index_exp.token.span,
+        ),
+        Expression::Match(match_exp) => Code::Match(
+            convert_expression(&match_exp.value),
+            match_exp
+                .arms
+                .iter()
+                .map(|arm| MatchArmCode {
+                    pattern: arm.pattern.clone(),
+                    guard: arm.guard.as_ref().map(convert_expression),
+                    body: convert_block(&arm.body.statements),
+                })
+                .collect(),
+            match_exp.token.span,
+        ),
+    })
+}
+
+fn convert_quote(node: &Expression) -> Code {
+    let mut template = node.clone();
+    let mut unquoted: Vec<Rc<Code>> = Vec::new();
+    modify_expression(&mut template, &mut |exp: &mut Expression| {
+        if let Expression::Call(call_exp) = exp {
+            if call_exp.function.token_literal() == "unquote" && call_exp.arguments.len() == 1 {
+                unquoted.push(convert_expression(&call_exp.arguments[0]));
+            }
+        }
+    });
+    Code::Quote(template, unquoted)
+}
+
+fn convert_function(function_literal: &FunctionLiteral) -> FunctionBody {
+    FunctionBody {
+        defaults: function_literal
+            .defaults
+            .iter()
+            .map(|default| default.as_ref().map(convert_expression))
+            .collect(),
+        body: convert_block(&function_literal.body.statements),
     }
-    env
 }
 
-fn eval_expressions(
-    exps: &Vec<Box<dyn Expression>>,
-    env: &Rc<RefCell<Environment>>,
-) -> Vec<Option<Box<dyn Object>>> {
-    println!("eval_expressions:");
-    for ee in exps.iter() {
-        println!("\t{:?}", ee);
+fn limit_error(message: &str) -> Rc<Error> {
+    Rc::new(new_error(LIMIT_ERROR, format_args!("{}", message)))
+}
+
+// Errors that stop the script rather than report to it.
+fn is_uncatchable(unwind: &Unwind) -> bool {
+    matches!(unwind, Unwind::Error(err) if err.kind == LIMIT_ERROR || err.kind == INTERRUPT_ERROR)
+}
+
+// Roughly what making `val` took, when it is a value that has to be made.
+fn allocation_size(val: &Value) -> Option<usize> {
+    match val {
+        Value::Str(string) => Some(string.len()),
+        Value::Array(elements) => Some(elements.len() * std::mem::size_of::<Value>()),
+        Value::Hash(pairs) => Some(pairs.len() * std::mem::size_of::<(HashKey, HashPair)>()),
+        Value::Function(_) => Some(std::mem::size_of::<Function>()),
+        Value::Macro(_) => Some(std::mem::size_of::<Macro>()),
+        Value::Quote(_) => Some(std::mem::size_of::<Expression>()),
+        _ => None,
     }
-    let mut result: Vec<Option<Box<dyn Object>>> = Vec::new();
-    for e in exps.iter() {
-        let evaluated = eval_expression(e.as_ref(), env);
-        if evaluated.is_some() && is_error(&evaluated.as_ref().unwrap()) {
-            return vec![Some(evaluated.unwrap().duplicate())];
+}
+
+fn is_quote(call_exp: &CallExpression) -> bool {
+    call_exp.function.token_literal() == "quote" && call_exp.arguments.len() == 1
+}
+
+pub fn convert_object_to_ast_node(obj: &Value) -> Option<Expression> {
+    match obj {
+        Value::Integer(value) => Some(Expression::Integer(IntegerLiteral {
+            token: Token {
+                tk_type: TokenType::INT,
+                literal: value.to_string(),
+                span: Span::default(),
+            },
+            value: *value,
+        })),
+        Value::Boolean(value) => {
+            let tk_type = if *value {
+                TokenType::TRUE
+            } else {
+                TokenType::FALSE
+            };
+            Some(Expression::Boolean(Boolean {
+                token: Token {
+                    tk_type,
+                    literal: value.to_string(),
+                    span: Span::default(),
+                },
+                value: *value,
+            }))
+        }
+        Value::Str(value) => Some(Expression::String(StringLiteral {
+            token: Token {
+                tk_type: TokenType::STRING,
+                literal: value.to_string(),
+                span: Span::default(),
+            },
+            value: value.to_string(),
+        })),
+        Value::Array(array) => {
+            let mut elements = Vec::new();
+            for element in array.iter() {
+                elements.push(convert_object_to_ast_node(element)?);
+            }
+            Some(Expression::Array(ArrayLiteral {
+                token: Token::new(TokenType::LBRACKET, '['),
+                elements,
+            }))
         }
-        result.push(evaluated);
+        Value::Hash(hash) => {
+            let mut pairs = Vec::new();
+            for pair in hash.values() {
+                pairs.push((
+                    convert_object_to_ast_node(&pair.key)?,
+                    convert_object_to_ast_node(&pair.value)?,
+                ));
+            }
+            Some(Expression::Hash(HashLiteral {
+                token: Token::new(TokenType::LBRACE, '{'),
+                pairs,
+            }))
+        }
+        Value::Quote(node) => Some(node.as_ref().clone()),
+        _ => None,
     }
-    result
 }
 
-fn is_error(node: &Box<dyn Object>) -> bool {
-    node.get_type() == ObjectType::ErrorObj
+fn apply_builtin(builtin: &Builtin, args: Vec<Value>, named: NamedArguments) -> Value {
+    let mut named_map: HashMap<String, Value> = HashMap::new();
+    for (name, val) in named {
+        if named_map.contains_key(&name) {
+            return Value::from(new_error(
+                ARGUMENT_ERROR,
+                format_args!("duplicate argument: {}", name),
+            ));
+        }
+        named_map.insert(name, val);
+    }
+    (builtin.function)(args, named_map)
 }
 
-fn eval_program(program: &Program, env: &Rc<RefCell<Environment>>) -> Option<Box<dyn Object>> {
-    println!("eval_program: {:?}", program.string());
-    let mut result: Option<Box<dyn Object>> = None;
-    for statement in program.statements.iter() {
-        result = eval_statement(statement.as_ref(), env);
+// Puts each argument in the slot of its parameter. Arguments past the parameters are handed back for the
+// rest parameter.
+fn fill_parameter_slots(
+    function_literal: &FunctionLiteral,
+    args: Vec<Value>,
+    named: NamedArguments,
+) -> Result<(Slots, Vec<Value>), Rc<Error>> {
+    let got = args.len() + named.len();
+    if function_literal.rest.is_none() && args.len() > function_literal.parameters.len() {
+        return Err(Rc::new(wrong_number_of_arguments(function_literal, got)));
+    }
 
-        if result.is_some() {
-            let return_value = result
-                .as_ref()
-                .unwrap()
-                .as_any()
-                .downcast_ref::<ReturnValue>();
-            if return_value.is_some() {
-                return Some(return_value.unwrap().value.duplicate());
+    let mut args = args.into_iter();
+    let mut slots: Slots = function_literal
+        .parameters
+        .iter()
+        .map(|_| args.next())
+        .collect();
+    for (name, val) in named {
+        match function_literal
+            .parameters
+            .iter()
+            .position(|p| p.value == name)
+        {
+            Some(idx) if slots[idx].is_some() => {
+                return Err(Rc::new(new_error(
+                    ARGUMENT_ERROR,
+                    format_args!("duplicate argument: {}", name),
+                )));
             }
-
-            let error = result
-                .as_ref()
-                .unwrap()
-                .as_any()
-                .downcast_ref::<super::object::Error>();
-            if error.is_some() {
-                return Some(error.unwrap().duplicate());
+            Some(idx) => slots[idx] = Some(val),
+            _ => {
+                return Err(Rc::new(new_error(
+                    ARGUMENT_ERROR,
+                    format_args!("unknown named argument: {}", name),
+                )));
             }
         }
     }
-    result
+    if slots
+        .iter()
+        .zip(function_literal.defaults.iter())
+        .any(|(slot, default)| slot.is_none() && default.is_none())
+    {
+        return Err(Rc::new(wrong_number_of_arguments(function_literal, got)));
+    }
+    Ok((slots, args.collect()))
 }
 
-fn eval_block_statement(
-    block: &BlockStatement,
+fn bind_parameter(parameters: &Parameters, idx: usize, val: Value) -> Result<(), Rc<Error>> {
+    let function_literal = &parameters.function.function_literal;
+    match &function_literal.patterns[idx] {
+        Some(pattern) => bind_pattern(pattern, &val, &parameters.env),
+        _ => {
+            define(&parameters.env, &function_literal.parameters[idx], val);
+            Ok(())
+        }
+    }
+}
+
+pub fn wrong_number_of_arguments(function_literal: &FunctionLiteral, got: usize) -> Error {
+    let max = function_literal.parameters.len();
+    let min = function_literal
+        .defaults
+        .iter()
+        .take_while(|d| d.is_none())
+        .count();
+    let want = if function_literal.rest.is_some() {
+        format!("{}..", min)
+    } else if min != max {
+        format!("{}..{}", min, max)
+    } else {
+        format!("{}", max)
+    };
+    new_error(
+        ARGUMENT_ERROR,
+        format_args!("wrong number of arguments: want={}, got={}", want, got),
+    )
+}
+
+pub fn is_error(obj: &Value) -> bool {
+    matches!(obj, Value::Error(_))
+}
+
+fn eval_import_statement(
+    is: &ImportStatement,
     env: &Rc<RefCell<Environment>>,
-) -> Option<Box<dyn Object>> {
-    println!("eval_block_statement: {:?}", block.string());
-    let mut result: Option<Box<dyn Object>> = None;
-    for statement in block.statements.iter() {
-        result = eval_statement(statement.as_ref(), env);
-        if result.is_some() {
-            let rt = result.as_ref().unwrap().get_type();
-            if rt == ObjectType::ReturnValueObj || rt == ObjectType::ErrorObj {
-                return Some(result.unwrap().duplicate());
-            }
+) -> Result<(), Rc<Error>> {
+    let (loader, file) = {
+        let env = env.borrow();
+        (env.loader(), env.file())
+    };
+    let loader = match loader {
+        Some(loader) => loader,
+        _ => {
+            return Err(Rc::new(new_error(
+                IMPORT_ERROR,
+                format_args!("no module loader available for: {}", is.path),
+            )))
+        }
+    };
+    match import_module(&loader, &is.path, file.as_deref()) {
+        Ok(module) => {
+            define(env, &is.alias, Value::Module(Rc::new(module)));
+            Ok(())
         }
+        Err(Value::Error(err)) => Err(err),
+        Err(val) => Err(Rc::new(Error::from_value(&val))),
     }
-    result
 }
 
-fn eval_prefix_expression(operator: &str, right: Box<dyn Object>) -> Option<Box<dyn Object>> {
-    println!("eval_prefix_expression: {} {:?}", operator, right);
+fn eval_prefix_expression(operator: &str, right: Value) -> Option<Value> {
     match operator {
         "!" => eval_bang_operator_expression(right),
         "-" => eval_minus_prefix_operator_expression(right),
-        _ => Some(Box::new(new_error(format_args!(
-            "unknown operator: {}{}",
-            operator,
-            right.get_type()
-        )))),
+        _ => Some(Value::from(new_error(
+            TYPE_ERROR,
+            format_args!("unknown operator: {}{}", operator, right.get_type()),
+        ))),
     }
 }
 
-fn eval_bang_operator_expression(right: Box<dyn Object>) -> Option<Box<dyn Object>> {
-    println!("eval_bang_operator_expression: {:?}", right);
-    let v = right.as_any().downcast_ref::<super::object::Boolean>();
-    match v {
-        Some(t) => native_bool_to_boolean_object(!t.value),
-        _ => {
-            let v = right.as_any().downcast_ref::<Null>();
-            match v {
-                Some(_) => native_bool_to_boolean_object(true),
-                _ => native_bool_to_boolean_object(false),
-            }
-        }
+fn eval_bang_operator_expression(right: Value) -> Option<Value> {
+    match right {
+        Value::Boolean(value) => native_bool_to_boolean_object(!value),
+        Value::Null => native_bool_to_boolean_object(true),
+        _ => native_bool_to_boolean_object(false),
     }
 }
 
-fn native_bool_to_boolean_object(val: bool) -> Option<Box<dyn Object>> {
+fn native_bool_to_boolean_object(val: bool) -> Option<Value> {
     if val {
-        return Some(Box::new(TRUE));
+        return Some(TRUE);
     }
-    return Some(Box::new(FALSE));
+    Some(FALSE)
 }
 
-fn eval_minus_prefix_operator_expression(right: Box<dyn Object>) -> Option<Box<dyn Object>> {
-    println!("eval_minus_prefix_operator_expression: {:?}", right);
-    if right.get_type() != ObjectType::IntegerObj {
-        return Some(Box::new(new_error(format_args!(
-            "unknown operator: -{}",
-            right.get_type()
-        ))));
+fn eval_minus_prefix_operator_expression(right: Value) -> Option<Value> {
+    match right {
+        Value::Integer(value) => Some(match value.checked_neg() {
+            Some(negated) => Value::Integer(negated),
+            None => Value::from(new_error(
+                ARITHMETIC_ERROR,
+                format_args!("integer overflow: -({})", value),
+            )),
+        }),
+        _ => Some(Value::from(new_error(
+            TYPE_ERROR,
+            format_args!("unknown operator: -{}", right.get_type()),
+        ))),
     }
-
-    let value = right.as_any().downcast_ref::<Integer>().unwrap().value;
-    Some(Box::new(Integer { value: -value }))
 }
 
-fn eval_infix_expression(
-    operator: &str,
-    left: Box<dyn Object>,
-    right: Box<dyn Object>,
-) -> Option<Box<dyn Object>> {
-    println!("eval_infix_expression: {} {:?} {:?}", operator, left, right);
-    if left.get_type() == ObjectType::IntegerObj && right.get_type() == ObjectType::IntegerObj {
-        return eval_integer_infix_expression(operator, left, right);
-    }
-    if left.get_type() == ObjectType::BooleanObj && right.get_type() == ObjectType::BooleanObj {
-        return eval_boolean_infix_expression(operator, left, right);
-    }
-    if left.get_type() != right.get_type() {
-        return Some(Box::new(new_error(format_args!(
-            "type mismatch: {} {} {}",
+fn eval_infix_expression(operator: &str, left: Value, right: Value) -> Option<Value> {
+    match (&left, &right) {
+        (Value::Integer(left_val), Value::Integer(right_val)) => {
+            eval_integer_infix_expression(operator, *left_val, *right_val)
+        }
+        (Value::Boolean(left_val), Value::Boolean(right_val)) => {
+            eval_boolean_infix_expression(operator, *left_val, *right_val)
+        }
+        (Value::Str(left_val), Value::Str(right_val)) => {
+            eval_string_infix_expression(operator, left_val, right_val)
+        }
+        _ if left.get_type() != right.get_type() => Some(Value::from(new_error(
+            TYPE_ERROR,
+            format_args!(
+                "type mismatch: {} {} {}",
+                left.get_type(),
+                operator,
+                right.get_type()
+            ),
+        ))),
+        _ => Some(unknown_infix_operator(
             left.get_type(),
             operator,
-            right.get_type()
-        ))));
-    }
-    Some(Box::new(new_error(format_args!(
-        "unknown operator: {} {} {}",
-        left.get_type(),
-        operator,
-        right.get_type()
-    ))))
-}
-
-fn eval_integer_infix_expression(
-    operator: &str,
-    left: Box<dyn Object>,
-    right: Box<dyn Object>,
-) -> Option<Box<dyn Object>> {
-    println!(
-        "eval_integer_infix_expression: {} {:?} {:?}",
-        operator, left, right
-    );
-    let left_val = left.as_any().downcast_ref::<Integer>().unwrap().value;
-    let right_val = right.as_any().downcast_ref::<Integer>().unwrap().value;
+            right.get_type(),
+        )),
+    }
+}
+
+fn unknown_infix_operator(left: ObjectType, operator: &str, right: ObjectType) -> Value {
+    Value::from(new_error(
+        TYPE_ERROR,
+        format_args!("unknown operator: {} {} {}", left, operator, right),
+    ))
+}
+
+fn eval_integer_infix_expression(operator: &str, left_val: i64, right_val: i64) -> Option<Value> {
     match operator {
-        "+" => Some(Box::new(Integer {
-            value: left_val + right_val,
-        })),
-        "-" => Some(Box::new(Integer {
-            value: left_val - right_val,
-        })),
-        "*" => Some(Box::new(Integer {
-            value: left_val * right_val,
-        })),
-        "/" => Some(Box::new(Integer {
-            value: left_val / right_val,
-        })),
+        "+" => Some(checked_integer(
+            operator,
+            left_val,
+            right_val,
+            left_val.checked_add(right_val),
+        )),
+        "-" => Some(checked_integer(
+            operator,
+            left_val,
+            right_val,
+            left_val.checked_sub(right_val),
+        )),
+        "*" => Some(checked_integer(
+            operator,
+            left_val,
+            right_val,
+            left_val.checked_mul(right_val),
+        )),
+        "/" if right_val == 0 => Some(Value::from(new_error(
+            ARITHMETIC_ERROR,
+            format_args!("division by zero: {} / 0", left_val),
+        ))),
+        "/" => Some(checked_integer(
+            operator,
+            left_val,
+            right_val,
+            left_val.checked_div(right_val),
+        )),
         "<" => native_bool_to_boolean_object(left_val < right_val),
         ">" => native_bool_to_boolean_object(left_val > right_val),
         "==" => native_bool_to_boolean_object(left_val == right_val),
         "!=" => native_bool_to_boolean_object(left_val != right_val),
-        _ => Some(Box::new(new_error(format_args!(
-            "unknown operator: {} {} {}",
-            left.get_type(),
+        _ => Some(unknown_infix_operator(
+            ObjectType::IntegerObj,
             operator,
-            right.get_type()
-        )))),
-    }
-}
-
-fn eval_boolean_infix_expression(
-    operator: &str,
-    left: Box<dyn Object>,
-    right: Box<dyn Object>,
-) -> Option<Box<dyn Object>> {
-    println!(
-        "eval_boolean_infix_expression: {} {:?} {:?}",
-        operator, left, right
-    );
-    let left_val = left
-        .as_any()
-        .downcast_ref::<super::object::Boolean>()
-        .unwrap()
-        .value;
-    let right_val = right
-        .as_any()
-        .downcast_ref::<super::object::Boolean>()
-        .unwrap()
-        .value;
+            ObjectType::IntegerObj,
+        )),
+    }
+}
+
+fn checked_integer(operator: &str, left_val: i64, right_val: i64, result: Option<i64>) -> Value {
+    match result {
+        Some(value) => Value::Integer(value),
+        None => Value::from(new_error(
+            ARITHMETIC_ERROR,
+            format_args!("integer overflow: {} {} {}", left_val, operator, right_val),
+        )),
+    }
+}
+
+fn eval_boolean_infix_expression(operator: &str, left_val: bool, right_val: bool) -> Option<Value> {
     match operator {
         "==" => native_bool_to_boolean_object(left_val == right_val),
         "!=" => native_bool_to_boolean_object(left_val != right_val),
-        _ => Some(Box::new(new_error(format_args!(
-            "unknown operator: {} {} {}",
-            left.get_type(),
+        _ => Some(unknown_infix_operator(
+            ObjectType::BooleanObj,
             operator,
-            right.get_type()
-        )))),
+            ObjectType::BooleanObj,
+        )),
     }
 }
 
-fn eval_if_expression(
-    ie: &IfExpression,
+fn eval_string_infix_expression(operator: &str, left_val: &str, right_val: &str) -> Option<Value> {
+    match operator {
+        "+" => Some(Value::Str(Rc::from(format!("{}{}", left_val, right_val)))),
+        "==" => native_bool_to_boolean_object(left_val == right_val),
+        "!=" => native_bool_to_boolean_object(left_val != right_val),
+        _ => Some(unknown_infix_operator(
+            ObjectType::StringObj,
+            operator,
+            ObjectType::StringObj,
+        )),
+    }
+}
+
+fn eval_index_expression(left: Value, index: Value) -> Option<Value> {
+    match (&left, &index) {
+        (Value::Array(elements), Value::Integer(idx)) => {
+            if *idx < 0 || *idx as usize >= elements.len() {
+                return Some(NULL);
+            }
+            Some(elements[*idx as usize].clone())
+        }
+        (Value::Module(module), _) => {
+            let name = match &index {
+                Value::Str(name) => name,
+                _ => {
+                    return Some(Value::from(new_error(
+                        TYPE_ERROR,
+                        format_args!(
+                            "module member name must be STRING, got {}",
+                            index.get_type()
+                        ),
+                    )))
+                }
+            };
+            match module.exports.get(name.as_ref()) {
+                Some(val) => Some(val.clone()),
+                _ => Some(Value::from(new_error(
+                    NAME_ERROR,
+                    format_args!("module {} has no export {}", module.name, name),
+                ))),
+            }
+        }
+        (Value::Hash(pairs), _) => match index.hash_key() {
+            Some(hash_key) => match pairs.get(&hash_key) {
+                Some(pair) => Some(pair.value.clone()),
+                _ => Some(NULL),
+            },
+            _ => Some(Value::from(new_error(
+                TYPE_ERROR,
+                format_args!("unusable as hash key: {}", index.get_type()),
+            ))),
+        },
+        _ => Some(Value::from(new_error(
+            TYPE_ERROR,
+            format_args!("index operator not supported: {}", left.get_type()),
+        ))),
+    }
+}
+
+fn bind_pattern(
+    pattern: &Pattern,
+    value: &Value,
     env: &Rc<RefCell<Environment>>,
-) -> Option<Box<dyn Object>> {
-    println!("eval_if_expression: {:?}", ie.string());
-    let condition = eval_expression(ie.condition.as_ref(), env);
-    if condition.is_some() && is_error(&condition.as_ref().unwrap()) {
-        return Some(condition.unwrap());
-    }
-    if is_truthy(condition) {
-        return eval_statement(&ie.consequence, env);
-    } else if ie.alternative.is_some() {
-        return eval_statement(ie.alternative.as_ref().unwrap(), env);
-    } else {
-        return Some(Box::new(NULL));
+) -> Result<(), Rc<Error>> {
+    let mut bindings: Vec<(&Identifier, Value)> = Vec::new();
+    if !match_pattern(pattern, value, env, &mut bindings)? {
+        return Err(Rc::new(new_error(
+            MATCH_ERROR,
+            format_args!(
+                "pattern {} does not match value: {}",
+                pattern.string(),
+                value.inspect()
+            ),
+        )));
     }
+    for (ident, val) in bindings {
+        define(env, ident, val);
+    }
+    Ok(())
 }
 
-fn is_truthy(obj: Option<Box<dyn Object>>) -> bool {
-    // TODO what about other value
-    match obj {
-        Some(v) => {
-            let null = v.as_any().downcast_ref::<Null>();
-            if null.is_some() {
-                return false;
+fn match_pattern<'a>(
+    pattern: &'a Pattern,
+    value: &Value,
+    env: &Rc<RefCell<Environment>>,
+    bindings: &mut Vec<(&'a Identifier, Value)>,
+) -> Result<bool, Rc<Error>> {
+    match pattern {
+        Pattern::Wildcard(_) => Ok(true),
+        Pattern::Binding(binding) => {
+            bindings.push((&binding.name, value.clone()));
+            Ok(true)
+        }
+        Pattern::Literal(literal) => {
+            let expected = eval_nested(&literal.value, env)?;
+            Ok(objects_equal(&expected, value))
+        }
+        Pattern::Array(array_pattern) => {
+            let elements = match value {
+                Value::Array(elements) => elements,
+                _ => return Ok(false),
+            };
+            let count = array_pattern.elements.len();
+            if elements.len() < count || (array_pattern.rest.is_none() && elements.len() != count) {
+                return Ok(false);
             }
-            let bo = v.as_any().downcast_ref::<super::object::Boolean>();
-            if bo.is_some() {
-                return bo.unwrap().value;
+            for (p, v) in array_pattern.elements.iter().zip(elements.iter()) {
+                if !match_pattern(p, v, env, bindings)? {
+                    return Ok(false);
+                }
             }
-            return true;
+            if let Some(rest) = &array_pattern.rest {
+                let rest_elements = elements[count..].to_vec();
+                bindings.push((rest, Value::Array(Rc::new(rest_elements))));
+            }
+            Ok(true)
         }
+        Pattern::Hash(hash_pattern) => {
+            let pairs = match value {
+                Value::Hash(pairs) => pairs,
+                _ => return Ok(false),
+            };
+            for (key_node, p) in hash_pattern.pairs.iter() {
+                let key = eval_nested(key_node, env)?;
+                let pair = key.hash_key().and_then(|hash_key| pairs.get(&hash_key));
+                match pair {
+                    Some(pair) => {
+                        if !match_pattern(p, &pair.value, env, bindings)? {
+                            return Ok(false);
+                        }
+                    }
+                    _ => return Ok(false),
+                }
+            }
+            Ok(true)
+        }
+    }
+}
+
+pub fn objects_equal(left: &Value, right: &Value) -> bool {
+    if left.get_type() != right.get_type() {
+        return false;
+    }
+    if left.get_type() == ObjectType::NullObj {
+        return true;
+    }
+    match (left.hash_key(), right.hash_key()) {
+        (Some(l), Some(r)) => l == r,
+        _ => false,
+    }
+}
+
+fn is_truthy(obj: Option<Value>) -> bool {
+    // TODO what about other value
+    match obj {
+        Some(Value::Null) => false,
+        Some(Value::Boolean(value)) => value,
+        Some(_) => true,
         _ => false,
     }
 }
 
-fn new_error(args: std::fmt::Arguments<'_>) -> super::object::Error {
-    super::object::Error {
+pub fn new_error(kind: &str, args: std::fmt::Arguments<'_>) -> Error {
+    Error {
         message: std::fmt::format(args),
+        kind: String::from(kind),
+        stack: Vec::new(),
+        span: Span::default(),
+    }
+}
+
+// Points `err` at `span`, unless it already knows where it was raised.
+pub fn raised_at(mut err: Rc<Error>, span: Span) -> Rc<Error> {
+    if err.span == Span::default() {
+        Rc::make_mut(&mut err).span = span;
     }
+    err
 }
 
-fn eval_identifier(node: &Identifier, env: &Rc<RefCell<Environment>>) -> Option<Box<dyn Object>> {
-    println!("eval_identifier: {:?}", node.string());
-    match env.borrow().get(&node.value) {
-        Some(val) => Some(val.duplicate()),
-        _ => Some(Box::new(new_error(format_args!(
-            "identifier not found: {}",
-            node.value
-        )))),
+// Binds `ident` in `env`, in its slot when the resolver gave it one.
+pub fn define(env: &Rc<RefCell<Environment>>, ident: &Identifier, val: Value) {
+    match ident.address {
+        Some(address) => env.borrow_mut().set_at(address.slot, val),
+        _ => {
+            env.borrow_mut().set(ident.value.clone(), val);
+        }
+    }
+}
+
+fn eval_identifier(node: &Identifier, env: &Rc<RefCell<Environment>>) -> Option<Value> {
+    // an empty slot means the local is not bound yet, look for an outer one by name
+    if let Some(val) = node
+        .address
+        .and_then(|address| env.borrow().get_at(address))
+    {
+        return Some(val);
+    }
+    if let Some(val) = env.borrow().get(&node.value) {
+        return Some(val);
+    }
+    match lookup(&node.value) {
+        Some(builtin) => Some(Value::Builtin(Rc::new(builtin))),
+        _ => {
+            let names = env.borrow().names();
+            let candidates = names
+                .iter()
+                .map(String::as_str)
+                .chain(NAMES.iter().copied());
+            Some(Value::from(new_error(
+                NAME_ERROR,
+                format_args!(
+                    "identifier not found: {}{}",
+                    node.value,
+                    did_you_mean(&node.value, candidates)
+                ),
+            )))
+        }
     }
 }
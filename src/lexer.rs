@@ -5,6 +5,7 @@ pub enum TokenType {
     // Identifiers + literals
     IDENT, // add, foobar, x, y, ...
     INT,
+    STRING,
     // Operators
     ASSIGN,
     PLUS,
@@ -17,10 +18,19 @@ pub enum TokenType {
     // Delimiters
     COMMA,
     SEMICOLON,
+    COLON,
     LPAREN,
     RPAREN,
     LBRACE,
     RBRACE,
+    LBRACKET,
+    RBRACKET,
+    DOT,
+    ELLIPSIS,
+    BAR,
+    PIPE,
+    ARROW,
+    THINARROW,
     // Keywords
     FUNCTION,
     LET,
@@ -29,15 +39,91 @@ pub enum TokenType {
     TRUE,
     FALSE,
     RETURN,
+    MATCH,
+    THROW,
+    TRY,
+    CATCH,
+    FINALLY,
+    MACRO,
+    IMPORT,
+    EXPORT,
+    AS,
     EQ,
     NOTEQ,
 }
 impl std::cmp::Eq for TokenType {}
+// How messages name a token: its text, or what kind of literal it is.
+impl std::fmt::Display for TokenType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = match self {
+            TokenType::ILLEGAL => return write!(f, "illegal character"),
+            TokenType::EOF => return write!(f, "end of input"),
+            TokenType::IDENT => return write!(f, "identifier"),
+            TokenType::INT => return write!(f, "integer"),
+            TokenType::STRING => return write!(f, "string"),
+            TokenType::ASSIGN => "=",
+            TokenType::PLUS => "+",
+            TokenType::MINUS => "-",
+            TokenType::BANG => "!",
+            TokenType::ASTERISK => "*",
+            TokenType::SLASH => "/",
+            TokenType::LT => "<",
+            TokenType::GT => ">",
+            TokenType::COMMA => ",",
+            TokenType::SEMICOLON => ";",
+            TokenType::COLON => ":",
+            TokenType::LPAREN => "(",
+            TokenType::RPAREN => ")",
+            TokenType::LBRACE => "{",
+            TokenType::RBRACE => "}",
+            TokenType::LBRACKET => "[",
+            TokenType::RBRACKET => "]",
+            TokenType::DOT => ".",
+            TokenType::ELLIPSIS => "...",
+            TokenType::BAR => "|",
+            TokenType::PIPE => "|>",
+            TokenType::ARROW => "=>",
+            TokenType::THINARROW => "->",
+            TokenType::FUNCTION => "fn",
+            TokenType::LET => "let",
+            TokenType::IF => "if",
+            TokenType::ELSE => "else",
+            TokenType::TRUE => "true",
+            TokenType::FALSE => "false",
+            TokenType::RETURN => "return",
+            TokenType::MATCH => "match",
+            TokenType::THROW => "throw",
+            TokenType::TRY => "try",
+            TokenType::CATCH => "catch",
+            TokenType::FINALLY => "finally",
+            TokenType::MACRO => "macro",
+            TokenType::IMPORT => "import",
+            TokenType::EXPORT => "export",
+            TokenType::AS => "as",
+            TokenType::EQ => "==",
+            TokenType::NOTEQ => "!=",
+        };
+        write!(f, "'{}'", text)
+    }
+}
+
+// 1-based position of the first character of a token
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Default)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+}
+impl std::fmt::Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Token {
     pub tk_type: TokenType,
     pub literal: String,
+    pub span: Span,
 }
 
 impl Token {
@@ -47,6 +133,16 @@ impl Token {
         Token {
             tk_type: token_type,
             literal: s,
+            span: Span::default(),
+        }
+    }
+
+    // Characters the token takes up in the source, at least one so that the
+    // end of input can be pointed at.
+    pub fn width(&self) -> usize {
+        match self.tk_type {
+            TokenType::STRING => self.literal.chars().count() + 2,
+            _ => self.literal.chars().count().max(1),
         }
     }
 
@@ -57,6 +153,15 @@ impl Token {
             "if" => TokenType::IF,
             "else" => TokenType::ELSE,
             "return" => TokenType::RETURN,
+            "match" => TokenType::MATCH,
+            "throw" => TokenType::THROW,
+            "try" => TokenType::TRY,
+            "catch" => TokenType::CATCH,
+            "finally" => TokenType::FINALLY,
+            "macro" => TokenType::MACRO,
+            "import" => TokenType::IMPORT,
+            "export" => TokenType::EXPORT,
+            "as" => TokenType::AS,
             "true" => TokenType::TRUE,
             "false" => TokenType::FALSE,
             _ => TokenType::IDENT,
@@ -64,22 +169,27 @@ impl Token {
     }
 }
 
+#[derive(Clone)]
 pub struct Lexer<'a> {
     input: &'a str,
     position: usize,      // current position in input (points to current char)
     read_position: usize, // current reading position in input (after current char)
     pub ch: char,         // current char under examination
+    line: usize,          // line of the current char
+    column: usize,        // column of the current char
 }
 
 const NIL: char = 0 as char;
 
 impl<'a> Lexer<'a> {
-    pub fn new(input: &str) -> Lexer {
+    pub fn new(input: &str) -> Lexer<'_> {
         let mut l: Lexer = Lexer {
-            input: input,
+            input,
             position: 0,
             read_position: 0,
             ch: NIL,
+            line: 1,
+            column: 0,
         };
 
         l.read_char();
@@ -87,6 +197,12 @@ impl<'a> Lexer<'a> {
     }
 
     fn read_char(&mut self) {
+        if self.ch == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
         if self.read_position >= self.input.len() {
             self.ch = NIL;
         } else {
@@ -97,9 +213,18 @@ impl<'a> Lexer<'a> {
     }
 
     pub fn next_token(&mut self) -> Token {
-        let mut tok: Token;
-
         self.skip_whitespace();
+        let span = Span {
+            line: self.line,
+            column: self.column,
+        };
+        let mut tok = self.read_token();
+        tok.span = span;
+        tok
+    }
+
+    fn read_token(&mut self) -> Token {
+        let mut tok: Token;
 
         match self.ch {
             '=' => {
@@ -109,8 +234,17 @@ impl<'a> Lexer<'a> {
                         let tk = Token {
                             tk_type: TokenType::EQ,
                             literal: format!("{}{}", self.ch, ch),
+                            span: Span::default(),
+                        };
+                        self.read_char();
+                        tk
+                    } else if ch == '>' {
+                        let tk = Token {
+                            tk_type: TokenType::ARROW,
+                            literal: format!("{}{}", self.ch, ch),
+                            span: Span::default(),
                         };
-                        self.read_position += 1;
+                        self.read_char();
                         tk
                     } else {
                         Token::new(TokenType::ASSIGN, self.ch)
@@ -118,11 +252,27 @@ impl<'a> Lexer<'a> {
                 }
             }
             ';' => tok = Token::new(TokenType::SEMICOLON, self.ch),
+            ':' => tok = Token::new(TokenType::COLON, self.ch),
             '(' => tok = Token::new(TokenType::LPAREN, self.ch),
             ')' => tok = Token::new(TokenType::RPAREN, self.ch),
             ',' => tok = Token::new(TokenType::COMMA, self.ch),
             '+' => tok = Token::new(TokenType::PLUS, self.ch),
-            '-' => tok = Token::new(TokenType::MINUS, self.ch),
+            '-' => {
+                tok = {
+                    let ch = self.peek_char();
+                    if ch == '>' {
+                        let tk = Token {
+                            tk_type: TokenType::THINARROW,
+                            literal: format!("{}{}", self.ch, ch),
+                            span: Span::default(),
+                        };
+                        self.read_char();
+                        tk
+                    } else {
+                        Token::new(TokenType::MINUS, self.ch)
+                    }
+                }
+            }
             '!' => {
                 tok = {
                     let ch = self.peek_char();
@@ -130,8 +280,9 @@ impl<'a> Lexer<'a> {
                         let tk = Token {
                             tk_type: TokenType::NOTEQ,
                             literal: format!("{}{}", self.ch, ch),
+                            span: Span::default(),
                         };
-                        self.read_position += 1;
+                        self.read_char();
                         tk
                     } else {
                         Token::new(TokenType::BANG, self.ch)
@@ -142,19 +293,62 @@ impl<'a> Lexer<'a> {
             '*' => tok = Token::new(TokenType::ASTERISK, self.ch),
             '<' => tok = Token::new(TokenType::LT, self.ch),
             '>' => tok = Token::new(TokenType::GT, self.ch),
+            '.' => {
+                tok = {
+                    if self.input[self.position..].starts_with("...") {
+                        let tk = Token {
+                            tk_type: TokenType::ELLIPSIS,
+                            literal: String::from("..."),
+                            span: Span::default(),
+                        };
+                        self.read_char();
+                        self.read_char();
+                        tk
+                    } else {
+                        Token::new(TokenType::DOT, self.ch)
+                    }
+                }
+            }
+            '|' => {
+                tok = {
+                    let ch = self.peek_char();
+                    if ch == '>' {
+                        let tk = Token {
+                            tk_type: TokenType::PIPE,
+                            literal: format!("{}{}", self.ch, ch),
+                            span: Span::default(),
+                        };
+                        self.read_char();
+                        tk
+                    } else {
+                        Token::new(TokenType::BAR, self.ch)
+                    }
+                }
+            }
             '{' => tok = Token::new(TokenType::LBRACE, self.ch),
             '}' => tok = Token::new(TokenType::RBRACE, self.ch),
+            '[' => tok = Token::new(TokenType::LBRACKET, self.ch),
+            ']' => tok = Token::new(TokenType::RBRACKET, self.ch),
+            '"' => {
+                tok = Token {
+                    tk_type: TokenType::STRING,
+                    literal: self.read_string(),
+                    span: Span::default(),
+                }
+            }
             NIL => {
                 tok = Token {
                     tk_type: TokenType::EOF,
                     literal: String::new(),
+                    span: Span::default(),
                 }
             }
             _ => {
-                if self.ch.is_ascii_alphabetic() {
+                if is_letter(self.ch) {
                     tok = Token {
                         tk_type: TokenType::IDENT,
                         literal: self.read_identifier(),
+                        span: Span::default(),
                     };
                     tok.tk_type = Token::lookup_ident(&tok.literal);
                     return tok; // need not read_char, show return now
@@ -162,6 +356,7 @@ impl<'a> Lexer<'a> {
                     tok = Token {
                         tk_type: TokenType::INT,
                         literal: self.read_number(),
+                        span: Span::default(),
                     };
                     return tok; // need not read_char, show return now
                 } else {
@@ -175,12 +370,23 @@ impl<'a> Lexer<'a> {
 
     fn read_identifier(&mut self) -> String {
         let position = self.position;
-        while self.ch.is_ascii_alphabetic() {
+        while is_letter(self.ch) {
             self.read_char();
         }
         String::from(&self.input[position..self.position])
     }
 
+    fn read_string(&mut self) -> String {
+        let position = self.position + 1;
+        loop {
+            self.read_char();
+            if self.ch == '"' || self.ch == NIL {
+                break;
+            }
+        }
+        String::from(&self.input[position..self.position])
+    }
+
     fn skip_whitespace(&mut self) {
         loop {
             match self.ch {
@@ -210,3 +416,7 @@ impl<'a> Lexer<'a> {
         }
     }
 }
+
+fn is_letter(ch: char) -> bool {
+    ch.is_ascii_alphabetic() || ch == '_'
+}
@@ -1,7 +1,19 @@
 pub mod ast;
+pub mod builtins;
+pub mod closure_compiler;
+pub mod code;
+pub mod compiler;
+pub mod diagnostics;
 pub mod environment;
 pub mod evaluator;
 pub mod lexer;
+pub mod macro_expansion;
+pub mod module;
 pub mod object;
+pub mod optimizer;
 pub mod parser;
 pub mod repl;
+pub mod resolver;
+pub mod serialize;
+pub mod typechecker;
+pub mod vm;
@@ -0,0 +1,106 @@
+use super::ast::*;
+use super::environment::*;
+use super::evaluator::*;
+use super::object::*;
+use std::cell::*;
+use std::rc::*;
+
+// Moves top-level `let name = macro(...) { ... };` definitions out of the
+// program and into `env`.
+pub fn define_macros(program: &mut Program, env: &Rc<RefCell<Environment>>) {
+    program.statements.retain(|stmt| {
+        let let_stmt = match stmt {
+            Statement::Let(let_stmt) => let_stmt,
+            _ => return true,
+        };
+        let macro_literal = match &let_stmt.value {
+            Expression::Macro(macro_literal) => macro_literal,
+            _ => return true,
+        };
+        env.borrow_mut().set(
+            let_stmt.name.value.clone(),
+            Value::Macro(Rc::new(Macro {
+                macro_literal: Rc::clone(macro_literal),
+                env: Rc::clone(env),
+            })),
+        );
+        false
+    });
+}
+
+// Replaces every call of a macro defined in `env` with the AST it returns.
+pub fn expand_macros(program: &mut Program, env: &Rc<RefCell<Environment>>) -> Result<(), Value> {
+    let mut error: Option<Value> = None;
+    modify_program(program, &mut |exp: &mut Expression| {
+        if error.is_some() {
+            return;
+        }
+        let call_exp = match exp {
+            Expression::Call(call_exp) => call_exp,
+            _ => return,
+        };
+        let macro_obj = match call_exp.function.as_ref() {
+            Expression::Identifier(ident) => env.borrow().get(&ident.value),
+            _ => None,
+        };
+        let macro_obj = match macro_obj {
+            Some(Value::Macro(macro_obj)) => macro_obj,
+            _ => return,
+        };
+        match expand_macro_call(&macro_obj, call_exp) {
+            Ok(node) => *exp = node,
+            Err(err) => error = Some(err),
+        }
+    });
+    match error {
+        Some(err) => Err(err),
+        _ => Ok(()),
+    }
+}
+
+fn expand_macro_call(macro_obj: &Macro, call_exp: &CallExpression) -> Result<Expression, Value> {
+    let parameters = &macro_obj.macro_literal.parameters;
+    if !call_exp.named_arguments.is_empty() {
+        return Err(Value::from(new_error(
+            ARGUMENT_ERROR,
+            format_args!("macros do not accept named arguments"),
+        )));
+    }
+    if call_exp.arguments.len() != parameters.len() {
+        return Err(Value::from(new_error(
+            ARGUMENT_ERROR,
+            format_args!(
+                "wrong number of arguments: want={}, got={}",
+                parameters.len(),
+                call_exp.arguments.len()
+            ),
+        )));
+    }
+
+    let extended_env = Rc::new(RefCell::new(new_enclosed_environment(&macro_obj.env)));
+    for (param, arg) in parameters.iter().zip(call_exp.arguments.iter()) {
+        extended_env
+            .borrow_mut()
+            .set(param.value.clone(), Value::Quote(Rc::new(arg.clone())));
+    }
+
+    let evaluated =
+        eval_block_statement(&macro_obj.macro_literal.body, &extended_env).unwrap_or(NULL);
+    let evaluated = match evaluated {
+        Value::ReturnValue(value) => *value,
+        _ => evaluated,
+    };
+    if is_error(&evaluated) {
+        return Err(evaluated);
+    }
+    match &evaluated {
+        Value::Quote(node) => Ok(node.as_ref().clone()),
+        _ => Err(Value::from(new_error(
+            TYPE_ERROR,
+            format_args!(
+                "macro must return a quoted expression, got {}",
+                evaluated.get_type()
+            ),
+        ))),
+    }
+}
@@ -1,25 +1,244 @@
 include!("lib.rs");
 
+use ast::Node;
 use std::cell::*;
+use std::io::IsTerminal;
+use std::path::PathBuf;
 use std::rc::*;
 
 fn main() {
-    let env = Rc::new(RefCell::new(environment::new_environment()));
-    let mut l = lexer::Lexer::new(
-        //         "
-        // let newAdder = fn(x) {
-        //     fn(y) { x + y };
-        // };
-        // let addTwo = newAdder(2);
-        // addTwo(2);
-        //    ",
-        "let add = fn(x, y) { x + y; }; add(5 + 5, add(5, 5));",
-    );
+    let mut search_path: Vec<PathBuf> = Vec::new();
+    let mut file: Option<String> = None;
+    let mut output: Option<String> = None;
+    let mut engine = module::Engine::Eval;
+    let mut max_depth: Option<usize> = None;
+    let mut dump_optimized_ast = false;
+    let mut format = diagnostics::DiagnosticFormat::default();
+    let mut args = std::env::args().skip(1).peekable();
+    let command = match args.peek().map(String::as_str) {
+        Some("check") | Some("compile") => args.next(),
+        _ => None,
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-I" | "--module-path" => match args.next() {
+                Some(dir) => search_path.push(PathBuf::from(dir)),
+                _ => {
+                    eprintln!("missing directory after {}", arg);
+                    std::process::exit(2);
+                }
+            },
+            "-o" | "--output" => match args.next() {
+                Some(out) => output = Some(out),
+                _ => {
+                    eprintln!("missing file after {}", arg);
+                    std::process::exit(2);
+                }
+            },
+            "--engine" => match args.next().as_deref() {
+                Some("eval") => engine = module::Engine::Eval,
+                Some("closures") => engine = module::Engine::Closures,
+                Some("vm") => engine = module::Engine::Vm,
+                _ => {
+                    eprintln!("--engine must be eval, closures or vm");
+                    std::process::exit(2);
+                }
+            },
+            "--max-depth" => match args.next().and_then(|depth| depth.parse().ok()) {
+                Some(depth) => max_depth = Some(depth),
+                _ => {
+                    eprintln!("--max-depth must be a number");
+                    std::process::exit(2);
+                }
+            },
+            "--dump-optimized-ast" => dump_optimized_ast = true,
+            "--color" => format.colour = true,
+            "--error-format" => match args.next().as_deref() {
+                Some("human") => format.json = false,
+                Some("json") => format.json = true,
+                _ => {
+                    eprintln!("--error-format must be human or json");
+                    std::process::exit(2);
+                }
+            },
+            _ => file = Some(arg),
+        }
+    }
+    let loader = Rc::new(RefCell::new(module::ModuleLoader::with_search_path(
+        search_path,
+    )));
+    loader.borrow_mut().set_engine(engine);
+    loader.borrow_mut().set_diagnostic_format(format);
+    if let Some(max_depth) = max_depth {
+        loader.borrow_mut().set_max_depth(max_depth);
+    }
+
+    match (command.as_deref(), &file) {
+        (Some("check"), Some(file)) => std::process::exit(check_file(file, format)),
+        (Some("check"), _) => {
+            eprintln!("usage: waiir check FILE");
+            std::process::exit(2);
+        }
+        (Some("compile"), Some(file)) => {
+            let output = output.unwrap_or_else(|| {
+                PathBuf::from(file)
+                    .with_extension("mkc")
+                    .display()
+                    .to_string()
+            });
+            std::process::exit(compile_file(&loader, file, &output));
+        }
+        (Some(_), _) => {
+            eprintln!("usage: waiir compile FILE [-o OUTPUT]");
+            std::process::exit(2);
+        }
+        _ => {}
+    }
+
+    if let (true, Some(file)) = (dump_optimized_ast, &file) {
+        std::process::exit(dump_program(&loader, file));
+    }
+
+    if let Some(file) = file {
+        // precompiled programs always run on the VM, and so do their imports
+        let result = if file.ends_with(".mkc") {
+            loader.borrow_mut().set_engine(module::Engine::Vm);
+            module::run_compiled(&loader, &file).map_err(module::RunError::from)
+        } else {
+            module::run_file(&loader, &file)
+        };
+        if let Err(err) = result {
+            report_run_error(&file, err, format);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // piped input runs as a script, with errors on stderr
+    let stdin = std::io::stdin();
+    let summary = if stdin.is_terminal() {
+        // Ctrl-C stops the line being evaluated or drops the one being typed;
+        // anywhere else it ends the process as usual
+        let interrupt = evaluator::InterruptHandle::new();
+        let raise = interrupt.clone();
+        if ctrlc::set_handler(move || raise.interrupt()).is_ok() {
+            let mut limits = loader.borrow().limits().clone();
+            limits.interrupt = Some(interrupt);
+            loader.borrow_mut().set_limits(limits);
+        }
+        println!("Hello! This is the Monkey programming language!");
+        println!("Feel free to type in commands");
+        repl::start_with_loader(&mut stdin.lock(), &mut std::io::stdout(), &loader)
+    } else {
+        repl::run_script(
+            &mut stdin.lock(),
+            &mut std::io::stdout(),
+            &mut std::io::stderr(),
+            &loader,
+        )
+    };
+    match summary {
+        Ok(summary) if summary.is_ok() => {}
+        Ok(summary) => {
+            eprintln!("{}", summary);
+            std::process::exit(1);
+        }
+        Err(err) => {
+            eprintln!("cannot run the REPL: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+// Writes `file` compiled to bytecode to `output`. Returns the exit code.
+fn compile_file(loader: &Rc<RefCell<module::ModuleLoader>>, file: &str, output: &str) -> i32 {
+    let bytes = match module::compile_file(loader, file) {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            eprintln!("{}", err.inspect());
+            return 1;
+        }
+    };
+    if let Err(err) = std::fs::write(output, bytes) {
+        eprintln!("cannot write {}: {}", output, err);
+        return 2;
+    }
+    0
+}
+
+// Prints `file` the way it runs after optimization. Returns the exit code.
+fn dump_program(loader: &Rc<RefCell<module::ModuleLoader>>, file: &str) -> i32 {
+    match module::optimized_program(loader, file) {
+        Ok(program) => {
+            for stmt in program.statements.iter() {
+                println!("{}", stmt.string());
+            }
+            0
+        }
+        Err(err) => {
+            eprintln!("{}", err.inspect());
+            1
+        }
+    }
+}
+
+// Writes why `file` did not run to stderr: the problems found in its source,
+// or the error it raised and where it raised it.
+fn report_run_error(file: &str, err: module::RunError, format: diagnostics::DiagnosticFormat) {
+    let (source, diagnostics) = match err {
+        module::RunError::Diagnostics {
+            source,
+            diagnostics,
+            ..
+        } => (source, diagnostics),
+        module::RunError::Error(err) => {
+            let err = match err {
+                object::Value::Error(err) => err,
+                val => Rc::new(object::Error::from_value(&val)),
+            };
+            // a precompiled program has no source to show
+            let source = if file.ends_with(".mkc") {
+                String::new()
+            } else {
+                std::fs::read_to_string(file).unwrap_or_default()
+            };
+            (source, vec![diagnostics::Diagnostic::from(err.as_ref())])
+        }
+    };
+    eprint!("{}", format.show(file, &source, &diagnostics));
+}
+
+// Parses and resolves `file` without evaluating it. Returns the exit code.
+fn check_file(file: &str, format: diagnostics::DiagnosticFormat) -> i32 {
+    let input = match std::fs::read_to_string(file) {
+        Ok(input) => input,
+        Err(err) => {
+            eprintln!("cannot read {}: {}", file, err);
+            return 2;
+        }
+    };
+    let mut l = lexer::Lexer::new(&input);
     let mut p = parser::Parser::new(&mut l);
     let program = p.parse_program().unwrap();
-    let result = evaluator::eval(&program, &env);
-    println!("{:#?}", result);
-    println!("Hello! This is the Monkey programming language!");
-    println!("Feel free to type in commands");
-    repl::start(&mut std::io::stdin(), &mut std::io::stdout());
+    // the resolver only runs on programs that parse
+    let diagnostics: Vec<diagnostics::Diagnostic> = if p.diagnostics().is_empty() {
+        resolver::resolve_program(&program)
+            .iter()
+            .map(diagnostics::Diagnostic::from)
+            .collect()
+    } else {
+        p.diagnostics().to_vec()
+    };
+
+    let shown = format.show(file, &input, &diagnostics);
+    if format.json {
+        print!("{}", shown);
+    } else {
+        eprint!("{}", shown);
+    }
+    if diagnostics.iter().any(|d| d.is_error()) {
+        1
+    } else {
+        0
+    }
 }
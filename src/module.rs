@@ -0,0 +1,363 @@
+use super::ast::*;
+use super::closure_compiler;
+use super::compiler::*;
+use super::diagnostics::{Diagnostic, DiagnosticFormat};
+use super::environment::*;
+use super::evaluator::*;
+use super::lexer::*;
+use super::macro_expansion::*;
+use super::object::*;
+use super::optimizer::*;
+use super::parser::*;
+use super::resolver::*;
+use super::serialize::*;
+use super::typechecker::*;
+use super::vm::*;
+use std::cell::*;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fs;
+use std::path::*;
+use std::rc::*;
+
+type Exports = Rc<BTreeMap<String, Value>>;
+
+// How programs are run: by walking the AST, as compiled closures, or on the
+// bytecode VM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Engine {
+    #[default]
+    Eval,
+    Closures,
+    Vm,
+}
+
+#[derive(Debug, Default)]
+pub struct ModuleLoader {
+    search_path: Vec<PathBuf>,
+    cache: HashMap<PathBuf, Exports>,
+    loading: Vec<PathBuf>, // modules being evaluated, outermost first
+    engine: Engine,
+    limits: ExecutionLimits, // for the evaluator, each module gets them afresh
+    format: DiagnosticFormat, // for the diagnostics of the file run and the REPL
+}
+impl ModuleLoader {
+    pub fn new() -> ModuleLoader {
+        ModuleLoader::default()
+    }
+    pub fn with_search_path(search_path: Vec<PathBuf>) -> ModuleLoader {
+        ModuleLoader {
+            search_path,
+            ..ModuleLoader::default()
+        }
+    }
+    pub fn add_search_path<P: Into<PathBuf>>(&mut self, path: P) {
+        self.search_path.push(path.into());
+    }
+    pub fn search_path(&self) -> &[PathBuf] {
+        &self.search_path
+    }
+    pub fn engine(&self) -> Engine {
+        self.engine
+    }
+    pub fn set_engine(&mut self, engine: Engine) {
+        self.engine = engine;
+    }
+    pub fn limits(&self) -> &ExecutionLimits {
+        &self.limits
+    }
+    pub fn set_limits(&mut self, limits: ExecutionLimits) {
+        self.limits = limits;
+    }
+    pub fn max_depth(&self) -> usize {
+        self.limits.max_depth
+    }
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.limits.max_depth = max_depth;
+    }
+    pub fn diagnostic_format(&self) -> DiagnosticFormat {
+        self.format
+    }
+    pub fn set_diagnostic_format(&mut self, format: DiagnosticFormat) {
+        self.format = format;
+    }
+
+    // `./` and `../` paths are only looked up next to the importing file,
+    // other paths fall back to the search path.
+    fn resolve(&self, path: &str, importer: Option<&Path>) -> Result<PathBuf, Value> {
+        let base = match importer.and_then(|file| file.parent()) {
+            Some(dir) => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let mut candidates = vec![base.join(path)];
+        if !path.starts_with("./") && !path.starts_with("../") {
+            candidates.extend(self.search_path.iter().map(|dir| dir.join(path)));
+        }
+        for candidate in candidates.iter() {
+            if candidate.is_file() {
+                return candidate.canonicalize().map_err(|err| {
+                    import_error(format_args!("cannot read module {}: {}", path, err))
+                });
+            }
+        }
+        Err(import_error(format_args!("module not found: {}", path)))
+    }
+}
+
+fn import_error(args: std::fmt::Arguments<'_>) -> Value {
+    Value::from(new_error(IMPORT_ERROR, args))
+}
+
+// Why a file did not run.
+#[derive(Debug)]
+pub enum RunError {
+    // Problems found in its source before it ran, and the error importing
+    // it raises for them.
+    Diagnostics {
+        source: String,
+        diagnostics: Vec<Diagnostic>,
+        error: Value,
+    },
+    // The error it raised, or one finding or reading it.
+    Error(Value),
+}
+impl From<Value> for RunError {
+    fn from(err: Value) -> RunError {
+        RunError::Error(err)
+    }
+}
+impl From<RunError> for Value {
+    fn from(err: RunError) -> Value {
+        match err {
+            RunError::Diagnostics { error, .. } | RunError::Error(error) => error,
+        }
+    }
+}
+
+// Evaluates the module at `path` once and returns its exports. `importer` is
+// the file containing the import statement, if any.
+pub fn import_module(
+    loader: &Rc<RefCell<ModuleLoader>>,
+    path: &str,
+    importer: Option<&Path>,
+) -> Result<Module, Value> {
+    load_module(loader, path, importer).map_err(Value::from)
+}
+
+fn load_module(
+    loader: &Rc<RefCell<ModuleLoader>>,
+    path: &str,
+    importer: Option<&Path>,
+) -> Result<Module, RunError> {
+    let file = loader.borrow().resolve(path, importer)?;
+    let cached = loader.borrow().cache.get(&file).map(Rc::clone);
+    if let Some(exports) = cached {
+        return Ok(Module {
+            name: String::from(path),
+            exports,
+        });
+    }
+
+    let cycle_start = loader.borrow().loading.iter().position(|f| *f == file);
+    if let Some(start) = cycle_start {
+        let mut cycle: Vec<String> = loader.borrow().loading[start..]
+            .iter()
+            .map(|f| f.display().to_string())
+            .collect();
+        cycle.push(file.display().to_string());
+        return Err(RunError::from(import_error(format_args!(
+            "import cycle detected: {}",
+            cycle.join(" -> ")
+        ))));
+    }
+
+    loader.borrow_mut().loading.push(file.clone());
+    let exports = eval_module(loader, &file);
+    loader.borrow_mut().loading.pop();
+
+    let exports = match (exports, importer) {
+        // where the error was raised and the calls in its stack are in the
+        // imported file, not the importer; the frame for the module says so
+        (Err(RunError::Error(Value::Error(mut err))), Some(_))
+            if !err.stack.is_empty() || err.span != Span::default() =>
+        {
+            let module = format!("<module {}>", path);
+            let raised = Rc::make_mut(&mut err);
+            raised.span = Span::default();
+            raised
+                .stack
+                .push(StackFrame::new(&module, Span::default(), 0));
+            return Err(RunError::Error(Value::Error(err)));
+        }
+        (exports, _) => Rc::new(exports?),
+    };
+    loader.borrow_mut().cache.insert(file, Rc::clone(&exports));
+    Ok(Module {
+        name: String::from(path),
+        exports,
+    })
+}
+
+// Runs `path` as a program, resolving its imports against `loader`.
+pub fn run_file(loader: &Rc<RefCell<ModuleLoader>>, path: &str) -> Result<(), RunError> {
+    load_module(loader, path, None).map(|_| ())
+}
+
+// Compiles `path` for the VM and returns it in the `.mkc` format.
+pub fn compile_file(loader: &Rc<RefCell<ModuleLoader>>, path: &str) -> Result<Vec<u8>, Value> {
+    let file = Path::new(path);
+    let program = load_program(loader, file).map_err(Value::from)?;
+    let bytecode = compile_program(&program, file)?;
+    serialize(&bytecode).map_err(|err| {
+        Value::from(new_error(
+            ERROR,
+            format_args!("cannot compile module {}: {}", file.display(), err),
+        ))
+    })
+}
+
+// Runs a `.mkc` file written by `compile_file` on the VM.
+pub fn run_compiled(loader: &Rc<RefCell<ModuleLoader>>, path: &str) -> Result<(), Value> {
+    let data = fs::read(path)
+        .map_err(|err| import_error(format_args!("cannot read module {}: {}", path, err)))?;
+    let bytecode = deserialize(&data)
+        .map_err(|err| import_error(format_args!("cannot load module {}: {}", path, err)))?;
+    let file = Path::new(path).canonicalize().ok();
+    let mut vm = Vm::with_loader(loader, file);
+    match vm.run(&bytecode) {
+        Some(evaluated) if is_error(&evaluated) => Err(evaluated),
+        _ => Ok(()),
+    }
+}
+
+fn compile_program(program: &Program, file: &Path) -> Result<Bytecode, Value> {
+    Compiler::new().compile(program).map_err(|err| {
+        Value::from(new_error(
+            ERROR,
+            format_args!("cannot compile module {}: {}", file.display(), err),
+        ))
+    })
+}
+
+// Returns `path` as it would run, after macro expansion and optimization.
+pub fn optimized_program(loader: &Rc<RefCell<ModuleLoader>>, path: &str) -> Result<Program, Value> {
+    load_program(loader, Path::new(path)).map_err(Value::from)
+}
+
+// Parses `file` and prepares it for running: macros are expanded, types
+// checked, the program optimized and local variables resolved.
+fn load_program(loader: &Rc<RefCell<ModuleLoader>>, file: &Path) -> Result<Program, RunError> {
+    let input = fs::read_to_string(file).map_err(|err| {
+        import_error(format_args!(
+            "cannot read module {}: {}",
+            file.display(),
+            err
+        ))
+    })?;
+    let mut l = Lexer::new(&input);
+    let mut p = Parser::new(&mut l);
+    let mut program = p.parse_program().unwrap();
+    if !p.get_errors().is_empty() {
+        let diagnostics = p.diagnostics().to_vec();
+        let error = import_error(format_args!(
+            "parse errors in module {}: {}",
+            file.display(),
+            diagnostics
+                .iter()
+                .map(|d| d.to_string())
+                .collect::<Vec<_>>()
+                .join("; ")
+        ));
+        return Err(RunError::Diagnostics {
+            source: input,
+            diagnostics,
+            error,
+        });
+    }
+
+    let macro_env = Rc::new(RefCell::new(new_module_environment(
+        loader,
+        Some(file.to_path_buf()),
+    )));
+    define_macros(&mut program, &macro_env);
+    expand_macros(&mut program, &macro_env)?;
+
+    let mut checker = TypeChecker::new();
+    checker.check_program(&program);
+    if !checker.errors().is_empty() {
+        let errors: Vec<String> = checker.errors().iter().map(|e| e.to_string()).collect();
+        let error = Value::from(new_error(
+            TYPE_ERROR,
+            format_args!(
+                "type errors in module {}: {}",
+                file.display(),
+                errors.join("; ")
+            ),
+        ));
+        return Err(RunError::Diagnostics {
+            source: input,
+            diagnostics: checker.errors().iter().map(Diagnostic::from).collect(),
+            error,
+        });
+    }
+    optimize(&mut program);
+    resolve_addresses(&mut program);
+    Ok(program)
+}
+
+fn eval_module(
+    loader: &Rc<RefCell<ModuleLoader>>,
+    file: &Path,
+) -> Result<BTreeMap<String, Value>, RunError> {
+    let program = load_program(loader, file)?;
+    let names: Vec<&String> = program
+        .statements
+        .iter()
+        .filter_map(|stmt| match stmt {
+            Statement::Export(export_stmt) => Some(&export_stmt.statement.name.value),
+            _ => None,
+        })
+        .collect();
+    let mut exports = BTreeMap::new();
+    let (engine, limits) = {
+        let loader = loader.borrow();
+        (loader.engine(), loader.limits().clone())
+    };
+    match engine {
+        Engine::Eval | Engine::Closures => {
+            let env = Rc::new(RefCell::new(new_module_environment(
+                loader,
+                Some(file.to_path_buf()),
+            )));
+            let evaluated = match engine {
+                Engine::Closures => closure_compiler::eval_with_limits(&program, &env, &limits),
+                _ => eval_with_limits(&program, &env, &limits),
+            };
+            if let Some(evaluated) = evaluated {
+                if is_error(&evaluated) {
+                    return Err(RunError::from(evaluated));
+                }
+            }
+            for name in names {
+                if let Some(val) = env.borrow().get(name) {
+                    exports.insert(name.clone(), val);
+                }
+            }
+        }
+        Engine::Vm => {
+            let bytecode = compile_program(&program, file)?;
+            let mut vm = Vm::with_loader(loader, Some(file.to_path_buf()));
+            if let Some(evaluated) = vm.run(&bytecode) {
+                if is_error(&evaluated) {
+                    return Err(RunError::from(evaluated));
+                }
+            }
+            for name in names {
+                if let Some(val) = vm.global(name) {
+                    exports.insert(name.clone(), val);
+                }
+            }
+        }
+    }
+    Ok(exports)
+}
@@ -1,11 +1,16 @@
 use super::ast::*;
+use super::closure_compiler::FunctionCode;
+use super::code::*;
 use super::environment::*;
-use std::any::Any;
+use super::evaluator::FunctionBody;
+use super::lexer::Span;
 use std::cell::*;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
 use std::fmt::*;
 use std::rc::*;
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Debug)]
 pub enum ObjectType {
     IntegerObj,
     BooleanObj,
@@ -13,6 +18,13 @@ pub enum ObjectType {
     ReturnValueObj,
     ErrorObj,
     FunctionObj,
+    ArrayObj,
+    BuiltinObj,
+    StringObj,
+    HashObj,
+    QuoteObj,
+    MacroObj,
+    ModuleObj,
 }
 impl Display for ObjectType {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
@@ -23,134 +35,425 @@ impl Display for ObjectType {
             ObjectType::ReturnValueObj => write!(f, "RETURN_VALUE"),
             ObjectType::ErrorObj => write!(f, "ERROR"),
             ObjectType::FunctionObj => write!(f, "FUNCTION"),
+            ObjectType::ArrayObj => write!(f, "ARRAY"),
+            ObjectType::BuiltinObj => write!(f, "BUILTIN"),
+            ObjectType::StringObj => write!(f, "STRING"),
+            ObjectType::HashObj => write!(f, "HASH"),
+            ObjectType::QuoteObj => write!(f, "QUOTE"),
+            ObjectType::MacroObj => write!(f, "MACRO"),
+            ObjectType::ModuleObj => write!(f, "MODULE"),
         }
     }
 }
 
-pub trait AsAny {
-    fn as_any(&self) -> &dyn Any;
+// Every runtime value. Scalars are stored inline and everything else is
+// shared through an `Rc`, so cloning a value never copies its contents.
+#[derive(Debug, Clone)]
+pub enum Value {
+    Integer(i64),
+    Boolean(bool),
+    Null,
+    ReturnValue(Box<Value>),
+    Error(Rc<Error>),
+    Function(Rc<Function>),
+    Closure(Rc<Closure>),
+    Array(Rc<Vec<Value>>),
+    Builtin(Rc<Builtin>),
+    Str(Rc<str>),
+    Hash(Rc<BTreeMap<HashKey, HashPair>>),
+    Quote(Rc<Expression>),
+    Macro(Rc<Macro>),
+    Module(Rc<Module>),
 }
-
-pub trait Object: Debug + Any + AsAny {
-    fn get_type(&self) -> ObjectType;
-    fn inspect(&self) -> String;
-    fn duplicate(&self) -> Box<dyn Object>;
+impl Value {
+    pub fn get_type(&self) -> ObjectType {
+        match self {
+            Value::Integer(_) => ObjectType::IntegerObj,
+            Value::Boolean(_) => ObjectType::BooleanObj,
+            Value::Null => ObjectType::NullObj,
+            Value::ReturnValue(_) => ObjectType::ReturnValueObj,
+            Value::Error(_) => ObjectType::ErrorObj,
+            Value::Function(_) | Value::Closure(_) => ObjectType::FunctionObj,
+            Value::Array(_) => ObjectType::ArrayObj,
+            Value::Builtin(_) => ObjectType::BuiltinObj,
+            Value::Str(_) => ObjectType::StringObj,
+            Value::Hash(_) => ObjectType::HashObj,
+            Value::Quote(_) => ObjectType::QuoteObj,
+            Value::Macro(_) => ObjectType::MacroObj,
+            Value::Module(_) => ObjectType::ModuleObj,
+        }
+    }
+    pub fn inspect(&self) -> String {
+        match self {
+            Value::Integer(value) => format!("{}", value),
+            Value::Boolean(value) => format!("{}", value),
+            Value::Null => String::from("null"),
+            Value::ReturnValue(value) => value.inspect(),
+            Value::Error(err) => format!("ERROR: {}", err.message),
+            Value::Function(function) => function.inspect(),
+            Value::Closure(closure) => closure.function.display.clone(),
+            Value::Array(elements) => {
+                let elements: Vec<String> = elements.iter().map(|e| e.inspect()).collect();
+                format!("[{}]", elements.join(", "))
+            }
+            Value::Builtin(_) => String::from("builtin function"),
+            Value::Str(value) => value.to_string(),
+            Value::Hash(pairs) => {
+                let pairs: Vec<String> = pairs
+                    .values()
+                    .map(|pair| format!("{}: {}", pair.key.inspect(), pair.value.inspect()))
+                    .collect();
+                format!("{{{}}}", pairs.join(", "))
+            }
+            Value::Quote(node) => format!("QUOTE({})", node.string()),
+            Value::Macro(macro_obj) => macro_obj.inspect(),
+            Value::Module(module) => format!("<module {}>", module.name),
+        }
+    }
+    // The value as the REPL and the command line show it, errors with
+    // their stack trace.
+    pub fn report(&self) -> String {
+        match self {
+            Value::Error(err) => err.traceback(),
+            _ => self.inspect(),
+        }
+    }
+    // The name a function was bound with, for stack traces.
+    pub fn function_name(&self) -> Option<&str> {
+        match self {
+            Value::Function(function) => function.function_literal.name.as_deref(),
+            Value::Builtin(builtin) => Some(&builtin.name),
+            _ => None,
+        }
+    }
+    pub fn hash_key(&self) -> Option<HashKey> {
+        match self {
+            Value::Integer(value) => Some(HashKey::Integer(*value)),
+            Value::Boolean(value) => Some(HashKey::Boolean(*value)),
+            Value::Str(value) => Some(HashKey::String(value.to_string())),
+            _ => None,
+        }
+    }
 }
-impl<T: Object> AsAny for T {
-    fn as_any(&self) -> &dyn Any {
-        self
+impl From<Error> for Value {
+    fn from(err: Error) -> Value {
+        Value::Error(Rc::new(err))
     }
 }
 
-#[derive(Debug)]
-pub struct Integer {
-    pub value: i64,
+pub const ERROR: &str = "Error";
+pub const TYPE_ERROR: &str = "TypeError";
+pub const NAME_ERROR: &str = "NameError";
+pub const ARGUMENT_ERROR: &str = "ArgumentError";
+pub const MATCH_ERROR: &str = "MatchError";
+pub const IMPORT_ERROR: &str = "ImportError";
+pub const ARITHMETIC_ERROR: &str = "ArithmeticError";
+pub const RECURSION_ERROR: &str = "RecursionError";
+pub const LIMIT_ERROR: &str = "LimitError";
+pub const INTERRUPT_ERROR: &str = "InterruptError";
+
+// A call the error was raised in. Frames restored from a thrown hash only
+// know their name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StackFrame {
+    pub name: String,
+    pub span: Span, // of the call
+    pub args: usize,
 }
-impl Object for Integer {
-    fn get_type(&self) -> ObjectType {
-        ObjectType::IntegerObj
-    }
-    fn inspect(&self) -> String {
-        format!("{}", self.value)
+impl StackFrame {
+    pub fn new(name: &str, span: Span, args: usize) -> StackFrame {
+        StackFrame {
+            name: String::from(name),
+            span,
+            args,
+        }
     }
-    fn duplicate(&self) -> Box<dyn Object> {
-        Box::new(Integer { value: self.value })
+    fn describe(&self) -> String {
+        if self.span == Span::default() {
+            return format!("in {}", self.name);
+        }
+        format!(
+            "line {}, column {}, in {} ({} argument{})",
+            self.span.line,
+            self.span.column,
+            self.name,
+            self.args,
+            if self.args == 1 { "" } else { "s" }
+        )
     }
 }
 
-#[derive(Debug)]
-pub struct Boolean {
-    pub value: bool,
+// Identical frames in a row past this many are only counted.
+const TRACEBACK_REPEATS: usize = 3;
+
+#[derive(Debug, Clone)]
+pub struct Error {
+    pub message: String,
+    pub kind: String,
+    pub stack: Vec<StackFrame>, // innermost call first
+    pub span: Span,             // of the expression that raised it, when known
 }
-impl Object for Boolean {
-    fn get_type(&self) -> ObjectType {
-        ObjectType::BooleanObj
+impl Error {
+    // The error as Python prints it, innermost call last.
+    pub fn traceback(&self) -> String {
+        let mut out = String::new();
+        if !self.stack.is_empty() {
+            out.push_str("Traceback (most recent call last):\n");
+        }
+        for line in self.trace() {
+            out.push_str(&format!("  {}\n", line));
+        }
+        out.push_str(&format!("{}: {}", self.kind, self.message));
+        out
     }
-    fn inspect(&self) -> String {
-        format!("{}", self.value)
+
+    // The calls of the traceback, innermost last. Identical calls in a row
+    // past TRACEBACK_REPEATS are only counted.
+    pub fn trace(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        let mut frames = self.stack.iter().rev().peekable();
+        while let Some(frame) = frames.next() {
+            let mut count = 1;
+            while frames.peek() == Some(&frame) {
+                frames.next();
+                count += 1;
+            }
+            for _ in 0..count.min(TRACEBACK_REPEATS) {
+                lines.push(frame.describe());
+            }
+            if count > TRACEBACK_REPEATS {
+                let more = count - TRACEBACK_REPEATS;
+                lines.push(format!(
+                    "[Previous line repeated {} more time{}]",
+                    more,
+                    if more == 1 { "" } else { "s" }
+                ));
+            }
+        }
+        lines
     }
-    fn duplicate(&self) -> Box<dyn Object> {
-        Box::new(Boolean { value: self.value })
+
+    // the value a `catch` block sees
+    pub fn to_value(&self) -> Value {
+        let mut pairs = BTreeMap::new();
+        let mut insert = |key: &str, value: Value| {
+            pairs.insert(
+                HashKey::String(String::from(key)),
+                HashPair {
+                    key: Value::Str(Rc::from(key)),
+                    value,
+                },
+            );
+        };
+        insert("message", Value::Str(Rc::from(self.message.as_str())));
+        insert("kind", Value::Str(Rc::from(self.kind.as_str())));
+        insert(
+            "stack",
+            Value::Array(Rc::new(
+                self.stack
+                    .iter()
+                    .map(|frame| Value::Str(Rc::from(frame.name.as_str())))
+                    .collect(),
+            )),
+        );
+        Value::Hash(Rc::new(pairs))
+    }
+    // the error raised by `throw value`
+    pub fn from_value(value: &Value) -> Error {
+        match value {
+            Value::Str(string) => Error {
+                message: string.to_string(),
+                kind: String::from(ERROR),
+                stack: Vec::new(),
+                span: Span::default(),
+            },
+            Value::Hash(pairs) => {
+                let field = |key: &str| {
+                    pairs
+                        .get(&HashKey::String(String::from(key)))
+                        .map(|pair| &pair.value)
+                };
+                let message = match field("message") {
+                    Some(message) => message.inspect(),
+                    _ => value.inspect(),
+                };
+                let kind = match field("kind") {
+                    Some(kind) => kind.inspect(),
+                    _ => String::from(ERROR),
+                };
+                let stack = match field("stack") {
+                    Some(Value::Array(stack)) => stack
+                        .iter()
+                        .map(|e| StackFrame::new(&e.inspect(), Span::default(), 0))
+                        .collect(),
+                    _ => Vec::new(),
+                };
+                Error {
+                    message,
+                    kind,
+                    stack,
+                    span: Span::default(),
+                }
+            }
+            _ => Error {
+                message: value.inspect(),
+                kind: String::from(ERROR),
+                stack: Vec::new(),
+                span: Span::default(),
+            },
+        }
     }
 }
 
-#[derive(Debug)]
-pub struct Null {}
-impl Object for Null {
-    fn get_type(&self) -> ObjectType {
-        ObjectType::NullObj
-    }
+pub struct Function {
+    pub function_literal: Rc<FunctionLiteral>,
+    pub env: Rc<RefCell<Environment>>,
+    pub compiled: Option<Rc<FunctionCode>>, // body, when made by the closure compiler
+    pub body: Option<Rc<FunctionBody>>,     // body, when made by the evaluator
+}
+impl Function {
     fn inspect(&self) -> String {
-        String::from("null")
+        let mut out = String::new();
+        out.push_str("fn");
+        out.push('(');
+        out.push_str(&self.function_literal.parameters_string());
+        out.push_str(") {\n");
+        out.push_str(&self.function_literal.body.string());
+        out.push_str("\n}");
+        out
     }
-    fn duplicate(&self) -> Box<dyn Object> {
-        Box::new(Null {})
+}
+impl Debug for Function {
+    // the environment may hold this very function, so it is left out
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(
+            f,
+            "Function {{ function_literal: {:?} }}",
+            self.function_literal
+        )
     }
 }
 
+// A function lowered to bytecode by the compiler. Parameters occupy the first
+// local slots, followed by the rest parameter if there is one.
 #[derive(Debug)]
-pub struct ReturnValue {
-    pub value: Box<dyn Object>,
+pub struct CompiledFunction {
+    pub instructions: Instructions,
+    pub constants: Vec<Value>,
+    pub functions: Vec<Rc<CompiledFunction>>, // literals nested in this one
+    pub parameters: Vec<String>,
+    pub defaults: Vec<bool>, // one entry per parameter
+    pub rest: bool,
+    pub local_names: Vec<String>,
+    pub upvalue_names: Vec<String>, // locals captured by nested functions
+    pub free: Vec<(String, Capture)>,
+    // (offset, span) of the first instruction compiled from each source
+    // position, by offset
+    pub spans: Vec<(usize, Span)>,
+    pub display: String,
 }
-impl Object for ReturnValue {
-    fn get_type(&self) -> ObjectType {
-        ObjectType::ReturnValueObj
-    }
-    fn inspect(&self) -> String {
-        self.value.inspect()
-    }
-    fn duplicate(&self) -> Box<dyn Object> {
-        Box::new(ReturnValue {
-            value: self.value.duplicate(),
-        })
+impl CompiledFunction {
+    // Source position of the instruction at `offset`.
+    pub fn span_at(&self, offset: usize) -> Option<Span> {
+        let idx = self.spans.partition_point(|(start, _)| *start <= offset);
+        self.spans[..idx].last().map(|(_, span)| *span)
     }
 }
 
-#[derive(Debug)]
-pub struct Error {
-    pub message: String,
+// Where a closure finds a free variable when it is created: an upvalue of the
+// enclosing frame or one of the enclosing closure's own free variables.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Capture {
+    Upvalue(usize),
+    Free(usize),
+}
+
+// A captured local shared by a frame and the closures created in it. `None`
+// until the local is bound.
+pub type Upvalue = Rc<RefCell<Option<Value>>>;
+
+// The global slots of a compiled program with the names the compiler gave
+// them. Closures keep the table they were made under, so a function exported
+// from a module still sees that module's globals.
+#[derive(Debug, Default)]
+pub struct Globals {
+    pub values: Vec<Option<Value>>,
+    pub names: Vec<String>,
+}
+
+pub struct Closure {
+    pub function: Rc<CompiledFunction>,
+    pub free: Vec<Upvalue>,
+    pub globals: Rc<RefCell<Globals>>,
 }
-impl Object for Error {
-    fn get_type(&self) -> ObjectType {
-        ObjectType::ErrorObj
+impl Debug for Closure {
+    // free variables may hold this very closure, so they are left out
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "Closure {{ function: {:?} }}", self.function.display)
     }
-    fn inspect(&self) -> String {
-        format!("ERROR: {}", self.message)
+}
+
+pub type BuiltinFunction = fn(Vec<Value>, HashMap<String, Value>) -> Value;
+
+pub struct Builtin {
+    pub name: String,
+    pub function: BuiltinFunction,
+}
+impl Builtin {
+    pub fn new(name: &str, function: BuiltinFunction) -> Builtin {
+        Builtin {
+            name: String::from(name),
+            function,
+        }
     }
-    fn duplicate(&self) -> Box<dyn Object> {
-        Box::new(Error {
-            message: self.message.clone(),
-        })
+}
+impl Debug for Builtin {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "Builtin {{ name: {:?} }}", self.name)
     }
 }
 
-#[derive(Debug)]
-pub struct Function {
-    pub function_literal: Rc<RefCell<FunctionLiteral>>,
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
+pub enum HashKey {
+    Integer(i64),
+    Boolean(bool),
+    String(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct HashPair {
+    pub key: Value,
+    pub value: Value,
+}
+
+pub struct Macro {
+    pub macro_literal: Rc<MacroLiteral>,
     pub env: Rc<RefCell<Environment>>,
 }
-impl Object for Function {
-    fn get_type(&self) -> ObjectType {
-        ObjectType::FunctionObj
-    }
+impl Macro {
     fn inspect(&self) -> String {
         let mut out = String::new();
         let mut params: Vec<String> = Vec::new();
-        for p in self.function_literal.borrow().parameters.iter() {
+        for p in self.macro_literal.parameters.iter() {
             params.push(p.string());
         }
-        out.push_str("fn");
-        out.push_str("(");
+        out.push_str("macro");
+        out.push('(');
         out.push_str(&params.join(", "));
         out.push_str(") {\n");
-        out.push_str(&self.function_literal.borrow().body.string());
+        out.push_str(&self.macro_literal.body.string());
         out.push_str("\n}");
         out
     }
-    fn duplicate(&self) -> Box<dyn Object> {
-        Box::new(Function {
-            function_literal: Rc::clone(&self.function_literal),
-            env: Rc::clone(&self.env),
-        })
+}
+impl Debug for Macro {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "Macro {{ macro_literal: {:?} }}", self.macro_literal)
     }
 }
+
+#[derive(Debug)]
+pub struct Module {
+    pub name: String,
+    pub exports: Rc<BTreeMap<String, Value>>,
+}
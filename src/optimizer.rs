@@ -0,0 +1,351 @@
+use super::ast::*;
+use super::evaluator::convert_object_to_ast_node;
+use super::lexer::Span;
+use super::object::*;
+use super::vm::is_truthy;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::rc::*;
+
+// A rewrite of the whole program that keeps its meaning.
+pub type Pass = fn(&mut Program);
+
+// Folding runs again after inlining, which turns names into constants.
+pub const DEFAULT_PASSES: &[Pass] = &[
+    fold_constants,
+    inline_constants,
+    fold_constants,
+    simplify_conditionals,
+    remove_dead_code,
+];
+
+pub fn optimize(program: &mut Program) {
+    optimize_with(program, DEFAULT_PASSES);
+}
+
+pub fn optimize_with(program: &mut Program, passes: &[Pass]) {
+    for pass in passes.iter() {
+        pass(program);
+    }
+}
+
+// Replaces operators applied to literals with their result. Anything that
+// would fail at runtime, like `1 / 0` or `1 + true`, is left for the runtime
+// to report.
+pub fn fold_constants(program: &mut Program) {
+    modify_program(program, &mut fold);
+}
+
+fn fold(exp: &mut Expression) {
+    let folded = match exp {
+        Expression::Prefix(prefix_exp) => {
+            constant(&prefix_exp.right).and_then(|right| fold_prefix(&prefix_exp.operator, &right))
+        }
+        Expression::Infix(infix_exp) => {
+            match (constant(&infix_exp.left), constant(&infix_exp.right)) {
+                (Some(left), Some(right)) => fold_infix(&infix_exp.operator, &left, &right),
+                _ => None,
+            }
+        }
+        _ => None,
+    };
+    if let Some(folded) = folded.and_then(|val| literal(&val, exp.span())) {
+        *exp = folded;
+    }
+}
+
+// Replaces `if` expressions whose condition is a literal by the branch that
+// always runs. Blocks run in the enclosing environment, so a branch in
+// statement position can stand in for the whole `if`.
+pub fn simplify_conditionals(program: &mut Program) {
+    modify_statement_lists(program, &mut |statements: &mut Vec<Statement>| {
+        let last = statements.len().saturating_sub(1);
+        let mut idx = 0;
+        while idx < statements.len() {
+            let branch = match &statements[idx] {
+                Statement::Expression(ExpressionStmt {
+                    expression: Expression::If(if_exp),
+                    ..
+                }) => taken_branch(if_exp),
+                _ => None,
+            };
+            match branch {
+                Some(Some(block)) if idx != last || ends_in_expression(&block) => {
+                    statements[idx] = Statement::Block(block);
+                }
+                // an `if` without `else` is only worth its `null` at the end
+                Some(None) if idx != last => {
+                    statements.remove(idx);
+                    continue;
+                }
+                _ => {}
+            }
+            idx += 1;
+        }
+    });
+    modify_program(program, &mut |exp: &mut Expression| {
+        let branch = match exp {
+            Expression::If(if_exp) => taken_branch(if_exp),
+            _ => None,
+        };
+        if let Some(Some(mut block)) = branch {
+            if block.statements.len() == 1 {
+                if let Some(Statement::Expression(expression_stmt)) = block.statements.pop() {
+                    *exp = expression_stmt.expression;
+                }
+            }
+        }
+    });
+}
+
+// Drops the statements after a `return` or `throw`, which can never run.
+pub fn remove_dead_code(program: &mut Program) {
+    modify_statement_lists(program, &mut |statements: &mut Vec<Statement>| {
+        if let Some(exit) = statements.iter().position(always_exits) {
+            statements.truncate(exit + 1);
+        }
+    });
+}
+
+// Replaces names bound once, by a `let` of a literal, with the literal in the
+// statements after the `let`. The `let` stays, other code may still look the
+// name up.
+pub fn inline_constants(program: &mut Program) {
+    let mut bindings: HashMap<String, usize> = HashMap::new();
+    count_bindings(program, &mut bindings);
+    // quoted code is data, it must keep the names it was written with
+    let mut quoted: HashSet<String> = HashSet::new();
+    modify_program(program, &mut |exp: &mut Expression| {
+        if let Expression::Call(call_exp) = exp {
+            if call_exp.function.token_literal() == "quote" {
+                for arg in call_exp.arguments.iter_mut() {
+                    modify_expression(arg, &mut |exp: &mut Expression| {
+                        if let Expression::Identifier(ident) = exp {
+                            quoted.insert(ident.value.clone());
+                        }
+                    });
+                }
+            }
+        }
+    });
+
+    modify_statement_lists(program, &mut |statements: &mut Vec<Statement>| {
+        for idx in 0..statements.len() {
+            // names inlined by earlier lets may have made the value constant
+            if let Statement::Let(LetStatement { value, .. })
+            | Statement::Export(ExportStatement {
+                statement: LetStatement { value, .. },
+                ..
+            }) = &mut statements[idx]
+            {
+                modify_expression(value, &mut fold);
+            }
+            let (name, value) = match &statements[idx] {
+                Statement::Let(let_stmt) => (&let_stmt.name.value, &let_stmt.value),
+                Statement::Export(export_stmt) => (
+                    &export_stmt.statement.name.value,
+                    &export_stmt.statement.value,
+                ),
+                _ => continue,
+            };
+            if constant(value).is_none() || bindings.get(name) != Some(&1) || quoted.contains(name)
+            {
+                continue;
+            }
+            let (name, value) = (name.clone(), value.clone());
+            for stmt in statements[idx + 1..].iter_mut() {
+                modify_statement(stmt, &mut |exp: &mut Expression| {
+                    let span = match exp {
+                        Expression::Identifier(ident) if ident.value == name => ident.token.span,
+                        _ => return,
+                    };
+                    *exp = with_span(value.clone(), span);
+                });
+            }
+        }
+    });
+}
+
+// Hands every statement list of the program to `modifier`, inner lists
+// before the ones containing them.
+fn modify_statement_lists(program: &mut Program, modifier: &mut dyn FnMut(&mut Vec<Statement>)) {
+    modify_program(program, &mut |exp: &mut Expression| match exp {
+        Expression::If(if_exp) => {
+            modify_statements(&mut if_exp.consequence.statements, modifier);
+            if let Some(alternative) = &mut if_exp.alternative {
+                modify_statements(&mut alternative.statements, modifier);
+            }
+        }
+        Expression::Try(try_exp) => {
+            modify_statements(&mut try_exp.block.statements, modifier);
+            if let Some(catch_block) = &mut try_exp.catch_block {
+                modify_statements(&mut catch_block.statements, modifier);
+            }
+            if let Some(finally_block) = &mut try_exp.finally_block {
+                modify_statements(&mut finally_block.statements, modifier);
+            }
+        }
+        Expression::Function(function_literal) => modify_statements(
+            &mut Rc::make_mut(function_literal).body.statements,
+            modifier,
+        ),
+        Expression::Macro(macro_literal) => {
+            modify_statements(&mut Rc::make_mut(macro_literal).body.statements, modifier)
+        }
+        Expression::Match(match_exp) => {
+            for arm in match_exp.arms.iter_mut() {
+                modify_statements(&mut arm.body.statements, modifier);
+            }
+        }
+        _ => {}
+    });
+    modify_statements(&mut program.statements, modifier);
+}
+
+fn modify_statements(
+    statements: &mut Vec<Statement>,
+    modifier: &mut dyn FnMut(&mut Vec<Statement>),
+) {
+    for stmt in statements.iter_mut() {
+        if let Statement::Block(block) = stmt {
+            modify_statements(&mut block.statements, modifier);
+        }
+    }
+    modifier(statements);
+}
+
+fn count_bindings(program: &mut Program, bindings: &mut HashMap<String, usize>) {
+    let mut bind = |name: &Identifier| *bindings.entry(name.value.clone()).or_insert(0) += 1;
+    modify_statement_lists(program, &mut |statements: &mut Vec<Statement>| {
+        for stmt in statements.iter() {
+            match stmt {
+                Statement::Let(let_stmt) => bind(&let_stmt.name),
+                Statement::Export(export_stmt) => bind(&export_stmt.statement.name),
+                Statement::LetPattern(let_stmt) => pattern_bindings(&let_stmt.pattern, &mut bind),
+                Statement::Import(import_stmt) => bind(&import_stmt.alias),
+                _ => {}
+            }
+        }
+    });
+    modify_program(program, &mut |exp: &mut Expression| match exp {
+        Expression::Function(function_literal) => {
+            function_literal.parameters.iter().for_each(&mut bind);
+            function_literal.rest.iter().for_each(&mut bind);
+            for pattern in function_literal.patterns.iter().flatten() {
+                pattern_bindings(pattern, &mut bind);
+            }
+        }
+        Expression::Macro(macro_literal) => macro_literal.parameters.iter().for_each(&mut bind),
+        Expression::Match(match_exp) => {
+            for arm in match_exp.arms.iter() {
+                pattern_bindings(&arm.pattern, &mut bind);
+            }
+        }
+        Expression::Try(try_exp) => try_exp.catch_parameter.iter().for_each(&mut bind),
+        _ => {}
+    });
+}
+
+fn pattern_bindings(pattern: &Pattern, bind: &mut dyn FnMut(&Identifier)) {
+    match pattern {
+        Pattern::Binding(binding) => bind(&binding.name),
+        Pattern::Array(array_pattern) => {
+            for element in array_pattern.elements.iter() {
+                pattern_bindings(element, bind);
+            }
+            if let Some(rest) = &array_pattern.rest {
+                bind(rest);
+            }
+        }
+        Pattern::Hash(hash_pattern) => {
+            for (_, value) in hash_pattern.pairs.iter() {
+                pattern_bindings(value, bind);
+            }
+        }
+        Pattern::Wildcard(_) | Pattern::Literal(_) => {}
+    }
+}
+
+// `Some(branch)` when the condition is a literal, the branch being `None`
+// for a false condition without `else`.
+fn taken_branch(if_exp: &IfExpression) -> Option<Option<BlockStatement>> {
+    let condition = constant(&if_exp.condition)?;
+    if is_truthy(&condition) {
+        Some(Some(if_exp.consequence.clone()))
+    } else {
+        Some(if_exp.alternative.clone())
+    }
+}
+
+fn ends_in_expression(block: &BlockStatement) -> bool {
+    matches!(block.statements.last(), Some(Statement::Expression(_)))
+}
+
+fn always_exits(stmt: &Statement) -> bool {
+    match stmt {
+        Statement::Return(_) | Statement::Throw(_) => true,
+        Statement::Block(block) => block.statements.iter().any(always_exits),
+        _ => false,
+    }
+}
+
+fn constant(exp: &Expression) -> Option<Value> {
+    match exp {
+        Expression::Integer(integer_literal) => Some(Value::Integer(integer_literal.value)),
+        Expression::Boolean(boolean) => Some(Value::Boolean(boolean.value)),
+        Expression::String(string_literal) => {
+            Some(Value::Str(Rc::from(string_literal.value.as_str())))
+        }
+        _ => None,
+    }
+}
+
+fn literal(val: &Value, span: Span) -> Option<Expression> {
+    convert_object_to_ast_node(val).map(|exp| with_span(exp, span))
+}
+
+fn with_span(mut exp: Expression, span: Span) -> Expression {
+    match &mut exp {
+        Expression::Integer(integer_literal) => integer_literal.token.span = span,
+        Expression::Boolean(boolean) => boolean.token.span = span,
+        Expression::String(string_literal) => string_literal.token.span = span,
+        _ => {}
+    }
+    exp
+}
+
+fn fold_prefix(operator: &str, right: &Value) -> Option<Value> {
+    match (operator, right) {
+        ("!", _) => Some(Value::Boolean(!is_truthy(right))),
+        ("-", Value::Integer(value)) => value.checked_neg().map(Value::Integer),
+        _ => None,
+    }
+}
+
+fn fold_infix(operator: &str, left: &Value, right: &Value) -> Option<Value> {
+    match (left, right) {
+        (Value::Integer(left), Value::Integer(right)) => match operator {
+            "+" => left.checked_add(*right).map(Value::Integer),
+            "-" => left.checked_sub(*right).map(Value::Integer),
+            "*" => left.checked_mul(*right).map(Value::Integer),
+            "/" => left.checked_div(*right).map(Value::Integer),
+            "<" => Some(Value::Boolean(left < right)),
+            ">" => Some(Value::Boolean(left > right)),
+            "==" => Some(Value::Boolean(left == right)),
+            "!=" => Some(Value::Boolean(left != right)),
+            _ => None,
+        },
+        (Value::Boolean(left), Value::Boolean(right)) => match operator {
+            "==" => Some(Value::Boolean(left == right)),
+            "!=" => Some(Value::Boolean(left != right)),
+            _ => None,
+        },
+        (Value::Str(left), Value::Str(right)) => match operator {
+            "+" => Some(Value::Str(Rc::from(format!("{}{}", left, right)))),
+            "==" => Some(Value::Boolean(left == right)),
+            "!=" => Some(Value::Boolean(left != right)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
@@ -1,25 +1,39 @@
 use super::ast::*;
+use super::diagnostics::Diagnostic;
 use super::lexer::*;
 use std::collections::HashMap;
+use std::rc::Rc;
 
 pub struct Parser<'a> {
     l: &'a mut Lexer<'a>,
     cur_token: Token,
     peek_token: Token,
-    errors: Vec<Box<String>>,
+    errors: Vec<Diagnostic>,
     precedences: HashMap<TokenType, Precedence>,
 }
 
+struct FunctionParameters {
+    parameters: Vec<Identifier>,
+    defaults: Vec<Option<Expression>>,
+    patterns: Vec<Option<Pattern>>,
+    rest: Option<Identifier>,
+    types: Vec<Option<TypeAnnotation>>,
+}
+
+type CallArguments = (Vec<Expression>, Vec<(Identifier, Expression)>);
+
 #[derive(PartialOrd, PartialEq, Copy, Clone)]
-#[allow(dead_code)]
+#[allow(dead_code, clippy::upper_case_acronyms)]
 enum Precedence {
     LOWEST,
+    PIPELINE,
     EQUALS,
     LESSGEREATER,
     SUM,
     PRODUCT,
     PREFIX,
     CALL,
+    INDEX,
 }
 
 impl<'a> Parser<'a> {
@@ -43,6 +57,9 @@ impl<'a> Parser<'a> {
         p.precedences
             .insert(TokenType::ASTERISK, Precedence::PRODUCT);
         p.precedences.insert(TokenType::LPAREN, Precedence::CALL);
+        p.precedences.insert(TokenType::LBRACKET, Precedence::INDEX);
+        p.precedences.insert(TokenType::DOT, Precedence::INDEX);
+        p.precedences.insert(TokenType::PIPE, Precedence::PIPELINE);
 
         p.next_token();
         p.next_token();
@@ -71,15 +88,18 @@ impl<'a> Parser<'a> {
     }
 
     #[allow(dead_code)]
-    pub fn get_errors(&self) -> &Vec<Box<String>> {
+    pub fn get_errors(&self) -> Vec<String> {
+        self.errors.iter().map(|d| d.message.clone()).collect()
+    }
+    pub fn diagnostics(&self) -> &[Diagnostic] {
         &self.errors
     }
     fn peek_error(&mut self, t: TokenType) {
         let msg = format!(
-            "expected next token to be {:?}, got {:?} instead",
+            "expected next token to be {}, got {} instead",
             t, self.peek_token.tk_type
         );
-        self.errors.push(Box::new(msg));
+        self.errors.push(error_at(&self.peek_token, msg));
     }
 
     fn peek_precedence(&self) -> Precedence {
@@ -96,33 +116,65 @@ impl<'a> Parser<'a> {
     }
 
     pub fn parse_program(&mut self) -> Option<Program> {
-        println!("parse_program: {:?}", self.cur_token);
         let mut program = Program {
             statements: Vec::new(),
         };
+        let mut previous: Option<Token> = None;
         while self.cur_token.tk_type != TokenType::EOF {
-            let stmt = self.parse_statement();
-            if stmt.is_some() {
-                program.statements.push(stmt.unwrap());
+            let errors = self.errors.len();
+            match self.parse_statement_after(previous.as_ref()) {
+                Some(stmt) if self.errors.len() == errors => {
+                    program.statements.push(stmt);
+                    previous = Some(self.cur_token.clone());
+                }
+                stmt => {
+                    program.statements.extend(stmt);
+                    previous = None;
+                }
             }
             self.next_token();
         }
         Some(program)
     }
 
-    fn parse_statement(&mut self) -> Option<Box<dyn Statement>> {
-        println!("parse_statement: {:?}", self.cur_token);
+    // A statement that fails to parse right after one that did, on the same
+    // line and with no `;` between them, may be missing that `;`.
+    fn parse_statement_after(&mut self, previous: Option<&Token>) -> Option<Statement> {
+        let joined = previous.is_some_and(|previous| {
+            previous.tk_type != TokenType::SEMICOLON
+                && previous.span.line == self.cur_token.span.line
+        });
+        let errors = self.errors.len();
+        let stmt = self.parse_statement();
+        // a more specific hint wins
+        match self.errors.get_mut(errors) {
+            Some(err) if joined && err.help.is_empty() => err
+                .help
+                .push(String::from("a `;` may be missing before this statement")),
+            _ => {}
+        }
+        stmt
+    }
+
+    fn parse_statement(&mut self) -> Option<Statement> {
         match self.cur_token.tk_type {
             TokenType::LET => self.parse_let_statement(),
             TokenType::RETURN => self.parse_return_statement(),
+            TokenType::THROW => self.parse_throw_statement(),
+            TokenType::IMPORT => self.parse_import_statement(),
+            TokenType::EXPORT => self.parse_export_statement(),
             _ => self.parse_expression_statement(),
         }
     }
 
-    fn parse_let_statement(&mut self) -> Option<Box<dyn Statement>> {
-        println!("parse_let_statement: {:?}", self.cur_token);
+    fn parse_let_statement(&mut self) -> Option<Statement> {
         let token = self.cur_token.clone();
 
+        if self.peek_token_is(TokenType::LBRACKET) || self.peek_token_is(TokenType::LBRACE) {
+            self.next_token();
+            return self.parse_let_pattern_statement(token);
+        }
+
         if !self.expect_peek(TokenType::IDENT) {
             return None;
         }
@@ -130,76 +182,162 @@ impl<'a> Parser<'a> {
         let name_token = self.cur_token.clone();
         let name_value = self.cur_token.literal.clone();
 
+        let mut type_annotation = None;
+        if self.peek_token_is(TokenType::COLON) {
+            self.next_token();
+            self.next_token();
+            type_annotation = Some(self.parse_type_annotation()?);
+        }
+
         if !self.expect_peek(TokenType::ASSIGN) {
             return None;
         }
 
         self.next_token();
 
-        let value = self.parse_expression(Precedence::LOWEST);
-        if value.is_none() {
-            return None;
+        let mut value = self.parse_expression(Precedence::LOWEST)?;
+        if let Expression::Function(function_literal) = &mut value {
+            Rc::make_mut(function_literal).name = Some(name_value.clone());
         }
 
         let stmt = LetStatement {
-            token: token,
+            token,
             name: Identifier {
                 token: name_token,
                 value: name_value,
+                address: None,
             },
-            value: value.unwrap(),
+            type_annotation,
+            value,
         };
 
-        while !self.cur_token_is(TokenType::SEMICOLON) {
+        if self.peek_token_is(TokenType::SEMICOLON) {
             self.next_token();
         }
-        Some(Box::new(stmt))
+        Some(Statement::Let(stmt))
     }
-    fn parse_return_statement(&mut self) -> Option<Box<dyn Statement>> {
-        println!("parse_return_statement: {:?}", self.cur_token);
-        let token = self.cur_token.clone();
-        self.next_token();
 
-        let return_value = self.parse_expression(Precedence::LOWEST);
-        if return_value.is_none() {
+    fn parse_let_pattern_statement(&mut self, token: Token) -> Option<Statement> {
+        let pattern = self.parse_pattern()?;
+
+        if !self.expect_peek(TokenType::ASSIGN) {
             return None;
         }
+
+        self.next_token();
+
+        let value = self.parse_expression(Precedence::LOWEST)?;
+
+        let stmt = LetPatternStatement {
+            token,
+            pattern,
+            value,
+        };
+
+        if self.peek_token_is(TokenType::SEMICOLON) {
+            self.next_token();
+        }
+        Some(Statement::LetPattern(stmt))
+    }
+    fn parse_return_statement(&mut self) -> Option<Statement> {
+        let token = self.cur_token.clone();
+        self.next_token();
+
+        let return_value = self.parse_expression(Precedence::LOWEST)?;
         let stmt = ReturnStatement {
-            token: token,
-            return_value: return_value.unwrap(),
+            token,
+            return_value,
         };
 
         if self.peek_token_is(TokenType::SEMICOLON) {
             self.next_token();
         }
 
-        Some(Box::new(stmt))
+        Some(Statement::Return(stmt))
     }
 
-    fn parse_expression_statement(&mut self) -> Option<Box<dyn Statement>> {
-        println!("parse_expression_statement: {:?}", self.cur_token);
+    fn parse_throw_statement(&mut self) -> Option<Statement> {
         let token = self.cur_token.clone();
-        let expression = self.parse_expression(Precedence::LOWEST);
-        if expression.is_none() {
+        self.next_token();
+
+        let value = self.parse_expression(Precedence::LOWEST)?;
+        let stmt = ThrowStatement { token, value };
+
+        if self.peek_token_is(TokenType::SEMICOLON) {
+            self.next_token();
+        }
+
+        Some(Statement::Throw(stmt))
+    }
+
+    fn parse_import_statement(&mut self) -> Option<Statement> {
+        let token = self.cur_token.clone();
+
+        if !self.expect_peek(TokenType::STRING) {
             return None;
         }
-        let stmt = ExpressionStmt {
-            token: token,
-            expression: expression.unwrap(),
+        let path = self.cur_token.literal.clone();
+
+        if !self.expect_peek(TokenType::AS) {
+            return None;
+        }
+        if !self.expect_peek(TokenType::IDENT) {
+            return None;
+        }
+        let alias = Identifier {
+            token: self.cur_token.clone(),
+            value: self.cur_token.literal.clone(),
+            address: None,
         };
 
         if self.peek_token_is(TokenType::SEMICOLON) {
             self.next_token();
         }
-        Some(Box::new(stmt))
+        Some(Statement::Import(ImportStatement { token, path, alias }))
+    }
+
+    fn parse_export_statement(&mut self) -> Option<Statement> {
+        let token = self.cur_token.clone();
+
+        if !self.expect_peek(TokenType::LET) {
+            return None;
+        }
+        let stmt = self.parse_let_statement()?;
+        match stmt {
+            Statement::Let(let_stmt) => Some(Statement::Export(ExportStatement {
+                token,
+                statement: let_stmt,
+            })),
+            _ => {
+                self.errors.push(
+                    error_at(&token, "only `let name = value` can be exported")
+                        .with_help("write `export let name = value;`"),
+                );
+                None
+            }
+        }
+    }
+
+    fn parse_expression_statement(&mut self) -> Option<Statement> {
+        let token = self.cur_token.clone();
+        let expression = self.parse_expression(Precedence::LOWEST)?;
+        let stmt = ExpressionStmt { token, expression };
+
+        if self.peek_token_is(TokenType::SEMICOLON) {
+            self.next_token();
+        }
+        Some(Statement::Expression(stmt))
     }
 
-    fn parse_expression(&mut self, precedence: Precedence) -> Option<Box<dyn Expression>> {
-        println!("parse_expression: {:?}", self.cur_token);
-        let mut left_exp: Option<Box<dyn Expression>>;
+    fn parse_expression(&mut self, precedence: Precedence) -> Option<Expression> {
+        let mut left_exp: Option<Expression>;
         match self.cur_token.tk_type {
             TokenType::IDENT => {
-                left_exp = self.parse_identifier();
+                if self.peek_token_is(TokenType::ARROW) {
+                    left_exp = self.parse_arrow_function();
+                } else {
+                    left_exp = self.parse_identifier();
+                }
             }
             TokenType::INT => {
                 left_exp = self.parse_integer_literal();
@@ -225,15 +363,34 @@ impl<'a> Parser<'a> {
             TokenType::FUNCTION => {
                 left_exp = self.parse_function_literal();
             }
+            TokenType::BAR => {
+                left_exp = self.parse_lambda_literal();
+            }
+            TokenType::STRING => {
+                left_exp = self.parse_string_literal();
+            }
+            TokenType::LBRACKET => {
+                left_exp = self.parse_array_literal();
+            }
+            TokenType::LBRACE => {
+                left_exp = self.parse_hash_literal();
+            }
+            TokenType::MATCH => {
+                left_exp = self.parse_match_expression();
+            }
+            TokenType::TRY => {
+                left_exp = self.parse_try_expression();
+            }
+            TokenType::MACRO => {
+                left_exp = self.parse_macro_literal();
+            }
             _ => {
                 self.no_prefix_parse_fn_error(self.cur_token.tk_type);
                 return None;
             }
         };
 
-        if left_exp.is_none() {
-            return None;
-        }
+        left_exp.as_ref()?;
 
         while !self.peek_token_is(TokenType::SEMICOLON) && precedence < self.peek_precedence() {
             match self.peek_token.tk_type {
@@ -273,6 +430,18 @@ impl<'a> Parser<'a> {
                     self.next_token();
                     left_exp = self.parse_call_expression(left_exp.unwrap());
                 }
+                TokenType::LBRACKET => {
+                    self.next_token();
+                    left_exp = self.parse_index_expression(left_exp.unwrap());
+                }
+                TokenType::DOT => {
+                    self.next_token();
+                    left_exp = self.parse_member_expression(left_exp.unwrap());
+                }
+                TokenType::PIPE => {
+                    self.next_token();
+                    left_exp = self.parse_pipeline_expression(left_exp.unwrap());
+                }
                 _ => {
                     return left_exp;
                 }
@@ -281,16 +450,48 @@ impl<'a> Parser<'a> {
         left_exp
     }
 
-    fn parse_identifier(&mut self) -> Option<Box<dyn Expression>> {
-        println!("parse_identifier: {:?}", self.cur_token);
-        Some(Box::new(Identifier {
+    fn parse_identifier(&mut self) -> Option<Expression> {
+        // `function(x) { ... }` is no call, so it is read on as a function
+        // literal and one mistake makes one error; `function` is a fine name
+        if self.cur_token.literal == "function"
+            && self.peek_token_is(TokenType::LPAREN)
+            && self.peek_is_function_tail()
+        {
+            self.errors.push(
+                error_at(&self.cur_token, "`function` is not a keyword")
+                    .with_help("functions are written `fn(x) { ... }`"),
+            );
+            self.cur_token.tk_type = TokenType::FUNCTION;
+            self.cur_token.literal = String::from("fn");
+            return self.parse_function_literal();
+        }
+        Some(Expression::Identifier(Identifier {
             token: self.cur_token.clone(),
             value: self.cur_token.literal.clone(),
+            address: None,
         }))
     }
 
-    fn parse_integer_literal(&mut self) -> Option<Box<dyn Expression>> {
-        println!("parse_integer_literal: {:?}", self.cur_token);
+    // Whether the `(` in the peek token opens parameters followed by a body,
+    // with or without a return type, rather than the arguments of a call.
+    fn peek_is_function_tail(&self) -> bool {
+        let mut l = self.l.clone();
+        let mut depth = 1;
+        while depth > 0 {
+            match l.next_token().tk_type {
+                TokenType::LPAREN => depth += 1,
+                TokenType::RPAREN => depth -= 1,
+                TokenType::EOF => return false,
+                _ => {}
+            }
+        }
+        matches!(
+            l.next_token().tk_type,
+            TokenType::LBRACE | TokenType::THINARROW
+        )
+    }
+
+    fn parse_integer_literal(&mut self) -> Option<Expression> {
         let mut lit = IntegerLiteral {
             token: self.cur_token.clone(),
             value: 0,
@@ -299,71 +500,65 @@ impl<'a> Parser<'a> {
         match self.cur_token.literal.parse::<i64>() {
             Ok(v) => {
                 lit.value = v;
-                Some(Box::new(lit) as Box<dyn Expression>)
+                Some(Expression::Integer(lit))
             }
             _ => {
                 let msg = format!("could not parse {} as integer", self.cur_token.literal);
-                self.errors.push(Box::new(msg));
+                self.errors.push(error_at(&self.cur_token, msg));
                 None
             }
         }
     }
 
     fn no_prefix_parse_fn_error(&mut self, t: TokenType) {
-        let msg = format!("no prefix parse function for {:?} found", t);
-        self.errors.push(Box::new(msg));
+        let msg = format!("no prefix parse function for {} found", t);
+        let mut err = error_at(&self.cur_token, msg);
+        if t == TokenType::ASSIGN {
+            err = err.with_help("names are bound with `let name = value`, compared with `==`");
+        }
+        self.errors.push(err);
     }
 
-    fn parse_prefix_expression(&mut self) -> Option<Box<dyn Expression>> {
-        println!("parse_prefix_expression: {:?}", self.cur_token);
+    fn parse_prefix_expression(&mut self) -> Option<Expression> {
         let token = self.cur_token.clone();
         let operator = self.cur_token.literal.clone();
         self.next_token();
-        let right = self.parse_expression(Precedence::PREFIX);
-        if right.is_none() {
-            return None;
-        }
+        let right = self.parse_expression(Precedence::PREFIX)?;
         let expression = PrefixExpression {
-            token: token,
-            operator: operator,
-            right: right.unwrap(),
+            token,
+            operator,
+            right: Box::new(right),
         };
 
-        Some(Box::new(expression))
+        Some(Expression::Prefix(expression))
     }
 
-    fn parse_infix_expression(&mut self, left: Box<dyn Expression>) -> Option<Box<dyn Expression>> {
-        println!("parse_infix_expression: {:?}", self.cur_token);
+    fn parse_infix_expression(&mut self, left: Expression) -> Option<Expression> {
         let token = self.cur_token.clone();
         let operator = self.cur_token.literal.clone();
 
         let precedence = self.cur_precedence();
         self.next_token();
 
-        let right = self.parse_expression(precedence);
-        if right.is_none() {
-            return None;
-        }
+        let right = self.parse_expression(precedence)?;
         let expression = InfixExpression {
-            token: token,
-            left: left,
-            operator: operator,
-            right: right.unwrap(),
+            token,
+            left: Box::new(left),
+            operator,
+            right: Box::new(right),
         };
 
-        Some(Box::new(expression))
+        Some(Expression::Infix(expression))
     }
 
-    fn parse_boolean(&mut self) -> Option<Box<dyn Expression>> {
-        println!("parse_boolean: {:?}", self.cur_token);
-        Some(Box::new(Boolean {
+    fn parse_boolean(&mut self) -> Option<Expression> {
+        Some(Expression::Boolean(Boolean {
             token: self.cur_token.clone(),
             value: self.cur_token_is(TokenType::TRUE),
         }))
     }
 
-    fn parse_grouped_expression(&mut self) -> Option<Box<dyn Expression>> {
-        println!("parse_grouped_expression: {:?}", self.cur_token);
+    fn parse_grouped_expression(&mut self) -> Option<Expression> {
         self.next_token();
         let exp = self.parse_expression(Precedence::LOWEST);
         if !self.expect_peek(TokenType::RPAREN) {
@@ -373,16 +568,23 @@ impl<'a> Parser<'a> {
         }
     }
 
-    fn parse_if_expression(&mut self) -> Option<Box<dyn Expression>> {
-        println!("parse_if_expression: {:?}", self.cur_token);
+    fn parse_if_expression(&mut self) -> Option<Expression> {
         let token = self.cur_token.clone();
         if !self.expect_peek(TokenType::LPAREN) {
             return None;
         }
         self.next_token();
-        let condition = self.parse_expression(Precedence::LOWEST);
-        if condition.is_none() {
-            return None;
+        let mut condition = self.parse_expression(Precedence::LOWEST)?;
+        // read on as a comparison, so that one mistake makes one error
+        if self.peek_token_is(TokenType::ASSIGN) {
+            self.errors.push(
+                error_at(&self.peek_token, "`=` in an if condition")
+                    .with_help("use `==` to compare values"),
+            );
+            self.next_token();
+            self.cur_token.tk_type = TokenType::EQ;
+            self.cur_token.literal = String::from("==");
+            condition = self.parse_infix_expression(condition)?;
         }
         if !self.expect_peek(TokenType::RPAREN) {
             return None;
@@ -392,15 +594,12 @@ impl<'a> Parser<'a> {
             return None;
         }
 
-        let consequence = self.parse_block_statement();
-        if consequence.is_none() {
-            return None;
-        }
+        let consequence = self.parse_block_statement()?;
 
         let mut expression = IfExpression {
-            token: token,
-            condition: condition.unwrap(),
-            consequence: consequence.unwrap(),
+            token,
+            condition: Box::new(condition),
+            consequence,
             alternative: None,
         };
 
@@ -413,11 +612,68 @@ impl<'a> Parser<'a> {
 
             expression.alternative = self.parse_block_statement();
         }
-        Some(Box::new(expression))
+        Some(Expression::If(Box::new(expression)))
+    }
+
+    fn parse_try_expression(&mut self) -> Option<Expression> {
+        let token = self.cur_token.clone();
+        if !self.expect_peek(TokenType::LBRACE) {
+            return None;
+        }
+        let block = self.parse_block_statement()?;
+
+        let mut expression = TryExpression {
+            token,
+            block,
+            catch_parameter: None,
+            catch_block: None,
+            finally_block: None,
+        };
+
+        if self.peek_token_is(TokenType::CATCH) {
+            self.next_token();
+            if self.peek_token_is(TokenType::LPAREN) {
+                self.next_token();
+                if !self.expect_peek(TokenType::IDENT) {
+                    return None;
+                }
+                expression.catch_parameter = Some(Identifier {
+                    token: self.cur_token.clone(),
+                    value: self.cur_token.literal.clone(),
+                    address: None,
+                });
+                if !self.expect_peek(TokenType::RPAREN) {
+                    return None;
+                }
+            }
+            if !self.expect_peek(TokenType::LBRACE) {
+                return None;
+            }
+            expression.catch_block = Some(self.parse_block_statement()?);
+        }
+
+        if self.peek_token_is(TokenType::FINALLY) {
+            self.next_token();
+            if !self.expect_peek(TokenType::LBRACE) {
+                return None;
+            }
+            expression.finally_block = Some(self.parse_block_statement()?);
+        }
+
+        if expression.catch_block.is_none() && expression.finally_block.is_none() {
+            self.errors.push(
+                error_at(
+                    &expression.token,
+                    "try expression requires catch or finally",
+                )
+                .with_help("add `catch (e) { ... }` or `finally { ... }` after the block"),
+            );
+            return None;
+        }
+        Some(Expression::Try(Box::new(expression)))
     }
 
     fn parse_block_statement(&mut self) -> Option<BlockStatement> {
-        println!("parse_block_statement: {:?}", self.cur_token);
         let mut block = BlockStatement {
             token: self.cur_token.clone(),
             statements: Vec::new(),
@@ -425,119 +681,703 @@ impl<'a> Parser<'a> {
 
         self.next_token();
 
+        let mut previous: Option<Token> = None;
         while !self.cur_token_is(TokenType::RBRACE) && !self.cur_token_is(TokenType::EOF) {
-            let stmt = self.parse_statement();
-            match stmt {
-                Some(s) => {
-                    block.statements.push(s);
+            let errors = self.errors.len();
+            match self.parse_statement_after(previous.as_ref()) {
+                Some(stmt) if self.errors.len() == errors => {
+                    block.statements.push(stmt);
+                    previous = Some(self.cur_token.clone());
+                }
+                stmt => {
+                    block.statements.extend(stmt);
+                    previous = None;
                 }
-                _ => {}
             }
             self.next_token();
         }
         Some(block)
     }
 
-    fn parse_function_literal(&mut self) -> Option<Box<dyn Expression>> {
-        println!("parse_function_literal: {:?}", self.cur_token);
+    fn parse_function_literal(&mut self) -> Option<Expression> {
         let token = self.cur_token.clone();
         if !self.expect_peek(TokenType::LPAREN) {
             return None;
         }
 
-        let parameters = self.parse_function_parameters();
+        let params = self.parse_function_parameters(TokenType::RPAREN)?;
+        let return_type = self.parse_return_type()?;
 
         if !self.expect_peek(TokenType::LBRACE) {
             return None;
         }
-        let body = self.parse_block_statement();
-        if body.is_none() {
+        let body = self.parse_block_statement()?;
+        let lit = FunctionLiteral {
+            token,
+            parameters: params.parameters,
+            defaults: params.defaults,
+            patterns: params.patterns,
+            rest: params.rest,
+            parameter_types: params.types,
+            return_type,
+            body,
+            name: None,
+        };
+
+        Some(Expression::Function(Rc::new(lit)))
+    }
+
+    fn parse_macro_literal(&mut self) -> Option<Expression> {
+        let token = self.cur_token.clone();
+        if !self.expect_peek(TokenType::LPAREN) {
             return None;
         }
+
+        let params = self.parse_function_parameters(TokenType::RPAREN)?;
+        // macro arguments are unevaluated expressions, so only plain names are allowed
+        if params.rest.is_some()
+            || params.defaults.iter().any(|d| d.is_some())
+            || params.patterns.iter().any(|p| p.is_some())
+        {
+            self.errors.push(
+                error_at(&token, "macro parameters must be plain identifiers")
+                    .with_note("macro arguments are passed unevaluated, as quoted code"),
+            );
+            return None;
+        }
+
+        if !self.expect_peek(TokenType::LBRACE) {
+            return None;
+        }
+        let body = self.parse_block_statement()?;
+        Some(Expression::Macro(Rc::new(MacroLiteral {
+            token,
+            parameters: params.parameters,
+            body,
+        })))
+    }
+
+    fn parse_function_parameters(&mut self, end: TokenType) -> Option<FunctionParameters> {
+        let mut params = FunctionParameters {
+            parameters: Vec::new(),
+            defaults: Vec::new(),
+            patterns: Vec::new(),
+            rest: None,
+            types: Vec::new(),
+        };
+        if self.peek_token_is(end) {
+            self.next_token();
+            return Some(params);
+        }
+
+        loop {
+            let is_rest = self.peek_token_is(TokenType::ELLIPSIS);
+            if is_rest {
+                self.next_token();
+            }
+            let ident;
+            if !is_rest
+                && (self.peek_token_is(TokenType::LBRACKET)
+                    || self.peek_token_is(TokenType::LBRACE))
+            {
+                self.next_token();
+                let token = self.cur_token.clone();
+                let pattern = self.parse_pattern()?;
+                ident = Identifier {
+                    token,
+                    value: pattern.string(),
+                    address: None,
+                };
+                params.patterns.push(Some(pattern));
+            } else {
+                if !self.expect_peek(TokenType::IDENT) {
+                    return None;
+                }
+                ident = Identifier {
+                    token: self.cur_token.clone(),
+                    value: self.cur_token.literal.clone(),
+                    address: None,
+                };
+                if params.parameters.iter().any(|i| i.value == ident.value)
+                    || params.rest.as_ref().is_some_and(|r| r.value == ident.value)
+                {
+                    let msg = format!("duplicate parameter name: {}", ident.value);
+                    self.errors.push(error_at(&self.cur_token, msg));
+                    return None;
+                }
+                if !is_rest {
+                    params.patterns.push(None);
+                }
+            }
+
+            if is_rest {
+                params.rest = Some(ident);
+                if !self.peek_token_is(end) {
+                    let msg = format!(
+                        "rest parameter {} must be the last parameter",
+                        params.rest.unwrap().value
+                    );
+                    self.errors.push(error_at(&self.cur_token, msg));
+                    return None;
+                }
+                break;
+            }
+
+            if self.peek_token_is(TokenType::COLON) {
+                self.next_token();
+                self.next_token();
+                params.types.push(Some(self.parse_type_annotation()?));
+            } else {
+                params.types.push(None);
+            }
+
+            if self.peek_token_is(TokenType::ASSIGN) {
+                self.next_token();
+                self.next_token();
+                let default = self.parse_expression(Precedence::LOWEST)?;
+                params.defaults.push(Some(default));
+            } else if params.defaults.iter().any(|d| d.is_some()) {
+                let msg = format!(
+                    "parameter {} without default follows parameter with default",
+                    ident.value
+                );
+                self.errors.push(
+                    error_at(&self.cur_token, msg)
+                        .with_help("give it a default or move it before the ones with defaults"),
+                );
+                return None;
+            } else {
+                params.defaults.push(None);
+            }
+            params.parameters.push(ident);
+
+            if !self.peek_token_is(TokenType::COMMA) {
+                break;
+            }
+            self.next_token();
+        }
+
+        if !self.expect_peek(end) {
+            return None;
+        }
+        Some(params)
+    }
+
+    fn parse_return_type(&mut self) -> Option<Option<TypeAnnotation>> {
+        if !self.peek_token_is(TokenType::THINARROW) {
+            return Some(None);
+        }
+        self.next_token();
+        self.next_token();
+        Some(Some(self.parse_type_annotation()?))
+    }
+
+    fn parse_type_annotation(&mut self) -> Option<TypeAnnotation> {
+        match self.cur_token.tk_type {
+            TokenType::IDENT => match self.cur_token.literal.as_str() {
+                "int" | "bool" | "string" | "null" | "any" => {
+                    Some(TypeAnnotation::Named(self.cur_token.literal.clone()))
+                }
+                _ => {
+                    let msg = format!("unknown type name: {}", self.cur_token.literal);
+                    self.errors.push(
+                        error_at(&self.cur_token, msg)
+                            .with_note("the types are int, bool, string, null and any"),
+                    );
+                    None
+                }
+            },
+            TokenType::LBRACKET => {
+                self.next_token();
+                let element = self.parse_type_annotation()?;
+                if !self.expect_peek(TokenType::RBRACKET) {
+                    return None;
+                }
+                Some(TypeAnnotation::Array(Box::new(element)))
+            }
+            TokenType::LBRACE => {
+                self.next_token();
+                let key = self.parse_type_annotation()?;
+                if !self.expect_peek(TokenType::COLON) {
+                    return None;
+                }
+                self.next_token();
+                let value = self.parse_type_annotation()?;
+                if !self.expect_peek(TokenType::RBRACE) {
+                    return None;
+                }
+                Some(TypeAnnotation::Hash(Box::new(key), Box::new(value)))
+            }
+            TokenType::FUNCTION => {
+                if !self.expect_peek(TokenType::LPAREN) {
+                    return None;
+                }
+                let mut params = Vec::new();
+                while !self.peek_token_is(TokenType::RPAREN) {
+                    self.next_token();
+                    params.push(self.parse_type_annotation()?);
+                    if !self.peek_token_is(TokenType::RPAREN) && !self.expect_peek(TokenType::COMMA)
+                    {
+                        return None;
+                    }
+                }
+                self.next_token();
+                if !self.expect_peek(TokenType::THINARROW) {
+                    return None;
+                }
+                self.next_token();
+                let ret = self.parse_type_annotation()?;
+                Some(TypeAnnotation::Function(params, Box::new(ret)))
+            }
+            _ => {
+                let msg = format!("expected a type, got {}", self.cur_token.tk_type);
+                self.errors.push(error_at(&self.cur_token, msg));
+                None
+            }
+        }
+    }
+
+    fn parse_lambda_literal(&mut self) -> Option<Expression> {
+        let token = self.cur_token.clone();
+        let params = self.parse_function_parameters(TokenType::BAR)?;
+        let return_type = self.parse_return_type()?;
+        let body = self.parse_expression_body()?;
         let lit = FunctionLiteral {
-            token: token,
-            parameters: parameters,
-            body: body.unwrap(),
+            token: Token {
+                tk_type: TokenType::FUNCTION,
+                literal: String::from("fn"),
+                span: token.span,
+            },
+            parameters: params.parameters,
+            defaults: params.defaults,
+            patterns: params.patterns,
+            rest: params.rest,
+            parameter_types: params.types,
+            return_type,
+            body,
+            name: None,
         };
 
-        Some(Box::new(lit))
+        Some(Expression::Function(Rc::new(lit)))
     }
 
-    fn parse_function_parameters(&mut self) -> Vec<Identifier> {
-        println!("parse_function_parameters: {:?}", self.cur_token);
-        let mut identfiers = Vec::new();
-        if self.peek_token_is(TokenType::RPAREN) {
+    fn parse_arrow_function(&mut self) -> Option<Expression> {
+        let token = self.cur_token.clone();
+        let parameter = Identifier {
+            token: self.cur_token.clone(),
+            value: self.cur_token.literal.clone(),
+            address: None,
+        };
+        self.next_token();
+        let body = self.parse_expression_body()?;
+        let lit = FunctionLiteral {
+            token: Token {
+                tk_type: TokenType::FUNCTION,
+                literal: String::from("fn"),
+                span: token.span,
+            },
+            parameters: vec![parameter],
+            defaults: vec![None],
+            patterns: vec![None],
+            rest: None,
+            parameter_types: vec![None],
+            return_type: None,
+            body,
+            name: None,
+        };
+
+        Some(Expression::Function(Rc::new(lit)))
+    }
+
+    fn parse_expression_body(&mut self) -> Option<BlockStatement> {
+        if self.peek_token_is(TokenType::LBRACE) {
             self.next_token();
-            return identfiers;
+            return self.parse_block_statement();
         }
 
         self.next_token();
+        let token = self.cur_token.clone();
+        let expression = self.parse_expression(Precedence::LOWEST)?;
+        Some(BlockStatement {
+            token: token.clone(),
+            statements: vec![Statement::Expression(ExpressionStmt { token, expression })],
+        })
+    }
+
+    fn parse_pipeline_expression(&mut self, left: Expression) -> Option<Expression> {
+        let precedence = self.cur_precedence();
+        let span = self.cur_token.span;
+        self.next_token();
+
+        let mut right = self.parse_expression(precedence)?;
+        if let Expression::Call(call) = &mut right {
+            call.arguments.insert(0, left);
+            return Some(right);
+        }
 
-        let ident = Identifier {
+        let exp = CallExpression {
+            token: Token {
+                span,
+                ..Token::new(TokenType::LPAREN, '(')
+            },
+            function: Box::new(right),
+            arguments: vec![left],
+            named_arguments: Vec::new(),
+        };
+        Some(Expression::Call(exp))
+    }
+
+    fn parse_string_literal(&mut self) -> Option<Expression> {
+        Some(Expression::String(StringLiteral {
             token: self.cur_token.clone(),
             value: self.cur_token.literal.clone(),
-        };
-        identfiers.push(ident);
+        }))
+    }
+
+    fn parse_array_literal(&mut self) -> Option<Expression> {
+        let token = self.cur_token.clone();
+        let elements = self.parse_expression_list(TokenType::RBRACKET)?;
+        Some(Expression::Array(ArrayLiteral { token, elements }))
+    }
+
+    fn parse_expression_list(&mut self, end: TokenType) -> Option<Vec<Expression>> {
+        let mut list: Vec<Expression> = Vec::new();
+
+        if self.peek_token_is(end) {
+            self.next_token();
+            return Some(list);
+        }
+
+        self.next_token();
+        list.push(self.parse_expression(Precedence::LOWEST)?);
 
         while self.peek_token_is(TokenType::COMMA) {
             self.next_token();
             self.next_token();
-            let ident = Identifier {
-                token: self.cur_token.clone(),
-                value: self.cur_token.literal.clone(),
-            };
-            identfiers.push(ident);
+            list.push(self.parse_expression(Precedence::LOWEST)?);
         }
 
-        if !self.expect_peek(TokenType::RPAREN) {
-            return Vec::new();
+        if !self.expect_peek(end) {
+            return None;
         }
-        identfiers
+        Some(list)
     }
 
-    fn parse_call_expression(
-        &mut self,
-        function: Box<dyn Expression>,
-    ) -> Option<Box<dyn Expression>> {
-        println!("parse_call_expression: {:?}", self.cur_token);
-        let exp = CallExpression {
+    fn parse_hash_literal(&mut self) -> Option<Expression> {
+        let token = self.cur_token.clone();
+        let mut pairs: Vec<(Expression, Expression)> = Vec::new();
+
+        while !self.peek_token_is(TokenType::RBRACE) {
+            self.next_token();
+            let key = self.parse_expression(Precedence::LOWEST)?;
+            if !self.expect_peek(TokenType::COLON) {
+                return None;
+            }
+            self.next_token();
+            let value = self.parse_expression(Precedence::LOWEST)?;
+            pairs.push((key, value));
+
+            if !self.peek_token_is(TokenType::RBRACE) && !self.expect_peek(TokenType::COMMA) {
+                return None;
+            }
+        }
+
+        if !self.expect_peek(TokenType::RBRACE) {
+            return None;
+        }
+        Some(Expression::Hash(HashLiteral { token, pairs }))
+    }
+
+    fn parse_index_expression(&mut self, left: Expression) -> Option<Expression> {
+        let token = self.cur_token.clone();
+
+        self.next_token();
+        let index = self.parse_expression(Precedence::LOWEST)?;
+
+        if !self.expect_peek(TokenType::RBRACKET) {
+            return None;
+        }
+        Some(Expression::Index(IndexExpression {
+            token,
+            left: Box::new(left),
+            index: Box::new(index),
+        }))
+    }
+
+    fn parse_member_expression(&mut self, left: Expression) -> Option<Expression> {
+        let token = self.cur_token.clone();
+
+        if !self.expect_peek(TokenType::IDENT) {
+            return None;
+        }
+        // `a.b` is sugar for `a["b"]`
+        let index = Expression::String(StringLiteral {
             token: self.cur_token.clone(),
-            function: function,
-            arguments: self.parse_call_arguments(),
+            value: self.cur_token.literal.clone(),
+        });
+        Some(Expression::Index(IndexExpression {
+            token,
+            left: Box::new(left),
+            index: Box::new(index),
+        }))
+    }
+
+    fn parse_match_expression(&mut self) -> Option<Expression> {
+        let token = self.cur_token.clone();
+        if !self.expect_peek(TokenType::LPAREN) {
+            return None;
+        }
+        self.next_token();
+        let value = self.parse_expression(Precedence::LOWEST)?;
+        if !self.expect_peek(TokenType::RPAREN) {
+            return None;
+        }
+        if !self.expect_peek(TokenType::LBRACE) {
+            return None;
+        }
+
+        let mut arms: Vec<MatchArm> = Vec::new();
+        while !self.peek_token_is(TokenType::RBRACE) {
+            self.next_token();
+            arms.push(self.parse_match_arm()?);
+
+            if !self.peek_token_is(TokenType::RBRACE) && !self.expect_peek(TokenType::COMMA) {
+                return None;
+            }
+        }
+
+        if !self.expect_peek(TokenType::RBRACE) {
+            return None;
+        }
+        Some(Expression::Match(MatchExpression {
+            token,
+            value: Box::new(value),
+            arms,
+        }))
+    }
+
+    fn parse_match_arm(&mut self) -> Option<MatchArm> {
+        let token = self.cur_token.clone();
+        let pattern = self.parse_pattern()?;
+
+        let mut guard = None;
+        if self.peek_token_is(TokenType::IF) {
+            self.next_token();
+            self.next_token();
+            guard = Some(self.parse_expression(Precedence::LOWEST)?);
+        }
+
+        if !self.expect_peek(TokenType::ARROW) {
+            return None;
+        }
+        let body = self.parse_expression_body()?;
+        Some(MatchArm {
+            token,
+            pattern,
+            guard,
+            body,
+        })
+    }
+
+    fn parse_pattern(&mut self) -> Option<Pattern> {
+        match self.cur_token.tk_type {
+            TokenType::IDENT if self.cur_token.literal == "_" => {
+                Some(Pattern::Wildcard(WildcardPattern {
+                    token: self.cur_token.clone(),
+                }))
+            }
+            TokenType::IDENT => Some(Pattern::Binding(BindingPattern {
+                token: self.cur_token.clone(),
+                name: Identifier {
+                    token: self.cur_token.clone(),
+                    value: self.cur_token.literal.clone(),
+                    address: None,
+                },
+            })),
+            TokenType::INT | TokenType::STRING | TokenType::TRUE | TokenType::FALSE => {
+                self.parse_literal_pattern()
+            }
+            TokenType::MINUS if self.peek_token_is(TokenType::INT) => self.parse_literal_pattern(),
+            TokenType::LBRACKET => self.parse_array_pattern(),
+            TokenType::LBRACE => self.parse_hash_pattern(),
+            _ => {
+                let msg = format!(
+                    "no pattern parse function for {} found",
+                    self.cur_token.tk_type
+                );
+                self.errors.push(error_at(&self.cur_token, msg));
+                None
+            }
+        }
+    }
+
+    fn parse_literal_pattern(&mut self) -> Option<Pattern> {
+        let token = self.cur_token.clone();
+        let value = match self.cur_token.tk_type {
+            TokenType::INT => self.parse_integer_literal()?,
+            TokenType::STRING => self.parse_string_literal()?,
+            TokenType::TRUE | TokenType::FALSE => self.parse_boolean()?,
+            TokenType::MINUS => {
+                let operator = self.cur_token.literal.clone();
+                self.next_token();
+                let right = self.parse_integer_literal()?;
+                Expression::Prefix(PrefixExpression {
+                    token: token.clone(),
+                    operator,
+                    right: Box::new(right),
+                })
+            }
+            _ => {
+                self.no_prefix_parse_fn_error(self.cur_token.tk_type);
+                return None;
+            }
         };
-        Some(Box::new(exp))
+        Some(Pattern::Literal(LiteralPattern { token, value }))
+    }
+
+    fn parse_array_pattern(&mut self) -> Option<Pattern> {
+        let token = self.cur_token.clone();
+        let mut elements: Vec<Pattern> = Vec::new();
+        let mut rest = None;
+
+        while !self.peek_token_is(TokenType::RBRACKET) {
+            if self.peek_token_is(TokenType::ELLIPSIS) {
+                self.next_token();
+                if !self.expect_peek(TokenType::IDENT) {
+                    return None;
+                }
+                rest = Some(Identifier {
+                    token: self.cur_token.clone(),
+                    value: self.cur_token.literal.clone(),
+                    address: None,
+                });
+                if !self.peek_token_is(TokenType::RBRACKET) {
+                    let msg = format!(
+                        "rest pattern {} must be the last element",
+                        self.cur_token.literal
+                    );
+                    self.errors.push(error_at(&self.cur_token, msg));
+                    return None;
+                }
+                break;
+            }
+
+            self.next_token();
+            elements.push(self.parse_pattern()?);
+
+            if !self.peek_token_is(TokenType::RBRACKET) && !self.expect_peek(TokenType::COMMA) {
+                return None;
+            }
+        }
+
+        if !self.expect_peek(TokenType::RBRACKET) {
+            return None;
+        }
+        Some(Pattern::Array(ArrayPattern {
+            token,
+            elements,
+            rest,
+        }))
     }
 
-    fn parse_call_arguments(&mut self) -> Vec<Box<dyn Expression>> {
-        println!("parse_call_arguments: {:?}", self.cur_token);
-        let mut args: Vec<Box<dyn Expression>> = Vec::new();
+    fn parse_hash_pattern(&mut self) -> Option<Pattern> {
+        let token = self.cur_token.clone();
+        let mut pairs: Vec<(Expression, Pattern)> = Vec::new();
 
-        if self.peek_token_is(TokenType::RPAREN) {
+        while !self.peek_token_is(TokenType::RBRACE) {
+            self.next_token();
+            let key = match self.cur_token.tk_type {
+                TokenType::INT => self.parse_integer_literal()?,
+                TokenType::STRING => self.parse_string_literal()?,
+                TokenType::TRUE | TokenType::FALSE => self.parse_boolean()?,
+                _ => {
+                    let msg = format!(
+                        "hash pattern key must be a literal, got {}",
+                        self.cur_token.tk_type
+                    );
+                    self.errors.push(error_at(&self.cur_token, msg));
+                    return None;
+                }
+            };
+            if !self.expect_peek(TokenType::COLON) {
+                return None;
+            }
             self.next_token();
-            return args;
+            let value = self.parse_pattern()?;
+            pairs.push((key, value));
+
+            if !self.peek_token_is(TokenType::RBRACE) && !self.expect_peek(TokenType::COMMA) {
+                return None;
+            }
         }
 
-        self.next_token();
-        let a = self.parse_expression(Precedence::LOWEST);
-        if a.is_none() {
-            return Vec::new();
+        if !self.expect_peek(TokenType::RBRACE) {
+            return None;
         }
-        args.push(a.unwrap());
+        Some(Pattern::Hash(HashPattern { token, pairs }))
+    }
 
-        while self.peek_token_is(TokenType::COMMA) {
+    fn parse_call_expression(&mut self, function: Expression) -> Option<Expression> {
+        let token = self.cur_token.clone();
+        let (arguments, named_arguments) = self.parse_call_arguments()?;
+        let exp = CallExpression {
+            token,
+            function: Box::new(function),
+            arguments,
+            named_arguments,
+        };
+        Some(Expression::Call(exp))
+    }
+
+    fn parse_call_arguments(&mut self) -> Option<CallArguments> {
+        let mut args: Vec<Expression> = Vec::new();
+        let mut named_args: Vec<(Identifier, Expression)> = Vec::new();
+
+        if self.peek_token_is(TokenType::RPAREN) {
             self.next_token();
+            return Some((args, named_args));
+        }
+
+        loop {
             self.next_token();
-            let a = self.parse_expression(Precedence::LOWEST);
-            if a.is_none() {
-                return Vec::new();
+            if self.cur_token_is(TokenType::IDENT) && self.peek_token_is(TokenType::COLON) {
+                let name = Identifier {
+                    token: self.cur_token.clone(),
+                    value: self.cur_token.literal.clone(),
+                    address: None,
+                };
+                self.next_token();
+                self.next_token();
+                let a = self.parse_expression(Precedence::LOWEST)?;
+                named_args.push((name, a));
+            } else {
+                if let Some((name, _)) = named_args.last() {
+                    let msg = format!("positional argument follows named argument {}", name.value);
+                    self.errors.push(
+                        error_at(&self.cur_token, msg)
+                            .with_help("pass positional arguments before named ones"),
+                    );
+                    return None;
+                }
+                let a = self.parse_expression(Precedence::LOWEST)?;
+                args.push(a);
             }
-            args.push(a.unwrap());
+
+            if !self.peek_token_is(TokenType::COMMA) {
+                break;
+            }
+            self.next_token();
         }
 
         if !self.expect_peek(TokenType::RPAREN) {
-            return Vec::new();
+            return None;
         }
 
-        args
+        Some((args, named_args))
     }
 }
+
+// An error pointing at `token`.
+fn error_at(token: &Token, message: impl Into<String>) -> Diagnostic {
+    Diagnostic::error(token.span, message).with_width(token.width())
+}
@@ -1,16 +1,34 @@
+use super::ast::*;
+use super::closure_compiler;
+use super::compiler::*;
+use super::diagnostics::{Diagnostic, DiagnosticFormat};
 use super::environment::*;
 use super::evaluator::*;
 use super::lexer::*;
+use super::macro_expansion::*;
+use super::module::*;
+use super::object::*;
 use super::parser::*;
+use super::resolver::*;
+use super::typechecker::*;
+use super::vm::*;
 use std::cell::*;
+use std::fs;
+use std::io;
 use std::io::BufRead;
 use std::io::BufReader;
 use std::io::BufWriter;
 use std::io::Read;
 use std::io::Write;
+use std::path::PathBuf;
 use std::rc::*;
+use std::time::Instant;
 
 const PROMPT: &str = ">> ";
+// shown while a statement is still open
+const CONTINUATION_PROMPT: &str = ".. ";
+// what diagnostics call the line typed in
+const INPUT_NAME: &str = "<stdin>";
 const MONKEY_FACE: &str = r#"
             __,__
    .--.  .-"     "-.  .--.
@@ -24,44 +42,493 @@ const MONKEY_FACE: &str = r#"
         '._ '-=-' _.'
            '-----'
 "#;
-pub fn start(input: &mut dyn Read, output: &mut dyn Write) {
-    let mut reader = BufReader::new(input);
-    let mut fmt = BufWriter::new(output);
-    let env = Rc::new(RefCell::new(new_environment()));
-    loop {
-        fmt.write_fmt(format_args!("{}", PROMPT)).unwrap();
-        fmt.flush().unwrap();
-        let mut line = String::new();
-        reader.read_line(&mut line).unwrap();
-        let mut l = Lexer::new(&mut line);
-        let mut p = Parser::new(&mut l);
-        let program = p.parse_program();
-        if p.get_errors().len() != 0 {
-            print_parser_errors(&mut fmt, p.get_errors());
+// How a session went: the inputs read, commands included, and how many of
+// them failed.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Summary {
+    pub inputs: usize,
+    pub errors: usize,
+}
+impl Summary {
+    pub fn is_ok(&self) -> bool {
+        self.errors == 0
+    }
+}
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} input{}, {} failed",
+            self.inputs,
+            if self.inputs == 1 { "" } else { "s" },
+            self.errors
+        )
+    }
+}
+
+pub fn start(input: &mut dyn Read, output: &mut dyn Write) -> io::Result<Summary> {
+    start_with_loader(input, output, &Rc::new(RefCell::new(ModuleLoader::new())))
+}
+
+pub fn start_with_loader(
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+    loader: &Rc<RefCell<ModuleLoader>>,
+) -> io::Result<Summary> {
+    let mut console = Console {
+        out: BufWriter::new(output),
+        err: None,
+        format: loader.borrow().diagnostic_format(),
+    };
+    run(&mut BufReader::new(input), &mut console, loader)
+}
+
+// Runs input that is not typed in, such as a script piped to the REPL: there
+// are no prompts, blank lines do not cancel, and errors go to `errors`.
+pub fn run_script(
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+    errors: &mut dyn Write,
+    loader: &Rc<RefCell<ModuleLoader>>,
+) -> io::Result<Summary> {
+    let mut console = Console {
+        out: BufWriter::new(output),
+        err: Some(BufWriter::new(errors)),
+        format: loader.borrow().diagnostic_format(),
+    };
+    run(&mut BufReader::new(input), &mut console, loader)
+}
+
+fn run(
+    reader: &mut dyn BufRead,
+    console: &mut Console,
+    loader: &Rc<RefCell<ModuleLoader>>,
+) -> io::Result<Summary> {
+    let mut session = Session::new(loader);
+    let mut summary = Summary::default();
+    while let Some(line) = read_input(reader, console, &session.interrupt)? {
+        // Ctrl-C stops a script, not only the line it came in
+        if !console.interactive() && session.interrupt.is_interrupted() {
+            break;
+        }
+        if line.trim().is_empty() {
             continue;
         }
+        summary.inputs += 1;
+        let flow = match line.trim_start().strip_prefix(':') {
+            Some(command) => run_command(command.trim(), &mut session, console)?,
+            // only the end of input leaves a statement open
+            _ if is_incomplete(&line) => {
+                console.err().write_fmt(format_args!(
+                    "{}: input ended before the statement did\n",
+                    INPUT_NAME
+                ))?;
+                Flow::Failed
+            }
+            _ => session.eval(INPUT_NAME, &line, console)?,
+        };
+        console.flush()?;
+        match flow {
+            Flow::Done => {}
+            Flow::Failed => summary.errors += 1,
+            Flow::Quit => break,
+        }
+    }
+    Ok(summary)
+}
 
-        match eval(&program.unwrap(), &env) {
+// Where the REPL writes, and how it shows errors. Typed in, everything goes
+// to `out`; a script has its errors written to `err`.
+struct Console<'a> {
+    out: BufWriter<&'a mut dyn Write>,
+    err: Option<BufWriter<&'a mut dyn Write>>,
+    format: DiagnosticFormat,
+}
+impl<'a> Console<'a> {
+    fn interactive(&self) -> bool {
+        self.err.is_none()
+    }
+    fn err(&mut self) -> &mut BufWriter<&'a mut dyn Write> {
+        match &mut self.err {
+            Some(err) => err,
+            _ => &mut self.out,
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()?;
+        self.err().flush()
+    }
+}
+
+// What a session has built up: bindings, macros and types, and for the VM the
+// compiler's global slots.
+struct Session {
+    loader: Rc<RefCell<ModuleLoader>>,
+    env: Rc<RefCell<Environment>>,
+    macro_env: Rc<RefCell<Environment>>,
+    checker: TypeChecker,
+    engine: Engine,
+    limits: ExecutionLimits,
+    interrupt: InterruptHandle,
+    compiler: Compiler,
+    vm: Vm,
+}
+impl Session {
+    fn new(loader: &Rc<RefCell<ModuleLoader>>) -> Session {
+        let (engine, mut limits) = {
+            let loader = loader.borrow();
+            (loader.engine(), loader.limits().clone())
+        };
+        // raised by Ctrl-C when the caller handles it: the line being
+        // evaluated stops, the session stays as it was
+        let interrupt = limits
+            .interrupt
+            .get_or_insert_with(InterruptHandle::new)
+            .clone();
+        let mut vm = Vm::with_loader(loader, None);
+        vm.set_limits(limits.clone());
+        Session {
+            loader: Rc::clone(loader),
+            env: Rc::new(RefCell::new(new_module_environment(loader, None))),
+            macro_env: Rc::new(RefCell::new(new_module_environment(loader, None))),
+            checker: TypeChecker::new(),
+            engine,
+            limits,
+            interrupt,
+            compiler: Compiler::new(),
+            vm,
+        }
+    }
+
+    // Runs `source` and prints its value; `name` is what diagnostics call it.
+    fn eval(&mut self, name: &str, source: &str, console: &mut Console) -> io::Result<Flow> {
+        let mut program = match parse(name, source, console)? {
+            Some(program) => program,
+            _ => return Ok(Flow::Failed),
+        };
+
+        define_macros(&mut program, &self.macro_env);
+        if let Err(err) = expand_macros(&mut program, &self.macro_env) {
+            console
+                .err()
+                .write_fmt(format_args!("{}\n", err.inspect()))?;
+            return Ok(Flow::Failed);
+        }
+
+        // a line with type errors is not evaluated, so its bindings are dropped
+        let snapshot = self.checker.clone();
+        self.checker.check_program(&program);
+        let type_errors = self.checker.take_errors();
+        if !type_errors.is_empty() {
+            print_type_errors(console, name, source, &type_errors)?;
+            self.checker = snapshot;
+            return Ok(Flow::Failed);
+        }
+
+        resolve_addresses(&mut program);
+        self.interrupt.reset();
+        let evaluated = match self.engine {
+            Engine::Eval => eval_with_limits(&program, &self.env, &self.limits),
+            Engine::Closures => {
+                closure_compiler::eval_with_limits(&program, &self.env, &self.limits)
+            }
+            Engine::Vm => match self.compiler.compile(&program) {
+                Ok(bytecode) => self.vm.run(&bytecode),
+                Err(err) => {
+                    console
+                        .err()
+                        .write_fmt(format_args!("compile error: {}\n", err))?;
+                    return Ok(Flow::Failed);
+                }
+            },
+        };
+        match evaluated {
+            Some(Value::Error(err)) => {
+                let shown = console
+                    .format
+                    .show(name, source, &[Diagnostic::from(err.as_ref())]);
+                console.err().write_fmt(format_args!("{}", shown))?;
+                Ok(Flow::Failed)
+            }
             Some(evaluated) => {
-                fmt.write_fmt(format_args!("{}\n", evaluated.inspect()))
-                    .unwrap();
+                console
+                    .out
+                    .write_fmt(format_args!("{}\n", evaluated.report()))?;
+                Ok(Flow::Done)
+            }
+            _ => Ok(Flow::Done),
+        }
+    }
+
+    // Runs the file at `path` in the session. Its imports are resolved next
+    // to it, those of the lines typed in afterwards are not.
+    fn load(&mut self, path: &str, console: &mut Console) -> io::Result<Flow> {
+        let source = match fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(err) => {
+                console
+                    .err()
+                    .write_fmt(format_args!("cannot read {}: {}\n", path, err))?;
+                return Ok(Flow::Failed);
             }
+        };
+        self.env.borrow_mut().set_file(Some(PathBuf::from(path)));
+        self.vm.set_file(Some(PathBuf::from(path)));
+        let flow = self.eval(path, &source, console);
+        self.env.borrow_mut().set_file(None);
+        self.vm.set_file(None);
+        flow
+    }
+
+    // The top-level bindings by name; the VM keeps them as globals.
+    fn bindings(&self) -> Vec<(String, Value)> {
+        match self.engine {
+            Engine::Vm => {
+                let mut globals = self.vm.globals();
+                globals.sort_by(|a, b| a.0.cmp(&b.0));
+                globals
+            }
+            _ => self.env.borrow().bindings(),
+        }
+    }
+}
+
+// What became of an input.
+enum Flow {
+    Done,
+    Failed,
+    Quit,
+}
+
+const HELP: &str = ":env          list the bindings of the session
+:type EXPR    show the type of EXPR without running it
+:ast EXPR     show the tree EXPR parses to
+:tokens EXPR  show the tokens of EXPR
+:load FILE    run FILE in the session
+:reset        forget every binding
+:time EXPR    run EXPR and show how long it took
+:quit         leave the REPL
+:help         show this list
+";
+
+// Runs a line starting with `:`, given without the colon.
+fn run_command(command: &str, session: &mut Session, console: &mut Console) -> io::Result<Flow> {
+    let (name, argument) = match command.split_once(char::is_whitespace) {
+        Some((name, argument)) => (name, argument.trim()),
+        _ => (command, ""),
+    };
+    let usage = match name {
+        "type" | "ast" | "tokens" | "time" => "EXPR",
+        "load" => "FILE",
+        _ => "",
+    };
+    if !usage.is_empty() && argument.is_empty() {
+        console
+            .err()
+            .write_fmt(format_args!("usage: :{} {}\n", name, usage))?;
+        return Ok(Flow::Failed);
+    }
+    match name {
+        "env" => {
+            for (name, val) in session.bindings() {
+                console
+                    .out
+                    .write_fmt(format_args!("{} = {}\n", name, val.inspect()))?;
+            }
+        }
+        "type" => {
+            let program = match parse(INPUT_NAME, argument, console)? {
+                Some(program) => program,
+                _ => return Ok(Flow::Failed),
+            };
+            // only a look: the session's own checker is left as it was
+            let mut checker = session.checker.clone();
+            let ty = checker.check_program(&program);
+            let type_errors = checker.take_errors();
+            if !type_errors.is_empty() {
+                print_type_errors(console, INPUT_NAME, argument, &type_errors)?;
+                return Ok(Flow::Failed);
+            }
+            console.out.write_fmt(format_args!("{}\n", ty))?;
+        }
+        "ast" => {
+            let program = match parse(INPUT_NAME, argument, console)? {
+                Some(program) => program,
+                _ => return Ok(Flow::Failed),
+            };
+            for stmt in program.statements.iter() {
+                console.out.write_fmt(format_args!("{}\n", stmt.string()))?;
+            }
+        }
+        "tokens" => {
+            let mut l = Lexer::new(argument);
+            loop {
+                let tok = l.next_token();
+                console.out.write_fmt(format_args!(
+                    "{}\t{:?}\t{}\n",
+                    tok.span, tok.tk_type, tok.literal
+                ))?;
+                if tok.tk_type == TokenType::EOF {
+                    break;
+                }
+            }
+        }
+        "load" => return session.load(argument, console),
+        "reset" => *session = Session::new(&Rc::clone(&session.loader)),
+        "time" => {
+            let started = Instant::now();
+            let flow = session.eval(INPUT_NAME, argument, console)?;
+            console
+                .out
+                .write_fmt(format_args!("time: {:?}\n", started.elapsed()))?;
+            return Ok(flow);
+        }
+        "quit" => return Ok(Flow::Quit),
+        "help" => console.out.write_fmt(format_args!("{}", HELP))?,
+        _ => {
+            console.err().write_fmt(format_args!(
+                "unknown command: :{}, :help lists the commands\n",
+                name
+            ))?;
+            return Ok(Flow::Failed);
+        }
+    }
+    Ok(Flow::Done)
+}
+
+// The program in `source`, or nothing after printing its parser errors.
+fn parse(name: &str, source: &str, console: &mut Console) -> io::Result<Option<Program>> {
+    let mut l = Lexer::new(source);
+    let mut p = Parser::new(&mut l);
+    let program = p.parse_program().unwrap();
+    if !p.get_errors().is_empty() {
+        print_parser_errors(console, name, source, p.diagnostics())?;
+        return Ok(None);
+    }
+    Ok(Some(program))
+}
+
+// Reads lines until they make whole statements, prompting for the rest with
+// CONTINUATION_PROMPT. Typed in, a blank line or Ctrl-C drops the statements
+// left open. Returns nothing at the end of input, or what was left open
+// before it.
+fn read_input(
+    reader: &mut dyn BufRead,
+    console: &mut Console,
+    interrupt: &InterruptHandle,
+) -> io::Result<Option<String>> {
+    let mut input = String::new();
+    if console.interactive() {
+        interrupt.reset();
+    }
+    loop {
+        if console.interactive() {
+            let prompt = if input.is_empty() {
+                PROMPT
+            } else {
+                CONTINUATION_PROMPT
+            };
+            console.out.write_fmt(format_args!("{}", prompt))?;
+            console.out.flush()?;
+        }
+        let mut line = String::new();
+        let read = reader.read_line(&mut line)?;
+        // Ctrl-C drops the lines typed so far, the terminal drops its own line
+        if console.interactive() && interrupt.is_interrupted() {
+            interrupt.reset();
+            input.clear();
+        }
+        if read == 0 {
+            if console.interactive() {
+                // leave the terminal on a line of its own
+                console.out.write_fmt(format_args!("\n"))?;
+                console.out.flush()?;
+            }
+            return Ok(Some(input).filter(|input| !input.is_empty()));
+        }
+        if console.interactive() && !input.is_empty() && line.trim().is_empty() {
+            return Ok(Some(String::new()));
+        }
+        input.push_str(&line);
+        // commands are a line each, whatever they hold
+        if input.trim_start().starts_with(':') || !is_incomplete(&input) {
+            return Ok(Some(input));
+        }
+    }
+}
+
+// Whether more lines are needed to finish `input`: a string, bracket, brace
+// or parenthesis is left open, or the last token is an operator waiting for
+// its right hand side. Too many closing brackets are left to the parser.
+pub fn is_incomplete(input: &str) -> bool {
+    // strings have no escapes, so quotes always pair up
+    if input.matches('"').count() % 2 == 1 {
+        return true;
+    }
+    let mut l = Lexer::new(input);
+    let mut depth = 0;
+    let mut last = TokenType::EOF;
+    loop {
+        let tok = l.next_token();
+        match tok.tk_type {
+            TokenType::EOF => break,
+            TokenType::LPAREN | TokenType::LBRACE | TokenType::LBRACKET => depth += 1,
+            TokenType::RPAREN | TokenType::RBRACE | TokenType::RBRACKET => depth -= 1,
             _ => {}
         }
+        last = tok.tk_type;
     }
+    depth > 0
+        || matches!(
+            last,
+            TokenType::ASSIGN
+                | TokenType::PLUS
+                | TokenType::MINUS
+                | TokenType::BANG
+                | TokenType::ASTERISK
+                | TokenType::SLASH
+                | TokenType::LT
+                | TokenType::GT
+                | TokenType::EQ
+                | TokenType::NOTEQ
+                | TokenType::COMMA
+                | TokenType::COLON
+                | TokenType::DOT
+                | TokenType::PIPE
+                | TokenType::ARROW
+                | TokenType::THINARROW
+        )
 }
 
-fn print_parser_errors(fmt: &mut BufWriter<&mut dyn Write>, errors: &Vec<Box<String>>) {
-    fmt.write_fmt(format_args!("{}", MONKEY_FACE)).unwrap();
+fn print_parser_errors(
+    console: &mut Console,
+    name: &str,
+    source: &str,
+    diagnostics: &[Diagnostic],
+) -> io::Result<()> {
+    let shown = console.format.show(name, source, diagnostics);
+    let json = console.format.json;
+    let fmt = console.err();
+    if json {
+        return fmt.write_fmt(format_args!("{}", shown));
+    }
+    fmt.write_fmt(format_args!("{}", MONKEY_FACE))?;
     fmt.write_fmt(format_args!(
         "{}",
         "Woops! We ran into some monkey business here!\n"
-    ))
-    .unwrap();
-    fmt.write_fmt(format_args!("{}", " parser errors:\n"))
-        .unwrap();
-    for msg in errors {
-        fmt.write_fmt(format_args!("\t{}\n", msg)).unwrap();
-    }
-    fmt.write_fmt(format_args!("\n")).unwrap();
+    ))?;
+    fmt.write_fmt(format_args!("{}", " parser errors:\n"))?;
+    fmt.write_fmt(format_args!("{}", shown))?;
+    fmt.write_fmt(format_args!("\n"))
+}
+
+fn print_type_errors(
+    console: &mut Console,
+    name: &str,
+    source: &str,
+    errors: &[TypeError],
+) -> io::Result<()> {
+    let diagnostics: Vec<Diagnostic> = errors.iter().map(Diagnostic::from).collect();
+    let shown = console.format.show(name, source, &diagnostics);
+    console.err().write_fmt(format_args!("{}", shown))
 }
@@ -0,0 +1,699 @@
+use super::ast::*;
+use super::builtins;
+use super::diagnostics::did_you_mean;
+use super::lexer::*;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt::*;
+use std::rc::Rc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiagnosticKind {
+    UndefinedName,
+    UnusedBinding,
+    ShadowedBinding,
+    UnreachableCode,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub kind: DiagnosticKind,
+    pub message: String,
+    pub span: Span,
+}
+impl Diagnostic {
+    // Only undefined names are certain to fail at runtime.
+    pub fn is_error(&self) -> bool {
+        self.kind == DiagnosticKind::UndefinedName
+    }
+    pub fn severity(&self) -> &'static str {
+        if self.is_error() {
+            "error"
+        } else {
+            "warning"
+        }
+    }
+}
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "{}: {}", self.span, self.message)
+    }
+}
+
+#[derive(Debug)]
+struct Binding {
+    span: Span,
+    used: bool,
+    what: &'static str, // "variable" or "parameter"
+}
+
+#[derive(Debug, Default)]
+struct Scope {
+    bindings: HashMap<String, Binding>,
+    // `let` names of the scope that are not defined yet, and the ones of
+    // them already referenced from inside a nested function
+    hoisted: HashSet<String>,
+    used_early: HashSet<String>,
+    function_depth: usize,
+}
+
+// Mirrors the evaluator's environments: programs, function bodies, match
+// arms and catch clauses get a scope of their own, plain blocks share the
+// scope they appear in.
+#[derive(Debug, Default)]
+struct Resolver {
+    scopes: Vec<Scope>,
+    function_depth: usize,
+    diagnostics: Vec<Diagnostic>,
+}
+
+// Reports undefined names, unused and shadowed bindings and unreachable
+// statements without evaluating anything. Diagnostics are ordered by span.
+pub fn resolve_program(program: &Program) -> Vec<Diagnostic> {
+    let mut resolver = Resolver::default();
+    resolver.push_scope(&program.statements);
+    resolver.resolve_statements(&program.statements);
+    // top-level bindings may be used by importers or later REPL input
+    resolver.scopes.pop();
+
+    let mut diagnostics = resolver.diagnostics;
+    diagnostics.sort_by_key(|d| (d.span.line, d.span.column));
+    diagnostics
+}
+
+impl Resolver {
+    fn report(&mut self, kind: DiagnosticKind, span: Span, message: String) {
+        self.diagnostics.push(Diagnostic {
+            kind,
+            message,
+            span,
+        });
+    }
+
+    fn push_scope(&mut self, statements: &[Statement]) {
+        let mut hoisted = HashSet::new();
+        for stmt in statements.iter() {
+            match stmt {
+                Statement::Let(let_stmt) => {
+                    hoisted.insert(let_stmt.name.value.clone());
+                }
+                Statement::Export(export_stmt) => {
+                    hoisted.insert(export_stmt.statement.name.value.clone());
+                }
+                _ => {}
+            }
+        }
+        self.scopes.push(Scope {
+            hoisted,
+            function_depth: self.function_depth,
+            ..Scope::default()
+        });
+    }
+
+    fn pop_scope(&mut self) {
+        let scope = self.scopes.pop().unwrap();
+        let mut unused: Vec<(&String, &Binding)> = scope
+            .bindings
+            .iter()
+            .filter(|(name, binding)| !binding.used && !name.starts_with('_'))
+            .collect();
+        unused.sort_by_key(|(_, b)| (b.span.line, b.span.column));
+        for (name, binding) in unused {
+            self.report(
+                DiagnosticKind::UnusedBinding,
+                binding.span,
+                format!("unused {}: {}", binding.what, name),
+            );
+        }
+    }
+
+    fn declare(&mut self, ident: &Identifier, what: &'static str) {
+        let name = &ident.value;
+        let span = ident.token.span;
+        let (current, outer) = self.scopes.split_last().unwrap();
+        if !current.bindings.contains_key(name) && !name.starts_with('_') {
+            let previous = outer.iter().rev().find_map(|s| s.bindings.get(name));
+            if let Some(previous_span) = previous.map(|b| b.span) {
+                let message = format!(
+                    "shadowed binding: {} (previously defined at {})",
+                    name, previous_span
+                );
+                self.report(DiagnosticKind::ShadowedBinding, span, message);
+            }
+        }
+
+        let current = self.scopes.last_mut().unwrap();
+        current.hoisted.remove(name);
+        let used = current.used_early.remove(name);
+        let previous = current
+            .bindings
+            .insert(name.clone(), Binding { span, used, what });
+        if let Some(previous) = previous {
+            if !previous.used && !name.starts_with('_') {
+                let message = format!("unused {}: {}", previous.what, name);
+                self.report(DiagnosticKind::UnusedBinding, previous.span, message);
+            }
+        }
+    }
+
+    fn mark_used(&mut self, name: &str) {
+        if let Some(binding) = self.scopes.last_mut().unwrap().bindings.get_mut(name) {
+            binding.used = true;
+        }
+    }
+
+    fn reference(&mut self, ident: &Identifier) {
+        let function_depth = self.function_depth;
+        for scope in self.scopes.iter_mut().rev() {
+            if let Some(binding) = scope.bindings.get_mut(&ident.value) {
+                binding.used = true;
+                return;
+            }
+            // a function body runs after the enclosing scope has been set up
+            if scope.hoisted.contains(&ident.value) && function_depth > scope.function_depth {
+                scope.used_early.insert(ident.value.clone());
+                return;
+            }
+        }
+        if builtins::lookup(&ident.value).is_some() {
+            return;
+        }
+        let candidates = self
+            .scopes
+            .iter()
+            .flat_map(|scope| scope.bindings.keys().chain(scope.hoisted.iter()))
+            .map(String::as_str)
+            .chain(builtins::NAMES.iter().copied());
+        let suggestion = did_you_mean(&ident.value, candidates);
+        self.report(
+            DiagnosticKind::UndefinedName,
+            ident.token.span,
+            format!("undefined name: {}{}", ident.value, suggestion),
+        );
+    }
+
+    fn resolve_statements(&mut self, statements: &[Statement]) {
+        let mut exit: Option<&str> = None;
+        for stmt in statements.iter() {
+            if let Some(keyword) = exit.take() {
+                self.report(
+                    DiagnosticKind::UnreachableCode,
+                    stmt.span(),
+                    format!("unreachable code after {}", keyword),
+                );
+            }
+            self.resolve_statement(stmt);
+            match stmt {
+                Statement::Return(_) => exit = Some("return"),
+                Statement::Throw(_) => exit = Some("throw"),
+                _ => {}
+            }
+        }
+    }
+
+    fn resolve_statement(&mut self, stmt: &Statement) {
+        match stmt {
+            Statement::Let(let_stmt) => {
+                self.resolve_expression(&let_stmt.value);
+                self.declare(&let_stmt.name, "variable");
+            }
+            Statement::Export(export_stmt) => {
+                let let_stmt = &export_stmt.statement;
+                self.resolve_expression(&let_stmt.value);
+                self.declare(&let_stmt.name, "variable");
+                self.mark_used(&let_stmt.name.value);
+            }
+            Statement::LetPattern(let_stmt) => {
+                self.resolve_expression(&let_stmt.value);
+                self.resolve_pattern(&let_stmt.pattern);
+            }
+            Statement::Import(import_stmt) => self.declare(&import_stmt.alias, "variable"),
+            Statement::Return(return_stmt) => self.resolve_expression(&return_stmt.return_value),
+            Statement::Throw(throw_stmt) => self.resolve_expression(&throw_stmt.value),
+            Statement::Expression(expression_stmt) => {
+                self.resolve_expression(&expression_stmt.expression)
+            }
+            Statement::Block(block) => self.resolve_statements(&block.statements),
+        }
+    }
+
+    fn resolve_expression(&mut self, exp: &Expression) {
+        match exp {
+            Expression::Identifier(ident) => self.reference(ident),
+            Expression::Prefix(prefix_exp) => self.resolve_expression(&prefix_exp.right),
+            Expression::Infix(infix_exp) => {
+                self.resolve_expression(&infix_exp.left);
+                self.resolve_expression(&infix_exp.right);
+            }
+            Expression::If(if_exp) => {
+                self.resolve_expression(&if_exp.condition);
+                self.resolve_statements(&if_exp.consequence.statements);
+                if let Some(alternative) = &if_exp.alternative {
+                    self.resolve_statements(&alternative.statements);
+                }
+            }
+            Expression::Try(try_exp) => {
+                self.resolve_statements(&try_exp.block.statements);
+                if let Some(catch_block) = &try_exp.catch_block {
+                    self.push_scope(&[]);
+                    if let Some(param) = &try_exp.catch_parameter {
+                        self.declare(param, "variable");
+                    }
+                    self.resolve_statements(&catch_block.statements);
+                    self.pop_scope();
+                }
+                if let Some(finally_block) = &try_exp.finally_block {
+                    self.resolve_statements(&finally_block.statements);
+                }
+            }
+            Expression::Function(function_literal) => {
+                self.resolve_function_literal(function_literal)
+            }
+            Expression::Macro(macro_literal) => {
+                self.function_depth += 1;
+                self.push_scope(&macro_literal.body.statements);
+                for param in macro_literal.parameters.iter() {
+                    self.declare(param, "parameter");
+                }
+                self.resolve_statements(&macro_literal.body.statements);
+                self.pop_scope();
+                self.function_depth -= 1;
+            }
+            Expression::Call(call_exp) => {
+                // quoted code is data, only `unquote` arguments are evaluated
+                if call_exp.function.token_literal() == "quote" {
+                    for arg in call_exp.arguments.iter() {
+                        self.resolve_unquoted(arg);
+                    }
+                    return;
+                }
+                if call_exp.function.token_literal() != "unquote" {
+                    self.resolve_expression(&call_exp.function);
+                }
+                for arg in call_exp.arguments.iter() {
+                    self.resolve_expression(arg);
+                }
+                for (_, arg) in call_exp.named_arguments.iter() {
+                    self.resolve_expression(arg);
+                }
+            }
+            Expression::Array(array_literal) => {
+                for e in array_literal.elements.iter() {
+                    self.resolve_expression(e);
+                }
+            }
+            Expression::Hash(hash_literal) => {
+                for (k, v) in hash_literal.pairs.iter() {
+                    self.resolve_expression(k);
+                    self.resolve_expression(v);
+                }
+            }
+            Expression::Index(index_exp) => {
+                self.resolve_expression(&index_exp.left);
+                self.resolve_expression(&index_exp.index);
+            }
+            Expression::Match(match_exp) => {
+                self.resolve_expression(&match_exp.value);
+                for arm in match_exp.arms.iter() {
+                    self.push_scope(&[]);
+                    self.resolve_pattern(&arm.pattern);
+                    if let Some(guard) = &arm.guard {
+                        self.resolve_expression(guard);
+                    }
+                    self.resolve_statements(&arm.body.statements);
+                    self.pop_scope();
+                }
+            }
+            Expression::Integer(_) | Expression::String(_) | Expression::Boolean(_) => {}
+        }
+    }
+
+    fn resolve_unquoted(&mut self, quoted: &Expression) {
+        let mut quoted = quoted.clone();
+        modify_expression(&mut quoted, &mut |exp: &mut Expression| {
+            let call_exp = match exp {
+                Expression::Call(call_exp) if call_exp.function.token_literal() == "unquote" => {
+                    call_exp
+                }
+                _ => return,
+            };
+            for arg in call_exp.arguments.iter() {
+                self.resolve_expression(arg);
+            }
+        });
+    }
+
+    fn resolve_function_literal(&mut self, node: &FunctionLiteral) {
+        self.function_depth += 1;
+        self.push_scope(&[]);
+        for ((param, default), pattern) in node
+            .parameters
+            .iter()
+            .zip(node.defaults.iter())
+            .zip(node.patterns.iter())
+        {
+            // defaults see the parameters before them
+            if let Some(default) = default {
+                self.resolve_expression(default);
+            }
+            match pattern {
+                Some(pattern) => self.resolve_pattern(pattern),
+                _ => self.declare(param, "parameter"),
+            }
+        }
+        if let Some(rest) = &node.rest {
+            self.declare(rest, "parameter");
+        }
+        self.resolve_statements(&node.body.statements);
+        self.pop_scope();
+        self.function_depth -= 1;
+    }
+
+    fn resolve_pattern(&mut self, pattern: &Pattern) {
+        match pattern {
+            Pattern::Binding(binding) => self.declare(&binding.name, "variable"),
+            Pattern::Literal(literal) => self.resolve_expression(&literal.value),
+            Pattern::Array(array_pattern) => {
+                for p in array_pattern.elements.iter() {
+                    self.resolve_pattern(p);
+                }
+                if let Some(rest) = &array_pattern.rest {
+                    self.declare(rest, "variable");
+                }
+            }
+            Pattern::Hash(hash_pattern) => {
+                for (k, p) in hash_pattern.pairs.iter() {
+                    self.resolve_expression(k);
+                    self.resolve_pattern(p);
+                }
+            }
+            Pattern::Wildcard(_) => {}
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct AddressScope {
+    slots: HashMap<String, usize>,
+    // slots of `let`s further down, reachable from nested functions only
+    hoisted: HashMap<String, usize>,
+    size: usize,
+    function_depth: usize,
+}
+
+#[derive(Debug, Default)]
+struct AddressResolver {
+    scopes: Vec<AddressScope>,
+    function_depth: usize,
+}
+
+// Rewrites every local variable reference and binding in `program` to a
+// lexical address. Top-level bindings stay in the environment's name store
+// so they can be shared with importers and later REPL input.
+pub fn resolve_addresses(program: &mut Program) {
+    let mut resolver = AddressResolver::default();
+    resolver.resolve_statements(&mut program.statements);
+}
+
+impl AddressResolver {
+    fn push_scope(&mut self, statements: &[Statement]) {
+        self.scopes.push(AddressScope {
+            function_depth: self.function_depth,
+            ..AddressScope::default()
+        });
+        self.hoist(statements);
+    }
+
+    fn hoist(&mut self, statements: &[Statement]) {
+        let mut names = Vec::new();
+        hoisted_lets(statements, &mut names);
+        let scope = self.scopes.last_mut().unwrap();
+        for name in names {
+            if !scope.slots.contains_key(&name) && !scope.hoisted.contains_key(&name) {
+                scope.hoisted.insert(name, scope.size);
+                scope.size += 1;
+            }
+        }
+    }
+
+    fn declare(&mut self, ident: &mut Identifier) {
+        let scope = match self.scopes.last_mut() {
+            Some(scope) => scope,
+            _ => {
+                ident.address = None;
+                return;
+            }
+        };
+        let slot = match scope.slots.get(&ident.value) {
+            Some(slot) => *slot,
+            _ => {
+                let slot = match scope.hoisted.remove(&ident.value) {
+                    Some(slot) => slot,
+                    _ => {
+                        scope.size += 1;
+                        scope.size - 1
+                    }
+                };
+                scope.slots.insert(ident.value.clone(), slot);
+                slot
+            }
+        };
+        ident.address = Some(LexicalAddress { depth: 0, slot });
+    }
+
+    fn lookup(&mut self, ident: &mut Identifier) {
+        ident.address = None;
+        for (depth, scope) in self.scopes.iter().rev().enumerate() {
+            let slot = match scope.slots.get(&ident.value) {
+                Some(slot) => Some(*slot),
+                // until the `let` runs, code at the same level sees outer bindings
+                _ if self.function_depth > scope.function_depth => {
+                    scope.hoisted.get(&ident.value).cloned()
+                }
+                _ => None,
+            };
+            if let Some(slot) = slot {
+                ident.address = Some(LexicalAddress { depth, slot });
+                return;
+            }
+        }
+    }
+
+    fn resolve_statements(&mut self, statements: &mut [Statement]) {
+        for stmt in statements.iter_mut() {
+            self.resolve_statement(stmt);
+        }
+    }
+
+    fn resolve_statement(&mut self, stmt: &mut Statement) {
+        match stmt {
+            Statement::Let(let_stmt) => self.resolve_let_statement(let_stmt),
+            Statement::Export(export_stmt) => {
+                self.resolve_let_statement(&mut export_stmt.statement)
+            }
+            Statement::LetPattern(let_stmt) => {
+                self.resolve_expression(&mut let_stmt.value);
+                self.resolve_pattern(&mut let_stmt.pattern);
+            }
+            Statement::Import(import_stmt) => self.declare(&mut import_stmt.alias),
+            Statement::Return(return_stmt) => {
+                self.resolve_expression(&mut return_stmt.return_value)
+            }
+            Statement::Throw(throw_stmt) => self.resolve_expression(&mut throw_stmt.value),
+            Statement::Expression(expression_stmt) => {
+                self.resolve_expression(&mut expression_stmt.expression)
+            }
+            Statement::Block(block) => self.resolve_statements(&mut block.statements),
+        }
+    }
+
+    fn resolve_let_statement(&mut self, let_stmt: &mut LetStatement) {
+        self.resolve_expression(&mut let_stmt.value);
+        self.declare(&mut let_stmt.name);
+    }
+
+    fn resolve_expression(&mut self, exp: &mut Expression) {
+        match exp {
+            Expression::Identifier(ident) => self.lookup(ident),
+            Expression::Prefix(prefix_exp) => self.resolve_expression(&mut prefix_exp.right),
+            Expression::Infix(infix_exp) => {
+                self.resolve_expression(&mut infix_exp.left);
+                self.resolve_expression(&mut infix_exp.right);
+            }
+            Expression::If(if_exp) => {
+                self.resolve_expression(&mut if_exp.condition);
+                self.resolve_statements(&mut if_exp.consequence.statements);
+                if let Some(alternative) = &mut if_exp.alternative {
+                    self.resolve_statements(&mut alternative.statements);
+                }
+            }
+            Expression::Try(try_exp) => {
+                self.resolve_statements(&mut try_exp.block.statements);
+                if let Some(catch_block) = &mut try_exp.catch_block {
+                    self.push_scope(&catch_block.statements);
+                    if let Some(param) = &mut try_exp.catch_parameter {
+                        self.declare(param);
+                    }
+                    self.resolve_statements(&mut catch_block.statements);
+                    self.scopes.pop();
+                }
+                if let Some(finally_block) = &mut try_exp.finally_block {
+                    self.resolve_statements(&mut finally_block.statements);
+                }
+            }
+            Expression::Function(function_literal) => {
+                self.resolve_function_literal(Rc::make_mut(function_literal))
+            }
+            Expression::Call(call_exp) => {
+                if call_exp.function.token_literal() == "quote" {
+                    for arg in call_exp.arguments.iter_mut() {
+                        self.resolve_unquoted(arg);
+                    }
+                    return;
+                }
+                self.resolve_expression(&mut call_exp.function);
+                for arg in call_exp.arguments.iter_mut() {
+                    self.resolve_expression(arg);
+                }
+                for (_, arg) in call_exp.named_arguments.iter_mut() {
+                    self.resolve_expression(arg);
+                }
+            }
+            Expression::Array(array_literal) => {
+                for e in array_literal.elements.iter_mut() {
+                    self.resolve_expression(e);
+                }
+            }
+            Expression::Hash(hash_literal) => {
+                for (k, v) in hash_literal.pairs.iter_mut() {
+                    self.resolve_expression(k);
+                    self.resolve_expression(v);
+                }
+            }
+            Expression::Index(index_exp) => {
+                self.resolve_expression(&mut index_exp.left);
+                self.resolve_expression(&mut index_exp.index);
+            }
+            Expression::Match(match_exp) => {
+                self.resolve_expression(&mut match_exp.value);
+                for arm in match_exp.arms.iter_mut() {
+                    // pattern literals are constants, so only the bindings use the arm scope
+                    self.push_scope(&arm.body.statements);
+                    self.resolve_pattern(&mut arm.pattern);
+                    if let Some(guard) = &mut arm.guard {
+                        self.resolve_expression(guard);
+                    }
+                    self.resolve_statements(&mut arm.body.statements);
+                    self.scopes.pop();
+                }
+            }
+            // macro bodies are evaluated during expansion and keep looking names up
+            Expression::Macro(_)
+            | Expression::Integer(_)
+            | Expression::String(_)
+            | Expression::Boolean(_) => {}
+        }
+    }
+
+    fn resolve_unquoted(&mut self, quoted: &mut Expression) {
+        modify_expression(quoted, &mut |exp: &mut Expression| {
+            let call_exp = match exp {
+                Expression::Call(call_exp) if call_exp.function.token_literal() == "unquote" => {
+                    call_exp
+                }
+                _ => return,
+            };
+            for arg in call_exp.arguments.iter_mut() {
+                self.resolve_expression(arg);
+            }
+        });
+    }
+
+    fn resolve_function_literal(&mut self, node: &mut FunctionLiteral) {
+        self.function_depth += 1;
+        self.push_scope(&[]);
+        for ((param, default), pattern) in node
+            .parameters
+            .iter_mut()
+            .zip(node.defaults.iter_mut())
+            .zip(node.patterns.iter_mut())
+        {
+            // defaults are evaluated in the call environment after the
+            // parameters before them have been bound
+            if let Some(default) = default {
+                self.resolve_expression(default);
+            }
+            match pattern {
+                Some(pattern) => self.resolve_pattern(pattern),
+                _ => self.declare(param),
+            }
+        }
+        if let Some(rest) = &mut node.rest {
+            self.declare(rest);
+        }
+        // parameters take the first slots
+        self.hoist(&node.body.statements);
+        self.resolve_statements(&mut node.body.statements);
+        self.scopes.pop();
+        self.function_depth -= 1;
+    }
+
+    fn resolve_pattern(&mut self, pattern: &mut Pattern) {
+        match pattern {
+            Pattern::Binding(binding) => self.declare(&mut binding.name),
+            Pattern::Array(array_pattern) => {
+                for p in array_pattern.elements.iter_mut() {
+                    self.resolve_pattern(p);
+                }
+                if let Some(rest) = &mut array_pattern.rest {
+                    self.declare(rest);
+                }
+            }
+            Pattern::Hash(hash_pattern) => {
+                for (_, p) in hash_pattern.pairs.iter_mut() {
+                    self.resolve_pattern(p);
+                }
+            }
+            Pattern::Literal(_) | Pattern::Wildcard(_) => {}
+        }
+    }
+}
+
+// Collects the `let` names a scope binds. Blocks of `if` and `try` run in
+// the environment they appear in, so their `let`s belong to the scope too.
+fn hoisted_lets(statements: &[Statement], names: &mut Vec<String>) {
+    for stmt in statements.iter() {
+        let exp = match stmt {
+            Statement::Let(let_stmt) => {
+                names.push(let_stmt.name.value.clone());
+                &let_stmt.value
+            }
+            Statement::Export(export_stmt) => {
+                names.push(export_stmt.statement.name.value.clone());
+                &export_stmt.statement.value
+            }
+            Statement::Expression(expression_stmt) => &expression_stmt.expression,
+            Statement::Return(return_stmt) => &return_stmt.return_value,
+            Statement::Block(block) => {
+                hoisted_lets(&block.statements, names);
+                continue;
+            }
+            _ => continue,
+        };
+        match exp {
+            Expression::If(if_exp) => {
+                hoisted_lets(&if_exp.consequence.statements, names);
+                if let Some(alternative) = &if_exp.alternative {
+                    hoisted_lets(&alternative.statements, names);
+                }
+            }
+            Expression::Try(try_exp) => {
+                hoisted_lets(&try_exp.block.statements, names);
+                if let Some(finally_block) = &try_exp.finally_block {
+                    hoisted_lets(&finally_block.statements, names);
+                }
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,401 @@
+use super::code::*;
+use super::compiler::*;
+use super::lexer::Span;
+use super::object::*;
+use std::rc::*;
+
+// Layout of a `.mkc` file, all numbers big-endian:
+//
+//   magic "\x7fMKC", version u16, CRC-32 of the payload u32, payload length u32
+//   payload: global names, then the main function
+//
+// A function is its display string, parameters, defaults, rest flag, local,
+// upvalue and free variable names, instructions, constants, nested functions
+// and the span table. Strings and lists are prefixed with a u32 length.
+pub const MAGIC: &[u8; 4] = b"\x7fMKC";
+pub const VERSION: u16 = 1;
+const HEADER_LEN: usize = 14;
+// keeps a crafted file from overflowing the stack of the reader
+const MAX_NESTING: usize = 256;
+
+const INTEGER_TAG: u8 = 0;
+const STRING_TAG: u8 = 1;
+const UPVALUE_TAG: u8 = 0;
+const FREE_TAG: u8 = 1;
+
+pub fn serialize(bytecode: &Bytecode) -> Result<Vec<u8>, String> {
+    let mut payload = Writer::default();
+    payload.strings(&bytecode.globals);
+    payload.function(&bytecode.main)?;
+    let payload = payload.bytes;
+    if payload.len() > u32::MAX as usize {
+        return Err(String::from("program too large"));
+    }
+
+    let mut out = Vec::with_capacity(HEADER_LEN + payload.len());
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&VERSION.to_be_bytes());
+    out.extend_from_slice(&crc32(&payload).to_be_bytes());
+    out.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    out.extend_from_slice(&payload);
+    Ok(out)
+}
+
+// Reads a file written by `serialize`. Anything that does not check out,
+// from the header to operands pointing past the end of a table, is an error.
+pub fn deserialize(data: &[u8]) -> Result<Bytecode, String> {
+    if data.len() < HEADER_LEN || &data[0..4] != MAGIC {
+        return Err(String::from("not a compiled program (bad magic number)"));
+    }
+    let version = u16::from_be_bytes([data[4], data[5]]);
+    if version != VERSION {
+        return Err(format!(
+            "unsupported bytecode version {} (expected {})",
+            version, VERSION
+        ));
+    }
+    let checksum = u32::from_be_bytes([data[6], data[7], data[8], data[9]]);
+    let len = u32::from_be_bytes([data[10], data[11], data[12], data[13]]) as usize;
+    let payload = &data[HEADER_LEN..];
+    if payload.len() != len {
+        return Err(format!(
+            "wrong payload length: header says {}, file has {}",
+            len,
+            payload.len()
+        ));
+    }
+    if crc32(payload) != checksum {
+        return Err(String::from("checksum mismatch"));
+    }
+
+    let mut reader = Reader {
+        data: payload,
+        pos: 0,
+        depth: 0,
+    };
+    let globals = reader.strings()?;
+    let main = reader.function()?;
+    if reader.pos != payload.len() {
+        return Err(String::from("trailing data after program"));
+    }
+    if !main.free.is_empty() {
+        return Err(String::from("main function has free variables"));
+    }
+    validate(&main, None, globals.len())?;
+    Ok(Bytecode {
+        main: Rc::new(main),
+        globals,
+    })
+}
+
+#[derive(Default)]
+struct Writer {
+    bytes: Vec<u8>,
+}
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+    fn u32(&mut self, value: usize) {
+        self.bytes.extend_from_slice(&(value as u32).to_be_bytes());
+    }
+    fn string(&mut self, value: &str) {
+        self.u32(value.len());
+        self.bytes.extend_from_slice(value.as_bytes());
+    }
+    fn strings(&mut self, values: &[String]) {
+        self.u32(values.len());
+        for value in values.iter() {
+            self.string(value);
+        }
+    }
+
+    fn function(&mut self, function: &CompiledFunction) -> Result<(), String> {
+        self.string(&function.display);
+        self.strings(&function.parameters);
+        self.u32(function.defaults.len());
+        for default in function.defaults.iter() {
+            self.u8(*default as u8);
+        }
+        self.u8(function.rest as u8);
+        self.strings(&function.local_names);
+        self.strings(&function.upvalue_names);
+        self.u32(function.free.len());
+        for (name, capture) in function.free.iter() {
+            self.string(name);
+            match capture {
+                Capture::Upvalue(idx) => {
+                    self.u8(UPVALUE_TAG);
+                    self.u32(*idx);
+                }
+                Capture::Free(idx) => {
+                    self.u8(FREE_TAG);
+                    self.u32(*idx);
+                }
+            }
+        }
+
+        self.u32(function.instructions.len());
+        self.bytes.extend_from_slice(&function.instructions);
+        self.u32(function.constants.len());
+        for constant in function.constants.iter() {
+            match constant {
+                Value::Integer(value) => {
+                    self.u8(INTEGER_TAG);
+                    self.bytes.extend_from_slice(&value.to_be_bytes());
+                }
+                Value::Str(value) => {
+                    self.u8(STRING_TAG);
+                    self.string(value);
+                }
+                _ => {
+                    return Err(format!(
+                        "cannot serialize constant of type {}",
+                        constant.get_type()
+                    ))
+                }
+            }
+        }
+        self.u32(function.functions.len());
+        for nested in function.functions.iter() {
+            self.function(nested)?;
+        }
+        self.u32(function.spans.len());
+        for (offset, span) in function.spans.iter() {
+            self.u32(*offset);
+            self.u32(span.line);
+            self.u32(span.column);
+        }
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    depth: usize, // of the function being read
+}
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], String> {
+        if self.data.len() - self.pos < len {
+            return Err(String::from("unexpected end of file"));
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+    fn bool(&mut self) -> Result<bool, String> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            byte => Err(format!("invalid flag {}", byte)),
+        }
+    }
+    fn u32(&mut self) -> Result<usize, String> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    }
+    fn i64(&mut self) -> Result<i64, String> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(i64::from_be_bytes(bytes))
+    }
+    fn string(&mut self) -> Result<String, String> {
+        let len = self.u32()?;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| String::from("invalid UTF-8"))
+    }
+    fn strings(&mut self) -> Result<Vec<String>, String> {
+        let count = self.u32()?;
+        (0..count).map(|_| self.string()).collect()
+    }
+
+    fn function(&mut self) -> Result<CompiledFunction, String> {
+        let display = self.string()?;
+        let parameters = self.strings()?;
+        let count = self.u32()?;
+        let defaults = (0..count)
+            .map(|_| self.bool())
+            .collect::<Result<Vec<bool>, String>>()?;
+        let rest = self.bool()?;
+        let local_names = self.strings()?;
+        let upvalue_names = self.strings()?;
+        let count = self.u32()?;
+        let mut free = Vec::new();
+        for _ in 0..count {
+            let name = self.string()?;
+            let capture = match self.u8()? {
+                UPVALUE_TAG => Capture::Upvalue(self.u32()?),
+                FREE_TAG => Capture::Free(self.u32()?),
+                tag => return Err(format!("invalid capture tag {}", tag)),
+            };
+            free.push((name, capture));
+        }
+
+        let len = self.u32()?;
+        let instructions = self.take(len)?.to_vec();
+        let count = self.u32()?;
+        let mut constants = Vec::new();
+        for _ in 0..count {
+            let constant = match self.u8()? {
+                INTEGER_TAG => Value::Integer(self.i64()?),
+                STRING_TAG => Value::Str(Rc::from(self.string()?.as_str())),
+                tag => return Err(format!("invalid constant tag {}", tag)),
+            };
+            constants.push(constant);
+        }
+        let count = self.u32()?;
+        if count > 0 && self.depth == MAX_NESTING {
+            return Err(String::from("functions nested too deeply"));
+        }
+        self.depth += 1;
+        let functions = (0..count)
+            .map(|_| self.function().map(Rc::new))
+            .collect::<Result<Vec<_>, String>>()?;
+        self.depth -= 1;
+        let count = self.u32()?;
+        let mut spans = Vec::new();
+        for _ in 0..count {
+            let offset = self.u32()?;
+            let line = self.u32()?;
+            let column = self.u32()?;
+            spans.push((offset, Span { line, column }));
+        }
+
+        Ok(CompiledFunction {
+            instructions,
+            constants,
+            functions,
+            parameters,
+            defaults,
+            rest,
+            local_names,
+            upvalue_names,
+            free,
+            spans,
+            display,
+        })
+    }
+}
+
+// Checks that every table index and jump target in `function` and the
+// functions nested in it is in range, so running it cannot read out of
+// bounds. `parent` is the function the closure is created in.
+fn validate(
+    function: &CompiledFunction,
+    parent: Option<&CompiledFunction>,
+    global_count: usize,
+) -> Result<(), String> {
+    let name = &function.display;
+    if function.defaults.len() != function.parameters.len() {
+        return Err(format!("{}: defaults do not match parameters", name));
+    }
+    if function.local_names.len() < function.parameters.len() + function.rest as usize {
+        return Err(format!("{}: too few local slots for parameters", name));
+    }
+    if let Some(parent) = parent {
+        for (free_name, capture) in function.free.iter() {
+            let in_range = match capture {
+                Capture::Upvalue(idx) => *idx < parent.upvalue_names.len(),
+                Capture::Free(idx) => *idx < parent.free.len(),
+            };
+            if !in_range {
+                return Err(format!("{}: invalid capture of {}", name, free_name));
+            }
+        }
+    }
+    if function.spans.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+        return Err(format!("{}: span table out of order", name));
+    }
+
+    let ins = &function.instructions;
+    let mut starts = vec![false; ins.len() + 1];
+    let mut targets = Vec::new();
+    let mut ip = 0;
+    let mut last = None;
+    while ip < ins.len() {
+        starts[ip] = true;
+        let op = Opcode::from_byte(ins[ip])
+            .ok_or_else(|| format!("{}: unknown opcode {} at {}", name, ins[ip], ip))?;
+        let width: usize = op.operand_widths().iter().sum();
+        if ins.len() - ip - 1 < width {
+            return Err(format!("{}: truncated {:?} at {}", name, op, ip));
+        }
+        let (operands, read) = read_operands(op, &ins[ip + 1..]);
+        let limit = |table: &str, len: usize, idx: usize| -> Result<(), String> {
+            if idx >= len {
+                return Err(format!(
+                    "{}: {:?} at {} refers to {} {} of {}",
+                    name, op, ip, table, idx, len
+                ));
+            }
+            Ok(())
+        };
+        let constants = function.constants.len();
+        match op {
+            Opcode::Constant | Opcode::PatternMismatch | Opcode::Import | Opcode::Quote => {
+                limit("constant", constants, operands[0])?
+            }
+            Opcode::Call => limit("constant", constants, operands[2])?,
+            Opcode::GetGlobal | Opcode::SetGlobal => limit("global", global_count, operands[0])?,
+            Opcode::GetLocal | Opcode::SetLocal => {
+                limit("local", function.local_names.len(), operands[0])?
+            }
+            Opcode::JumpIfBound => {
+                limit("local", function.local_names.len(), operands[0])?;
+                targets.push((ip, operands[1]));
+            }
+            Opcode::GetUpvalue | Opcode::SetUpvalue => {
+                limit("upvalue", function.upvalue_names.len(), operands[0])?
+            }
+            Opcode::GetFree => limit("free variable", function.free.len(), operands[0])?,
+            Opcode::Closure => limit("function", function.functions.len(), operands[0])?,
+            Opcode::Jump
+            | Opcode::JumpNotTruthy
+            | Opcode::SetupCatch
+            | Opcode::SetupFinally
+            | Opcode::MatchHash
+            | Opcode::MatchKey
+            | Opcode::MatchEqual => targets.push((ip, operands[0])),
+            Opcode::MatchArray => targets.push((ip, operands[2])),
+            _ => {}
+        }
+        last = Some(op);
+        ip += 1 + read;
+    }
+    for (at, target) in targets {
+        if target >= ins.len() || !starts[target] {
+            return Err(format!(
+                "{}: jump at {} to invalid target {}",
+                name, at, target
+            ));
+        }
+    }
+    match last {
+        Some(Opcode::Return)
+        | Some(Opcode::ReturnValue)
+        | Some(Opcode::Jump)
+        | Some(Opcode::Throw) => {}
+        _ => return Err(format!("{}: code can run past its end", name)),
+    }
+
+    for nested in function.functions.iter() {
+        validate(nested, Some(function), global_count)?;
+    }
+    Ok(())
+}
+
+// CRC-32 (IEEE), bit by bit; files are small enough not to need a table.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data.iter() {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
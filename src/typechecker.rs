@@ -0,0 +1,764 @@
+use super::ast::*;
+use super::lexer::*;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt::*;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type {
+    Int,
+    Bool,
+    Str,
+    Null,
+    Any, // unknown until runtime, compatible with every type
+    Var(usize),
+    Array(Box<Type>),
+    Hash(Box<Type>, Box<Type>),
+    Function(Vec<Type>, Box<Type>),
+}
+impl Display for Type {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            Type::Int => write!(f, "int"),
+            Type::Bool => write!(f, "bool"),
+            Type::Str => write!(f, "string"),
+            Type::Null => write!(f, "null"),
+            Type::Any => write!(f, "any"),
+            Type::Var(id) => write!(f, "t{}", id),
+            Type::Array(element) => write!(f, "[{}]", element),
+            Type::Hash(key, value) => write!(f, "{{{}: {}}}", key, value),
+            Type::Function(params, ret) => {
+                let params: Vec<String> = params.iter().map(|p| p.to_string()).collect();
+                write!(f, "fn({}) -> {}", params.join(", "), ret)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TypeError {
+    pub message: String,
+    pub span: Span,
+}
+impl Display for TypeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "{}: {}", self.span, self.message)
+    }
+}
+
+// A type with its quantified variables, e.g. `fn(t0) -> t0` for identity.
+#[derive(Debug, Clone)]
+struct Scheme {
+    vars: Vec<usize>,
+    ty: Type,
+}
+
+#[derive(Debug, Clone)]
+struct FunctionContext {
+    declared: Option<Type>,
+    returns: Vec<Type>,
+}
+
+// Hindley-Milner style inference with `any` as the escape hatch for code
+// whose type is only known at runtime. The checker keeps its bindings
+// between calls to `check_program`, so it can follow a REPL session.
+#[derive(Debug, Clone)]
+pub struct TypeChecker {
+    substitution: Vec<Option<Type>>,
+    scopes: Vec<HashMap<String, Scheme>>,
+    functions: Vec<FunctionContext>,
+    errors: Vec<TypeError>,
+}
+
+impl Default for TypeChecker {
+    fn default() -> Self {
+        TypeChecker::new()
+    }
+}
+
+impl TypeChecker {
+    pub fn new() -> TypeChecker {
+        let mut builtins = HashMap::new();
+        builtins.insert(
+            String::from("len"),
+            Scheme {
+                vars: Vec::new(),
+                ty: Type::Function(vec![Type::Any], Box::new(Type::Int)),
+            },
+        );
+        TypeChecker {
+            substitution: Vec::new(),
+            scopes: vec![builtins, HashMap::new()],
+            functions: Vec::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    // Returns the type of the last statement.
+    pub fn check_program(&mut self, program: &Program) -> Type {
+        let mut result = Type::Null;
+        for stmt in program.statements.iter() {
+            result = self.check_statement(stmt);
+        }
+        self.apply(&result)
+    }
+
+    pub fn errors(&self) -> &[TypeError] {
+        &self.errors
+    }
+
+    pub fn take_errors(&mut self) -> Vec<TypeError> {
+        std::mem::take(&mut self.errors)
+    }
+
+    fn error(&mut self, span: Span, message: String) {
+        self.errors.push(TypeError { message, span });
+    }
+
+    fn fresh(&mut self) -> Type {
+        self.substitution.push(None);
+        Type::Var(self.substitution.len() - 1)
+    }
+
+    fn resolve(&self, ty: &Type) -> Type {
+        let mut ty = ty.clone();
+        while let Type::Var(id) = ty {
+            match &self.substitution[id] {
+                Some(bound) => ty = bound.clone(),
+                _ => break,
+            }
+        }
+        ty
+    }
+
+    fn apply(&self, ty: &Type) -> Type {
+        match self.resolve(ty) {
+            Type::Array(element) => Type::Array(Box::new(self.apply(&element))),
+            Type::Hash(key, value) => {
+                Type::Hash(Box::new(self.apply(&key)), Box::new(self.apply(&value)))
+            }
+            Type::Function(params, ret) => Type::Function(
+                params.iter().map(|p| self.apply(p)).collect(),
+                Box::new(self.apply(&ret)),
+            ),
+            ty => ty,
+        }
+    }
+
+    fn occurs(&self, id: usize, ty: &Type) -> bool {
+        match self.resolve(ty) {
+            Type::Var(other) => other == id,
+            Type::Array(element) => self.occurs(id, &element),
+            Type::Hash(key, value) => self.occurs(id, &key) || self.occurs(id, &value),
+            Type::Function(params, ret) => {
+                params.iter().any(|p| self.occurs(id, p)) || self.occurs(id, &ret)
+            }
+            _ => false,
+        }
+    }
+
+    fn unify(&mut self, a: &Type, b: &Type) -> bool {
+        let a = self.resolve(a);
+        let b = self.resolve(b);
+        match (&a, &b) {
+            (Type::Var(x), Type::Var(y)) if x == y => true,
+            (Type::Var(id), other) | (other, Type::Var(id)) => {
+                if self.occurs(*id, other) {
+                    return false;
+                }
+                self.substitution[*id] = Some(other.clone());
+                true
+            }
+            (Type::Any, _) | (_, Type::Any) => true,
+            (Type::Int, Type::Int)
+            | (Type::Bool, Type::Bool)
+            | (Type::Str, Type::Str)
+            | (Type::Null, Type::Null) => true,
+            (Type::Array(x), Type::Array(y)) => self.unify(x, y),
+            (Type::Hash(k1, v1), Type::Hash(k2, v2)) => self.unify(k1, k2) && self.unify(v1, v2),
+            (Type::Function(p1, r1), Type::Function(p2, r2)) => {
+                // arity is checked at runtime, defaults and rest parameters make it flexible
+                let params_ok = p1.iter().zip(p2.iter()).all(|(x, y)| self.unify(x, y));
+                params_ok && self.unify(r1, r2)
+            }
+            _ => false,
+        }
+    }
+
+    // Unifies `expected` with `actual`, reporting a mismatch at `span`.
+    fn expect(&mut self, expected: &Type, actual: &Type, span: Span, context: &str) {
+        let snapshot = self.substitution.clone();
+        if !self.unify(expected, actual) {
+            self.substitution = snapshot;
+            let message = format!(
+                "{}: expected {}, got {}",
+                context,
+                self.shown(expected),
+                self.shown(actual)
+            );
+            self.error(span, message);
+        }
+    }
+
+    // The common type of two values, or `any` when they disagree. Branches
+    // say nothing about each other, so no variable is bound to make them
+    // agree: `if (c) { x } else { "a" }` must not make `x` a string.
+    fn join(&mut self, a: &Type, b: &Type) -> Type {
+        let snapshot = self.substitution.clone();
+        if self.unify(a, b) && self.substitution == snapshot {
+            a.clone()
+        } else {
+            self.substitution = snapshot;
+            Type::Any
+        }
+    }
+
+    // Joins the types of values that may each be the result, such as the
+    // elements of an array or the arms of a match.
+    fn join_all(&mut self, types: Vec<Type>) -> Option<Type> {
+        let mut types = types.into_iter();
+        let first = types.next()?;
+        Some(types.fold(first, |result, ty| self.join(&result, &ty)))
+    }
+
+    // Whether `ty` still has variables that later code may bind.
+    fn is_unresolved(&self, ty: &Type) -> bool {
+        let mut vars = HashSet::new();
+        self.free_vars(ty, &mut vars);
+        !vars.is_empty()
+    }
+
+    // `ty` as messages show it: what is not known yet is `any`.
+    fn shown(&self, ty: &Type) -> Type {
+        match self.resolve(ty) {
+            Type::Var(_) => Type::Any,
+            Type::Array(element) => Type::Array(Box::new(self.shown(&element))),
+            Type::Hash(key, value) => {
+                Type::Hash(Box::new(self.shown(&key)), Box::new(self.shown(&value)))
+            }
+            Type::Function(params, ret) => Type::Function(
+                params.iter().map(|p| self.shown(p)).collect(),
+                Box::new(self.shown(&ret)),
+            ),
+            ty => ty,
+        }
+    }
+
+    fn lookup(&mut self, name: &str) -> Option<Type> {
+        let scheme = self
+            .scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name))
+            .cloned()?;
+        Some(self.instantiate(&scheme))
+    }
+
+    fn define(&mut self, name: &str, ty: Type) {
+        self.define_scheme(
+            name,
+            Scheme {
+                vars: Vec::new(),
+                ty,
+            },
+        );
+    }
+
+    fn define_scheme(&mut self, name: &str, scheme: Scheme) {
+        self.scopes
+            .last_mut()
+            .unwrap()
+            .insert(String::from(name), scheme);
+    }
+
+    fn instantiate(&mut self, scheme: &Scheme) -> Type {
+        let mut mapping = HashMap::new();
+        for var in scheme.vars.iter() {
+            mapping.insert(*var, self.fresh());
+        }
+        self.replace_vars(&scheme.ty, &mapping)
+    }
+
+    fn replace_vars(&self, ty: &Type, mapping: &HashMap<usize, Type>) -> Type {
+        match self.resolve(ty) {
+            Type::Var(id) => mapping.get(&id).cloned().unwrap_or(Type::Var(id)),
+            Type::Array(element) => Type::Array(Box::new(self.replace_vars(&element, mapping))),
+            Type::Hash(key, value) => Type::Hash(
+                Box::new(self.replace_vars(&key, mapping)),
+                Box::new(self.replace_vars(&value, mapping)),
+            ),
+            Type::Function(params, ret) => Type::Function(
+                params
+                    .iter()
+                    .map(|p| self.replace_vars(p, mapping))
+                    .collect(),
+                Box::new(self.replace_vars(&ret, mapping)),
+            ),
+            ty => ty,
+        }
+    }
+
+    fn free_vars(&self, ty: &Type, vars: &mut HashSet<usize>) {
+        match self.resolve(ty) {
+            Type::Var(id) => {
+                vars.insert(id);
+            }
+            Type::Array(element) => self.free_vars(&element, vars),
+            Type::Hash(key, value) => {
+                self.free_vars(&key, vars);
+                self.free_vars(&value, vars);
+            }
+            Type::Function(params, ret) => {
+                for p in params.iter() {
+                    self.free_vars(p, vars);
+                }
+                self.free_vars(&ret, vars);
+            }
+            _ => {}
+        }
+    }
+
+    fn generalize(&self, ty: &Type) -> Scheme {
+        let mut env_vars = HashSet::new();
+        for scope in self.scopes.iter() {
+            for scheme in scope.values() {
+                let mut vars = HashSet::new();
+                self.free_vars(&scheme.ty, &mut vars);
+                for var in scheme.vars.iter() {
+                    vars.remove(var);
+                }
+                env_vars.extend(vars);
+            }
+        }
+        let mut vars = HashSet::new();
+        self.free_vars(ty, &mut vars);
+        let mut vars: Vec<usize> = vars.difference(&env_vars).cloned().collect();
+        vars.sort_unstable();
+        Scheme {
+            vars,
+            ty: self.apply(ty),
+        }
+    }
+
+    fn annotation_type(&mut self, annotation: &TypeAnnotation) -> Type {
+        match annotation {
+            TypeAnnotation::Named(name) => match name.as_str() {
+                "int" => Type::Int,
+                "bool" => Type::Bool,
+                "string" => Type::Str,
+                "null" => Type::Null,
+                _ => Type::Any,
+            },
+            TypeAnnotation::Array(element) => Type::Array(Box::new(self.annotation_type(element))),
+            TypeAnnotation::Hash(key, value) => Type::Hash(
+                Box::new(self.annotation_type(key)),
+                Box::new(self.annotation_type(value)),
+            ),
+            TypeAnnotation::Function(params, ret) => Type::Function(
+                params.iter().map(|p| self.annotation_type(p)).collect(),
+                Box::new(self.annotation_type(ret)),
+            ),
+        }
+    }
+
+    fn check_statement(&mut self, stmt: &Statement) -> Type {
+        match stmt {
+            Statement::Let(let_stmt) => {
+                self.check_let_statement(let_stmt);
+                Type::Null
+            }
+            Statement::Export(export_stmt) => {
+                self.check_let_statement(&export_stmt.statement);
+                Type::Null
+            }
+            Statement::LetPattern(let_stmt) => {
+                let ty = self.check_expression(&let_stmt.value);
+                self.bind_pattern(&let_stmt.pattern, &ty);
+                Type::Null
+            }
+            Statement::Import(import_stmt) => {
+                self.define(&import_stmt.alias.value, Type::Any);
+                Type::Null
+            }
+            Statement::Return(return_stmt) => {
+                let ty = self.check_expression(&return_stmt.return_value);
+                if let Some(context) = self.functions.last() {
+                    if let Some(declared) = context.declared.clone() {
+                        self.expect(
+                            &declared,
+                            &ty,
+                            return_stmt.token.span,
+                            "return type mismatch",
+                        );
+                    }
+                    self.functions.last_mut().unwrap().returns.push(ty.clone());
+                }
+                ty
+            }
+            Statement::Throw(throw_stmt) => {
+                self.check_expression(&throw_stmt.value);
+                Type::Any
+            }
+            Statement::Expression(expression_stmt) => {
+                self.check_expression(&expression_stmt.expression)
+            }
+            Statement::Block(block) => self.check_block_statement(block),
+        }
+    }
+
+    fn check_let_statement(&mut self, let_stmt: &LetStatement) {
+        let name = &let_stmt.name.value;
+        let is_function = matches!(let_stmt.value, Expression::Function(_));
+
+        // functions may refer to themselves
+        let own = self.fresh();
+        if is_function {
+            self.define(name, own.clone());
+        }
+        let mut ty = self.check_expression(&let_stmt.value);
+        if is_function {
+            self.unify(&own, &ty);
+        }
+
+        if let Some(annotation) = &let_stmt.type_annotation {
+            let declared = self.annotation_type(annotation);
+            let context = format!("type mismatch in let {}", name);
+            self.expect(&declared, &ty, let_stmt.token.span, &context);
+            ty = declared;
+        }
+
+        if is_function {
+            // the monomorphic binding used for recursion must not pin the variables
+            self.scopes.last_mut().unwrap().remove(name);
+        }
+        let scheme = self.generalize(&ty);
+        self.define_scheme(name, scheme);
+    }
+
+    fn check_block_statement(&mut self, block: &BlockStatement) -> Type {
+        let mut result = Type::Null;
+        for stmt in block.statements.iter() {
+            result = self.check_statement(stmt);
+        }
+        result
+    }
+
+    fn check_expression(&mut self, exp: &Expression) -> Type {
+        match exp {
+            Expression::Integer(_) => Type::Int,
+            Expression::Boolean(_) => Type::Bool,
+            Expression::String(_) => Type::Str,
+            Expression::Identifier(ident) => self.lookup(&ident.value).unwrap_or(Type::Any),
+            Expression::Prefix(prefix_exp) => self.check_prefix_expression(prefix_exp),
+            Expression::Infix(infix_exp) => self.check_infix_expression(infix_exp),
+            Expression::If(if_exp) => {
+                self.check_expression(&if_exp.condition);
+                let consequence = self.check_block_statement(&if_exp.consequence);
+                match &if_exp.alternative {
+                    Some(alternative) => {
+                        let alternative = self.check_block_statement(alternative);
+                        self.join(&consequence, &alternative)
+                    }
+                    _ => Type::Any,
+                }
+            }
+            Expression::Try(try_exp) => self.check_try_expression(try_exp),
+            Expression::Function(function_literal) => self.check_function_literal(function_literal),
+            Expression::Call(call_exp) => self.check_call_expression(call_exp),
+            Expression::Array(array_literal) => {
+                let elements = array_literal
+                    .elements
+                    .iter()
+                    .map(|e| self.check_expression(e))
+                    .collect();
+                let element = match self.join_all(elements) {
+                    Some(element) => element,
+                    _ => self.fresh(),
+                };
+                Type::Array(Box::new(element))
+            }
+            Expression::Hash(hash_literal) => {
+                let mut keys = Vec::new();
+                let mut values = Vec::new();
+                for (k, v) in hash_literal.pairs.iter() {
+                    keys.push(self.check_expression(k));
+                    values.push(self.check_expression(v));
+                }
+                let key = match self.join_all(keys) {
+                    Some(key) => key,
+                    _ => self.fresh(),
+                };
+                let value = match self.join_all(values) {
+                    Some(value) => value,
+                    _ => self.fresh(),
+                };
+                Type::Hash(Box::new(key), Box::new(value))
+            }
+            Expression::Index(index_exp) => self.check_index_expression(index_exp),
+            Expression::Match(match_exp) => {
+                let value = self.check_expression(&match_exp.value);
+                let mut bodies = Vec::new();
+                for arm in match_exp.arms.iter() {
+                    self.scopes.push(HashMap::new());
+                    self.bind_pattern(&arm.pattern, &value);
+                    if let Some(guard) = &arm.guard {
+                        self.check_expression(guard);
+                    }
+                    let body = self.check_block_statement(&arm.body);
+                    self.scopes.pop();
+                    bodies.push(body);
+                }
+                self.join_all(bodies).unwrap_or(Type::Any)
+            }
+            Expression::Macro(_) => Type::Any,
+        }
+    }
+
+    fn check_prefix_expression(&mut self, node: &PrefixExpression) -> Type {
+        let right = self.check_expression(&node.right);
+        match node.operator.as_str() {
+            "!" => Type::Bool,
+            "-" => {
+                // an operand not known yet stays that way, see below
+                if !self.is_unresolved(&right) && !self.unify(&Type::Int, &right) {
+                    let message = format!("unknown operator: -{}", self.shown(&right));
+                    self.error(node.token.span, message);
+                }
+                Type::Int
+            }
+            _ => Type::Any,
+        }
+    }
+
+    fn check_infix_expression(&mut self, node: &InfixExpression) -> Type {
+        let left = self.check_expression(&node.left);
+        let right = self.check_expression(&node.right);
+        let operator = node.operator.as_str();
+        // operands not known yet may fail at runtime, where a `try` can
+        // handle it, so only known types give errors here. Nor does an
+        // operator decide their types: `x > 10` in one match arm says
+        // nothing about the values the other arms take.
+        let unresolved = self.is_unresolved(&left) || self.is_unresolved(&right);
+
+        let operand = match operator {
+            "+" => {
+                // `+` adds integers and concatenates strings
+                let known = [self.resolve(&left), self.resolve(&right)];
+                match known
+                    .iter()
+                    .find(|t| !matches!(t, Type::Var(_) | Type::Any))
+                {
+                    Some(Type::Str) => Type::Str,
+                    Some(Type::Int) => Type::Int,
+                    Some(_) => {
+                        if !unresolved {
+                            self.operator_error(node, &left, &right);
+                        }
+                        return Type::Any;
+                    }
+                    _ => return Type::Any,
+                }
+            }
+            "-" | "*" | "/" | "<" | ">" => Type::Int,
+            "==" | "!=" => {
+                if !unresolved && !self.unify(&left, &right) {
+                    self.operator_error(node, &left, &right);
+                }
+                return Type::Bool;
+            }
+            _ => return Type::Any,
+        };
+
+        if !unresolved {
+            let agrees = self.unify(&operand, &left) && self.unify(&operand, &right);
+            if !agrees {
+                self.operator_error(node, &left, &right);
+            }
+        }
+        match operator {
+            "<" | ">" => Type::Bool,
+            _ => operand,
+        }
+    }
+
+    fn operator_error(&mut self, node: &InfixExpression, left: &Type, right: &Type) {
+        let left = self.shown(left);
+        let right = self.shown(right);
+        let concrete = |t: &Type| !matches!(t, Type::Var(_) | Type::Any);
+        let message = if concrete(&left) && concrete(&right) && left != right {
+            format!("type mismatch: {} {} {}", left, node.operator, right)
+        } else {
+            format!("unknown operator: {} {} {}", left, node.operator, right)
+        };
+        self.error(node.token.span, message);
+    }
+
+    fn check_try_expression(&mut self, node: &TryExpression) -> Type {
+        let mut result = self.check_block_statement(&node.block);
+        if let Some(catch_block) = &node.catch_block {
+            self.scopes.push(HashMap::new());
+            if let Some(param) = &node.catch_parameter {
+                self.define(
+                    &param.value,
+                    Type::Hash(Box::new(Type::Str), Box::new(Type::Any)),
+                );
+            }
+            let caught = self.check_block_statement(catch_block);
+            self.scopes.pop();
+            result = self.join(&result, &caught);
+        }
+        if let Some(finally_block) = &node.finally_block {
+            self.check_block_statement(finally_block);
+        }
+        result
+    }
+
+    fn check_function_literal(&mut self, node: &FunctionLiteral) -> Type {
+        self.scopes.push(HashMap::new());
+        let mut params = Vec::new();
+        for (idx, param) in node.parameters.iter().enumerate() {
+            let ty = match node.parameter_types.get(idx) {
+                Some(Some(annotation)) => self.annotation_type(annotation),
+                _ => self.fresh(),
+            };
+            if let Some(Some(default)) = node.defaults.get(idx) {
+                // callers may pass any type where the default is not annotated
+                let default_ty = self.check_expression(default);
+                if let Some(Some(_)) = node.parameter_types.get(idx) {
+                    let context = format!("default value of parameter {}", param.value);
+                    self.expect(&ty, &default_ty, param.token.span, &context);
+                }
+            }
+            match node.patterns.get(idx) {
+                Some(Some(pattern)) => self.bind_pattern(pattern, &ty),
+                _ => self.define(&param.value, ty.clone()),
+            }
+            params.push(ty);
+        }
+        if let Some(rest) = &node.rest {
+            let element = self.fresh();
+            self.define(&rest.value, Type::Array(Box::new(element)));
+        }
+
+        let declared = node
+            .return_type
+            .as_ref()
+            .map(|annotation| self.annotation_type(annotation));
+        self.functions.push(FunctionContext {
+            declared: declared.clone(),
+            returns: Vec::new(),
+        });
+        let body = self.check_block_statement(&node.body);
+        let context = self.functions.pop().unwrap();
+        self.scopes.pop();
+
+        let ends_with_return = node
+            .body
+            .statements
+            .last()
+            .is_some_and(|s| matches!(s, Statement::Return(_)));
+        let ret = match declared {
+            Some(declared) => {
+                if !ends_with_return {
+                    self.expect(&declared, &body, node.token.span, "return type mismatch");
+                }
+                declared
+            }
+            _ => {
+                let mut results = context.returns;
+                if !ends_with_return {
+                    results.push(body);
+                }
+                match self.join_all(results) {
+                    Some(ret) => ret,
+                    _ => self.fresh(),
+                }
+            }
+        };
+        Type::Function(params, Box::new(ret))
+    }
+
+    fn check_call_expression(&mut self, node: &CallExpression) -> Type {
+        if node.function.token_literal() == "quote" {
+            return Type::Any;
+        }
+        let function = self.check_expression(&node.function);
+        let mut args = Vec::new();
+        for arg in node.arguments.iter() {
+            args.push(self.check_expression(arg));
+        }
+        for (_, arg) in node.named_arguments.iter() {
+            self.check_expression(arg);
+        }
+
+        match self.resolve(&function) {
+            Type::Function(params, ret) => {
+                for (idx, (param, arg)) in params.iter().zip(args.iter()).enumerate() {
+                    let context = format!("argument {} of {}", idx + 1, node.function.string());
+                    self.expect(param, arg, node.token.span, &context);
+                }
+                *ret
+            }
+            Type::Var(_) => {
+                let ret = self.fresh();
+                self.unify(&function, &Type::Function(args, Box::new(ret.clone())));
+                ret
+            }
+            Type::Any => Type::Any,
+            other => {
+                let message = format!("not a function: {}", self.shown(&other));
+                self.error(node.token.span, message);
+                Type::Any
+            }
+        }
+    }
+
+    fn check_index_expression(&mut self, node: &IndexExpression) -> Type {
+        let left = self.check_expression(&node.left);
+        let index = self.check_expression(&node.index);
+        match self.resolve(&left) {
+            Type::Array(element) => {
+                self.expect(&Type::Int, &index, node.token.span, "array index");
+                *element
+            }
+            Type::Hash(key, value) => {
+                self.expect(&key, &index, node.token.span, "hash key");
+                *value
+            }
+            Type::Var(_) | Type::Any => Type::Any,
+            other => {
+                let message = format!("index operator not supported: {}", self.shown(&other));
+                self.error(node.token.span, message);
+                Type::Any
+            }
+        }
+    }
+
+    fn bind_pattern(&mut self, pattern: &Pattern, ty: &Type) {
+        match pattern {
+            Pattern::Binding(binding) => self.define(&binding.name.value, ty.clone()),
+            Pattern::Array(array_pattern) => {
+                let element = match self.resolve(ty) {
+                    Type::Array(element) => *element,
+                    _ => Type::Any,
+                };
+                for p in array_pattern.elements.iter() {
+                    self.bind_pattern(p, &element);
+                }
+                if let Some(rest) = &array_pattern.rest {
+                    self.define(&rest.value, Type::Array(Box::new(element)));
+                }
+            }
+            Pattern::Hash(hash_pattern) => {
+                let value = match self.resolve(ty) {
+                    Type::Hash(_, value) => *value,
+                    _ => Type::Any,
+                };
+                for (_, p) in hash_pattern.pairs.iter() {
+                    self.bind_pattern(p, &value);
+                }
+            }
+            Pattern::Literal(_) | Pattern::Wildcard(_) => {}
+        }
+    }
+}
@@ -0,0 +1,1019 @@
+use super::ast::*;
+use super::builtins::*;
+use super::code::*;
+use super::compiler::*;
+use super::diagnostics::did_you_mean;
+use super::evaluator::*;
+use super::module::*;
+use super::object::*;
+use std::cell::*;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::rc::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum HandlerKind {
+    Catch,
+    Finally,
+}
+
+// An active `try`. Errors raised in its frame jump to `target` with the stack
+// cut back to `sp` and the error pushed.
+#[derive(Debug)]
+struct Handler {
+    kind: HandlerKind,
+    target: usize,
+    frame: usize,
+    sp: usize,
+}
+
+struct Frame {
+    closure: Rc<Closure>,
+    ip: usize,
+    base: usize, // first local slot
+    sp: usize,   // stack height before the callee was pushed
+    upvalues: Vec<Upvalue>,
+    call_name: usize, // constant of the caller naming this call, for error stacks
+}
+
+// Runs bytecode from the `compiler`. Globals survive between runs, so a REPL
+// can run one line at a time.
+pub struct Vm {
+    stack: Vec<Value>,
+    locals: Vec<Option<Value>>,
+    frames: Vec<Frame>,
+    handlers: Vec<Handler>,
+    globals: Rc<RefCell<Globals>>,
+    loader: Rc<RefCell<ModuleLoader>>,
+    file: Option<PathBuf>,
+    limits: ExecutionLimits, // only max_depth and interrupt apply
+}
+impl Default for Vm {
+    fn default() -> Vm {
+        Vm::new()
+    }
+}
+impl Vm {
+    pub fn new() -> Vm {
+        Vm::with_loader(&Rc::new(RefCell::new(ModuleLoader::new())), None)
+    }
+
+    pub fn with_loader(loader: &Rc<RefCell<ModuleLoader>>, file: Option<PathBuf>) -> Vm {
+        Vm {
+            stack: Vec::new(),
+            locals: Vec::new(),
+            frames: Vec::new(),
+            handlers: Vec::new(),
+            globals: Rc::new(RefCell::new(Globals::default())),
+            loader: Rc::clone(loader),
+            file,
+            limits: loader.borrow().limits().clone(),
+        }
+    }
+
+    // The file imports are resolved next to.
+    pub fn set_file(&mut self, file: Option<PathBuf>) {
+        self.file = file;
+    }
+
+    pub fn set_limits(&mut self, limits: ExecutionLimits) {
+        self.limits = limits;
+    }
+
+    // Sets a global slot handed out by `Compiler::define_global`.
+    pub fn set_global(&mut self, idx: usize, val: Value) {
+        let mut globals = self.globals.borrow_mut();
+        if globals.values.len() <= idx {
+            globals.values.resize(idx + 1, None);
+        }
+        globals.values[idx] = Some(val);
+    }
+
+    pub fn global(&self, name: &str) -> Option<Value> {
+        let globals = self.globals.borrow();
+        let idx = globals.names.iter().position(|n| n == name)?;
+        globals.values.get(idx).cloned().flatten()
+    }
+
+    // The globals set so far and their values, in the order they were defined.
+    pub fn globals(&self) -> Vec<(String, Value)> {
+        let globals = self.globals.borrow();
+        globals
+            .names
+            .iter()
+            .zip(globals.values.iter())
+            .filter_map(|(name, val)| Some((name.clone(), val.clone()?)))
+            .collect()
+    }
+
+    // Returns the value of the program like `evaluator::eval`: `None` when it
+    // ends in a statement without a value, an error value when one escaped.
+    pub fn run(&mut self, bytecode: &Bytecode) -> Option<Value> {
+        {
+            let mut globals = self.globals.borrow_mut();
+            globals.names = bytecode.globals.clone();
+            if globals.values.len() < globals.names.len() {
+                let len = globals.names.len();
+                globals.values.resize(len, None);
+            }
+        }
+        self.stack.clear();
+        self.locals.clear();
+        self.frames.clear();
+        self.handlers.clear();
+
+        let main = Rc::new(Closure {
+            function: Rc::clone(&bytecode.main),
+            free: Vec::new(),
+            globals: Rc::clone(&self.globals),
+        });
+        self.locals.resize(main.function.local_names.len(), None);
+        self.frames.push(Frame {
+            upvalues: new_upvalues(&main.function),
+            closure: main,
+            ip: 0,
+            base: 0,
+            sp: 0,
+            call_name: 0,
+        });
+        self.execute()
+    }
+
+    fn execute(&mut self) -> Option<Value> {
+        let mut function = Rc::clone(&self.frames.last().unwrap().closure.function);
+        let mut ip = 0;
+        let mut base = 0;
+        let mut sp = 0;
+        let mut start; // of the instruction running, for the span of errors
+
+        macro_rules! reload {
+            () => {{
+                let frame = self.frames.last().unwrap();
+                function = Rc::clone(&frame.closure.function);
+                ip = frame.ip;
+                base = frame.base;
+                sp = frame.sp;
+            }};
+        }
+        macro_rules! throw {
+            ($err:expr) => {{
+                let span = function.span_at(start).unwrap_or_default();
+                match self.throw(raised_at($err, span)) {
+                    Ok(()) => {
+                        reload!();
+                        continue;
+                    }
+                    Err(err) => return Some(Value::Error(err)),
+                }
+            }};
+        }
+        // Pops an operand of the current frame. Only corrupt bytecode pops
+        // past the frame's own values.
+        macro_rules! pop {
+            () => {{
+                if self.stack.len() <= sp {
+                    throw!(stack_underflow());
+                }
+                self.stack.pop().unwrap()
+            }};
+        }
+        macro_rules! pop_many {
+            ($count:expr) => {{
+                let count = $count;
+                if self.stack.len() - sp < count {
+                    throw!(stack_underflow());
+                }
+                self.stack.split_off(self.stack.len() - count)
+            }};
+        }
+        macro_rules! operand {
+            ($offset:expr) => {
+                read_u16(&function.instructions, ip + $offset)
+            };
+        }
+
+        loop {
+            start = ip;
+            let op = match Opcode::from_byte(function.instructions[ip]) {
+                Some(op) => op,
+                _ => throw!(Rc::new(new_error(
+                    ERROR,
+                    format_args!("unknown opcode {}", function.instructions[ip]),
+                ))),
+            };
+            match op {
+                Opcode::Constant => {
+                    self.stack.push(function.constants[operand!(1)].clone());
+                    ip += 3;
+                }
+                Opcode::Pop => {
+                    self.stack.pop();
+                    ip += 1;
+                }
+                Opcode::Null => {
+                    self.stack.push(Value::Null);
+                    ip += 1;
+                }
+                Opcode::True => {
+                    self.stack.push(Value::Boolean(true));
+                    ip += 1;
+                }
+                Opcode::False => {
+                    self.stack.push(Value::Boolean(false));
+                    ip += 1;
+                }
+                Opcode::Add
+                | Opcode::Sub
+                | Opcode::Mul
+                | Opcode::Div
+                | Opcode::Equal
+                | Opcode::NotEqual
+                | Opcode::GreaterThan
+                | Opcode::LessThan => {
+                    ip += 1;
+                    let right = pop!();
+                    let left = pop!();
+                    match binary_operation(op, &left, &right) {
+                        Ok(val) => self.stack.push(val),
+                        Err(err) => throw!(err),
+                    }
+                }
+                Opcode::Minus => {
+                    ip += 1;
+                    match pop!() {
+                        Value::Integer(value) => match negate(value) {
+                            Ok(val) => self.stack.push(val),
+                            Err(err) => throw!(err),
+                        },
+                        right => throw!(Rc::new(new_error(
+                            TYPE_ERROR,
+                            format_args!("unknown operator: -{}", right.get_type()),
+                        ))),
+                    }
+                }
+                Opcode::Bang => {
+                    ip += 1;
+                    let right = pop!();
+                    self.stack.push(Value::Boolean(!is_truthy(&right)));
+                }
+                Opcode::Jump => ip = operand!(1),
+                Opcode::JumpNotTruthy => {
+                    let condition = pop!();
+                    if is_truthy(&condition) {
+                        ip += 3;
+                    } else {
+                        ip = operand!(1);
+                    }
+                }
+                Opcode::JumpIfBound => {
+                    if self.locals[base + operand!(1)].is_some() {
+                        ip = operand!(3);
+                    } else {
+                        ip += 5;
+                    }
+                }
+                Opcode::GetGlobal => {
+                    let idx = operand!(1);
+                    ip += 3;
+                    let globals = self.frames.last().unwrap().closure.globals.borrow();
+                    let val = match &globals.values[idx] {
+                        Some(val) => Ok(val.clone()),
+                        _ => builtin(&globals.names[idx], &globals),
+                    };
+                    drop(globals);
+                    match val {
+                        Ok(val) => self.stack.push(val),
+                        Err(err) => throw!(err),
+                    }
+                }
+                Opcode::SetGlobal => {
+                    let globals = &self.frames.last().unwrap().closure.globals;
+                    globals.borrow_mut().values[operand!(1)] = self.stack.pop();
+                    ip += 3;
+                }
+                Opcode::GetLocal => {
+                    let idx = operand!(1);
+                    ip += 3;
+                    match &self.locals[base + idx] {
+                        Some(val) => self.stack.push(val.clone()),
+                        _ => match self.unbound(&function.local_names[idx]) {
+                            Ok(val) => self.stack.push(val),
+                            Err(err) => throw!(err),
+                        },
+                    }
+                }
+                Opcode::SetLocal => {
+                    self.locals[base + operand!(1)] = self.stack.pop();
+                    ip += 3;
+                }
+                Opcode::GetUpvalue => {
+                    let idx = operand!(1);
+                    ip += 3;
+                    let val = self.frames.last().unwrap().upvalues[idx].borrow().clone();
+                    match val {
+                        Some(val) => self.stack.push(val),
+                        _ => match self.unbound(&function.upvalue_names[idx]) {
+                            Ok(val) => self.stack.push(val),
+                            Err(err) => throw!(err),
+                        },
+                    }
+                }
+                Opcode::SetUpvalue => {
+                    let val = self.stack.pop();
+                    *self.frames.last().unwrap().upvalues[operand!(1)].borrow_mut() = val;
+                    ip += 3;
+                }
+                Opcode::GetFree => {
+                    let idx = operand!(1);
+                    ip += 3;
+                    let val = self.frames.last().unwrap().closure.free[idx]
+                        .borrow()
+                        .clone();
+                    match val {
+                        Some(val) => self.stack.push(val),
+                        _ => match self.unbound(&function.free[idx].0) {
+                            Ok(val) => self.stack.push(val),
+                            Err(err) => throw!(err),
+                        },
+                    }
+                }
+                Opcode::Array => {
+                    let count = operand!(1);
+                    ip += 3;
+                    let elements = pop_many!(count);
+                    self.stack.push(Value::Array(Rc::new(elements)));
+                }
+                Opcode::Hash => {
+                    let count = operand!(1);
+                    ip += 3;
+                    let values = pop_many!(2 * count);
+                    let mut pairs = BTreeMap::new();
+                    let mut values = values.into_iter();
+                    while let (Some(key), Some(value)) = (values.next(), values.next()) {
+                        if let Some(hash_key) = key.hash_key() {
+                            pairs.insert(hash_key, HashPair { key, value });
+                        }
+                    }
+                    self.stack.push(Value::Hash(Rc::new(pairs)));
+                }
+                Opcode::CheckHashKey => {
+                    ip += 1;
+                    if self.stack.len() <= sp {
+                        throw!(stack_underflow());
+                    }
+                    let key = self.stack.last().unwrap();
+                    if key.hash_key().is_none() {
+                        throw!(Rc::new(new_error(
+                            TYPE_ERROR,
+                            format_args!("unusable as hash key: {}", key.get_type()),
+                        )));
+                    }
+                }
+                Opcode::Index => {
+                    ip += 1;
+                    let index = pop!();
+                    let left = pop!();
+                    match index_value(&left, &index) {
+                        Ok(val) => self.stack.push(val),
+                        Err(err) => throw!(err),
+                    }
+                }
+                Opcode::Call => {
+                    let argc = function.instructions[ip + 1] as usize;
+                    let named = function.instructions[ip + 2] as usize;
+                    let name = operand!(3);
+                    self.frames.last_mut().unwrap().ip = ip + 5;
+                    // code only repeats through calls, so they are where an
+                    // interrupt stops it; `catch` and `finally` do not see it
+                    if let Err(err) = self.limits.check_interrupt() {
+                        return Some(Value::Error(err));
+                    }
+                    match self.call(argc, named, name) {
+                        Ok(()) => reload!(),
+                        Err(err) => throw!(err),
+                    }
+                }
+                Opcode::ReturnValue => {
+                    let val = pop!();
+                    if let Some(result) = self.return_value(val) {
+                        return result;
+                    }
+                    reload!();
+                }
+                Opcode::Return => {
+                    if let Some(result) = self.pop_frame(None) {
+                        return result;
+                    }
+                    reload!();
+                }
+                Opcode::Closure => {
+                    let literal = Rc::clone(&function.functions[operand!(1)]);
+                    ip += 3;
+                    let frame = self.frames.last().unwrap();
+                    let free = literal
+                        .free
+                        .iter()
+                        .map(|(_, capture)| match capture {
+                            Capture::Upvalue(idx) => Rc::clone(&frame.upvalues[*idx]),
+                            Capture::Free(idx) => Rc::clone(&frame.closure.free[*idx]),
+                        })
+                        .collect();
+                    let globals = Rc::clone(&frame.closure.globals);
+                    self.stack.push(Value::Closure(Rc::new(Closure {
+                        function: literal,
+                        free,
+                        globals,
+                    })));
+                }
+                Opcode::Throw => {
+                    let val = pop!();
+                    throw!(Rc::new(Error::from_value(&val)));
+                }
+                Opcode::SetupCatch | Opcode::SetupFinally => {
+                    let kind = if op == Opcode::SetupCatch {
+                        HandlerKind::Catch
+                    } else {
+                        HandlerKind::Finally
+                    };
+                    self.handlers.push(Handler {
+                        kind,
+                        target: operand!(1),
+                        frame: self.frames.len() - 1,
+                        sp: self.stack.len(),
+                    });
+                    ip += 3;
+                }
+                Opcode::PopHandler => {
+                    self.handlers.pop();
+                    ip += 1;
+                }
+                Opcode::EndFinally => {
+                    ip += 1;
+                    self.stack.pop();
+                    // an error or return pending from the try block resumes
+                    match pop!() {
+                        Value::Error(err) => throw!(err),
+                        Value::ReturnValue(val) => {
+                            self.frames.last_mut().unwrap().ip = ip;
+                            if let Some(result) = self.return_value(*val) {
+                                return result;
+                            }
+                            reload!();
+                        }
+                        val => self.stack.push(val),
+                    }
+                }
+                Opcode::ErrorValue => {
+                    ip += 1;
+                    if let Some(Value::Error(err)) = self.stack.last() {
+                        let val = err.to_value();
+                        *self.stack.last_mut().unwrap() = val;
+                    }
+                }
+                Opcode::MatchArray => {
+                    let count = operand!(1);
+                    let rest = function.instructions[ip + 3] != 0;
+                    let matched = match pop!() {
+                        Value::Array(elements) => {
+                            elements.len() == count || (rest && elements.len() > count)
+                        }
+                        _ => false,
+                    };
+                    if matched {
+                        ip += 6;
+                    } else {
+                        ip = operand!(4);
+                    }
+                }
+                Opcode::MatchHash => match pop!() {
+                    Value::Hash(_) => ip += 3,
+                    _ => ip = operand!(1),
+                },
+                Opcode::MatchKey => {
+                    let key = pop!();
+                    let pairs = pop!();
+                    let value = match (&pairs, key.hash_key()) {
+                        (Value::Hash(pairs), Some(hash_key)) => {
+                            pairs.get(&hash_key).map(|pair| pair.value.clone())
+                        }
+                        _ => None,
+                    };
+                    match value {
+                        Some(value) => {
+                            self.stack.push(value);
+                            ip += 3;
+                        }
+                        _ => ip = operand!(1),
+                    }
+                }
+                Opcode::MatchEqual => {
+                    let expected = pop!();
+                    let value = pop!();
+                    if objects_equal(&expected, &value) {
+                        ip += 3;
+                    } else {
+                        ip = operand!(1);
+                    }
+                }
+                Opcode::Element => {
+                    let idx = operand!(1);
+                    ip += 3;
+                    let element = match pop!() {
+                        Value::Array(elements) => elements.get(idx).cloned(),
+                        _ => None,
+                    };
+                    self.stack.push(element.unwrap_or(Value::Null));
+                }
+                Opcode::RestOf => {
+                    let count = operand!(1);
+                    ip += 3;
+                    let rest = match pop!() {
+                        Value::Array(elements) if elements.len() >= count => {
+                            elements[count..].to_vec()
+                        }
+                        _ => Vec::new(),
+                    };
+                    self.stack.push(Value::Array(Rc::new(rest)));
+                }
+                Opcode::NoMatch => {
+                    let value = pop!();
+                    throw!(Rc::new(new_error(
+                        MATCH_ERROR,
+                        format_args!("no pattern matched value: {}", value.inspect()),
+                    )));
+                }
+                Opcode::PatternMismatch => {
+                    let value = pop!();
+                    throw!(Rc::new(new_error(
+                        MATCH_ERROR,
+                        format_args!(
+                            "pattern {} does not match value: {}",
+                            function.constants[operand!(1)].inspect(),
+                            value.inspect()
+                        ),
+                    )));
+                }
+                Opcode::Quote => {
+                    let node = match &function.constants[operand!(1)] {
+                        Value::Quote(node) => node.as_ref().clone(),
+                        _ => Expression::Identifier(Identifier {
+                            token: super::lexer::Token::new(super::lexer::TokenType::ILLEGAL, '?'),
+                            value: String::new(),
+                            address: None,
+                        }),
+                    };
+                    let count = operand!(3);
+                    ip += 5;
+                    let unquoted = pop_many!(count);
+                    match splice_unquoted(node, unquoted) {
+                        Ok(node) => self.stack.push(Value::Quote(Rc::new(node))),
+                        Err(err) => throw!(err),
+                    }
+                }
+                Opcode::Import => {
+                    let path = function.constants[operand!(1)].inspect();
+                    ip += 3;
+                    match import_module(&self.loader, &path, self.file.as_deref()) {
+                        Ok(module) => self.stack.push(Value::Module(Rc::new(module))),
+                        Err(Value::Error(err)) => throw!(err),
+                        Err(val) => throw!(Rc::new(Error::from_value(&val))),
+                    }
+                }
+            }
+        }
+    }
+
+    fn call(&mut self, argc: usize, named: usize, name: usize) -> Result<(), Rc<Error>> {
+        let sp = self.frames.last().unwrap().sp;
+        let callee_idx = match self.stack.len().checked_sub(argc + 2 * named + 1) {
+            Some(idx) if idx >= sp => idx,
+            _ => return Err(stack_underflow()),
+        };
+        let result = match self.stack[callee_idx].clone() {
+            Value::Closure(closure) => self.call_closure(closure, callee_idx, argc, named, name),
+            Value::Builtin(builtin) => self.call_builtin(&builtin, callee_idx, argc),
+            callee => Err(Rc::new(new_error(
+                TYPE_ERROR,
+                format_args!("not a function: {}", callee.get_type()),
+            ))),
+        };
+        result.map_err(|mut err| {
+            let call = call_site(self.frames.last().unwrap(), name);
+            Rc::make_mut(&mut err).stack.push(call);
+            err
+        })
+    }
+
+    fn call_builtin(
+        &mut self,
+        builtin: &Builtin,
+        callee_idx: usize,
+        argc: usize,
+    ) -> Result<(), Rc<Error>> {
+        let mut values = self.stack.split_off(callee_idx + 1);
+        self.stack.pop();
+        let named_values = values.split_off(argc);
+        let mut named_map: HashMap<String, Value> = HashMap::new();
+        let mut named_values = named_values.into_iter();
+        while let (Some(name), Some(val)) = (named_values.next(), named_values.next()) {
+            let name = name.inspect();
+            if named_map.contains_key(&name) {
+                return Err(Rc::new(new_error(
+                    ARGUMENT_ERROR,
+                    format_args!("duplicate argument: {}", name),
+                )));
+            }
+            named_map.insert(name, val);
+        }
+        match (builtin.function)(values, named_map) {
+            Value::Error(err) => Err(err),
+            val => {
+                self.stack.push(val);
+                Ok(())
+            }
+        }
+    }
+
+    fn call_closure(
+        &mut self,
+        closure: Rc<Closure>,
+        callee_idx: usize,
+        argc: usize,
+        named: usize,
+        name: usize,
+    ) -> Result<(), Rc<Error>> {
+        // the main frame is not a call
+        if self.frames.len() > self.limits.max_depth {
+            return Err(Rc::new(new_error(
+                RECURSION_ERROR,
+                format_args!("maximum recursion depth exceeded"),
+            )));
+        }
+        let function = Rc::clone(&closure.function);
+        let count = function.parameters.len();
+        if !function.rest && argc > count {
+            return Err(wrong_number_of_arguments(&function, argc + named));
+        }
+
+        let base = self.locals.len();
+        self.locals.resize(base + function.local_names.len(), None);
+        let first = callee_idx + 1;
+        for idx in 0..argc.min(count) {
+            self.locals[base + idx] =
+                Some(std::mem::replace(&mut self.stack[first + idx], Value::Null));
+        }
+        for idx in 0..named {
+            let name = self.stack[first + argc + 2 * idx].inspect();
+            match function.parameters.iter().position(|p| *p == name) {
+                Some(param) if self.locals[base + param].is_some() => {
+                    self.locals.truncate(base);
+                    return Err(Rc::new(new_error(
+                        ARGUMENT_ERROR,
+                        format_args!("duplicate argument: {}", name),
+                    )));
+                }
+                Some(param) => {
+                    self.locals[base + param] = Some(std::mem::replace(
+                        &mut self.stack[first + argc + 2 * idx + 1],
+                        Value::Null,
+                    ));
+                }
+                _ => {
+                    self.locals.truncate(base);
+                    return Err(Rc::new(new_error(
+                        ARGUMENT_ERROR,
+                        format_args!("unknown named argument: {}", name),
+                    )));
+                }
+            }
+        }
+        if (0..count).any(|idx| self.locals[base + idx].is_none() && !function.defaults[idx]) {
+            self.locals.truncate(base);
+            return Err(wrong_number_of_arguments(&function, argc + named));
+        }
+        if function.rest {
+            let rest = if argc > count {
+                self.stack[first + count..first + argc].to_vec()
+            } else {
+                Vec::new()
+            };
+            self.locals[base + count] = Some(Value::Array(Rc::new(rest)));
+        }
+
+        self.stack.truncate(callee_idx);
+        self.frames.push(Frame {
+            upvalues: new_upvalues(&function),
+            closure,
+            ip: 0,
+            base,
+            sp: callee_idx,
+            call_name: name,
+        });
+        Ok(())
+    }
+
+    // Returns from the current frame, running pending `finally` blocks first.
+    // Gives the result of the run once the main frame returns.
+    fn return_value(&mut self, val: Value) -> Option<Option<Value>> {
+        let frame_idx = self.frames.len() - 1;
+        while let Some(handler) = self.handlers.last() {
+            if handler.frame != frame_idx {
+                break;
+            }
+            let handler = self.handlers.pop().unwrap();
+            if handler.kind == HandlerKind::Finally {
+                self.stack.truncate(handler.sp);
+                self.stack.push(Value::ReturnValue(Box::new(val)));
+                self.frames[frame_idx].ip = handler.target;
+                return None;
+            }
+        }
+        self.pop_frame(Some(val))
+    }
+
+    fn pop_frame(&mut self, val: Option<Value>) -> Option<Option<Value>> {
+        let frame = self.frames.pop().unwrap();
+        self.locals.truncate(frame.base);
+        self.stack.truncate(frame.sp);
+        if self.frames.is_empty() {
+            return Some(val);
+        }
+        self.stack.push(val.unwrap_or(Value::Null));
+        None
+    }
+
+    // Unwinds to the innermost handler, adding the call of every frame left
+    // to the error's stack. Gives the error back if nothing handles it.
+    fn throw(&mut self, mut err: Rc<Error>) -> Result<(), Rc<Error>> {
+        loop {
+            let frame_idx = self.frames.len() - 1;
+            if self
+                .handlers
+                .last()
+                .is_some_and(|handler| handler.frame == frame_idx)
+            {
+                let handler = self.handlers.pop().unwrap();
+                self.stack.truncate(handler.sp);
+                self.stack.push(Value::Error(err));
+                self.frames[frame_idx].ip = handler.target;
+                return Ok(());
+            }
+            let frame = self.frames.pop().unwrap();
+            self.locals.truncate(frame.base);
+            self.stack.truncate(frame.sp);
+            match self.frames.last() {
+                Some(caller) => Rc::make_mut(&mut err)
+                    .stack
+                    .push(call_site(caller, frame.call_name)),
+                _ => return Err(err),
+            }
+        }
+    }
+
+    // A local read before its `let` ran falls back to the global or builtin
+    // of the same name, as in the evaluator.
+    fn unbound(&self, name: &str) -> Result<Value, Rc<Error>> {
+        let globals = self.frames.last().unwrap().closure.globals.borrow();
+        let global = globals
+            .names
+            .iter()
+            .position(|n| n == name)
+            .and_then(|idx| globals.values[idx].clone());
+        match global {
+            Some(val) => Ok(val),
+            _ => builtin(name, &globals),
+        }
+    }
+}
+
+// Compiled code never pops more than it pushed, so this only comes from a
+// corrupt or hand-made bytecode file that passed validation.
+fn stack_underflow() -> Rc<Error> {
+    Rc::new(new_error(ERROR, format_args!("stack underflow")))
+}
+
+// The `Call` a caller is waiting on, its `ip` is just past it.
+fn call_site(caller: &Frame, name: usize) -> StackFrame {
+    let function = &caller.closure.function;
+    let offset = caller.ip - 5;
+    let argc =
+        function.instructions[offset + 1] as usize + function.instructions[offset + 2] as usize;
+    StackFrame::new(
+        &function.constants[name].inspect(),
+        function.span_at(offset).unwrap_or_default(),
+        argc,
+    )
+}
+
+fn new_upvalues(function: &CompiledFunction) -> Vec<Upvalue> {
+    function
+        .upvalue_names
+        .iter()
+        .map(|_| Rc::new(RefCell::new(None)))
+        .collect()
+}
+
+// The builtin `name`, suggesting the globals bound so far when there is none.
+fn builtin(name: &str, globals: &Globals) -> Result<Value, Rc<Error>> {
+    match lookup(name) {
+        Some(builtin) => Ok(Value::Builtin(Rc::new(builtin))),
+        _ => {
+            let bound = globals
+                .names
+                .iter()
+                .zip(globals.values.iter())
+                .filter(|(_, val)| val.is_some())
+                .map(|(name, _)| name.as_str());
+            Err(Rc::new(new_error(
+                NAME_ERROR,
+                format_args!(
+                    "identifier not found: {}{}",
+                    name,
+                    did_you_mean(name, bound.chain(NAMES.iter().copied()))
+                ),
+            )))
+        }
+    }
+}
+
+pub fn is_truthy(val: &Value) -> bool {
+    match val {
+        Value::Null => false,
+        Value::Boolean(value) => *value,
+        _ => true,
+    }
+}
+
+fn wrong_number_of_arguments(function: &CompiledFunction, got: usize) -> Rc<Error> {
+    let max = function.parameters.len();
+    let min = function.defaults.iter().take_while(|d| !**d).count();
+    let want = if function.rest {
+        format!("{}..", min)
+    } else if min != max {
+        format!("{}..{}", min, max)
+    } else {
+        format!("{}", max)
+    };
+    Rc::new(new_error(
+        ARGUMENT_ERROR,
+        format_args!("wrong number of arguments: want={}, got={}", want, got),
+    ))
+}
+
+fn checked(op: Opcode, left: i64, right: i64, result: Option<i64>) -> Result<Value, Rc<Error>> {
+    result.map(Value::Integer).ok_or_else(|| {
+        Rc::new(new_error(
+            ARITHMETIC_ERROR,
+            format_args!("integer overflow: {} {} {}", left, operator(op), right),
+        ))
+    })
+}
+
+pub fn negate(value: i64) -> Result<Value, Rc<Error>> {
+    value.checked_neg().map(Value::Integer).ok_or_else(|| {
+        Rc::new(new_error(
+            ARITHMETIC_ERROR,
+            format_args!("integer overflow: -({})", value),
+        ))
+    })
+}
+
+pub fn binary_operation(op: Opcode, left: &Value, right: &Value) -> Result<Value, Rc<Error>> {
+    match (left, right) {
+        (Value::Integer(left), Value::Integer(right)) => match op {
+            Opcode::Add => checked(op, *left, *right, left.checked_add(*right)),
+            Opcode::Sub => checked(op, *left, *right, left.checked_sub(*right)),
+            Opcode::Mul => checked(op, *left, *right, left.checked_mul(*right)),
+            Opcode::Div if *right == 0 => Err(Rc::new(new_error(
+                ARITHMETIC_ERROR,
+                format_args!("division by zero: {} / 0", left),
+            ))),
+            Opcode::Div => checked(op, *left, *right, left.checked_div(*right)),
+            Opcode::LessThan => Ok(Value::Boolean(left < right)),
+            Opcode::GreaterThan => Ok(Value::Boolean(left > right)),
+            Opcode::Equal => Ok(Value::Boolean(left == right)),
+            _ => Ok(Value::Boolean(left != right)),
+        },
+        (Value::Boolean(left), Value::Boolean(right)) if op == Opcode::Equal => {
+            Ok(Value::Boolean(left == right))
+        }
+        (Value::Boolean(left), Value::Boolean(right)) if op == Opcode::NotEqual => {
+            Ok(Value::Boolean(left != right))
+        }
+        (Value::Str(left), Value::Str(right)) if op == Opcode::Add => {
+            Ok(Value::Str(Rc::from(format!("{}{}", left, right))))
+        }
+        (Value::Str(left), Value::Str(right)) if op == Opcode::Equal => {
+            Ok(Value::Boolean(left == right))
+        }
+        (Value::Str(left), Value::Str(right)) if op == Opcode::NotEqual => {
+            Ok(Value::Boolean(left != right))
+        }
+        _ if left.get_type() != right.get_type() => Err(Rc::new(new_error(
+            TYPE_ERROR,
+            format_args!(
+                "type mismatch: {} {} {}",
+                left.get_type(),
+                operator(op),
+                right.get_type()
+            ),
+        ))),
+        _ => Err(Rc::new(new_error(
+            TYPE_ERROR,
+            format_args!(
+                "unknown operator: {} {} {}",
+                left.get_type(),
+                operator(op),
+                right.get_type()
+            ),
+        ))),
+    }
+}
+
+fn operator(op: Opcode) -> &'static str {
+    match op {
+        Opcode::Add => "+",
+        Opcode::Sub => "-",
+        Opcode::Mul => "*",
+        Opcode::Div => "/",
+        Opcode::LessThan => "<",
+        Opcode::GreaterThan => ">",
+        Opcode::Equal => "==",
+        _ => "!=",
+    }
+}
+
+pub fn index_value(left: &Value, index: &Value) -> Result<Value, Rc<Error>> {
+    match (left, index) {
+        (Value::Array(elements), Value::Integer(idx)) => {
+            if *idx < 0 || *idx as usize >= elements.len() {
+                return Ok(Value::Null);
+            }
+            Ok(elements[*idx as usize].clone())
+        }
+        (Value::Module(module), Value::Str(name)) => match module.exports.get(name.as_ref()) {
+            Some(val) => Ok(val.clone()),
+            _ => Err(Rc::new(new_error(
+                NAME_ERROR,
+                format_args!("module {} has no export {}", module.name, name),
+            ))),
+        },
+        (Value::Module(_), _) => Err(Rc::new(new_error(
+            TYPE_ERROR,
+            format_args!(
+                "module member name must be STRING, got {}",
+                index.get_type()
+            ),
+        ))),
+        (Value::Hash(pairs), _) => match index.hash_key() {
+            Some(hash_key) => Ok(pairs
+                .get(&hash_key)
+                .map(|pair| pair.value.clone())
+                .unwrap_or(Value::Null)),
+            _ => Err(Rc::new(new_error(
+                TYPE_ERROR,
+                format_args!("unusable as hash key: {}", index.get_type()),
+            ))),
+        },
+        _ => Err(Rc::new(new_error(
+            TYPE_ERROR,
+            format_args!("index operator not supported: {}", left.get_type()),
+        ))),
+    }
+}
+
+// Replaces the `unquote(...)` calls of `node`, in the order the compiler
+// found them, with the values they evaluated to.
+pub fn splice_unquoted(
+    mut node: Expression,
+    unquoted: Vec<Value>,
+) -> Result<Expression, Rc<Error>> {
+    let mut unquoted = unquoted.into_iter();
+    let mut error: Option<Rc<Error>> = None;
+    modify_expression(&mut node, &mut |exp: &mut Expression| {
+        if error.is_some() {
+            return;
+        }
+        match exp {
+            Expression::Call(call_exp)
+                if call_exp.function.token_literal() == "unquote"
+                    && call_exp.arguments.len() == 1 => {}
+            _ => return,
+        }
+        let val = unquoted.next().unwrap_or(Value::Null);
+        match convert_object_to_ast_node(&val) {
+            Some(converted) => *exp = converted,
+            _ => {
+                error = Some(Rc::new(new_error(
+                    TYPE_ERROR,
+                    format_args!("cannot unquote value of type {}", val.get_type()),
+                )))
+            }
+        }
+    });
+    match error {
+        Some(err) => Err(err),
+        _ => Ok(node),
+    }
+}
@@ -0,0 +1,78 @@
+extern crate waiir;
+use waiir::ast::*;
+use waiir::lexer::*;
+use waiir::parser::*;
+
+fn parse(input: &str) -> Program {
+    let mut l = Lexer::new(input);
+    let mut p = Parser::new(&mut l);
+    let program = p.parse_program().unwrap();
+    assert!(
+        p.get_errors().is_empty(),
+        "parser errors: {:?}",
+        p.get_errors()
+    );
+    program
+}
+
+fn turn_one_into_two(exp: &mut Expression) {
+    let is_one = matches!(exp, Expression::Integer(integer) if integer.value == 1);
+    if is_one {
+        *exp = Expression::Integer(IntegerLiteral {
+            token: Token {
+                tk_type: TokenType::INT,
+                literal: String::from("2"),
+                span: Span::default(),
+            },
+            value: 2,
+        });
+    }
+}
+
+#[test]
+fn test_modify() {
+    let tests = [
+        ("1", "2"),
+        ("1 + 2", "(2 + 2)"),
+        ("-1", "(-2)"),
+        ("a[1]", "(a[2])"),
+        ("a.b", r#"(a["b"])"#),
+        ("if (1) { 1 } else { 1 }", "if2 2 else 2"),
+        ("return 1;", "return 2;"),
+        ("throw 1;", "throw 2;"),
+        ("let x = 1;", "let x = 2;"),
+        ("fn(x = 1) { 1 }", "fn(x = 2)2"),
+        ("macro(x) { 1 }", "macro(x)2"),
+        ("f(1, key: 1)", "f(2, key: 2)"),
+        ("[1, 1]", "[2, 2]"),
+        ("{1: 1}", "{2: 2}"),
+        (
+            "try { 1 } catch (e) { 1 } finally { 1 }",
+            "try 2 catch(e) 2 finally 2",
+        ),
+        (
+            "match (1) { 1 if 1 => 1, [1, x] => 1 }",
+            "match2 { 2 if 2 => 2, [2, x] => 2 }",
+        ),
+        ("let [1, {1: x}] = 1;", "let [2, {2: x}] = 2;"),
+    ];
+    for tt in tests.iter() {
+        let mut program = parse(tt.0);
+        modify_program(&mut program, &mut turn_one_into_two);
+        assert!(
+            program.string() == tt.1,
+            "not equal. want={}, got={}",
+            tt.1,
+            program.string()
+        );
+    }
+}
+
+#[test]
+fn test_modify_leaves_clones_untouched() {
+    let program = parse("fn(x) { x + 1 }");
+    let mut copy = program.clone();
+    modify_program(&mut copy, &mut turn_one_into_two);
+    assert!(program.string() == "fn(x)(x + 1)");
+    assert!(copy.string() == "fn(x)(x + 2)");
+}
@@ -0,0 +1,797 @@
+// Behaviour shared by every engine. The including test file provides
+// `test_eval` and `test_eval_unresolved`.
+use super::*;
+
+#[test]
+fn test_eval_integer_expression() {
+    let tests = [
+        ("5", 5),
+        ("10", 10),
+        ("-5", -5),
+        ("-10", -10),
+        ("5 + 5 + 5 + 5 - 10", 10),
+        ("2 * 2 * 2 * 2 * 2", 32),
+        ("-50 + 100 + -50", 0),
+        ("5 * 2 + 10", 20),
+        ("5 + 2 * 10", 25),
+        ("20 + 2 * -10", 0),
+        ("50 / 2 * 2 + 10", 60),
+        ("2 * (5 + 10)", 30),
+        ("3 * 3 * 3 + 10", 37),
+        ("3 * (3 * 3) + 10", 37),
+        ("(5 + 10 * 2 + 15 / 3) * 2 + -10", 50),
+    ];
+    for tt in tests.iter() {
+        let evaluated = test_eval(tt.0);
+        test_integer_object(evaluated, tt.1);
+    }
+}
+
+pub fn test_integer_object(obj: Value, expected: i64) {
+    let result = match &obj {
+        Value::Integer(result) => *result,
+        _ => panic!("object is not Integer. got={:?}", obj),
+    };
+
+    assert!(
+        result == expected,
+        "object has wrong value. got={}, want={}",
+        result,
+        expected
+    );
+}
+
+#[test]
+fn test_eval_boolean_expression() {
+    let tests = [
+        ("true", true),
+        ("false", false),
+        ("1 < 2", true),
+        ("1 > 2", false),
+        ("1 < 1", false),
+        ("1 > 1", false),
+        ("1 == 1", true),
+        ("1 != 1", false),
+        ("1 == 2", false),
+        ("1 != 2", true),
+        ("true == false", false),
+        ("true != false", true),
+        ("false != true", true),
+        ("(1 < 2) == true", true),
+        ("(1 < 2) == false", false),
+        ("(1 > 2) == true", false),
+        ("(1 > 2) == false", true),
+    ];
+    for tt in tests.iter() {
+        let evaluated = test_eval(tt.0);
+        test_boolean_object(evaluated, tt.1);
+    }
+}
+
+pub fn test_boolean_object(obj: Value, expected: bool) {
+    let result = match &obj {
+        Value::Boolean(result) => *result,
+        _ => panic!("object is not Boolean. got={:?}", obj),
+    };
+    assert!(
+        result == expected,
+        "object has wrong value. got={}, want={}",
+        result,
+        expected
+    );
+}
+
+#[test]
+fn test_bang_operator() {
+    let tests = [
+        ("!true", false),
+        ("!false", true),
+        ("!5", false),
+        ("!!true", true),
+        ("!!false", false),
+        ("!!5", true),
+    ];
+    for tt in tests.iter() {
+        let evaluated = test_eval(tt.0);
+        test_boolean_object(evaluated, tt.1);
+    }
+}
+
+#[test]
+fn test_if_else_expressions() {
+    let tests: [(&str, Value); 7] = [
+        ("if (true) { 10 }", Value::Integer(10)),
+        ("if (false) { 10 }", Value::Null),
+        ("if (1) { 10 }", Value::Integer(10)),
+        ("if (1 < 2) { 10 }", Value::Integer(10)),
+        ("if (1 > 2) { 10 }", Value::Null),
+        ("if (1 > 2) { 10 } else { 20 }", Value::Integer(20)),
+        ("if (1 < 2) { 10 } else { 20 }", Value::Integer(10)),
+    ];
+
+    for tt in tests.iter() {
+        let evaluated = test_eval(tt.0);
+        match &tt.1 {
+            Value::Integer(integer) => test_integer_object(evaluated, *integer),
+            _ => test_null_object(evaluated),
+        }
+    }
+}
+
+pub fn test_null_object(obj: Value) {
+    if !matches!(obj, Value::Null) {
+        panic!("object is not NULL. got={:?}", obj);
+    }
+}
+
+#[test]
+fn test_return_statement() {
+    let tests = [
+        ("return 10;", 10),
+        ("return 10; 9;", 10),
+        ("return 2 * 5; 9;", 10),
+        ("9; return 2 * 5; 9;", 10),
+        (
+            "if (10 > 1) { if (10 > 1) {
+            return 10; }
+            return 1; }",
+            10,
+        ),
+    ];
+
+    for tt in tests.iter() {
+        let evaluated = test_eval(tt.0);
+        test_integer_object(evaluated, tt.1);
+    }
+}
+
+#[test]
+fn test_error_handling() {
+    let tests = [
+        ("5 + true;", "type mismatch: INTEGER + BOOLEAN"),
+        ("5 + true; 5;", "type mismatch: INTEGER + BOOLEAN"),
+        ("-true", "unknown operator: -BOOLEAN"),
+        ("true + false;", "unknown operator: BOOLEAN + BOOLEAN"),
+        ("5; true + false; 5", "unknown operator: BOOLEAN + BOOLEAN"),
+        (
+            "if (10 > 1) { true + false; }",
+            "unknown operator: BOOLEAN + BOOLEAN",
+        ),
+        (
+            "if (10 > 1) {
+                if (10 > 1) {
+                    return true + false;
+                }
+                return 1;
+            }",
+            "unknown operator: BOOLEAN + BOOLEAN",
+        ),
+        ("foobar", "identifier not found: foobar"),
+        (
+            "let length = 1; lenght",
+            "identifier not found: lenght \u{2014} did you mean `length`?",
+        ),
+        (
+            "let lengths = 1; let length = 2; lenght",
+            "identifier not found: lenght \u{2014} did you mean `length` or `lengths`?",
+        ),
+        (
+            "putz",
+            "identifier not found: putz \u{2014} did you mean `puts`?",
+        ),
+    ];
+
+    for tt in tests.iter() {
+        let evaluated = test_eval(tt.0);
+        let err_obj = match &evaluated {
+            Value::Error(err_obj) => err_obj,
+            _ => panic!("no error object returned. got={:?}", evaluated),
+        };
+        assert!(
+            err_obj.message == tt.1,
+            "wrong error message. expected={}, got={}",
+            err_obj.message,
+            tt.1
+        );
+    }
+}
+
+#[test]
+fn test_arithmetic_errors() {
+    let tests = [
+        ("1 / 0", "division by zero: 1 / 0"),
+        (
+            "9223372036854775807 + 1",
+            "integer overflow: 9223372036854775807 + 1",
+        ),
+        (
+            "let min = -9223372036854775807 - 1; min / -1",
+            "integer overflow: -9223372036854775808 / -1",
+        ),
+        (
+            "let min = -9223372036854775807 - 1; -min",
+            "integer overflow: -(-9223372036854775808)",
+        ),
+    ];
+    for (input, expected) in tests.iter() {
+        match test_eval(input) {
+            Value::Error(err) => {
+                assert_eq!(err.kind, ARITHMETIC_ERROR);
+                assert_eq!(&err.message, expected);
+            }
+            evaluated => panic!("object is not Error. got={:?}", evaluated),
+        }
+    }
+    let input = "try { 1 / 0 } catch (e) { e.kind }";
+    match test_eval(input) {
+        Value::Str(kind) => assert_eq!(&*kind, ARITHMETIC_ERROR),
+        evaluated => panic!("object is not Str. got={:?}", evaluated),
+    }
+}
+
+#[test]
+fn test_let_statements() {
+    let tests = [
+        ("let a = 5; a;", 5),
+        ("let a = 5 * 5; a;", 25),
+        ("let a = 5; let b = a; b;", 5),
+        ("let a = 5; let b = a; let c = a + b + 5; c;", 15),
+    ];
+
+    for tt in tests.iter() {
+        test_integer_object(test_eval(tt.0), tt.1);
+    }
+}
+
+#[test]
+fn test_function_application() {
+    let tests = [
+        ("let identity = fn(x) { x; }; identity(5);", 5),
+        ("let identity = fn(x) { return x; }; identity(5);", 5),
+        ("let double = fn(x) { x * 2; }; double(5);", 10),
+        ("let add = fn(x, y) { x + y; }; add(5, 5);", 10),
+        ("let add = fn(x, y) { x + y; }; add(5 + 5, add(5, 5));", 20),
+        ("fn(x) { x; }(5)", 5),
+    ];
+
+    for tt in tests.iter() {
+        test_integer_object(test_eval(tt.0), tt.1);
+    }
+}
+
+#[test]
+fn test_closures() {
+    let input = "let newAdder = fn(x) {
+            fn(y) { x + y };
+        };
+        let addTwo = newAdder(2);
+        addTwo(2);
+        ";
+
+    test_integer_object(test_eval(input), 4);
+
+    // each closure keeps every scope around it alive, not just the nearest
+    let input = "let f = fn(a) { fn(b) { fn(c) { a + b + c } } }; f(1)(2)(3)";
+    test_integer_object(test_eval(input), 6);
+}
+
+#[test]
+fn test_lexical_addresses() {
+    let tests = [
+        ("let f = fn(x) { fn(y) { fn(z) { x + y + z } } }; let g = f(1); let h = g(2); h(3)", 6),
+        ("let x = 10; let f = fn(x) { x * 2 }; f(3) + x", 16),
+        ("let f = fn(a) { let a = a + 1; let a = a * 2; a }; f(1)", 4),
+        (
+            "let f = fn(n) { let even = fn(n) { if (n == 0) { 1 } else { odd(n - 1) } }; let odd = fn(n) { if (n == 0) { 0 } else { even(n - 1) } }; even(n) }; f(10)",
+            1,
+        ),
+        ("let x = 5; let f = fn() { let y = x; let x = 1; y + x }; f()", 6),
+        ("let f = fn(c) { if (c) { let y = 1; } y }; let y = 7; f(false)", 7),
+        ("let f = fn(a, b = a * 2) { a + b }; f(2) + f(b: 1, a: 1)", 8),
+        ("let f = fn([a, b], ...rest) { a + b + len(rest) }; f([1, 2], 3, 4)", 5),
+        ("let f = fn(x) { match (x) { [a, ...r] => a + len(r), _ => x } }; f([5, 6, 7])", 7),
+        ("let f = fn(x) { try { throw x; } catch (e) { len(e.message) + x } }; f(1)", 2),
+        ("let f = fn(x) { quote(unquote(x + 1)) }; len([f(1)])", 1),
+    ];
+    for tt in tests.iter() {
+        test_integer_object(test_eval(tt.0), tt.1);
+        test_integer_object(test_eval_unresolved(tt.0), tt.1);
+    }
+}
+
+#[test]
+fn test_function_arity() {
+    let tests = [
+        (
+            "let add = fn(x, y) { x + y; }; add(1);",
+            "wrong number of arguments: want=2, got=1",
+        ),
+        (
+            "let add = fn(x, y) { x + y; }; add(1, 2, 3);",
+            "wrong number of arguments: want=2, got=3",
+        ),
+        ("fn() { 1 }(1)", "wrong number of arguments: want=0, got=1"),
+        (
+            "let add = fn(x, y = 10) { x + y; }; add();",
+            "wrong number of arguments: want=1..2, got=0",
+        ),
+        (
+            "let add = fn(x, y = 10) { x + y; }; add(1, 2, 3);",
+            "wrong number of arguments: want=1..2, got=3",
+        ),
+        (
+            "let f = fn(x, ...rest) { x; }; f();",
+            "wrong number of arguments: want=1.., got=0",
+        ),
+    ];
+
+    for tt in tests.iter() {
+        let evaluated = test_eval(tt.0);
+        let err_obj = match &evaluated {
+            Value::Error(err_obj) => err_obj,
+            _ => panic!("no error object returned. got={:?}", evaluated),
+        };
+        assert!(
+            err_obj.message == tt.1,
+            "wrong error message. expected={}, got={}",
+            tt.1,
+            err_obj.message
+        );
+    }
+}
+
+#[test]
+fn test_default_parameters() {
+    let tests = [
+        ("let add = fn(x, y = 10) { x + y; }; add(1);", 11),
+        ("let add = fn(x, y = 10) { x + y; }; add(1, 2);", 3),
+        ("let f = fn(x, y = x * 2) { y; }; f(4);", 8),
+        ("let n = 7; let f = fn(x = n) { x; }; f();", 7),
+        ("let f = fn(x = 1, y = 2) { x - y; }; f(5);", 3),
+    ];
+
+    for tt in tests.iter() {
+        test_integer_object(test_eval(tt.0), tt.1);
+    }
+}
+
+#[test]
+fn test_rest_parameters() {
+    let tests = [
+        (
+            "let f = fn(first, ...rest) { rest; }; f(1, 2, 3);",
+            "[2, 3]",
+        ),
+        ("let f = fn(first, ...rest) { rest; }; f(1);", "[]"),
+        (
+            "let f = fn(...all) { all; }; f(1, true, 3 * 3);",
+            "[1, true, 9]",
+        ),
+        (
+            "let f = fn(x, y = 2, ...rest) { rest; }; f(1, 2, 3, 4);",
+            "[3, 4]",
+        ),
+    ];
+
+    for tt in tests.iter() {
+        let evaluated = test_eval(tt.0);
+        if !matches!(evaluated, Value::Array(_)) {
+            panic!("object is not Array. got={:?}", evaluated);
+        }
+        assert!(
+            evaluated.inspect() == tt.1,
+            "array has wrong elements. want={}, got={}",
+            tt.1,
+            evaluated.inspect()
+        );
+    }
+}
+
+#[test]
+fn test_named_arguments() {
+    let tests = [
+        ("let sub = fn(x, y) { x - y; }; sub(y: 1, x: 10);", 9),
+        ("let sub = fn(x, y) { x - y; }; sub(10, y: 1);", 9),
+        (
+            "let f = fn(x, y = 2, z = 3) { x * 100 + y * 10 + z; }; f(1, z: 9);",
+            129,
+        ),
+        ("let f = fn(x = 1, y = x + 1) { y; }; f(x: 5);", 6),
+    ];
+
+    for tt in tests.iter() {
+        test_integer_object(test_eval(tt.0), tt.1);
+    }
+}
+
+#[test]
+fn test_named_argument_errors() {
+    let tests = [
+        (
+            "let f = fn(x, y) { x; }; f(1, z: 2);",
+            "unknown named argument: z",
+        ),
+        (
+            "let f = fn(x, y) { x; }; f(1, x: 2);",
+            "duplicate argument: x",
+        ),
+        (
+            "let f = fn(x, y) { x; }; f(y: 1, y: 2);",
+            "duplicate argument: y",
+        ),
+        (
+            "let f = fn(x, y) { x; }; f(y: 1);",
+            "wrong number of arguments: want=2, got=1",
+        ),
+        (
+            "let f = fn(x, ...rest) { x; }; f(1, rest: 2);",
+            "unknown named argument: rest",
+        ),
+        ("len(x: 1)", "unknown named argument for len: x"),
+    ];
+
+    for tt in tests.iter() {
+        let evaluated = test_eval(tt.0);
+        let err_obj = match &evaluated {
+            Value::Error(err_obj) => err_obj,
+            _ => panic!("no error object returned. got={:?}", evaluated),
+        };
+        assert!(
+            err_obj.message == tt.1,
+            "wrong error message. expected={}, got={}",
+            tt.1,
+            err_obj.message
+        );
+    }
+}
+
+#[test]
+fn test_builtin_functions() {
+    let tests = [
+        ("len(fn(...r) { r }(1, 2, 3))", 3),
+        ("let args = fn(...r) { r }; len(args())", 0),
+    ];
+
+    for tt in tests.iter() {
+        test_integer_object(test_eval(tt.0), tt.1);
+    }
+
+    let evaluated = test_eval("len(1)");
+    let err_obj = match &evaluated {
+        Value::Error(err_obj) => err_obj,
+        _ => panic!("no error object returned. got={:?}", evaluated),
+    };
+    assert!(
+        err_obj.message == "argument to `len` not supported, got INTEGER",
+        "wrong error message. got={}",
+        err_obj.message
+    );
+}
+
+#[test]
+fn test_lambdas_and_pipelines() {
+    let tests = [
+        ("let double = |x| x * 2; double(4);", 8),
+        ("let double = x => x * 2; double(5);", 10),
+        ("let add = |x, y| { x + y }; add(1, 2);", 3),
+        ("let inc = |x, by = 1| x + by; inc(1) + inc(1, by: 10);", 13),
+        ("let double = |x| x * 2; 3 |> double;", 6),
+        ("let sub = |x, y| x - y; 10 |> sub(3);", 7),
+        (
+            "let add = |x, y| x + y; let double = x => x * 2; 1 |> add(2) |> double;",
+            6,
+        ),
+        ("let apply = |f, x| f(x); apply(x => x + 1, 41);", 42),
+        ("5 |> |x| x * x;", 25),
+        (
+            "let count = fn(...xs) { len(xs) }; let three = || 3; three() |> count(1, 2);",
+            3,
+        ),
+    ];
+
+    for tt in tests.iter() {
+        test_integer_object(test_eval(tt.0), tt.1);
+    }
+}
+
+#[test]
+fn test_strings_arrays_and_hashes() {
+    let tests = [
+        (r#"len("hello" + " " + "world")"#, 11),
+        ("[1, 2 * 2, 3 + 3][1]", 4),
+        ("let a = [1, 2, 3]; a[0] + a[2];", 4),
+        (r#"let h = {"one": 1, "two": 2}; h["two"];"#, 2),
+        ("{1: 10, true: 20}[true]", 20),
+    ];
+    for tt in tests.iter() {
+        test_integer_object(test_eval(tt.0), tt.1);
+    }
+
+    test_boolean_object(test_eval(r#""a" == "a""#), true);
+    test_boolean_object(test_eval(r#""a" != "a""#), false);
+    test_null_object(test_eval("[1, 2][2]"));
+    test_null_object(test_eval(r#"{"a": 1}["b"]"#));
+}
+
+#[test]
+fn test_match_expressions() {
+    let tests = [
+        ("match (1) { 1 => 10, _ => 20 }", 10),
+        ("match (5) { 1 => 10, _ => 20 }", 20),
+        ("match (-3) { -3 => 1, _ => 2 }", 1),
+        (r#"match ("b") { "a" => 1, "b" => 2 }"#, 2),
+        ("match (true) { false => 0, true => 1 }", 1),
+        ("match (7) { n => n * 2 }", 14),
+        ("match ([1, 2]) { [a] => a, [a, b] => a + b }", 3),
+        (
+            "match ([1, 2, 3, 4]) { [first, ...rest] => first + len(rest) }",
+            4,
+        ),
+        ("match ([[1, 2], 3]) { [[a, b], c] => a + b + c }", 6),
+        (
+            r#"match ({"x": 1, "y": 2}) { {"x": x, "y": y} => x + y }"#,
+            3,
+        ),
+        (r#"match ({"x": 1}) { {"y": y} => y, {"x": 1} => 9 }"#, 9),
+        ("match (5) { n if n > 10 => 1, n if n > 3 => 2, _ => 3 }", 2),
+        ("let n = 1; match (2) { x => x }; n;", 1),
+        (
+            "let f = fn(x) { match (x) { 0 => { return 100; }, _ => 1 }; 2 }; f(0);",
+            100,
+        ),
+    ];
+    for tt in tests.iter() {
+        test_integer_object(test_eval(tt.0), tt.1);
+    }
+}
+
+#[test]
+fn test_destructuring() {
+    let tests = [
+        ("let [a, b] = [1, 2]; a + b;", 3),
+        ("let [a, _, ...rest] = [1, 2, 3, 4]; a + len(rest);", 3),
+        (
+            r#"let {"x": x, "y": [y, z]} = {"x": 1, "y": [2, 3]}; x + y + z;"#,
+            6,
+        ),
+        ("let add = fn([a, b]) { a + b }; add([3, 4]);", 7),
+        (
+            r#"let get = fn({"x": x}, n) { x * n }; get({"x": 5}, 2);"#,
+            10,
+        ),
+        ("let first = |[head, ...tail]| head; first([9, 8]);", 9),
+    ];
+    for tt in tests.iter() {
+        test_integer_object(test_eval(tt.0), tt.1);
+    }
+}
+
+#[test]
+fn test_pattern_match_errors() {
+    let tests = [
+        (
+            "match (3) { 1 => 1, 2 => 2 }",
+            "no pattern matched value: 3",
+        ),
+        (
+            "match ([1, 2, 3]) { [a, b] => a }",
+            "no pattern matched value: [1, 2, 3]",
+        ),
+        (
+            r#"match ({"a": 1}) { n if false => n }"#,
+            "no pattern matched value: {a: 1}",
+        ),
+        (
+            "let [a, b] = [1];",
+            "pattern [a, b] does not match value: [1]",
+        ),
+        (
+            "let f = fn([a, b]) { a }; f(5);",
+            "pattern [a, b] does not match value: 5",
+        ),
+        ("{fn(x) { x }: 1}", "unusable as hash key: FUNCTION"),
+        ("5[0]", "index operator not supported: INTEGER"),
+        (r#""a" - "b""#, "unknown operator: STRING - STRING"),
+    ];
+    for tt in tests.iter() {
+        let evaluated = test_eval(tt.0);
+        let err = match &evaluated {
+            Value::Error(err) => err,
+            _ => panic!("no error object returned. got={:?}", evaluated),
+        };
+        assert!(
+            err.message == tt.1,
+            "wrong error message. expected={}, got={}",
+            tt.1,
+            err.message
+        );
+    }
+}
+
+#[test]
+fn test_try_catch() {
+    let tests = [
+        ("try { 1 } catch (e) { 2 }", 1),
+        (r#"try { throw "boom"; 1 } catch (e) { 2 }"#, 2),
+        (r#"try { throw "boom"; } catch { 3 }"#, 3),
+        ("try { 1 + true } catch (e) { 4 }", 4),
+        ("let f = fn() { throw 5; 1 }; try { f() } catch (e) { 6 }", 6),
+        ("let x = 0; try { 1 } finally { let x = 2 }; x", 2),
+        (
+            "let x = 0; try { throw 1 } catch (e) { 0 } finally { let x = 5 }; x",
+            5,
+        ),
+        ("try { 7 } finally { 8 }", 7),
+        ("let f = fn() { try { return 1; } finally { 2 } }; f();", 1),
+        ("let f = fn() { try { return 1; } finally { return 2; } }; f();", 2),
+        (
+            "let f = fn() { try { throw 1; } catch (e) { return 3; }; 4 }; f();",
+            3,
+        ),
+        (
+            "try { try { throw 1; } catch (e) { throw e; } } catch (e) { 9 }",
+            9,
+        ),
+        (r#"try { throw "x" } catch (e) { len(e.stack) }"#, 0),
+        (
+            "let g = fn() { 1 + true }; let f = fn() { g() }; try { f() } catch (e) { len(e.stack) }",
+            2,
+        ),
+    ];
+    for tt in tests.iter() {
+        test_integer_object(test_eval(tt.0), tt.1);
+    }
+}
+
+#[test]
+fn test_error_values() {
+    let tests = [
+        (r#"try { throw "boom"; } catch (e) { e.message }"#, "boom"),
+        (r#"try { throw "boom"; } catch (e) { e["kind"] }"#, "Error"),
+        ("try { 1 + true } catch (e) { e.message }", "type mismatch: INTEGER + BOOLEAN"),
+        ("try { 1 + true } catch (e) { e.kind }", "TypeError"),
+        ("try { foobar } catch (e) { e.kind }", "NameError"),
+        ("try { fn(x) { x }() } catch (e) { e.kind }", "ArgumentError"),
+        ("try { let [a] = 1; } catch (e) { e.kind }", "MatchError"),
+        (r#"try { len(1) } catch (e) { e.kind }"#, "TypeError"),
+        (
+            r#"try { throw {"message": "bad input", "kind": "ValueError"}; } catch (e) { e.kind + ": " + e.message }"#,
+            "ValueError: bad input",
+        ),
+        (
+            "let g = fn() { 1 + true }; let f = fn() { g() }; try { f() } catch (e) { e.stack[0] + \" <- \" + e.stack[1] }",
+            "g <- f",
+        ),
+    ];
+    for tt in tests.iter() {
+        let evaluated = test_eval(tt.0);
+        let string = match &evaluated {
+            Value::Str(string) => string,
+            _ => panic!("object is not Str. got={:?}", evaluated),
+        };
+        assert!(
+            string.as_ref() == tt.1,
+            "String has wrong value. got={}, want={}",
+            string,
+            tt.1
+        );
+    }
+}
+
+#[test]
+fn test_uncaught_errors() {
+    let tests = [
+        (r#"throw "boom";"#, "boom", "Error"),
+        ("throw 42; 1", "42", "Error"),
+        (r#"try { 1 } finally { throw "late" }"#, "late", "Error"),
+        (r#"try { throw "first" } finally { 1 }"#, "first", "Error"),
+        (
+            "try { 1 } catch (e) { 2 }; 1 + true",
+            "type mismatch: INTEGER + BOOLEAN",
+            "TypeError",
+        ),
+    ];
+    for tt in tests.iter() {
+        let evaluated = test_eval(tt.0);
+        let err = match &evaluated {
+            Value::Error(err) => err,
+            _ => panic!("no error object returned. got={:?}", evaluated),
+        };
+        assert!(
+            err.message == tt.1 && err.kind == tt.2,
+            "wrong error. expected={} {}, got={} {}",
+            tt.2,
+            tt.1,
+            err.kind,
+            err.message
+        );
+    }
+}
+
+#[test]
+fn test_error_spans() {
+    // errors point at the expression that raised them, not the calls around it
+    let tests = [
+        ("let f = fn(x) { x / 0 };\nf(1)", "1:19"),
+        ("1;\n  -true", "2:3"),
+        ("[1, 2][\"a\"]", "1:7"),
+        ("let x = 1;\nundefined", "2:1"),
+        ("let f = fn(a) { a };\nf(1, 2)", "2:2"),
+        ("len(1)", "1:4"),
+        ("match (3) { 1 => 1 }", "1:1"),
+        ("let f = fn() { throw \"boom\" };\nf()", "1:16"),
+    ];
+    for tt in tests.iter() {
+        match test_eval(tt.0) {
+            Value::Error(err) => assert_eq!(err.span.to_string(), tt.1, "{}", tt.0),
+            evaluated => panic!("no error object returned. got={:?}", evaluated),
+        }
+    }
+}
+
+#[test]
+fn test_deep_recursion() {
+    // on the test thread's own small stack
+    let sum = "let sum = fn(n) { if (n == 0) { 0 } else { n + sum(n - 1) } };";
+    test_integer_object(test_eval(&format!("{} sum(3000)", sum)), 4501500);
+    test_integer_object(test_eval(&format!("{} sum(9999)", sum)), 49995000);
+    match test_eval(&format!("{} sum(20000)", sum)) {
+        Value::Error(err) => assert!(
+            err.kind == "RecursionError" && err.message == "maximum recursion depth exceeded",
+            "wrong error. got={} {}",
+            err.kind,
+            err.message
+        ),
+        evaluated => panic!("no error object returned. got={:?}", evaluated),
+    }
+}
+
+#[test]
+fn test_quote_unquote() {
+    let tests = [
+        ("quote(5)", "5"),
+        ("quote(5 + 8)", "(5 + 8)"),
+        ("quote(foobar)", "foobar"),
+        ("quote(foobar + barfoo)", "(foobar + barfoo)"),
+        ("quote(unquote(4))", "4"),
+        ("quote(unquote(4 + 4))", "8"),
+        ("quote(8 + unquote(4 + 4))", "(8 + 8)"),
+        ("quote(unquote(4 + 4) + 8)", "(8 + 8)"),
+        ("let foobar = 8; quote(foobar)", "foobar"),
+        ("let foobar = 8; quote(unquote(foobar))", "8"),
+        ("quote(unquote(true))", "true"),
+        ("quote(unquote(true == false))", "false"),
+        ("quote(unquote(quote(4 + 4)))", "(4 + 4)"),
+        (
+            "let quoted = quote(4 + 4); quote(unquote(4 + 4) + unquote(quoted))",
+            "(8 + (4 + 4))",
+        ),
+        (r#"quote(unquote("a" + "b"))"#, r#""ab""#),
+        ("quote(unquote([1, 2]))", "[1, 2]"),
+        ("quote(fn(x) { unquote(1 + 1) })", "fn(x)2"),
+    ];
+    for tt in tests.iter() {
+        let evaluated = test_eval(tt.0);
+        let quote = match &evaluated {
+            Value::Quote(quote) => quote,
+            _ => panic!("expected Quote. got={:?}", evaluated),
+        };
+        assert!(
+            quote.string() == tt.1,
+            "not equal. got={}, want={}",
+            quote.string(),
+            tt.1
+        );
+    }
+
+    let evaluated = test_eval("quote(unquote(fn(x) { x }))");
+    let err = match &evaluated {
+        Value::Error(err) => err,
+        _ => panic!("no error object returned. got={:?}", evaluated),
+    };
+    assert!(
+        err.message == "cannot unquote value of type FUNCTION",
+        "wrong error message. got={}",
+        err.message
+    );
+}
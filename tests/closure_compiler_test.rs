@@ -0,0 +1,146 @@
+extern crate waiir;
+use std::cell::*;
+use std::rc::*;
+use waiir::ast::*;
+use waiir::closure_compiler::*;
+use waiir::environment::*;
+use waiir::evaluator::{ExecutionLimits, InterruptHandle};
+use waiir::lexer::*;
+use waiir::object::*;
+use waiir::parser::*;
+use waiir::resolver::*;
+
+fn test_eval(input: &str) -> Value {
+    let env = Rc::new(RefCell::new(new_environment()));
+    let mut l = Lexer::new(input);
+    let mut p = Parser::new(&mut l);
+    let mut program = p.parse_program().unwrap();
+    resolve_addresses(&mut program);
+
+    eval(&program, &env).unwrap()
+}
+
+fn test_eval_unresolved(input: &str) -> Value {
+    let env = Rc::new(RefCell::new(new_environment()));
+    let mut l = Lexer::new(input);
+    let mut p = Parser::new(&mut l);
+    let program = p.parse_program().unwrap();
+
+    eval(&program, &env).unwrap()
+}
+
+fn test_eval_with_limits(input: &str, limits: &ExecutionLimits) -> Value {
+    let env = Rc::new(RefCell::new(new_environment()));
+    let mut l = Lexer::new(input);
+    let mut p = Parser::new(&mut l);
+    let mut program = p.parse_program().unwrap();
+    resolve_addresses(&mut program);
+
+    eval_with_limits(&program, &env, limits).unwrap()
+}
+
+mod behaviour;
+use behaviour::*;
+
+#[test]
+fn test_function_object() {
+    let input = "fn(x) { x + 2; };";
+    let evaluated = test_eval(input);
+    let func = match &evaluated {
+        Value::Function(func) => func,
+        _ => panic!("object is not FUNCTION. got={:?}", evaluated),
+    };
+
+    assert!(
+        func.function_literal.parameters.len() == 1,
+        "function has wrong parameters. parameters={:?}",
+        func.function_literal.parameters
+    );
+    assert!(func.compiled.is_some(), "function body was not compiled");
+}
+
+#[test]
+fn test_program_code_runs_again() {
+    let env = Rc::new(RefCell::new(new_environment()));
+    let mut l = Lexer::new("let x = if (x) { x + 1 } else { 1 }; x;");
+    let mut p = Parser::new(&mut l);
+    let program = p.parse_program().unwrap();
+    let code = compile(&program);
+
+    env.borrow_mut().set(String::from("x"), Value::Null);
+    for expected in 1..4 {
+        test_integer_object(code.run(&env).unwrap(), expected);
+    }
+}
+
+#[test]
+fn test_host_function_named_arguments() {
+    let env = Rc::new(RefCell::new(new_environment()));
+    env.borrow_mut().set(
+        String::from("connect"),
+        Value::Builtin(Rc::new(Builtin::new("connect", |args, named| {
+            let port = match named.get("port") {
+                Some(Value::Integer(port)) => *port,
+                _ => 0,
+            };
+            Value::Integer(args.len() as i64 * 100_000 + named.len() as i64 * 10_000 + port)
+        }))),
+    );
+    let mut l = Lexer::new("connect(1, port: 5432, retries: 3)");
+    let mut p = Parser::new(&mut l);
+    let program = p.parse_program().unwrap();
+
+    test_integer_object(eval(&program, &env).unwrap(), 125432);
+}
+
+// Run with `cargo test --release --test closure_compiler_test -- --ignored --nocapture`.
+#[test]
+#[ignore]
+fn bench_fib() {
+    let input = "
+let fib = fn(n) { if (n < 2) { n } else { fib(n - 1) + fib(n - 2) } };
+fib(25);
+";
+    let start = std::time::Instant::now();
+    let evaluated = test_eval(input);
+    let elapsed = start.elapsed();
+    test_integer_object(evaluated, 75025);
+    eprintln!("fib(25) took {:?}", elapsed);
+}
+
+#[test]
+fn test_interrupt_handle() {
+    let interrupt = InterruptHandle::new();
+    let limits = ExecutionLimits {
+        interrupt: Some(interrupt.clone()),
+        ..ExecutionLimits::default()
+    };
+    let raise = interrupt.clone();
+    let raiser = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        raise.interrupt();
+    });
+    // runs for ages unless interrupted; `catch` and `finally` do not see it
+    let input = "let f = fn(n) { if (n == 0) { 0 } else { f(n - 1) + f(n - 1) } }; try { f(40) } catch (e) { 1 } finally { 2 }";
+    match test_eval_with_limits(input, &limits) {
+        Value::Error(err) => {
+            assert_eq!(err.kind, INTERRUPT_ERROR);
+            assert_eq!(err.message, "interrupted");
+        }
+        evaluated => panic!("object is not Error. got={:?}", evaluated),
+    }
+    raiser.join().unwrap();
+    interrupt.reset();
+    test_integer_object(test_eval_with_limits("1 + 1", &limits), 2);
+}
+
+#[test]
+fn test_max_depth_beyond_the_thread_stack() {
+    // calls run on stack the engine grows, not the test thread's own
+    let limits = ExecutionLimits {
+        max_depth: 1_000_000,
+        ..ExecutionLimits::default()
+    };
+    let input = "let sum = fn(n) { if (n == 0) { 0 } else { n + sum(n - 1) } }; sum(100000)";
+    test_integer_object(test_eval_with_limits(input, &limits), 5000050000);
+}
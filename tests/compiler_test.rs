@@ -0,0 +1,139 @@
+extern crate waiir;
+use waiir::code::*;
+use waiir::compiler::*;
+use waiir::lexer::*;
+use waiir::parser::*;
+
+fn test_compile(input: &str) -> Bytecode {
+    let mut l = Lexer::new(input);
+    let mut p = Parser::new(&mut l);
+    let program = p.parse_program().unwrap();
+    Compiler::new().compile(&program).unwrap()
+}
+
+#[test]
+fn test_make() {
+    let tests: [(Opcode, &[usize], &[u8]); 4] = [
+        (
+            Opcode::Constant,
+            &[65534],
+            &[Opcode::Constant as u8, 255, 254],
+        ),
+        (Opcode::Add, &[], &[Opcode::Add as u8]),
+        (
+            Opcode::Call,
+            &[2, 1, 258],
+            &[Opcode::Call as u8, 2, 1, 1, 2],
+        ),
+        (
+            Opcode::JumpIfBound,
+            &[1, 9],
+            &[Opcode::JumpIfBound as u8, 0, 1, 0, 9],
+        ),
+    ];
+    for tt in tests.iter() {
+        let instruction = make(tt.0, tt.1);
+        assert!(
+            instruction == tt.2,
+            "wrong instruction for {:?}. got={:?}, want={:?}",
+            tt.0,
+            instruction,
+            tt.2
+        );
+        let (operands, read) = read_operands(tt.0, &instruction[1..]);
+        assert!(
+            operands == tt.1 && read == instruction.len() - 1,
+            "wrong operands for {:?}. got={:?} ({} bytes)",
+            tt.0,
+            operands,
+            read
+        );
+    }
+}
+
+#[test]
+fn test_disassemble() {
+    let mut instructions = Vec::new();
+    instructions.extend(make(Opcode::Constant, &[1]));
+    instructions.extend(make(Opcode::GetLocal, &[2]));
+    instructions.extend(make(Opcode::Call, &[2, 0, 3]));
+    instructions.extend(make(Opcode::ReturnValue, &[]));
+    let expected = "0000 Constant 1
+0003 GetLocal 2
+0006 Call 2 0 3
+0011 ReturnValue
+";
+    let got = disassemble(&instructions);
+    assert!(got == expected, "wrong disassembly. got=\n{}", got);
+}
+
+#[test]
+fn test_compile_program() {
+    let tests = [
+        (
+            "1 + 2",
+            "0000 Constant 0
+0003 Constant 1
+0006 Add
+0007 ReturnValue
+",
+        ),
+        (
+            "let x = 1; x; x",
+            "0000 Constant 0
+0003 SetGlobal 0
+0006 GetGlobal 0
+0009 Pop
+0010 GetGlobal 0
+0013 ReturnValue
+",
+        ),
+        (
+            "if (true) { 10 }",
+            "0000 True
+0001 JumpNotTruthy 10
+0004 Constant 0
+0007 Jump 11
+0010 Null
+0011 ReturnValue
+",
+        ),
+        (
+            "let x = 1;",
+            "0000 Constant 0
+0003 SetGlobal 0
+0006 Return
+",
+        ),
+    ];
+    for tt in tests.iter() {
+        let bytecode = test_compile(tt.0);
+        let got = disassemble(&bytecode.main.instructions);
+        assert!(
+            got == tt.1,
+            "wrong instructions for {}. got=\n{}",
+            tt.0,
+            got
+        );
+    }
+}
+
+#[test]
+fn test_compile_closures() {
+    let bytecode = test_compile("fn(x) { fn(y) { x + y } }");
+    let outer = &bytecode.main.functions[0];
+    assert!(
+        outer.upvalue_names == ["x"],
+        "x is not moved to an upvalue. got={:?}",
+        outer.upvalue_names
+    );
+    let inner = &outer.functions[0];
+    let free: Vec<&str> = inner.free.iter().map(|(name, _)| name.as_str()).collect();
+    assert!(free == ["x"], "wrong free variables. got={:?}", free);
+    let got = disassemble(&inner.instructions);
+    assert!(
+        got.contains("GetFree 0\n") && got.contains("GetLocal 0\n"),
+        "wrong instructions. got=\n{}",
+        got
+    );
+}
@@ -0,0 +1,242 @@
+extern crate waiir;
+use waiir::diagnostics::*;
+use waiir::lexer::*;
+use waiir::parser::*;
+use waiir::resolver;
+
+fn test_parse_diagnostics(input: &str) -> Vec<Diagnostic> {
+    let mut l = Lexer::new(input);
+    let mut p = Parser::new(&mut l);
+    p.parse_program().unwrap();
+    p.diagnostics().to_vec()
+}
+
+#[test]
+fn test_parser_diagnostics() {
+    let tests = [
+        (
+            "let x = (1;",
+            "1:11: expected next token to be ')', got ';' instead",
+            1,
+        ),
+        (
+            "let f = fn(a) {\n  a\n};\nlet 5;",
+            "4:5: expected next token to be identifier, got integer instead",
+            1,
+        ),
+        (
+            "import \"lib.mk\" as 5;",
+            "1:20: expected next token to be identifier, got integer instead",
+            1,
+        ),
+        ("let x: strng = 1;", "1:8: unknown type name: strng", 5),
+        (
+            "import lib;",
+            "1:8: expected next token to be string, got identifier instead",
+            3,
+        ),
+    ];
+    for tt in tests.iter() {
+        let diagnostics = test_parse_diagnostics(tt.0);
+        assert_eq!(diagnostics[0].to_string(), tt.1, "wrong error for {}", tt.0);
+        assert_eq!(diagnostics[0].width, tt.2, "wrong width for {}", tt.0);
+        assert!(diagnostics[0].is_error());
+    }
+
+    let diagnostics = test_parse_diagnostics("f(a: 1, 2)");
+    assert_eq!(
+        diagnostics[0].help,
+        ["pass positional arguments before named ones"]
+    );
+}
+
+#[test]
+fn test_render() {
+    let diagnostics = test_parse_diagnostics("let x = 1;\nlet y = (x;\n");
+    let renderer = Renderer::new("main.mk", "let x = 1;\nlet y = (x;\n");
+    let expected = "error: expected next token to be ')', got ';' instead
+ --> main.mk:2:11
+  |
+2 | let y = (x;
+  |           ^
+";
+    assert_eq!(renderer.render(&diagnostics[0]), expected);
+
+    // the word at the span is underlined when the width is not known, and
+    // tabs are kept so that the underline lines up
+    let source = "let f = fn() {\n\tlet unused = 1;\n};";
+    let diagnostic = Diagnostic::warning(Span { line: 2, column: 6 }, "unused variable: unused")
+        .with_note("a note")
+        .with_help("a help");
+    let expected = "warning: unused variable: unused
+ --> main.mk:2:6
+  |
+2 | \tlet unused = 1;
+  | \t    ^^^^^^
+  = note: a note
+  = help: a help
+";
+    assert_eq!(
+        Renderer::new("main.mk", source).render(&diagnostic),
+        expected
+    );
+
+    let coloured = Renderer::new("main.mk", source)
+        .with_colour(true)
+        .render(&diagnostic);
+    assert!(
+        coloured.starts_with("\x1b[1;33mwarning\x1b[0m"),
+        "{}",
+        coloured
+    );
+    assert!(coloured.contains("\x1b[1;33m^^^^^^\x1b[0m"), "{}", coloured);
+
+    // without source, as for a precompiled program, only the position
+    let expected = "warning: unused variable: unused
+--> main.mkc:2:6
+ = note: a note
+ = help: a help
+";
+    assert_eq!(Renderer::new("main.mkc", "").render(&diagnostic), expected);
+}
+
+#[test]
+fn test_render_resolver_diagnostics() {
+    let input = "let f = fn(a, b) { a + c };";
+    let mut l = Lexer::new(input);
+    let mut p = Parser::new(&mut l);
+    let program = p.parse_program().unwrap();
+    let diagnostics: Vec<Diagnostic> = resolver::resolve_program(&program)
+        .iter()
+        .map(Diagnostic::from)
+        .collect();
+    let rendered: Vec<String> = diagnostics
+        .iter()
+        .map(|d| Renderer::new("main.mk", input).render(d))
+        .collect();
+    assert_eq!(
+        rendered.join(""),
+        "warning: unused parameter: b
+ --> main.mk:1:15
+  |
+1 | let f = fn(a, b) { a + c };
+  |               ^
+  = help: start the name with `_` if it is meant to be unused
+error: undefined name: c
+ --> main.mk:1:24
+  |
+1 | let f = fn(a, b) { a + c };
+  |                        ^
+"
+    );
+}
+
+#[test]
+fn test_json() {
+    let source = "let s = \"a\\tb\";\nlet 5;";
+    let diagnostics = test_parse_diagnostics(source);
+    let diagnostics = [
+        diagnostics[0].clone(),
+        Diagnostic::warning(Span { line: 1, column: 5 }, "say \"hi\"").with_note("tab\there"),
+    ];
+    let json = Renderer::new("dir\\main.mk", source).json(&diagnostics);
+    assert_eq!(
+        json,
+        concat!(
+            "[{\"file\":\"dir\\\\main.mk\",\"severity\":\"error\",",
+            "\"message\":\"expected next token to be identifier, got integer instead\",",
+            "\"line\":2,\"column\":5,\"width\":1,\"notes\":[],\"help\":[]},",
+            "{\"file\":\"dir\\\\main.mk\",\"severity\":\"warning\",\"message\":\"say \\\"hi\\\"\",",
+            "\"line\":1,\"column\":5,\"width\":1,\"notes\":[\"tab\\there\"],\"help\":[]}]"
+        )
+    );
+    assert_eq!(Renderer::new("main.mk", "").json(&[]), "[]");
+}
+
+#[test]
+fn test_edit_distance() {
+    let tests = [
+        ("", "", 0),
+        ("abc", "", 3),
+        ("kitten", "sitting", 3),
+        ("lenght", "length", 1),
+        ("puts", "puts", 0),
+        ("ab", "ba", 1),
+    ];
+    for tt in tests.iter() {
+        assert_eq!(edit_distance(tt.0, tt.1), tt.2, "{} -> {}", tt.0, tt.1);
+        assert_eq!(edit_distance(tt.1, tt.0), tt.2, "{} -> {}", tt.1, tt.0);
+    }
+
+    let names = ["length", "lengths", "width", "len", "x"];
+    assert_eq!(closest_names("lenght", names), ["length", "lengths"]);
+    assert_eq!(closest_names("widht", names), ["width"]);
+    // too short to guess from, or too far from everything
+    assert!(closest_names("y", names).is_empty());
+    assert!(closest_names("height", names).is_empty());
+
+    assert_eq!(
+        did_you_mean("widht", names),
+        " \u{2014} did you mean `width`?"
+    );
+    assert_eq!(
+        did_you_mean("lenght", names),
+        " \u{2014} did you mean `length` or `lengths`?"
+    );
+    assert_eq!(did_you_mean("height", names), "");
+}
+
+#[test]
+fn test_parser_hints() {
+    let tests = [
+        (
+            "if (x = 1) { 2 }",
+            "1:7: `=` in an if condition",
+            "use `==` to compare values",
+        ),
+        (
+            "let f = function(x) { x + 1 }; f(1);",
+            "1:9: `function` is not a keyword",
+            "functions are written `fn(x) { ... }`",
+        ),
+        (
+            "let x = 1; x = 2;",
+            "1:14: no prefix parse function for '=' found",
+            "names are bound with `let name = value`, compared with `==`",
+        ),
+    ];
+    for tt in tests.iter() {
+        let diagnostics = test_parse_diagnostics(tt.0);
+        // the parser reads on as if the mistake was not made
+        assert_eq!(
+            diagnostics.len(),
+            1,
+            "wrong errors for {}: {:?}",
+            tt.0,
+            diagnostics
+        );
+        assert_eq!(diagnostics[0].to_string(), tt.1);
+        assert_eq!(diagnostics[0].help, [tt.2]);
+    }
+
+    // `function` followed by arguments is a call of whatever has that name
+    assert!(test_parse_diagnostics("let function = fn(x) { x }; function(1)").is_empty());
+    assert!(test_parse_diagnostics("if (function(1)) { 2 }").is_empty());
+
+    // statements on one line need no `;` unless the second fails to parse
+    assert!(test_parse_diagnostics("let x = 5 let y = 6").is_empty());
+    // nor does a statement that failed itself
+    let diagnostics = test_parse_diagnostics("let x = 5 (]");
+    assert!(
+        diagnostics.iter().all(|d| d.help.is_empty()),
+        "{:?}",
+        diagnostics
+    );
+    let diagnostics = test_parse_diagnostics("let x = [1] ]; let y = 2 )");
+    let hinted: Vec<usize> = diagnostics
+        .iter()
+        .filter(|d| d.help == ["a `;` may be missing before this statement"])
+        .map(|d| d.span.column)
+        .collect();
+    assert_eq!(hinted, [13, 26], "{:?}", diagnostics);
+}
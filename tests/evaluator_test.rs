@@ -1,276 +1,397 @@
 extern crate waiir;
+use std::cell::*;
+use std::rc::*;
 use waiir::ast::*;
 use waiir::environment::*;
 use waiir::evaluator::*;
 use waiir::lexer::*;
 use waiir::object::*;
 use waiir::parser::*;
+use waiir::resolver::*;
 
-fn test_eval(input: &str) -> Box<dyn Object> {
-    let mut env = new_environment();
+fn test_eval(input: &str) -> Value {
+    let env = Rc::new(RefCell::new(new_environment()));
+    let mut l = Lexer::new(input);
+    let mut p = Parser::new(&mut l);
+    let mut program = p.parse_program().unwrap();
+    resolve_addresses(&mut program);
+
+    eval(&program, &env).unwrap()
+}
+
+fn test_eval_unresolved(input: &str) -> Value {
+    let env = Rc::new(RefCell::new(new_environment()));
     let mut l = Lexer::new(input);
     let mut p = Parser::new(&mut l);
     let program = p.parse_program().unwrap();
 
-    eval(&program, &mut env).unwrap()
+    eval(&program, &env).unwrap()
 }
 
+mod behaviour;
+use behaviour::*;
+
 #[test]
-fn test_eval_integer_expression() {
-    let tests = [
-        ("5", 5),
-        ("10", 10),
-        ("-5", -5),
-        ("-10", -10),
-        ("5 + 5 + 5 + 5 - 10", 10),
-        ("2 * 2 * 2 * 2 * 2", 32),
-        ("-50 + 100 + -50", 0),
-        ("5 * 2 + 10", 20),
-        ("5 + 2 * 10", 25),
-        ("20 + 2 * -10", 0),
-        ("50 / 2 * 2 + 10", 60),
-        ("2 * (5 + 10)", 30),
-        ("3 * 3 * 3 + 10", 37),
-        ("3 * (3 * 3) + 10", 37),
-        ("(5 + 10 * 2 + 15 / 3) * 2 + -10", 50),
-    ];
-    for tt in tests.iter() {
-        let evaluated = test_eval(tt.0);
-        test_integer_object(evaluated, tt.1);
-    }
-}
+fn test_function_object() {
+    let input = "fn(x) { x + 2; };";
+    let evaluated = test_eval(input);
+    let func = match &evaluated {
+        Value::Function(func) => func,
+        _ => panic!("object is not FUNCTION. got={:?}", evaluated),
+    };
 
-fn test_integer_object(obj: Box<dyn Object>, expected: i64) {
-    let result = obj
-        .as_any()
-        .downcast_ref::<Integer>()
-        .expect(&format!("object is not Integer. got={:?}", obj));
+    assert!(
+        func.function_literal.parameters.len() == 1,
+        "function has wrong parameters. parameters={:?}",
+        func.function_literal.parameters
+    );
 
     assert!(
-        result.value == expected,
-        "object has wrong value. got={}, want={}",
-        result.value,
-        expected
+        func.function_literal.parameters[0].string() == "x",
+        "parameter is not 'x'. got={:?}",
+        func.function_literal.parameters[0]
     );
-}
 
-#[test]
-fn test_eval_boolean_expression() {
-    let tests = [
-        ("true", true),
-        ("false", false),
-        ("1 < 2", true),
-        ("1 > 2", false),
-        ("1 < 1", false),
-        ("1 > 1", false),
-        ("1 == 1", true),
-        ("1 != 1", false),
-        ("1 == 2", false),
-        ("1 != 2", true),
-        ("true == false", false),
-        ("true != false", true),
-        ("false != true", true),
-        ("(1 < 2) == true", true),
-        ("(1 < 2) == false", false),
-        ("(1 > 2) == true", false),
-        ("(1 > 2) == false", true),
-    ];
-    for tt in tests.iter() {
-        let evaluated = test_eval(tt.0);
-        test_boolean_object(evaluated, tt.1);
-    }
+    let expected_body = "(x + 2)";
+    assert!(
+        func.function_literal.body.string() == expected_body,
+        "body is not {}. got={}",
+        expected_body,
+        func.function_literal.body.string()
+    );
 }
 
-fn test_boolean_object(obj: Box<dyn Object>, expected: bool) {
-    let result = obj
-        .as_any()
-        .downcast_ref::<waiir::object::Boolean>()
-        .expect(&format!("object is not Boolean. got={:?}", obj));
-    assert!(
-        result.value == expected,
-        "object has wrong value. got={}, want={}",
-        result.value,
-        expected
+#[test]
+fn test_host_function_named_arguments() {
+    let env = Rc::new(RefCell::new(new_environment()));
+    env.borrow_mut().set(
+        String::from("connect"),
+        Value::Builtin(Rc::new(Builtin::new("connect", |args, named| {
+            let port = match named.get("port") {
+                Some(Value::Integer(port)) => *port,
+                _ => 0,
+            };
+            Value::Integer(args.len() as i64 * 100_000 + named.len() as i64 * 10_000 + port)
+        }))),
     );
+    let mut l = Lexer::new("connect(1, port: 5432, retries: 3)");
+    let mut p = Parser::new(&mut l);
+    let program = p.parse_program().unwrap();
+
+    test_integer_object(eval(&program, &env).unwrap(), 125432);
 }
 
 #[test]
-fn test_bang_operator() {
+fn test_tail_calls_run_in_constant_stack() {
     let tests = [
-        ("!true", false),
-        ("!false", true),
-        ("!5", false),
-        ("!!true", true),
-        ("!!false", false),
-        ("!!5", true),
+        (
+            "let count = fn(n, acc) { if (n == 0) { acc } else { count(n - 1, acc + 1) } }; count(20000, 0)",
+            20000,
+        ),
+        (
+            "let count = fn(n) { if (n == 0) { return 0; } return count(n - 1); }; count(20000)",
+            0,
+        ),
+        (
+            "let count = fn(n) { match (n) { 0 => 7, _ => count(n - 1) } }; count(20000)",
+            7,
+        ),
+        (
+            "let even = fn(n) { if (n == 0) { 1 } else { odd(n - 1) } }; let odd = fn(n) { if (n == 0) { 0 } else { even(n - 1) } }; even(20001)",
+            0,
+        ),
+        (
+            "let count = fn(n, acc = 0) { if (n > 0) { return count(n - 1, acc: acc + 2); }; acc }; count(20000)",
+            40000,
+        ),
     ];
     for tt in tests.iter() {
-        let evaluated = test_eval(tt.0);
-        test_boolean_object(evaluated, tt.1);
+        test_integer_object(test_eval(tt.0), tt.1);
     }
 }
 
 #[test]
-fn test_if_else_expressions() {
-    let tests: [(&str, Box<dyn Object>); 7] = [
-        ("if (true) { 10 }", Box::new(Integer { value: 10 })),
-        ("if (false) { 10 }", Box::new(Null {})),
-        ("if (1) { 10 }", Box::new(Integer { value: 10 })),
-        ("if (1 < 2) { 10 }", Box::new(Integer { value: 10 })),
-        ("if (1 > 2) { 10 }", Box::new(Null {})),
+fn test_tail_calls_keep_error_stack() {
+    let tests = [
+        (
+            "let g = fn(n) { if (n == 0) { 1 + true } else { g(n - 1) } }; let f = fn() { g(3) }; try { f() } catch (e) { len(e.stack) }",
+            5,
+        ),
         (
-            "if (1 > 2) { 10 } else { 20 }",
-            Box::new(Integer { value: 20 }),
+            "let g = fn(n) { if (n == 0) { throw 1 } else { return g(n - 1); } }; let f = fn() { g(2) }; try { f() } catch (e) { e.stack[3] == \"f\" }",
+            1,
         ),
+        // the call is not in tail position inside `try`
         (
-            "if (1 < 2) { 10 } else { 20 }",
-            Box::new(Integer { value: 10 }),
+            "let f = fn() { try { g() } catch (e) { 2 } }; let g = fn() { throw 1 }; f()",
+            2,
         ),
     ];
-
     for tt in tests.iter() {
-        let evaluated = test_eval(tt.0);
-        match tt.1.as_any().downcast_ref::<Integer>() {
-            Some(integer) => test_integer_object(evaluated, integer.value),
-            _ => test_null_object(evaluated),
+        match test_eval(tt.0) {
+            Value::Boolean(value) => assert!(value, "wrong result for {}", tt.0),
+            evaluated => test_integer_object(evaluated, tt.1),
         }
     }
 }
 
-fn test_null_object(obj: Box<dyn Object>) {
-    obj.as_any()
-        .downcast_ref::<Null>()
-        .expect(&format!("object is not NULL. got={:?}", obj));
-}
-
 #[test]
-fn test_return_statement() {
+fn test_stack_traces() {
+    let input = "let g = fn(n) {
+  if (n == 0) { throw \"boom\" } else { g(n - 1) }
+};
+let f = fn(a, b) { let x = g(4); x };
+let h = f;
+h(1, b: 2);";
+    let err = match test_eval(input) {
+        Value::Error(err) => err,
+        evaluated => panic!("object is not Error. got={:?}", evaluated),
+    };
+    let frames: Vec<(&str, usize, usize, usize)> = err
+        .stack
+        .iter()
+        .map(|frame| {
+            (
+                frame.name.as_str(),
+                frame.span.line,
+                frame.span.column,
+                frame.args,
+            )
+        })
+        .collect();
+    let mut expected = vec![("g", 2, 40, 1); 4];
+    expected.push(("g", 4, 29, 1));
+    // an alias is called by the name the function was bound with
+    expected.push(("f", 6, 2, 2));
+    assert_eq!(frames, expected);
+
+    let expected = "Traceback (most recent call last):
+  line 6, column 2, in f (2 arguments)
+  line 4, column 29, in g (1 argument)
+  line 2, column 40, in g (1 argument)
+  line 2, column 40, in g (1 argument)
+  line 2, column 40, in g (1 argument)
+  [Previous line repeated 1 more time]
+Error: boom";
+    assert_eq!(err.traceback(), expected);
+
     let tests = [
-        ("return 10;", 10),
-        ("return 10; 9;", 10),
-        ("return 2 * 5; 9;", 10),
-        ("9; return 2 * 5; 9;", 10),
+        // the stack is taken where the error is raised, calls below the
+        // handler included
+        (
+            "let g = fn() { throw 1 }; let f = fn() { try { g() } catch (e) { len(e.stack) } }; f()",
+            2,
+        ),
+        // a rethrown error keeps its stack
+        (
+            "let g = fn() { throw 1 }; let f = fn() { try { g() } catch (e) { throw e } }; let k = fn() { f() }; try { k() } catch (e) { len(e.stack) }",
+            3,
+        ),
         (
-            "if (10 > 1) { if (10 > 1) {
-            return 10; }
-            return 1; }",
-            10,
+            "let f = fn() { len(1) }; try { f() } catch (e) { e.stack[0] == \"len\" }",
+            1,
         ),
     ];
-
     for tt in tests.iter() {
-        let evaluated = test_eval(tt.0);
-        test_integer_object(evaluated, tt.1);
+        match test_eval(tt.0) {
+            Value::Boolean(value) => assert!(value, "wrong result for {}", tt.0),
+            evaluated => test_integer_object(evaluated, tt.1),
+        }
     }
 }
 
+fn test_eval_with_max_depth(input: &str, max_depth: usize) -> Value {
+    let env = Rc::new(RefCell::new(new_environment()));
+    let mut l = Lexer::new(input);
+    let mut p = Parser::new(&mut l);
+    let mut program = p.parse_program().unwrap();
+    resolve_addresses(&mut program);
+
+    eval_with_max_depth(&program, &env, max_depth).unwrap()
+}
+
 #[test]
-fn test_error_handling() {
+fn test_deep_recursion() {
     let tests = [
-        ("5 + true;", "type mismatch: INTEGER + BOOLEAN"),
-        ("5 + true; 5;", "type mismatch: INTEGER + BOOLEAN"),
-        ("-true", "unknown operator: -BOOLEAN"),
-        ("true + false;", "unknown operator: BOOLEAN + BOOLEAN"),
-        ("5; true + false; 5", "unknown operator: BOOLEAN + BOOLEAN"),
         (
-            "if (10 > 1) { true + false; }",
-            "unknown operator: BOOLEAN + BOOLEAN",
+            "let depth = fn(n) { if (n == 0) { 0 } else { 1 + depth(n - 1) } }; depth(30000)",
+            30000,
         ),
         (
-            "if (10 > 1) {
-                if (10 > 1) {
-                    return true + false;
-                }
-                return 1;
-            }",
-            "unknown operator: BOOLEAN + BOOLEAN",
+            "let f = fn(n) { match (n) { 0 => 0, _ => 1 + g(n - 1) } }; let g = fn(n) { if (n == 0) { 0 } else { 2 + f(n - 1) } }; f(20000)",
+            30000,
+        ),
+        (
+            "let depth = fn(n) { try { if (n == 0) { 0 } else { 1 + depth(n - 1) } } finally { n } }; depth(20000)",
+            20000,
         ),
-        ("foobar", "identifier not found: foobar"),
     ];
-
     for tt in tests.iter() {
-        let evaluated = test_eval(tt.0);
-        let err_obj = evaluated
-            .as_any()
-            .downcast_ref::<Error>()
-            .expect(&format!("no error object returned. got={:?}", evaluated));
-        assert!(
-            err_obj.message == tt.1,
-            "wrong error message. expected={}, got={}",
-            err_obj.message,
-            tt.1
-        );
+        test_integer_object(test_eval_with_max_depth(tt.0, 100_000), tt.1);
     }
 }
 
 #[test]
-fn test_let_statements() {
+fn test_recursion_depth_limit() {
+    let input = "let depth = fn(n) { if (n == 0) { 0 } else { 1 + depth(n - 1) } }; depth(100)";
+    test_integer_object(test_eval_with_max_depth(input, 101), 100);
+    match test_eval_with_max_depth(input, 100) {
+        Value::Error(err) => {
+            assert_eq!(err.message, "maximum recursion depth exceeded");
+            assert_eq!(err.kind, RECURSION_ERROR);
+            assert_eq!(err.stack.len(), 101);
+        }
+        evaluated => panic!("object is not Error. got={:?}", evaluated),
+    }
+
     let tests = [
-        ("let a = 5; a;", 5),
-        ("let a = 5 * 5; a;", 25),
-        ("let a = 5; let b = a; b;", 5),
-        ("let a = 5; let b = a; let c = a + b + 5; c;", 15),
+        (
+            "let f = fn(n) { 1 + f(n + 1) }; try { f(0) } catch (e) { e.kind == \"RecursionError\" }",
+            1,
+        ),
+        // the depth is back to normal once the error is caught
+        (
+            "let f = fn(n) { if (n == 0) { 0 } else { 1 + f(n - 1) } }; try { f(1000000) } catch (e) { f(50) }",
+            50,
+        ),
+        // tail calls do not count
+        (
+            "let count = fn(n) { if (n == 0) { 0 } else { count(n - 1) } }; count(20000)",
+            0,
+        ),
     ];
-
     for tt in tests.iter() {
-        test_integer_object(test_eval(tt.0), tt.1);
+        match test_eval(tt.0) {
+            Value::Boolean(value) => assert!(value, "wrong result for {}", tt.0),
+            evaluated => test_integer_object(evaluated, tt.1),
+        }
     }
 }
 
-#[test]
-fn test_function_object() {
-    let input = "fn(x) { x + 2; };";
-    let evaluated = test_eval(input);
-    let func = evaluated
-        .as_any()
-        .downcast_ref::<FUNCTION>()
-        .expect(&format!("object is not FUNCTION. got={:?}", evaluated));
-
-    assert!(
-        func.function_literal.parameters.len() == 1,
-        "function has wrong parameters. parameters={:?}",
-        func.function_literal.parameters
-    );
-
-    assert!(
-        func.function_literal.parameters[0].string() == "x",
-        "parameter is not 'x'. got={:?}",
-        func.function_literal.parameters[0]
-    );
+fn test_eval_with_limits(input: &str, limits: ExecutionLimits) -> Value {
+    let env = Rc::new(RefCell::new(new_environment()));
+    let mut l = Lexer::new(input);
+    let mut p = Parser::new(&mut l);
+    let mut program = p.parse_program().unwrap();
+    resolve_addresses(&mut program);
 
-    let expected_body = "(x + 2)";
-    assert!(
-        func.function_literal.body.string() == expected_body,
-        "body is not {}. got={}",
-        expected_body,
-        func.function_literal.body.string()
-    );
+    eval_with_limits(&program, &env, &limits).unwrap()
 }
 
 #[test]
-fn test_function_application() {
+fn test_execution_limits() {
+    let fuel = ExecutionLimits {
+        fuel: Some(1000),
+        ..ExecutionLimits::default()
+    };
+    let timeout = ExecutionLimits {
+        timeout: Some(std::time::Duration::from_millis(50)),
+        ..ExecutionLimits::default()
+    };
+    let allocations = ExecutionLimits {
+        max_allocations: Some(100),
+        ..ExecutionLimits::default()
+    };
+    let bytes = ExecutionLimits {
+        max_allocated_bytes: Some(1 << 20),
+        ..ExecutionLimits::default()
+    };
     let tests = [
-        ("let identity = fn(x) { x; }; identity(5);", 5),
-        ("let identity = fn(x) { return x; }; identity(5);", 5),
-        ("let double = fn(x) { x * 2; }; double(5);", 10),
-        ("let add = fn(x, y) { x + y; }; add(5, 5);", 10),
-        ("let add = fn(x, y) { x + y; }; add(5 + 5, add(5, 5));", 20),
-        ("fn(x) { x; }(5)", 5),
+        (
+            "let f = fn() { f() }; f()",
+            fuel.clone(),
+            "evaluation step limit exceeded",
+        ),
+        // scripts cannot catch running out, nor run `finally` blocks
+        (
+            "let f = fn() { f() }; try { f() } catch (e) { 1 }",
+            fuel.clone(),
+            "evaluation step limit exceeded",
+        ),
+        (
+            "let f = fn() { f() }; try { f() } finally { 1 }",
+            fuel,
+            "evaluation step limit exceeded",
+        ),
+        ("let f = fn() { f() }; f()", timeout, "time limit exceeded"),
+        (
+            "let f = fn(a) { f([a]) }; f(0)",
+            allocations,
+            "allocation limit exceeded",
+        ),
+        (
+            "let f = fn(s) { f(s + s) }; f(\"a\")",
+            bytes,
+            "memory limit exceeded",
+        ),
     ];
-
     for tt in tests.iter() {
-        test_integer_object(test_eval(tt.0), tt.1);
+        match test_eval_with_limits(tt.0, tt.1.clone()) {
+            Value::Error(err) => {
+                assert_eq!(err.kind, LIMIT_ERROR, "wrong kind for {}", tt.0);
+                assert_eq!(err.message, tt.2, "wrong message for {}", tt.0);
+            }
+            evaluated => panic!("object is not Error. got={:?}", evaluated),
+        }
     }
+
+    // scripts within their budget run as usual
+    let limits = ExecutionLimits {
+        fuel: Some(1000),
+        timeout: Some(std::time::Duration::from_secs(60)),
+        max_allocations: Some(10),
+        max_allocated_bytes: Some(1 << 10),
+        ..ExecutionLimits::default()
+    };
+    let input =
+        "let f = fn(n) { if (n == 0) { 0 } else { f(n - 1) + n } }; f(10) + len(\"ab\" + \"c\")";
+    test_integer_object(test_eval_with_limits(input, limits), 58);
 }
 
 #[test]
-fn test_closures() {
-    let input = "let newAdder = fn(x) {
-            fn(y) { x + y };
-        };
-        let addTwo = newAdder(2);
-        addTwo(2);
-        ";
+fn test_interrupt_handle() {
+    let interrupt = InterruptHandle::new();
+    let limits = ExecutionLimits {
+        interrupt: Some(interrupt.clone()),
+        ..ExecutionLimits::default()
+    };
 
-    test_integer_object(test_eval(input), 4);
+    let raise = interrupt.clone();
+    let raiser = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        raise.interrupt();
+    });
+    let input = "let f = fn() { f() }; try { f() } catch (e) { 1 } finally { 2 }";
+    match test_eval_with_limits(input, limits.clone()) {
+        Value::Error(err) => {
+            assert_eq!(err.kind, INTERRUPT_ERROR);
+            assert_eq!(err.message, "interrupted");
+        }
+        evaluated => panic!("object is not Error. got={:?}", evaluated),
+    }
+    raiser.join().unwrap();
+
+    // the flag stays raised until reset
+    assert!(interrupt.is_interrupted());
+    match test_eval_with_limits("1", limits.clone()) {
+        Value::Error(err) => assert_eq!(err.kind, INTERRUPT_ERROR),
+        evaluated => panic!("object is not Error. got={:?}", evaluated),
+    }
+    interrupt.reset();
+    test_integer_object(test_eval_with_limits("1 + 1", limits), 2);
+}
+
+// Run with `cargo test --release --test evaluator_test -- --ignored --nocapture`.
+#[test]
+#[ignore]
+fn bench_fib() {
+    let input = "
+let fib = fn(n) { if (n < 2) { n } else { fib(n - 1) + fib(n - 2) } };
+fib(25);
+";
+    let start = std::time::Instant::now();
+    let evaluated = test_eval(input);
+    let elapsed = start.elapsed();
+    test_integer_object(evaluated, 75025);
+    eprintln!("fib(25) took {:?}", elapsed);
 }
@@ -216,3 +216,203 @@ fn test_next_token3() {
         );
     }
 }
+
+#[test]
+fn test_next_token_ellipsis() {
+    let input = "fn(x, ...rest) {}; .";
+    let tests = [
+        (TokenType::FUNCTION, "fn"),
+        (TokenType::LPAREN, "("),
+        (TokenType::IDENT, "x"),
+        (TokenType::COMMA, ","),
+        (TokenType::ELLIPSIS, "..."),
+        (TokenType::IDENT, "rest"),
+        (TokenType::RPAREN, ")"),
+        (TokenType::LBRACE, "{"),
+        (TokenType::RBRACE, "}"),
+        (TokenType::SEMICOLON, ";"),
+        (TokenType::DOT, "."),
+        (TokenType::EOF, ""),
+    ];
+    let mut l = Lexer::new(input);
+    for (index, value) in tests.iter().enumerate() {
+        let tok = l.next_token();
+        assert!(
+            tok.tk_type == value.0,
+            "tests[{}] - tokentype wrong. expected={:?}, got={:?}",
+            index,
+            value.0,
+            tok.tk_type
+        );
+        assert!(
+            tok.literal == value.1,
+            "test[{}] - literal wrong. expected={}, got={}",
+            index,
+            value.1,
+            tok.literal
+        );
+    }
+}
+
+#[test]
+fn test_next_token_lambda_and_pipe() {
+    let input = "|x| x => is_even |> f";
+    let tests = [
+        (TokenType::BAR, "|"),
+        (TokenType::IDENT, "x"),
+        (TokenType::BAR, "|"),
+        (TokenType::IDENT, "x"),
+        (TokenType::ARROW, "=>"),
+        (TokenType::IDENT, "is_even"),
+        (TokenType::PIPE, "|>"),
+        (TokenType::IDENT, "f"),
+        (TokenType::EOF, ""),
+    ];
+    let mut l = Lexer::new(input);
+    for (index, value) in tests.iter().enumerate() {
+        let tok = l.next_token();
+        assert!(
+            tok.tk_type == value.0,
+            "tests[{}] - tokentype wrong. expected={:?}, got={:?}",
+            index,
+            value.0,
+            tok.tk_type
+        );
+        assert!(
+            tok.literal == value.1,
+            "test[{}] - literal wrong. expected={}, got={}",
+            index,
+            value.1,
+            tok.literal
+        );
+    }
+}
+
+#[test]
+fn test_next_token_match_and_collections() {
+    let input = r#"match (x) { [a, "foo bar"] => {"k": 1} }"#;
+    let tests = [
+        (TokenType::MATCH, "match"),
+        (TokenType::LPAREN, "("),
+        (TokenType::IDENT, "x"),
+        (TokenType::RPAREN, ")"),
+        (TokenType::LBRACE, "{"),
+        (TokenType::LBRACKET, "["),
+        (TokenType::IDENT, "a"),
+        (TokenType::COMMA, ","),
+        (TokenType::STRING, "foo bar"),
+        (TokenType::RBRACKET, "]"),
+        (TokenType::ARROW, "=>"),
+        (TokenType::LBRACE, "{"),
+        (TokenType::STRING, "k"),
+        (TokenType::COLON, ":"),
+        (TokenType::INT, "1"),
+        (TokenType::RBRACE, "}"),
+        (TokenType::RBRACE, "}"),
+        (TokenType::EOF, ""),
+    ];
+    let mut l = Lexer::new(input);
+    for (index, value) in tests.iter().enumerate() {
+        let tok = l.next_token();
+        assert!(
+            tok.tk_type == value.0,
+            "tests[{}] - tokentype wrong. expected={:?}, got={:?}",
+            index,
+            value.0,
+            tok.tk_type
+        );
+        assert!(
+            tok.literal == value.1,
+            "test[{}] - literal wrong. expected={}, got={}",
+            index,
+            value.1,
+            tok.literal
+        );
+    }
+}
+
+#[test]
+fn test_next_token_exceptions() {
+    let input = "try {} catch (e) { throw e.message; } finally {}";
+    let tests = [
+        (TokenType::TRY, "try"),
+        (TokenType::LBRACE, "{"),
+        (TokenType::RBRACE, "}"),
+        (TokenType::CATCH, "catch"),
+        (TokenType::LPAREN, "("),
+        (TokenType::IDENT, "e"),
+        (TokenType::RPAREN, ")"),
+        (TokenType::LBRACE, "{"),
+        (TokenType::THROW, "throw"),
+        (TokenType::IDENT, "e"),
+        (TokenType::DOT, "."),
+        (TokenType::IDENT, "message"),
+        (TokenType::SEMICOLON, ";"),
+        (TokenType::RBRACE, "}"),
+        (TokenType::FINALLY, "finally"),
+        (TokenType::LBRACE, "{"),
+        (TokenType::RBRACE, "}"),
+        (TokenType::EOF, ""),
+    ];
+    let mut l = Lexer::new(input);
+    for (index, value) in tests.iter().enumerate() {
+        let tok = l.next_token();
+        assert!(
+            tok.tk_type == value.0,
+            "tests[{}] - tokentype wrong. expected={:?}, got={:?}",
+            index,
+            value.0,
+            tok.tk_type
+        );
+        assert!(
+            tok.literal == value.1,
+            "test[{}] - literal wrong. expected={}, got={}",
+            index,
+            value.1,
+            tok.literal
+        );
+    }
+}
+
+#[test]
+fn test_next_token_type_annotations_and_spans() {
+    let input = "let x: int = 5;\nfn(a) -> bool {}";
+    let tests = [
+        (TokenType::LET, "let", 1, 1),
+        (TokenType::IDENT, "x", 1, 5),
+        (TokenType::COLON, ":", 1, 6),
+        (TokenType::IDENT, "int", 1, 8),
+        (TokenType::ASSIGN, "=", 1, 12),
+        (TokenType::INT, "5", 1, 14),
+        (TokenType::SEMICOLON, ";", 1, 15),
+        (TokenType::FUNCTION, "fn", 2, 1),
+        (TokenType::LPAREN, "(", 2, 3),
+        (TokenType::IDENT, "a", 2, 4),
+        (TokenType::RPAREN, ")", 2, 5),
+        (TokenType::THINARROW, "->", 2, 7),
+        (TokenType::IDENT, "bool", 2, 10),
+        (TokenType::LBRACE, "{", 2, 15),
+        (TokenType::RBRACE, "}", 2, 16),
+    ];
+    let mut l = Lexer::new(input);
+    for (index, value) in tests.iter().enumerate() {
+        let tok = l.next_token();
+        assert!(
+            tok.tk_type == value.0 && tok.literal == value.1,
+            "tests[{}] - token wrong. expected={:?} {}, got={:?} {}",
+            index,
+            value.0,
+            value.1,
+            tok.tk_type,
+            tok.literal
+        );
+        assert!(
+            tok.span.line == value.2 && tok.span.column == value.3,
+            "tests[{}] - span wrong. expected={}:{}, got={}",
+            index,
+            value.2,
+            value.3,
+            tok.span
+        );
+    }
+}
@@ -0,0 +1,182 @@
+extern crate waiir;
+use std::cell::*;
+use std::rc::*;
+use waiir::ast::*;
+use waiir::environment::*;
+use waiir::evaluator::*;
+use waiir::lexer::*;
+use waiir::macro_expansion::*;
+use waiir::object::*;
+use waiir::parser::*;
+
+fn test_parse_program(input: &str) -> Program {
+    let mut l = Lexer::new(input);
+    let mut p = Parser::new(&mut l);
+    let program = p.parse_program().unwrap();
+    assert!(
+        p.get_errors().is_empty(),
+        "parser errors: {:?}",
+        p.get_errors()
+    );
+    program
+}
+
+#[test]
+fn test_define_macros() {
+    let input = "
+let number = 1;
+let function = fn(x, y) { x + y };
+let mymacro = macro(x, y) { x + y; };
+";
+    let env = Rc::new(RefCell::new(new_environment()));
+    let mut program = test_parse_program(input);
+
+    define_macros(&mut program, &env);
+
+    assert!(
+        program.statements.len() == 2,
+        "Wrong number of statements. got={}",
+        program.statements.len()
+    );
+    assert!(env.borrow().get(&String::from("number")).is_none());
+    assert!(env.borrow().get(&String::from("function")).is_none());
+
+    let obj = env
+        .borrow()
+        .get(&String::from("mymacro"))
+        .expect("macro not in environment.");
+    let macro_obj = match &obj {
+        Value::Macro(macro_obj) => macro_obj,
+        _ => panic!("object is not Macro. got={:?}", obj),
+    };
+    assert!(macro_obj.macro_literal.parameters.len() == 2);
+    assert!(macro_obj.macro_literal.parameters[0].string() == "x");
+    assert!(macro_obj.macro_literal.parameters[1].string() == "y");
+    assert!(
+        macro_obj.macro_literal.body.string() == "(x + y)",
+        "body is not (x + y). got={}",
+        macro_obj.macro_literal.body.string()
+    );
+}
+
+#[test]
+fn test_expand_macros() {
+    let tests = [
+        (
+            "let infixExpression = macro() { quote(1 + 2); }; infixExpression();",
+            "(1 + 2)",
+        ),
+        (
+            "let reverse = macro(a, b) { quote(unquote(b) - unquote(a)); }; reverse(2 + 2, 10 - 5);",
+            "(10 - 5) - (2 + 2)",
+        ),
+        (
+            r#"
+let unless = macro(condition, consequence, alternative) {
+    quote(if (!(unquote(condition))) {
+        unquote(consequence);
+    } else {
+        unquote(alternative);
+    });
+};
+
+unless(10 > 5, puts("not greater"), puts("greater"));
+"#,
+            r#"if (!(10 > 5)) { puts("not greater") } else { puts("greater") }"#,
+        ),
+        (
+            "let twice = macro(x) { quote(unquote(x) + unquote(x)); }; let f = fn() { twice(a) }; f;",
+            "let f = fn() { a + a }; f;",
+        ),
+    ];
+    for tt in tests.iter() {
+        let expected = test_parse_program(tt.1);
+        let mut program = test_parse_program(tt.0);
+
+        let env = Rc::new(RefCell::new(new_environment()));
+        define_macros(&mut program, &env);
+        expand_macros(&mut program, &env).expect("expansion failed");
+
+        assert!(
+            program.string() == expected.string(),
+            "not equal. want={}, got={}",
+            expected.string(),
+            program.string()
+        );
+    }
+}
+
+#[test]
+fn test_expanded_program_evaluation() {
+    let tests = [
+        (
+            "let unless = macro(cond, a, b) { quote(if (!(unquote(cond))) { unquote(a) } else { unquote(b) }) }; unless(1 > 2, 10, 20);",
+            10,
+        ),
+        (
+            r#"let assert_eq = macro(a, b) { quote(if (unquote(a) != unquote(b)) { throw "assertion failed" } else { 1 }) }; assert_eq(1 + 1, 2);"#,
+            1,
+        ),
+        (
+            "let early = macro(x) { return quote(unquote(x) * 2); }; early(21);",
+            42,
+        ),
+    ];
+    for tt in tests.iter() {
+        let mut program = test_parse_program(tt.0);
+        let macro_env = Rc::new(RefCell::new(new_environment()));
+        define_macros(&mut program, &macro_env);
+        expand_macros(&mut program, &macro_env).expect("expansion failed");
+
+        let env = Rc::new(RefCell::new(new_environment()));
+        let evaluated = eval(&program, &env).unwrap();
+        let integer = match &evaluated {
+            Value::Integer(integer) => integer,
+            _ => panic!("object is not Integer. got={:?}", evaluated),
+        };
+        assert!(
+            *integer == tt.1,
+            "object has wrong value. got={}, want={}",
+            integer,
+            tt.1
+        );
+    }
+}
+
+#[test]
+fn test_expand_macro_errors() {
+    let tests = [
+        (
+            "let m = macro(x) { x }; m();",
+            "wrong number of arguments: want=1, got=0",
+        ),
+        (
+            "let m = macro(x) { 1 }; m(2);",
+            "macro must return a quoted expression, got INTEGER",
+        ),
+        (
+            "let m = macro(x) { quote(unquote(y)) }; m(2);",
+            "identifier not found: y",
+        ),
+        (
+            "let m = macro(x) { x }; m(x: 1);",
+            "macros do not accept named arguments",
+        ),
+    ];
+    for tt in tests.iter() {
+        let mut program = test_parse_program(tt.0);
+        let env = Rc::new(RefCell::new(new_environment()));
+        define_macros(&mut program, &env);
+        let err = expand_macros(&mut program, &env).expect_err("expected an error");
+        let err = match &err {
+            Value::Error(err) => err,
+            _ => panic!("no error object returned. got={:?}", err),
+        };
+        assert!(
+            err.message == tt.1,
+            "wrong error message. expected={}, got={}",
+            tt.1,
+            err.message
+        );
+    }
+}
@@ -0,0 +1,397 @@
+extern crate waiir;
+use std::cell::*;
+use std::fs;
+use std::path::*;
+use std::rc::*;
+use waiir::diagnostics::Diagnostic;
+use waiir::environment::*;
+use waiir::evaluator::*;
+use waiir::lexer::*;
+use waiir::module::*;
+use waiir::object::*;
+use waiir::parser::*;
+
+// Writes `files` into a fresh directory and returns its path.
+fn write_modules(name: &str, files: &[(&str, &str)]) -> PathBuf {
+    let dir =
+        std::env::temp_dir().join(format!("waiir_module_test_{}_{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    for (path, source) in files.iter() {
+        let file = dir.join(path);
+        fs::create_dir_all(file.parent().unwrap()).unwrap();
+        fs::write(file, source).unwrap();
+    }
+    dir
+}
+
+fn test_eval_in(loader: &Rc<RefCell<ModuleLoader>>, file: &Path, input: &str) -> Value {
+    let env = Rc::new(RefCell::new(new_module_environment(
+        loader,
+        Some(file.to_path_buf()),
+    )));
+    let mut l = Lexer::new(input);
+    let mut p = Parser::new(&mut l);
+    let program = p.parse_program().unwrap();
+    assert!(
+        p.get_errors().is_empty(),
+        "parser errors: {:?}",
+        p.get_errors()
+    );
+    eval(&program, &env).unwrap()
+}
+
+fn test_integer_object(obj: Value, expected: i64) {
+    let result = match &obj {
+        Value::Integer(result) => result,
+        _ => panic!("object is not Integer. got={:?}", obj),
+    };
+    assert!(
+        *result == expected,
+        "object has wrong value. got={}, want={}",
+        result,
+        expected
+    );
+}
+
+fn test_error_object(obj: Value, kind: &str) -> String {
+    let err = match &obj {
+        Value::Error(err) => err,
+        _ => panic!("no error object returned. got={:?}", obj),
+    };
+    assert!(
+        err.kind == kind,
+        "wrong error kind. want={}, got={}",
+        kind,
+        err.kind
+    );
+    err.message.clone()
+}
+
+#[test]
+fn test_import_exports() {
+    let dir = write_modules(
+        "exports",
+        &[
+            (
+                "lib/math.mk",
+                "let helper = fn(x) { x * 2 }; export let double = fn(x) { helper(x) }; export let answer = 42;",
+            ),
+        ],
+    );
+    let loader = Rc::new(RefCell::new(ModuleLoader::new()));
+    let main = dir.join("main.mk");
+
+    let tests = [
+        (r#"import "lib/math.mk" as math; math.double(4);"#, 8),
+        (r#"import "lib/math.mk" as math; math.answer;"#, 42),
+        (r#"import "./lib/math.mk" as m; m["answer"] + 1;"#, 43),
+        (
+            r#"let f = fn() { import "lib/math.mk" as math; math.double(5) }; f();"#,
+            10,
+        ),
+    ];
+    for tt in tests.iter() {
+        test_integer_object(test_eval_in(&loader, &main, tt.0), tt.1);
+    }
+
+    let evaluated = test_eval_in(
+        &loader,
+        &main,
+        r#"import "lib/math.mk" as math; math.helper"#,
+    );
+    let message = test_error_object(evaluated, NAME_ERROR);
+    assert!(
+        message == "module lib/math.mk has no export helper",
+        "wrong message. got={}",
+        message
+    );
+}
+
+#[test]
+fn test_import_relative_to_importing_file() {
+    let dir = write_modules(
+        "relative",
+        &[
+            ("lib/util.mk", "export let one = 1;"),
+            (
+                "lib/math.mk",
+                r#"import "./util.mk" as util; export let two = util.one + util.one;"#,
+            ),
+            ("util.mk", "export let one = 100;"),
+        ],
+    );
+    let loader = Rc::new(RefCell::new(ModuleLoader::new()));
+    let evaluated = test_eval_in(
+        &loader,
+        &dir.join("main.mk"),
+        r#"import "lib/math.mk" as math; import "util.mk" as util; math.two + util.one;"#,
+    );
+    test_integer_object(evaluated, 102);
+}
+
+#[test]
+fn test_import_caches_modules() {
+    let dir = write_modules("cache", &[("a.mk", "export let x = 1;")]);
+    let loader = Rc::new(RefCell::new(ModuleLoader::new()));
+    let main = dir.join("main.mk");
+
+    let first = import_module(&loader, "a.mk", Some(&main)).unwrap();
+    let second = import_module(&loader, "./a.mk", Some(&main)).unwrap();
+    assert!(
+        Rc::ptr_eq(&first.exports, &second.exports),
+        "module was evaluated twice"
+    );
+}
+
+#[test]
+fn test_import_cycle() {
+    let dir = write_modules(
+        "cycle",
+        &[
+            ("a.mk", r#"import "b.mk" as b; export let x = 1;"#),
+            ("b.mk", r#"import "a.mk" as a; export let y = 2;"#),
+        ],
+    );
+    let loader = Rc::new(RefCell::new(ModuleLoader::new()));
+    let evaluated = test_eval_in(&loader, &dir.join("main.mk"), r#"import "a.mk" as a;"#);
+    let message = test_error_object(evaluated, IMPORT_ERROR);
+    let a = dir.join("a.mk").canonicalize().unwrap();
+    let b = dir.join("b.mk").canonicalize().unwrap();
+    let expected = format!(
+        "import cycle detected: {} -> {} -> {}",
+        a.display(),
+        b.display(),
+        a.display()
+    );
+    assert!(
+        message == expected,
+        "wrong message. want={}, got={}",
+        expected,
+        message
+    );
+}
+
+#[test]
+fn test_import_search_path() {
+    let dir = write_modules(
+        "search",
+        &[("vendor/lib/strings.mk", r#"export let hi = "hi";"#)],
+    );
+    let main = dir.join("main.mk");
+
+    let loader = Rc::new(RefCell::new(ModuleLoader::new()));
+    let evaluated = test_eval_in(&loader, &main, r#"import "lib/strings.mk" as s; s.hi"#);
+    let message = test_error_object(evaluated, IMPORT_ERROR);
+    assert!(
+        message == "module not found: lib/strings.mk",
+        "got={}",
+        message
+    );
+
+    loader.borrow_mut().add_search_path(dir.join("vendor"));
+    let evaluated = test_eval_in(&loader, &main, r#"import "lib/strings.mk" as s; s.hi"#);
+    let string = match &evaluated {
+        Value::Str(string) => string,
+        _ => panic!("object is not Str. got={:?}", evaluated),
+    };
+    assert!(string.as_ref() == "hi");
+
+    // relative imports never consult the search path
+    let evaluated = test_eval_in(&loader, &main, r#"import "./lib/strings.mk" as s;"#);
+    test_error_object(evaluated, IMPORT_ERROR);
+}
+
+#[test]
+fn test_module_errors() {
+    let dir = write_modules(
+        "errors",
+        &[
+            ("broken.mk", "let = 1;"),
+            (
+                "failing.mk",
+                r#"let h = {"b": true, "i": 1}; export let x = 1 + h["b"];"#,
+            ),
+            ("mistyped.mk", "export let x = 1 + true;"),
+        ],
+    );
+    let loader = Rc::new(RefCell::new(ModuleLoader::new()));
+    let main = dir.join("main.mk");
+
+    let evaluated = test_eval_in(&loader, &main, r#"import "broken.mk" as b;"#);
+    let message = test_error_object(evaluated, IMPORT_ERROR);
+    assert!(
+        message.starts_with("parse errors in module"),
+        "got={}",
+        message
+    );
+
+    let evaluated = test_eval_in(&loader, &main, r#"import "failing.mk" as f;"#);
+    let message = test_error_object(evaluated, TYPE_ERROR);
+    assert!(
+        message == "type mismatch: INTEGER + BOOLEAN",
+        "got={}",
+        message
+    );
+
+    let evaluated = test_eval_in(
+        &loader,
+        &main,
+        r#"try { import "failing.mk" as f; 1 } catch (e) { 2 }"#,
+    );
+    test_integer_object(evaluated, 2);
+
+    let evaluated = test_eval_in(&loader, &main, r#"import "mistyped.mk" as m;"#);
+    let message = test_error_object(evaluated, TYPE_ERROR);
+    assert!(
+        message.starts_with("type errors in module")
+            && message.ends_with("1:18: type mismatch: int + bool"),
+        "got={}",
+        message
+    );
+}
+
+#[test]
+fn test_import_with_vm_engine() {
+    let dir = write_modules(
+        "vm_engine",
+        &[
+            ("lib/util.mk", "export let one = 1;"),
+            (
+                "lib/math.mk",
+                r#"import "./util.mk" as util; let helper = fn(x) { x * 2 + util.one }; export let double = fn(x) { helper(x) };"#,
+            ),
+            (
+                "main.mk",
+                r#"import "lib/math.mk" as math; let x = math.double(4);"#,
+            ),
+            ("bad.mk", r#"let f = fn() { throw "boom" }; f();"#),
+        ],
+    );
+    let loader = Rc::new(RefCell::new(ModuleLoader::new()));
+    loader.borrow_mut().set_engine(Engine::Vm);
+
+    let module = import_module(&loader, "lib/math.mk", Some(&dir.join("main.mk"))).unwrap();
+    assert!(
+        module.exports.keys().collect::<Vec<_>>() == ["double"],
+        "wrong exports. got={:?}",
+        module.exports.keys()
+    );
+    let result = run_file(&loader, dir.join("main.mk").to_str().unwrap());
+    assert!(result.is_ok(), "main.mk failed. got={:?}", result);
+
+    let err = run_file(&loader, dir.join("bad.mk").to_str().unwrap()).unwrap_err();
+    let message = test_error_object(Value::from(err), ERROR);
+    assert!(message == "boom", "wrong message. got={}", message);
+}
+
+#[test]
+fn test_import_with_closures_engine() {
+    let dir = write_modules(
+        "closures_engine",
+        &[
+            ("util.mk", "export let one = 1;"),
+            (
+                "main.mk",
+                r#"import "./util.mk" as util; let f = fn() { throw util.one }; f();"#,
+            ),
+        ],
+    );
+    let loader = Rc::new(RefCell::new(ModuleLoader::new()));
+    loader.borrow_mut().set_engine(Engine::Closures);
+
+    let module = import_module(&loader, "util.mk", Some(&dir.join("main.mk"))).unwrap();
+    match module.exports.get("one") {
+        Some(Value::Integer(1)) => {}
+        other => panic!("wrong export. got={:?}", other),
+    }
+
+    let err = run_file(&loader, dir.join("main.mk").to_str().unwrap()).unwrap_err();
+    let message = test_error_object(Value::from(err), ERROR);
+    assert!(message == "1", "wrong message. got={}", message);
+}
+
+#[test]
+fn test_run_file_with_max_depth() {
+    let dir = write_modules(
+        "max_depth",
+        &[(
+            "main.mk",
+            "let depth = fn(n) { if (n == 0) { 0 } else { 1 + depth(n - 1) } }; depth(50);",
+        )],
+    );
+    let main = dir.join("main.mk");
+    let loader = Rc::new(RefCell::new(ModuleLoader::new()));
+    assert_eq!(loader.borrow().max_depth(), MAX_DEPTH);
+    assert!(run_file(&loader, main.to_str().unwrap()).is_ok());
+
+    // modules are cached, the limit needs a loader of its own
+    let loader = Rc::new(RefCell::new(ModuleLoader::new()));
+    loader.borrow_mut().set_max_depth(10);
+    let err = run_file(&loader, main.to_str().unwrap()).unwrap_err();
+    let message = test_error_object(Value::from(err), RECURSION_ERROR);
+    assert!(
+        message == "maximum recursion depth exceeded",
+        "wrong message. got={}",
+        message
+    );
+}
+
+#[test]
+fn test_run_file_diagnostics() {
+    let dir = write_modules(
+        "diagnostics",
+        &[
+            ("broken.mk", "let x = 1;\nlet = 2;"),
+            (
+                "calls.mk",
+                "let f = fn(x) { throw \"boom\" };\nlet y = f(1);",
+            ),
+            ("lib.mk", "let g = fn() { throw \"deep\" };\ng();"),
+            ("imports.mk", "let x = 1;\nimport \"./lib.mk\" as lib;"),
+        ],
+    );
+    let loader = Rc::new(RefCell::new(ModuleLoader::new()));
+    let run = |name: &str| run_file(&loader, dir.join(name).to_str().unwrap()).unwrap_err();
+
+    // parser errors keep their positions, with the source to show them in
+    match run("broken.mk") {
+        RunError::Diagnostics {
+            source,
+            diagnostics,
+            error,
+        } => {
+            assert_eq!(source, "let x = 1;\nlet = 2;");
+            assert_eq!(
+                diagnostics[0].to_string(),
+                "2:5: expected next token to be identifier, got '=' instead"
+            );
+            test_error_object(error, IMPORT_ERROR);
+        }
+        err => panic!("expected diagnostics. got={:?}", err),
+    }
+
+    // a runtime error is shown where it was raised, with its calls as notes
+    let err = match Value::from(run("calls.mk")) {
+        Value::Error(err) => err,
+        err => panic!("expected an error. got={:?}", err),
+    };
+    let diagnostic = Diagnostic::from(err.as_ref());
+    assert_eq!(diagnostic.to_string(), "1:17: Error: boom");
+    assert_eq!(diagnostic.notes, ["line 2, column 10, in f (1 argument)"]);
+
+    // an error raised in an imported file is shown at the import, the
+    // calls made there are not in the importer
+    let err = match Value::from(run("imports.mk")) {
+        Value::Error(err) => err,
+        err => panic!("expected an error. got={:?}", err),
+    };
+    let diagnostic = Diagnostic::from(err.as_ref());
+    assert_eq!(diagnostic.to_string(), "2:1: Error: deep");
+    assert_eq!(
+        diagnostic.notes,
+        [
+            "in <module ./lib.mk>",
+            "line 2, column 2, in g (0 arguments)"
+        ]
+    );
+}
@@ -0,0 +1,184 @@
+extern crate waiir;
+use std::cell::*;
+use std::rc::*;
+use waiir::ast::*;
+use waiir::environment::*;
+use waiir::evaluator::*;
+use waiir::lexer::*;
+use waiir::optimizer::*;
+use waiir::parser::*;
+use waiir::resolver::*;
+
+fn parse(input: &str) -> Program {
+    let mut l = Lexer::new(input);
+    let mut p = Parser::new(&mut l);
+    let program = p.parse_program().unwrap();
+    assert!(
+        p.get_errors().is_empty(),
+        "parser errors: {:?}",
+        p.get_errors()
+    );
+    program
+}
+
+fn test_pass(pass: Pass, tests: &[(&str, &str)]) {
+    for tt in tests.iter() {
+        let mut program = parse(tt.0);
+        pass(&mut program);
+        assert!(
+            program.string() == tt.1,
+            "wrong result for {}. want={}, got={}",
+            tt.0,
+            tt.1,
+            program.string()
+        );
+    }
+}
+
+#[test]
+fn test_fold_constants() {
+    test_pass(
+        fold_constants,
+        &[
+            ("60 * 60 * 24", "86400"),
+            ("1 + 2 * x", "(1 + (2 * x))"),
+            ("-(3 - 5)", "2"),
+            ("!true", "false"),
+            ("!5", "false"),
+            (r#""a" + "b""#, r#""ab""#),
+            (r#""a" == "b""#, "false"),
+            ("1 < 2 == true", "true"),
+            ("fn() { 2 * 3 }", "fn()6"),
+            // left for the runtime to report
+            ("1 / 0", "(1 / 0)"),
+            ("1 + true", "(1 + true)"),
+            ("9223372036854775807 + 1", "(9223372036854775807 + 1)"),
+            (r#""a" - "b""#, r#"("a" - "b")"#),
+        ],
+    );
+}
+
+#[test]
+fn test_fold_constants_keeps_spans() {
+    let mut program = parse("let x =\n  2 * 3;");
+    fold_constants(&mut program);
+    let value = match &program.statements[0] {
+        Statement::Let(let_stmt) => &let_stmt.value,
+        stmt => panic!("not a let statement. got={:?}", stmt),
+    };
+    assert!(
+        value.span() == Span { line: 2, column: 5 },
+        "wrong span. got={:?}",
+        value.span()
+    );
+}
+
+#[test]
+fn test_simplify_conditionals() {
+    test_pass(
+        simplify_conditionals,
+        &[
+            ("if (true) { 1 } else { 2 }", "1"),
+            ("if (false) { 1 } else { 2 }", "2"),
+            ("let x = if (1) { 1 } else { 2 };", "let x = 1;"),
+            ("if (false) { 1 }; 2", "2"),
+            // the `null` is the program's value
+            ("if (false) { 1 }", "iffalse 1"),
+            ("if (true) { let x = 1; }", "iftrue let x = 1;"),
+            ("if (true) { let x = 1; }; x", "let x = 1;x"),
+            ("if (c) { 1 } else { 2 }", "ifc 1 else 2"),
+        ],
+    );
+}
+
+#[test]
+fn test_remove_dead_code() {
+    test_pass(
+        remove_dead_code,
+        &[
+            ("fn() { return 1; 2; 3 }", "fn()return 1;"),
+            ("fn() { throw 1; 2 }", "fn()throw 1;"),
+            ("if (c) { return 1; 2 } else { 3 }", "ifc return 1; else 3"),
+            ("fn() { 1; 2 }", "fn()12"),
+        ],
+    );
+}
+
+#[test]
+fn test_inline_constants() {
+    test_pass(
+        inline_constants,
+        &[
+            ("let x = 5; x + x", "let x = 5;(5 + 5)"),
+            ("let x = 2 * 3; x", "let x = 6;6"),
+            ("let a = 2; let b = a * 3; b", "let a = 2;let b = 6;6"),
+            (
+                "let x = 5; let f = fn() { x }; f()",
+                "let x = 5;let f = fn()5;f()",
+            ),
+            (r#"export let x = "a"; x"#, r#"export let x = "a";"a""#),
+            // not a literal, or bound more than once
+            ("let x = y; x", "let x = y;x"),
+            ("let x = 1; let x = 2; x", "let x = 1;let x = 2;x"),
+            ("let x = 1; fn(x) { x }", "let x = 1;fn(x)x"),
+            (
+                "let x = 1; match (2) { x => x }",
+                "let x = 1;match2 { x => x }",
+            ),
+            // uses before the `let` may run first
+            (
+                "let f = fn() { x }; let x = 1; x",
+                "let f = fn()x;let x = 1;1",
+            ),
+            ("let x = 1; quote(x)", "let x = 1;quote(x)"),
+        ],
+    );
+}
+
+#[test]
+fn test_optimized_programs_evaluate_the_same() {
+    let tests = [
+        "let secs = 60 * 60 * 24; secs / 3600",
+        "let f = fn(n) { if (true) { return n * 2; puts(n) } }; f(21)",
+        "let x = 1; let f = fn() { if (x == 1) { \"one\" } else { \"other\" } }; f()",
+        "if (false) { 1 }",
+        "let g = fn() { if (1 > 2) { 1 } }; g()",
+        "let a = 2; let b = if (a > 1) { let c = 3; c * a } else { 0 }; b + c",
+        "try { if (true) { throw \"up\" }; 1 } catch (e) { e.message }",
+    ];
+    for input in tests.iter() {
+        let plain = eval_program_string(input, false);
+        let optimized = eval_program_string(input, true);
+        assert!(
+            plain == optimized,
+            "optimizing changed the result of {}. want={}, got={}",
+            input,
+            plain,
+            optimized
+        );
+    }
+}
+
+fn eval_program_string(input: &str, optimized: bool) -> String {
+    let mut program = parse(input);
+    if optimized {
+        optimize(&mut program);
+    }
+    resolve_addresses(&mut program);
+    let env = Rc::new(RefCell::new(new_environment()));
+    match eval(&program, &env) {
+        Some(val) => val.inspect(),
+        None => String::from("<none>"),
+    }
+}
+
+#[test]
+fn test_custom_passes() {
+    let mut program = parse("let x = 2; if (x > 1) { return 1; 2 }");
+    optimize_with(&mut program, &[inline_constants, fold_constants]);
+    assert!(
+        program.string() == "let x = 2;iftrue return 1;2",
+        "wrong result. got={}",
+        program.string()
+    );
+}
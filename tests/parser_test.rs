@@ -1,3 +1,4 @@
+#![allow(clippy::borrowed_box)]
 extern crate waiir;
 
 use std::any::Any;
@@ -7,20 +8,20 @@ use waiir::parser::*;
 
 fn check_parser_errors(p: &Parser) {
     let errors = &p.get_errors();
-    if errors.len() == 0 {
+    if errors.is_empty() {
         return;
     }
     println!("parser has {} errors", errors.len());
     for msg in errors.iter() {
         println!("parser error: {}", *msg);
     }
-    assert!(false, "parser error!!!");
+    panic!("parser error!!!");
 }
 
 #[test]
 fn test_let_statements() {
     let tests: [(&str, &str, Box<dyn Any>); 3] = [
-        ("let x = 5;", "x", Box::new(5 as i64)),
+        ("let x = 5;", "x", Box::new(5_i64)),
         ("let y = true;", "y", Box::new(true)),
         ("let foobar = y;", "foobar", Box::new("y")),
     ];
@@ -37,21 +38,24 @@ fn test_let_statements() {
         let stmt = &program.statements[0];
         test_let_statement(stmt, tt.1);
 
-        let val = &stmt.as_any().downcast_ref::<LetStatement>().unwrap().value;
+        let val = match stmt {
+            Statement::Let(let_stmt) => &let_stmt.value,
+            other => panic!("unexpected node: {:?}", other),
+        };
         test_literal_expression(val, &tt.2);
     }
 }
 
-fn test_let_statement(s: &Box<dyn Statement>, name: &str) {
+fn test_let_statement(s: &Statement, name: &str) {
     assert!(
         s.token_literal() == "let",
         "s.token_literal not 'let'. got={}",
         s.token_literal()
     );
-    let let_stmt = s
-        .as_any()
-        .downcast_ref::<LetStatement>()
-        .expect(&format!("s not ast.LetStatement. got={:?}", s));
+    let let_stmt = match &s {
+        Statement::Let(let_stmt) => let_stmt,
+        _ => panic!("s not ast.LetStatement. got={:?}", s),
+    };
 
     assert!(
         let_stmt.name.value == *name,
@@ -74,7 +78,7 @@ fn test_return_statements() {
         return 10;
         return 993322;
         ";
-    let mut l = Lexer::new(&input);
+    let mut l = Lexer::new(input);
     let mut p = Parser::new(&mut l);
 
     let program = p.parse_program().expect("parse_program() returned None");
@@ -86,10 +90,10 @@ fn test_return_statements() {
         program.statements.len()
     );
     for stmt in program.statements.iter() {
-        let return_stmt = stmt
-            .as_any()
-            .downcast_ref::<ReturnStatement>()
-            .expect(&format!("stmt not ReturnStatment. got={:?}", stmt));
+        let return_stmt = match &stmt {
+            Statement::Return(return_stmt) => return_stmt,
+            _ => panic!("stmt not ReturnStatment. got={:?}", stmt),
+        };
         assert!(
             stmt.token_literal() == "return",
             "return_stmt.token_literal not 'return', got {}",
@@ -101,26 +105,32 @@ fn test_return_statements() {
 #[test]
 fn test_string() {
     let program = Program {
-        statements: vec![Box::new(LetStatement {
+        statements: vec![Statement::Let(LetStatement {
             token: Token {
                 tk_type: TokenType::LET,
                 literal: String::from("let"),
+                span: Span::default(),
             },
             name: Identifier {
                 token: Token {
                     tk_type: TokenType::IDENT,
                     literal: String::from("myVar"),
+                    span: Span::default(),
                 },
                 value: String::from("myVar"),
+                address: None,
             },
-            value: Box::new(Identifier {
+            type_annotation: None,
+            value: Expression::Identifier(Identifier {
                 token: Token {
                     tk_type: TokenType::IDENT,
                     literal: String::from("anotherVar"),
+                    span: Span::default(),
                 },
                 value: String::from("anotherVar"),
+                address: None,
             }),
-        }) as Box<dyn Statement>],
+        })],
     };
 
     assert!(
@@ -136,24 +146,23 @@ fn test_identifier_expresion() {
     let mut l = Lexer::new(input);
     let mut p = Parser::new(&mut l);
     let program = p.parse_program().expect("parse_program() returned nil");
-    check_parser_errors(&mut p);
+    check_parser_errors(&p);
     assert!(
         program.statements.len() == 1,
         "program has not enough statements. got={}",
         program.statements.len()
     );
-    let stmt = &program.statements[0]
-        .as_any()
-        .downcast_ref::<ExpressionStmt>()
-        .expect(&format!(
+    let stmt = match &program.statements[0] {
+        Statement::Expression(stmt) => stmt,
+        _ => panic!(
             "program.statements[0] is not ExpressionStmt. got={:?}",
             program.statements[0]
-        ));
-    let ident = stmt
-        .expression
-        .as_any()
-        .downcast_ref::<Identifier>()
-        .expect(&format!("exp not Identifier. got={:?}", stmt.expression));
+        ),
+    };
+    let ident = match &stmt.expression {
+        Expression::Identifier(ident) => ident,
+        _ => panic!("exp not Identifier. got={:?}", stmt.expression),
+    };
     assert!(
         ident.value == "foobar",
         "ident.value not {}. got={}",
@@ -174,19 +183,19 @@ fn test_integer_literal_expression() {
     let mut l = Lexer::new(input);
     let mut p = Parser::new(&mut l);
     let program = p.parse_program().expect("parse_program() returned nil");
-    check_parser_errors(&mut p);
+    check_parser_errors(&p);
     assert!(
         program.statements.len() == 1,
         "program has not enough statements. got={}",
         program.statements.len()
     );
-    let stmt = program.statements[0]
-        .as_any()
-        .downcast_ref::<ExpressionStmt>()
-        .expect(&format!(
+    let stmt = match &program.statements[0] {
+        Statement::Expression(stmt) => stmt,
+        _ => panic!(
             "program.statements[0] is not ast.ExpressionStmt. got={:?}",
             program.statements[0]
-        ));
+        ),
+    };
 
     test_integer_literal(&stmt.expression, 5);
 }
@@ -194,8 +203,8 @@ fn test_integer_literal_expression() {
 #[test]
 fn test_parsing_prefix_expressions() {
     let prefix_tests: [(&str, &str, Box<dyn Any>); 4] = [
-        ("!5;", "!", Box::new(5 as i64)),
-        ("-15;", "-", Box::new(15 as i64)),
+        ("!5;", "!", Box::new(5_i64)),
+        ("-15;", "-", Box::new(15_i64)),
         ("!true", "!", Box::new(true)),
         ("!false", "!", Box::new(false)),
     ];
@@ -203,27 +212,26 @@ fn test_parsing_prefix_expressions() {
         let mut l = Lexer::new(tt.0);
         let mut p = Parser::new(&mut l);
         let program = p.parse_program().expect("parse_program() returned None");
-        check_parser_errors(&mut p);
+        check_parser_errors(&p);
         assert!(
             program.statements.len() == 1,
             "program has not enough statements. got={}",
             program.statements.len()
         );
-        let stmt = program.statements[0]
-            .as_any()
-            .downcast_ref::<ExpressionStmt>()
-            .expect(&format!(
+        let stmt = match &program.statements[0] {
+            Statement::Expression(stmt) => stmt,
+            _ => panic!(
                 "program.statements[0] is not ast.ExpressionStmt. got={:?}",
                 program.statements[0]
-            ));
-        let exp = stmt
-            .expression
-            .as_any()
-            .downcast_ref::<PrefixExpression>()
-            .expect(&format!(
+            ),
+        };
+        let exp = match &stmt.expression {
+            Expression::Prefix(exp) => exp,
+            _ => panic!(
                 "stmt is not ast.PrefixExpression. got={:?}",
                 stmt.expression
-            ));
+            ),
+        };
         assert!(
             exp.operator == tt.1,
             "exp.operator is not '{}'. got={}",
@@ -234,11 +242,11 @@ fn test_parsing_prefix_expressions() {
     }
 }
 
-fn test_integer_literal(il: &Box<dyn Expression>, value: i64) {
-    let literal = il
-        .as_any()
-        .downcast_ref::<IntegerLiteral>()
-        .expect(&format!("exp not ast.IntegerLiteral. got={:?}", il));
+fn test_integer_literal(il: &Expression, value: i64) {
+    let literal = match &il {
+        Expression::Integer(literal) => literal,
+        _ => panic!("exp not ast.IntegerLiteral. got={:?}", il),
+    };
     assert!(
         literal.value == value,
         "literal.value not {}, got={}",
@@ -252,21 +260,23 @@ fn test_integer_literal(il: &Box<dyn Expression>, value: i64) {
         literal.token_literal()
     );
 }
+type InfixTest = (&'static str, Box<dyn Any>, &'static str, Box<dyn Any>);
+
 #[test]
 fn test_parsing_infix_expressions() {
-    let infix_tests: [(&str, Box<dyn Any>, &str, Box<dyn Any>); 13] = [
-        ("5 + 5;", Box::new(5 as i64), "+", Box::new(5 as i64)),
-        ("5 - 5;", Box::new(5 as i64), "-", Box::new(5 as i64)),
-        ("5 * 5;", Box::new(5 as i64), "*", Box::new(5 as i64)),
-        ("5 / 5;", Box::new(5 as i64), "/", Box::new(5 as i64)),
-        ("5 > 5;", Box::new(5 as i64), ">", Box::new(5 as i64)),
-        ("5 < 5;", Box::new(5 as i64), "<", Box::new(5 as i64)),
-        ("5 == 5;", Box::new(5 as i64), "==", Box::new(5 as i64)),
-        ("5 != 5;", Box::new(5 as i64), "!=", Box::new(5 as i64)),
+    let infix_tests: [InfixTest; 13] = [
+        ("5 + 5;", Box::new(5_i64), "+", Box::new(5_i64)),
+        ("5 - 5;", Box::new(5_i64), "-", Box::new(5_i64)),
+        ("5 * 5;", Box::new(5_i64), "*", Box::new(5_i64)),
+        ("5 / 5;", Box::new(5_i64), "/", Box::new(5_i64)),
+        ("5 > 5;", Box::new(5_i64), ">", Box::new(5_i64)),
+        ("5 < 5;", Box::new(5_i64), "<", Box::new(5_i64)),
+        ("5 == 5;", Box::new(5_i64), "==", Box::new(5_i64)),
+        ("5 != 5;", Box::new(5_i64), "!=", Box::new(5_i64)),
         ("true == true", Box::new(true), "==", Box::new(true)),
         ("true != false", Box::new(true), "!=", Box::new(false)),
         ("false == false", Box::new(false), "==", Box::new(false)),
-        ("5 + 10", Box::new(5 as i64), "+", Box::new(10 as i64)),
+        ("5 + 10", Box::new(5_i64), "+", Box::new(10_i64)),
         ("alice * bob", Box::new("alice"), "*", Box::new("bob")),
     ];
 
@@ -274,27 +284,23 @@ fn test_parsing_infix_expressions() {
         let mut l = Lexer::new(tt.0);
         let mut p = Parser::new(&mut l);
         let program = p.parse_program().expect("parse_program() returned None");
-        check_parser_errors(&mut p);
+        check_parser_errors(&p);
         assert!(
             program.statements.len() == 1,
             "program has not enough statements. got={}",
             program.statements.len()
         );
-        let stmt = program.statements[0]
-            .as_any()
-            .downcast_ref::<ExpressionStmt>()
-            .expect(&format!(
+        let stmt = match &program.statements[0] {
+            Statement::Expression(stmt) => stmt,
+            _ => panic!(
                 "program.statements[0] is not ast.ExpressionStmt. got={:?}",
                 program.statements[0]
-            ));
-        let exp = stmt
-            .expression
-            .as_any()
-            .downcast_ref::<InfixExpression>()
-            .expect(&format!(
-                "stmt is not ast.InfixExpression. got={:?}",
-                stmt.expression
-            ));
+            ),
+        };
+        let exp = match &stmt.expression {
+            Expression::Infix(exp) => exp,
+            _ => panic!("stmt is not ast.InfixExpression. got={:?}", stmt.expression),
+        };
         test_literal_expression(&exp.left, &tt.1);
         assert!(
             exp.operator == tt.2,
@@ -347,17 +353,17 @@ fn test_operator_precedence_parsing() {
         let mut l = Lexer::new(tt.0);
         let mut p = Parser::new(&mut l);
         let program = p.parse_program().expect("parse_program() returned None");
-        check_parser_errors(&mut p);
+        check_parser_errors(&p);
         let actual = program.string();
         assert!(actual == tt.1, "expected={}, got={}", tt.1, actual);
     }
 }
 
-fn test_identifier(exp: &Box<dyn Expression>, value: &str) {
-    let ident = exp
-        .as_any()
-        .downcast_ref::<Identifier>()
-        .expect(&format!("exp not ast.Identifier. got={:?}", exp));
+fn test_identifier(exp: &Expression, value: &str) {
+    let ident = match &exp {
+        Expression::Identifier(ident) => ident,
+        _ => panic!("exp not ast.Identifier. got={:?}", exp),
+    };
 
     assert!(
         ident.value == value,
@@ -373,21 +379,21 @@ fn test_identifier(exp: &Box<dyn Expression>, value: &str) {
     );
 }
 
-fn test_literal_expression(exp: &Box<dyn Expression>, expected: &Box<dyn Any>) {
+fn test_literal_expression(exp: &Expression, expected: &Box<dyn Any>) {
     match expected.downcast_ref::<i64>() {
         Some(i64_value) => {
             test_integer_literal(exp, *i64_value);
         }
         _ => match expected.downcast_ref::<&str>() {
             Some(string_value) => {
-                test_identifier(exp, &string_value);
+                test_identifier(exp, string_value);
             }
             _ => match expected.downcast_ref::<bool>() {
                 Some(bool_value) => {
                     test_bool_literal(exp, *bool_value);
                 }
                 _ => {
-                    assert!(false, "type of exp not handled. got={:?}", exp);
+                    panic!("type of exp not handled. got={:?}", exp);
                 }
             },
         },
@@ -395,15 +401,15 @@ fn test_literal_expression(exp: &Box<dyn Expression>, expected: &Box<dyn Any>) {
 }
 
 fn test_infix_expression(
-    exp: &Box<dyn Expression>,
-    left: Box<dyn Object>,
+    exp: &Expression,
+    left: Box<dyn Any>,
     operator: &str,
-    right: Box<dyn Object>,
+    right: Box<dyn Any>,
 ) {
-    let op_exp = exp
-        .as_any()
-        .downcast_ref::<InfixExpression>()
-        .expect(&format!("exp is not ast.OperatorExpression. got={:?}", exp));
+    let op_exp = match &exp {
+        Expression::Infix(op_exp) => op_exp,
+        _ => panic!("exp is not ast.OperatorExpression. got={:?}", exp),
+    };
     test_literal_expression(&op_exp.left, &left);
     assert!(
         op_exp.operator == operator,
@@ -414,11 +420,11 @@ fn test_infix_expression(
     test_literal_expression(&op_exp.right, &right);
 }
 
-fn test_bool_literal(exp: &Box<dyn Expression>, value: bool) {
-    let bo = exp
-        .as_any()
-        .downcast_ref::<Boolean>()
-        .expect(&format!("exp not ast.Boolean. got={:?}", exp));
+fn test_bool_literal(exp: &Expression, value: bool) {
+    let bo = match &exp {
+        Expression::Boolean(bo) => bo,
+        _ => panic!("exp not ast.Boolean. got={:?}", exp),
+    };
     assert!(
         bo.value == value,
         "bo.value not {}. got={}",
@@ -445,44 +451,39 @@ fn test_if_expression() {
         "program.statements does not contain 1 statements. got={}",
         program.statements.len()
     );
-    let stmt = program.statements[0]
-        .as_any()
-        .downcast_ref::<ExpressionStmt>()
-        .expect(&format!(
+    let stmt = match &program.statements[0] {
+        Statement::Expression(stmt) => stmt,
+        _ => panic!(
             "program.statement[0] is not ast.ExpressionStmt. got={:?}",
             program.statements[0]
-        ));
-    let exp = stmt
-        .expression
-        .as_any()
-        .downcast_ref::<IfExpression>()
-        .expect(&format!(
+        ),
+    };
+    let exp = match &stmt.expression {
+        Expression::If(exp) => exp,
+        _ => panic!(
             "stmt.expression is not ast.IfExpression. got={:?}",
             stmt.expression
-        ));
+        ),
+    };
     test_infix_expression(&exp.condition, Box::new("x"), "<", Box::new("y"));
     assert!(
         exp.consequence.statements.len() == 1,
         "consequence is not 1 statements. got={}",
         exp.consequence.statements.len()
     );
-    let consequence = exp.consequence.statements[0]
-        .as_any()
-        .downcast_ref::<ExpressionStmt>()
-        .expect(&format!(
+    let consequence = match &exp.consequence.statements[0] {
+        Statement::Expression(consequence) => consequence,
+        _ => panic!(
             "statements[0] is not ast.ExpressionStmt. got={:?}",
             exp.consequence.statements[0]
-        ));
+        ),
+    };
     test_identifier(&consequence.expression, "x");
-    match &exp.alternative {
-        Some(alternative) => {
-            assert!(
-                false,
-                "exp.alternative.statements was not None, got={:?}",
-                alternative
-            );
-        }
-        _ => {}
+    if let Some(alternative) = &exp.alternative {
+        panic!(
+            "exp.alternative.statements was not None, got={:?}",
+            alternative
+        );
     }
 }
 
@@ -498,53 +499,49 @@ fn test_if_else_expression() {
         "program.statements does not contain 1 statements. got={}",
         program.statements.len()
     );
-    let stmt = program.statements[0]
-        .as_any()
-        .downcast_ref::<ExpressionStmt>()
-        .expect(&format!(
+    let stmt = match &program.statements[0] {
+        Statement::Expression(stmt) => stmt,
+        _ => panic!(
             "program.statement[0] is not ast.ExpressionStmt. got={:?}",
             program.statements[0]
-        ));
-    let exp = stmt
-        .expression
-        .as_any()
-        .downcast_ref::<IfExpression>()
-        .expect(&format!(
+        ),
+    };
+    let exp = match &stmt.expression {
+        Expression::If(exp) => exp,
+        _ => panic!(
             "stmt.expression is not ast.IfExpression. got={:?}",
             stmt.expression
-        ));
+        ),
+    };
     test_infix_expression(&exp.condition, Box::new("x"), "<", Box::new("y"));
     assert!(
         exp.consequence.statements.len() == 1,
         "consequence is not 1 statements. got={}",
         exp.consequence.statements.len()
     );
-    let consequence = exp.consequence.statements[0]
-        .as_any()
-        .downcast_ref::<ExpressionStmt>()
-        .expect(&format!(
+    let consequence = match &exp.consequence.statements[0] {
+        Statement::Expression(consequence) => consequence,
+        _ => panic!(
             "statements[0] is not ast.ExpressionStmt. got={:?}",
             exp.consequence.statements[0]
-        ));
+        ),
+    };
     test_identifier(&consequence.expression, "x");
-    match &exp.alternative {
-        Some(alternative) => {
-            assert!(
-                alternative.statements.len() == 1,
-                "alternative is not 1 statements. got={}",
-                alternative.statements.len()
-            );
+    if let Some(alternative) = &exp.alternative {
+        assert!(
+            alternative.statements.len() == 1,
+            "alternative is not 1 statements. got={}",
+            alternative.statements.len()
+        );
 
-            let a = alternative.statements[0]
-                .as_any()
-                .downcast_ref::<ExpressionStmt>()
-                .expect(&format!(
-                    "statements[0] is not ast.ExpressionStmt. got={:?}",
-                    alternative.statements[0],
-                ));
-            test_identifier(&a.expression, "y");
-        }
-        _ => {}
+        let a = match &alternative.statements[0] {
+            Statement::Expression(a) => a,
+            _ => panic!(
+                "statements[0] is not ast.ExpressionStmt. got={:?}",
+                alternative.statements[0]
+            ),
+        };
+        test_identifier(&a.expression, "y");
     }
 }
 
@@ -560,32 +557,31 @@ fn test_function_literal_parsing() {
         "program.statements does not contain 1 statements. got={}",
         program.statements.len()
     );
-    let stmt = program.statements[0]
-        .as_any()
-        .downcast_ref::<ExpressionStmt>()
-        .expect(&format!(
+    let stmt = match &program.statements[0] {
+        Statement::Expression(stmt) => stmt,
+        _ => panic!(
             "program.statement[0] is not ast.ExpressionStmt. got={:?}",
             program.statements[0]
-        ));
-    let function = stmt
-        .expression
-        .as_any()
-        .downcast_ref::<FunctionLiteral>()
-        .expect(&format!(
+        ),
+    };
+    let function = match &stmt.expression {
+        Expression::Function(function) => function,
+        _ => panic!(
             "stmt.expression is not ast.FunctionLiteral. got={:?}",
             stmt.expression
-        ));
+        ),
+    };
     assert!(
         function.parameters.len() == 2,
         "function literal parameters wrong. want 2, got={}",
         function.parameters.len()
     );
     test_literal_expression(
-        &(Box::new(function.parameters[0].clone()) as Box<dyn Expression>),
+        &Expression::Identifier(function.parameters[0].clone()),
         &(Box::new("x") as Box<dyn Any>),
     );
     test_literal_expression(
-        &(Box::new(function.parameters[1].clone()) as Box<dyn Expression>),
+        &Expression::Identifier(function.parameters[1].clone()),
         &(Box::new("y") as Box<dyn Any>),
     );
 
@@ -595,16 +591,47 @@ fn test_function_literal_parsing() {
         function.body.statements.len()
     );
 
-    let body_stmt = function.body.statements[0]
-        .as_any()
-        .downcast_ref::<ExpressionStmt>()
-        .expect(&format!(
+    let body_stmt = match &function.body.statements[0] {
+        Statement::Expression(body_stmt) => body_stmt,
+        _ => panic!(
             "function body stmt is not ast.ExpressionStmt. got={:?}",
             function.body.statements[0]
-        ));
+        ),
+    };
     test_infix_expression(&body_stmt.expression, Box::new("x"), "+", Box::new("y"));
 }
 
+#[test]
+fn test_function_literal_with_name() {
+    let input = "let myFunction = fn() { }; let other = [fn() { }];";
+    let mut l = Lexer::new(input);
+    let mut p = Parser::new(&mut l);
+    let program = p.parse_program().expect("parse_program() returned None");
+    check_parser_errors(&p);
+
+    let value = match &program.statements[0] {
+        Statement::Let(let_stmt) => &let_stmt.value,
+        stmt => panic!("stmt is not ast.LetStatement. got={:?}", stmt),
+    };
+    match value {
+        Expression::Function(function) => assert_eq!(function.name.as_deref(), Some("myFunction")),
+        _ => panic!("value is not ast.FunctionLiteral. got={:?}", value),
+    }
+
+    // only a function bound directly takes the name
+    let value = match &program.statements[1] {
+        Statement::Let(let_stmt) => &let_stmt.value,
+        stmt => panic!("stmt is not ast.LetStatement. got={:?}", stmt),
+    };
+    match value {
+        Expression::Array(array) => match &array.elements[0] {
+            Expression::Function(function) => assert_eq!(function.name, None),
+            element => panic!("element is not ast.FunctionLiteral. got={:?}", element),
+        },
+        _ => panic!("value is not ast.ArrayLiteral. got={:?}", value),
+    }
+}
+
 #[test]
 fn test_function_parameter_parsing() {
     let tests = [
@@ -618,17 +645,15 @@ fn test_function_parameter_parsing() {
         let program = p.parse_program().unwrap();
         check_parser_errors(&p);
 
-        let stmt = program.statements[0]
-            .as_ref()
-            .as_any()
-            .downcast_ref::<ExpressionStmt>()
-            .unwrap();
+        let stmt = match &program.statements[0] {
+            Statement::Expression(stmt) => stmt,
+            other => panic!("unexpected node: {:?}", other),
+        };
 
-        let function = stmt
-            .expression
-            .as_any()
-            .downcast_ref::<FunctionLiteral>()
-            .unwrap();
+        let function = match &stmt.expression {
+            Expression::Function(function) => function,
+            other => panic!("unexpected node: {:?}", other),
+        };
 
         assert!(
             function.parameters.len() == tt.1.len(),
@@ -639,8 +664,8 @@ fn test_function_parameter_parsing() {
 
         for (i, ident) in tt.1.iter().enumerate() {
             test_literal_expression(
-                &(Box::new(function.parameters[i].clone()) as Box<dyn Expression>),
-                &(Box::new(ident.clone()) as Box<dyn Any>),
+                &Expression::Identifier(function.parameters[i].clone()),
+                &(Box::new(*ident) as Box<dyn Any>),
             );
         }
     }
@@ -660,20 +685,18 @@ fn test_call_expression_parsing() {
         program.statements.len()
     );
 
-    let stmt = program.statements[0]
-        .as_ref()
-        .as_any()
-        .downcast_ref::<ExpressionStmt>()
-        .unwrap();
-
-    let exp = stmt
-        .expression
-        .as_any()
-        .downcast_ref::<CallExpression>()
-        .expect(&format!(
+    let stmt = match &program.statements[0] {
+        Statement::Expression(stmt) => stmt,
+        other => panic!("unexpected node: {:?}", other),
+    };
+
+    let exp = match &stmt.expression {
+        Expression::Call(exp) => exp,
+        _ => panic!(
             "stmt.expression is not ast.CallExpression. got={:?}",
             stmt.expression
-        ));
+        ),
+    };
 
     test_identifier(&exp.function, "add");
 
@@ -683,17 +706,471 @@ fn test_call_expression_parsing() {
         exp.arguments.len()
     );
 
-    test_literal_expression(&exp.arguments[0], &(Box::new(1 as i64) as Box<dyn Any>));
-    test_infix_expression(
-        &exp.arguments[1],
-        Box::new(2 as i64),
-        "*",
-        Box::new(3 as i64),
+    test_literal_expression(&exp.arguments[0], &(Box::new(1_i64) as Box<dyn Any>));
+    test_infix_expression(&exp.arguments[1], Box::new(2_i64), "*", Box::new(3_i64));
+    test_infix_expression(&exp.arguments[2], Box::new(4_i64), "+", Box::new(5_i64));
+}
+
+#[test]
+fn test_function_default_and_rest_parameters() {
+    let tests = [
+        ("fn(x, y = 10) {};", "fn(x, y = 10)", 2),
+        ("fn(x = 1 + 2) {};", "fn(x = (1 + 2))", 1),
+        ("fn(x, ...rest) {};", "fn(x, ...rest)", 1),
+        ("fn(...rest) {};", "fn(...rest)", 0),
+        ("fn(a, b = a, ...c) {};", "fn(a, b = a, ...c)", 2),
+    ];
+    for tt in tests.iter() {
+        let mut l = Lexer::new(tt.0);
+        let mut p = Parser::new(&mut l);
+        let program = p.parse_program().unwrap();
+        check_parser_errors(&p);
+
+        assert!(
+            program.string() == tt.1,
+            "program.string() wrong. want={}, got={}",
+            tt.1,
+            program.string()
+        );
+
+        let stmt = match &program.statements[0] {
+            Statement::Expression(stmt) => stmt,
+            other => panic!("unexpected node: {:?}", other),
+        };
+        let function = match &stmt.expression {
+            Expression::Function(function) => function,
+            other => panic!("unexpected node: {:?}", other),
+        };
+        assert!(
+            function.parameters.len() == tt.2,
+            "length parameters wrong. want {}, got={}",
+            tt.2,
+            function.parameters.len()
+        );
+    }
+}
+
+#[test]
+fn test_function_parameter_errors() {
+    let tests = [
+        (
+            "fn(1) {};",
+            "expected next token to be identifier, got integer instead",
+        ),
+        (
+            "fn(x, true) {};",
+            "expected next token to be identifier, got 'true' instead",
+        ),
+        (
+            "fn(x,) {};",
+            "expected next token to be identifier, got ')' instead",
+        ),
+        ("fn(x, x) {};", "duplicate parameter name: x"),
+        ("fn(x, ...x) {};", "duplicate parameter name: x"),
+        (
+            "fn(...rest, x) {};",
+            "rest parameter rest must be the last parameter",
+        ),
+        (
+            "fn(x = 1, y) {};",
+            "parameter y without default follows parameter with default",
+        ),
+    ];
+    for tt in tests.iter() {
+        let mut l = Lexer::new(tt.0);
+        let mut p = Parser::new(&mut l);
+        p.parse_program();
+        let errors = p.get_errors();
+        assert!(
+            !errors.is_empty() && errors[0] == tt.1,
+            "wrong parser error for {}. want={}, got={:?}",
+            tt.0,
+            tt.1,
+            errors
+        );
+    }
+}
+
+#[test]
+fn test_call_expression_named_arguments() {
+    let tests = [
+        (
+            "connect(host, port: 5432, retries: 1 + 2);",
+            "connect(host, port: 5432, retries: (1 + 2))",
+            1,
+            2,
+        ),
+        ("f(a: b)", "f(a: b)", 0, 1),
+        ("f(a, b)", "f(a, b)", 2, 0),
+    ];
+    for tt in tests.iter() {
+        let mut l = Lexer::new(tt.0);
+        let mut p = Parser::new(&mut l);
+        let program = p.parse_program().unwrap();
+        check_parser_errors(&p);
+
+        assert!(
+            program.string() == tt.1,
+            "program.string() wrong. want={}, got={}",
+            tt.1,
+            program.string()
+        );
+        let stmt = match &program.statements[0] {
+            Statement::Expression(stmt) => stmt,
+            other => panic!("unexpected node: {:?}", other),
+        };
+        let exp = match &stmt.expression {
+            Expression::Call(exp) => exp,
+            other => panic!("unexpected node: {:?}", other),
+        };
+        assert!(
+            exp.arguments.len() == tt.2 && exp.named_arguments.len() == tt.3,
+            "wrong number of arguments. got={}, named={}",
+            exp.arguments.len(),
+            exp.named_arguments.len()
+        );
+    }
+
+    let mut l = Lexer::new("f(a: 1, 2)");
+    let mut p = Parser::new(&mut l);
+    p.parse_program();
+    let errors = p.get_errors();
+    assert!(
+        !errors.is_empty() && errors[0] == "positional argument follows named argument a",
+        "wrong parser errors. got={:?}",
+        errors
     );
-    test_infix_expression(
-        &exp.arguments[2],
-        Box::new(4 as i64),
-        "+",
-        Box::new(5 as i64),
+}
+
+#[test]
+fn test_lambda_and_pipeline_parsing() {
+    let tests = [
+        ("|x| x * 2", "fn(x)(x * 2)"),
+        ("x => x * 2", "fn(x)(x * 2)"),
+        ("|x, y| { x + y }", "fn(x, y)(x + y)"),
+        ("|| 1", "fn()1"),
+        ("|x, y = 1, ...r| x", "fn(x, y = 1, ...r)x"),
+        ("map(xs, |x| x * 2)", "map(xs, fn(x)(x * 2))"),
+        ("a |> f", "f(a)"),
+        ("a |> f(b)", "f(a, b)"),
+        ("a |> f(b, key: c)", "f(a, b, key: c)"),
+        (
+            "data |> filter(is_even) |> map(|x| x * 2)",
+            "map(filter(data, is_even), fn(x)(x * 2))",
+        ),
+        ("a + b |> f", "f((a + b))"),
+        ("a == b |> f", "f((a == b))"),
+        ("a |> x => x + 1", "fn(x)(x + 1)(a)"),
+    ];
+    for tt in tests.iter() {
+        let mut l = Lexer::new(tt.0);
+        let mut p = Parser::new(&mut l);
+        let program = p.parse_program().unwrap();
+        check_parser_errors(&p);
+
+        assert!(
+            program.string() == tt.1,
+            "program.string() wrong. want={}, got={}",
+            tt.1,
+            program.string()
+        );
+    }
+}
+
+#[test]
+fn test_collection_literal_parsing() {
+    let tests = [
+        (r#""hello world""#, r#""hello world""#),
+        ("[1, 2 * 2, x]", "[1, (2 * 2), x]"),
+        ("[]", "[]"),
+        (r#"{"one": 1, two: 2 + 2}"#, r#"{"one": 1, two: (2 + 2)}"#),
+        ("{}", "{}"),
+        ("a[1 + 1]", "(a[(1 + 1)])"),
+        ("a * [1, 2][b]", "(a * ([1, 2][b]))"),
+        ("add(a[0], b[1])", "add((a[0]), (b[1]))"),
+    ];
+    for tt in tests.iter() {
+        let mut l = Lexer::new(tt.0);
+        let mut p = Parser::new(&mut l);
+        let program = p.parse_program().unwrap();
+        check_parser_errors(&p);
+
+        assert!(
+            program.string() == tt.1,
+            "program.string() wrong. want={}, got={}",
+            tt.1,
+            program.string()
+        );
+    }
+}
+
+#[test]
+fn test_match_and_pattern_parsing() {
+    let tests = [
+        ("match (x) { 1 => a, _ => b }", "matchx { 1 => a, _ => b }"),
+        (
+            "match (x) { n if n > 1 => { n * 2 } }",
+            "matchx { n if (n > 1) => (n * 2) }",
+        ),
+        (
+            r#"match (p) { [a, -1, ...rest] => a, {"k": [v]} => v, "s" => true }"#,
+            r#"matchp { [a, (-1), ...rest] => a, {"k": [v]} => v, "s" => true }"#,
+        ),
+        ("let [a, b] = xs;", "let [a, b] = xs;"),
+        (r#"let {"x": x} = h;"#, r#"let {"x": x} = h;"#),
+        ("fn([a, b], c) { a }", "fn([a, b], c)a"),
+    ];
+    for tt in tests.iter() {
+        let mut l = Lexer::new(tt.0);
+        let mut p = Parser::new(&mut l);
+        let program = p.parse_program().unwrap();
+        check_parser_errors(&p);
+
+        assert!(
+            program.string() == tt.1,
+            "program.string() wrong. want={}, got={}",
+            tt.1,
+            program.string()
+        );
+    }
+}
+
+#[test]
+fn test_pattern_errors() {
+    let tests = [
+        (
+            "match (x) { [...r, a] => 1 }",
+            "rest pattern r must be the last element",
+        ),
+        (
+            "match (x) { {k: v} => 1 }",
+            "hash pattern key must be a literal, got identifier",
+        ),
+        (
+            "match (x) { + => 1 }",
+            "no pattern parse function for '+' found",
+        ),
+    ];
+    for tt in tests.iter() {
+        let mut l = Lexer::new(tt.0);
+        let mut p = Parser::new(&mut l);
+        p.parse_program();
+        let errors = p.get_errors();
+        assert!(
+            errors.iter().any(|e| e == tt.1),
+            "expected error {:?}, got={:?}",
+            tt.1,
+            errors
+        );
+    }
+}
+
+#[test]
+fn test_try_and_throw_parsing() {
+    let tests = [
+        ("try { a } catch (e) { b }", "try a catch(e) b"),
+        (
+            "try { a } catch { b } finally { c }",
+            "try a catch b finally c",
+        ),
+        ("try { a } finally { c }", "try a finally c"),
+        (r#"throw "boom";"#, r#"throw "boom";"#),
+        ("throw e.message + 1;", r#"throw ((e["message"]) + 1);"#),
+        ("a.b.c(1)", r#"((a["b"])["c"])(1)"#),
+    ];
+    for tt in tests.iter() {
+        let mut l = Lexer::new(tt.0);
+        let mut p = Parser::new(&mut l);
+        let program = p.parse_program().unwrap();
+        check_parser_errors(&p);
+
+        assert!(
+            program.string() == tt.1,
+            "program.string() wrong. want={}, got={}",
+            tt.1,
+            program.string()
+        );
+    }
+
+    let mut l = Lexer::new("try { a }");
+    let mut p = Parser::new(&mut l);
+    p.parse_program();
+    let errors = p.get_errors();
+    assert!(
+        errors
+            .iter()
+            .any(|e| e == "try expression requires catch or finally"),
+        "expected try error, got={:?}",
+        errors
     );
 }
+
+#[test]
+fn test_macro_literal_parsing() {
+    let input = "macro(x, y) { x + y; }";
+    let mut l = Lexer::new(input);
+    let mut p = Parser::new(&mut l);
+    let program = p.parse_program().unwrap();
+    check_parser_errors(&p);
+
+    let stmt = match &program.statements[0] {
+        Statement::Expression(stmt) => stmt,
+        _ => panic!("statement is not ExpressionStmt"),
+    };
+    let macro_literal = match &stmt.expression {
+        Expression::Macro(macro_literal) => macro_literal,
+        _ => panic!("expression is not MacroLiteral"),
+    };
+    assert!(macro_literal.parameters.len() == 2);
+    assert!(macro_literal.parameters[0].value == "x");
+    assert!(macro_literal.parameters[1].value == "y");
+    assert!(
+        macro_literal.body.string() == "(x + y)",
+        "body wrong. got={}",
+        macro_literal.body.string()
+    );
+
+    for input in [
+        "macro(x = 1) { x }",
+        "macro(...xs) { xs }",
+        "macro([a]) { a }",
+    ]
+    .iter()
+    {
+        let mut l = Lexer::new(input);
+        let mut p = Parser::new(&mut l);
+        p.parse_program();
+        let errors = p.get_errors();
+        assert!(
+            errors
+                .iter()
+                .any(|e| e == "macro parameters must be plain identifiers"),
+            "expected macro parameter error for {}, got={:?}",
+            input,
+            errors
+        );
+    }
+}
+
+#[test]
+fn test_import_and_export_parsing() {
+    let tests = [
+        (
+            r#"import "lib/math.mk" as math;"#,
+            r#"import "lib/math.mk" as math;"#,
+        ),
+        (r#"import "a.mk" as a"#, r#"import "a.mk" as a;"#),
+        ("export let x = 1 + 2;", "export let x = (1 + 2);"),
+    ];
+    for tt in tests.iter() {
+        let mut l = Lexer::new(tt.0);
+        let mut p = Parser::new(&mut l);
+        let program = p.parse_program().unwrap();
+        check_parser_errors(&p);
+
+        assert!(
+            program.string() == tt.1,
+            "program.string() wrong. want={}, got={}",
+            tt.1,
+            program.string()
+        );
+    }
+
+    let tests = [
+        (
+            "import math;",
+            "expected next token to be string, got identifier instead",
+        ),
+        (
+            r#"import "a.mk";"#,
+            "expected next token to be 'as', got ';' instead",
+        ),
+        (
+            "export fn() {};",
+            "expected next token to be 'let', got 'fn' instead",
+        ),
+        (
+            "export let [a] = b;",
+            "only `let name = value` can be exported",
+        ),
+    ];
+    for tt in tests.iter() {
+        let mut l = Lexer::new(tt.0);
+        let mut p = Parser::new(&mut l);
+        p.parse_program();
+        let errors = p.get_errors();
+        assert!(
+            !errors.is_empty() && errors[0] == tt.1,
+            "wrong parser error for {}. want={}, got={:?}",
+            tt.0,
+            tt.1,
+            errors
+        );
+    }
+}
+
+#[test]
+fn test_type_annotation_parsing() {
+    let tests = [
+        ("let x: int = 5;", "let x: int = 5;"),
+        ("let xs: [string] = y;", "let xs: [string] = y;"),
+        ("let h: {string: int} = y;", "let h: {string: int} = y;"),
+        (
+            "let f: fn(int, bool) -> [int] = g;",
+            "let f: fn(int, bool) -> [int] = g;",
+        ),
+        (
+            "fn(a: int, b: string) -> bool { true }",
+            "fn(a: int, b: string) -> bool true",
+        ),
+        ("fn(a, b: int = 1) { a }", "fn(a, b: int = 1)a"),
+        ("fn(a) -> any { a }", "fn(a) -> any a"),
+    ];
+    for tt in tests.iter() {
+        let mut l = Lexer::new(tt.0);
+        let mut p = Parser::new(&mut l);
+        let program = p.parse_program().unwrap();
+        check_parser_errors(&p);
+
+        assert!(
+            program.string() == tt.1,
+            "program.string() wrong. want={}, got={}",
+            tt.1,
+            program.string()
+        );
+    }
+
+    let mut l = Lexer::new("let x: [int] = 5;");
+    let mut p = Parser::new(&mut l);
+    let program = p.parse_program().unwrap();
+    check_parser_errors(&p);
+    let stmt = match &program.statements[0] {
+        Statement::Let(stmt) => stmt,
+        other => panic!("unexpected node: {:?}", other),
+    };
+    match &stmt.type_annotation {
+        Some(TypeAnnotation::Array(element)) => {
+            assert!(**element == TypeAnnotation::Named(String::from("int")))
+        }
+        other => panic!("type annotation wrong. got={:?}", other),
+    }
+
+    let tests = [
+        ("let x: float = 1;", "unknown type name: float"),
+        ("let x: = 1;", "expected a type, got '='"),
+        ("fn(a) -> 5 { a }", "expected a type, got integer"),
+    ];
+    for tt in tests.iter() {
+        let mut l = Lexer::new(tt.0);
+        let mut p = Parser::new(&mut l);
+        p.parse_program();
+        let errors = p.get_errors();
+        assert!(
+            !errors.is_empty() && errors[0] == tt.1,
+            "wrong parser error for {}. want={}, got={:?}",
+            tt.0,
+            tt.1,
+            errors
+        );
+    }
+}
@@ -0,0 +1,281 @@
+extern crate waiir;
+use std::cell::RefCell;
+use std::rc::Rc;
+use waiir::diagnostics::DiagnosticFormat;
+use waiir::evaluator::{ExecutionLimits, InterruptHandle};
+use waiir::module::{Engine, ModuleLoader};
+use waiir::repl::*;
+
+#[test]
+fn test_is_incomplete() {
+    let tests = [
+        ("let x = 5;", false),
+        ("", false),
+        ("let add = fn(a, b) {", true),
+        ("let add = fn(a, b) {\n  a + b\n};", false),
+        ("puts(1,", true),
+        ("[1, 2", true),
+        ("let x = 5 +", true),
+        ("let x =\n", true),
+        ("[1] |>", true),
+        ("let s = \"open", true),
+        ("let s = \"{\";", false),
+        ("let s = \"a\nb\";", false),
+        // the parser reports these
+        ("let x = 5; }", false),
+        ("let x = )", false),
+    ];
+    for tt in tests.iter() {
+        assert_eq!(is_incomplete(tt.0), tt.1, "wrong answer for {:?}", tt.0);
+    }
+}
+
+fn run(input: &str) -> String {
+    let mut output = Vec::new();
+    start(&mut input.as_bytes(), &mut output).unwrap();
+    String::from_utf8(output).unwrap()
+}
+
+#[test]
+fn test_commands() {
+    let output = run(":help\n:quit\n");
+    assert!(output.contains(":env "), "{}", output);
+    assert!(output.contains(":quit "), "{}", output);
+
+    let tests = [
+        ("let b = 2; let a = [1];\n:env\n", "a = [1]\nb = 2\n"),
+        (":type fn(x: int) { x + 1 }\n", "fn(int) -> int\n"),
+        ("let s = \"a\";\n:type s\n", "string\n"),
+        (
+            ":tokens x;\n",
+            "1:1\tIDENT\tx\n1:2\tSEMICOLON\t;\n1:3\tEOF\t\n",
+        ),
+        (":ast 1 + 2 * 3; -a[0]\n", "(1 + (2 * 3))\n(-(a[0]))\n"),
+        ("let a = 1;\n:reset\nlet b = 2;\n:env\n", "b = 2\n"),
+        (":type\n", "usage: :type EXPR\n"),
+        (
+            ":nope\n",
+            "unknown command: :nope, :help lists the commands\n",
+        ),
+        (":load missing.mk\n", "cannot read missing.mk: "),
+    ];
+    for tt in tests.iter() {
+        let output = run(&format!("{}:quit\n", tt.0));
+        // the answer to the last command, up to the prompt for `:quit`
+        let answer = output.trim_end_matches(">> ").rsplit(">> ").next().unwrap();
+        assert!(
+            answer.starts_with(tt.1),
+            "wrong output for {:?}: {:?}",
+            tt.0,
+            output
+        );
+    }
+
+    let output = run(":time 1 + 2\n:quit\n");
+    assert!(output.contains("3\ntime: "), "{}", output);
+}
+
+#[test]
+fn test_load_resolves_imports_next_to_the_file() {
+    let dir = std::env::temp_dir().join(format!("waiir_repl_test_load_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("lib")).unwrap();
+    std::fs::write(dir.join("lib/util.mk"), "export let one = 1;").unwrap();
+    std::fs::write(
+        dir.join("lib/main.mk"),
+        r#"import "./util.mk" as util; let two = util.one + 1;"#,
+    )
+    .unwrap();
+
+    for engine in [Engine::Eval, Engine::Closures, Engine::Vm].iter() {
+        let loader = Rc::new(RefCell::new(ModuleLoader::new()));
+        loader.borrow_mut().set_engine(*engine);
+        let input = format!(":load {}\ntwo\n:quit\n", dir.join("lib/main.mk").display());
+        let mut output = Vec::new();
+        start_with_loader(&mut input.as_bytes(), &mut output, &loader).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.ends_with(">> 2\n>> "), "{:?}: {:?}", engine, output);
+    }
+}
+
+#[test]
+fn test_end_of_input() {
+    // no `:quit` needed, and the open prompt is ended with a newline
+    let mut output = Vec::new();
+    let summary = start(&mut "let a = 1;\na + 1\n".as_bytes(), &mut output).unwrap();
+    assert_eq!(
+        summary,
+        Summary {
+            inputs: 2,
+            errors: 0
+        }
+    );
+    assert_eq!(String::from_utf8(output).unwrap(), ">> >> 2\n>> \n");
+
+    let tests = [
+        ("1\n", 1, 0),
+        ("1\n\n\n2\n", 2, 0),
+        ("x\n1\n", 2, 1),
+        ("let x = 1 +;\n:nope\n1 + true\n", 3, 3),
+        // a blank line cancels what was typed
+        ("let f = fn(x) {\n\n:env\n", 1, 0),
+        ("let f = fn(x) {\n", 1, 1),
+        ("1\n:quit\nx\n", 2, 0),
+    ];
+    for tt in tests.iter() {
+        let mut output = Vec::new();
+        let summary = start(&mut tt.0.as_bytes(), &mut output).unwrap();
+        assert_eq!(
+            summary,
+            Summary {
+                inputs: tt.1,
+                errors: tt.2
+            },
+            "wrong summary for {:?}",
+            tt.0
+        );
+    }
+}
+
+#[test]
+fn test_run_script() {
+    let input = "let f = fn(x) {\n\n  x * 2\n};\nf(2)\nundefined\nf(3)\n";
+    let (mut output, mut errors) = (Vec::new(), Vec::new());
+    let summary = run_script(
+        &mut input.as_bytes(),
+        &mut output,
+        &mut errors,
+        &Rc::new(RefCell::new(ModuleLoader::new())),
+    )
+    .unwrap();
+    // no prompts, and the blank line inside the function is kept
+    assert_eq!(String::from_utf8(output).unwrap(), "4\n6\n");
+    assert_eq!(
+        String::from_utf8(errors).unwrap(),
+        "error: NameError: identifier not found: undefined\n --> <stdin>:1:1\n  |\n1 | undefined\n  | ^^^^^^^^^\n"
+    );
+    assert_eq!(
+        summary,
+        Summary {
+            inputs: 4,
+            errors: 1
+        }
+    );
+    assert!(!summary.is_ok());
+    assert_eq!(summary.to_string(), "4 inputs, 1 failed");
+}
+
+#[test]
+fn test_error_format() {
+    let loader = Rc::new(RefCell::new(ModuleLoader::new()));
+    loader.borrow_mut().set_diagnostic_format(DiagnosticFormat {
+        colour: false,
+        json: true,
+    });
+    let input = "let f = fn(x) { x + true };\nf(1)\nlet x 1\n";
+    let (mut output, mut errors) = (Vec::new(), Vec::new());
+    let summary = run_script(&mut input.as_bytes(), &mut output, &mut errors, &loader).unwrap();
+    assert_eq!(summary.errors, 2);
+    assert_eq!(
+        String::from_utf8(errors).unwrap(),
+        concat!(
+            r#"[{"file":"<stdin>","severity":"error","message":"TypeError: type mismatch: INTEGER + BOOLEAN","line":1,"column":19,"width":1,"notes":["line 1, column 2, in f (1 argument)"],"help":[]}]"#,
+            "\n",
+            r#"[{"file":"<stdin>","severity":"error","message":"expected next token to be '=', got integer instead","line":1,"column":7,"width":1,"notes":[],"help":[]}]"#,
+            "\n"
+        )
+    );
+}
+
+#[test]
+fn test_piped_script_stdout() {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    let input =
+        "let double = fn(x) { x * 2 };\nputs(double(2));\nlet xs = [1, 2, 3];\nxs[1] + double(5)\n";
+    for engine in &["eval", "closures", "vm"] {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_waiir"))
+            .args(["--engine", engine])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(input.as_bytes())
+            .unwrap();
+        let result = child.wait_with_output().unwrap();
+        assert!(result.status.success(), "{}", engine);
+        // only `puts` output and the value of each expression, no traces
+        assert_eq!(
+            String::from_utf8(result.stdout).unwrap(),
+            "4\nnull\n12\n",
+            "{}",
+            engine
+        );
+        assert_eq!(String::from_utf8(result.stderr).unwrap(), "", "{}", engine);
+    }
+}
+
+// Input typed a line at a time, with Ctrl-C pressed before the lines marked.
+struct Typing {
+    lines: Vec<(bool, &'static str)>,
+    interrupt: InterruptHandle,
+}
+impl std::io::Read for Typing {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.lines.is_empty() {
+            return Ok(0);
+        }
+        let (ctrl_c, line) = self.lines.remove(0);
+        if ctrl_c {
+            self.interrupt.interrupt();
+        }
+        buf[..line.len()].copy_from_slice(line.as_bytes());
+        Ok(line.len())
+    }
+}
+
+fn interruptible_loader(interrupt: &InterruptHandle) -> Rc<RefCell<ModuleLoader>> {
+    let loader = Rc::new(RefCell::new(ModuleLoader::new()));
+    loader.borrow_mut().set_limits(ExecutionLimits {
+        interrupt: Some(interrupt.clone()),
+        ..ExecutionLimits::default()
+    });
+    loader
+}
+
+#[test]
+fn test_ctrl_c() {
+    // at the prompt, Ctrl-C drops the statement left open
+    let interrupt = InterruptHandle::new();
+    let mut input = Typing {
+        lines: vec![(false, "let f = fn(x) {\n"), (true, "1 + 1\n")],
+        interrupt: interrupt.clone(),
+    };
+    let mut output = Vec::new();
+    let summary =
+        start_with_loader(&mut input, &mut output, &interruptible_loader(&interrupt)).unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(), ">> .. 2\n>> \n");
+    assert!(summary.is_ok());
+
+    // in a script, it stops the whole run
+    let mut input = Typing {
+        lines: vec![(false, "1\n"), (true, "2\n"), (false, "3\n")],
+        interrupt: interrupt.clone(),
+    };
+    let (mut output, mut errors) = (Vec::new(), Vec::new());
+    let summary = run_script(
+        &mut input,
+        &mut output,
+        &mut errors,
+        &interruptible_loader(&interrupt),
+    )
+    .unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(), "1\n");
+    assert_eq!(summary.inputs, 1);
+}
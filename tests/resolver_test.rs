@@ -0,0 +1,247 @@
+extern crate waiir;
+use waiir::ast::*;
+use waiir::lexer::*;
+use waiir::parser::*;
+use waiir::resolver::*;
+
+fn test_resolve(input: &str) -> Vec<Diagnostic> {
+    let mut l = Lexer::new(input);
+    let mut p = Parser::new(&mut l);
+    let program = p.parse_program().unwrap();
+    assert!(
+        p.get_errors().is_empty(),
+        "parser errors: {:?}",
+        p.get_errors()
+    );
+
+    resolve_program(&program)
+}
+
+fn test_diagnostics(input: &str, expected: &[(DiagnosticKind, &str)]) {
+    let diagnostics = test_resolve(input);
+    assert!(
+        diagnostics.len() == expected.len(),
+        "wrong number of diagnostics for {}. want={}, got={:?}",
+        input,
+        expected.len(),
+        diagnostics
+    );
+    for (diagnostic, (kind, message)) in diagnostics.iter().zip(expected.iter()) {
+        assert!(
+            diagnostic.kind == *kind && diagnostic.to_string() == *message,
+            "wrong diagnostic for {}. want={:?} {}, got={:?} {}",
+            input,
+            kind,
+            message,
+            diagnostic.kind,
+            diagnostic
+        );
+    }
+}
+
+#[test]
+fn test_clean_programs() {
+    let tests = [
+        "let x = 5; x + len([1]);",
+        "let add = fn(a, b) { a + b }; add(1, 2);",
+        "let fact = fn(n) { if (n < 2) { return 1; } n * fact(n - 1) };",
+        "let even = fn(n) { if (n == 0) { true } else { odd(n - 1) } }; let odd = fn(n) { even(n) };",
+        "let f = fn(a, b = a) { b }; f(1);",
+        "let f = fn(...xs) { xs }; f;",
+        "let [a, b] = [1, 2]; a + b;",
+        "match ([1, 2]) { [x, ...rest] => x + len(rest), _ => 0 };",
+        "try { throw 1; } catch (e) { e.message } finally { puts(1) };",
+        "let unless = macro(c, a) { quote(if (!(unquote(c))) { unquote(a) }) };",
+        "import \"m.mk\" as m; m.value;",
+        "export let answer = 42;",
+        "let f = fn(_unused) { 1 }; f;",
+        "if (true) { let y = 1; } y;",
+    ];
+    for tt in tests.iter() {
+        let diagnostics = test_resolve(tt);
+        assert!(
+            diagnostics.is_empty(),
+            "unexpected diagnostics for {}: {:?}",
+            tt,
+            diagnostics
+        );
+    }
+}
+
+#[test]
+fn test_undefined_names() {
+    test_diagnostics(
+        "if (false) { foobar }",
+        &[(
+            DiagnosticKind::UndefinedName,
+            "1:14: undefined name: foobar",
+        )],
+    );
+    test_diagnostics(
+        "let f = fn() { x }; let y = x; let x = 1;",
+        &[(DiagnosticKind::UndefinedName, "1:29: undefined name: x")],
+    );
+    test_diagnostics(
+        "match (1) { n => n }; n",
+        &[(DiagnosticKind::UndefinedName, "1:23: undefined name: n")],
+    );
+    test_diagnostics(
+        "try { 1 } catch (e) { e }; e",
+        &[(DiagnosticKind::UndefinedName, "1:28: undefined name: e")],
+    );
+    test_diagnostics(
+        "let counter = 0; let f = fn(total) { countr + totl + lne([]) };",
+        &[
+            (
+                DiagnosticKind::UnusedBinding,
+                "1:29: unused parameter: total",
+            ),
+            (
+                DiagnosticKind::UndefinedName,
+                "1:38: undefined name: countr \u{2014} did you mean `counter`?",
+            ),
+            (
+                DiagnosticKind::UndefinedName,
+                "1:47: undefined name: totl \u{2014} did you mean `total`?",
+            ),
+            (
+                DiagnosticKind::UndefinedName,
+                "1:54: undefined name: lne \u{2014} did you mean `len`?",
+            ),
+        ],
+    );
+    let diagnostics = test_resolve("undefined_name");
+    assert!(diagnostics[0].is_error() && diagnostics[0].severity() == "error");
+}
+
+#[test]
+fn test_unused_bindings() {
+    test_diagnostics(
+        "let f = fn(a, b) { let c = 1; a };",
+        &[
+            (DiagnosticKind::UnusedBinding, "1:15: unused parameter: b"),
+            (DiagnosticKind::UnusedBinding, "1:24: unused variable: c"),
+        ],
+    );
+    test_diagnostics(
+        "let f = fn() { let x = 1; let x = 2; x };",
+        &[(DiagnosticKind::UnusedBinding, "1:20: unused variable: x")],
+    );
+    test_diagnostics(
+        "match ([1, 2]) { [a, b] => a };",
+        &[(DiagnosticKind::UnusedBinding, "1:22: unused variable: b")],
+    );
+    // top-level bindings are left alone
+    assert!(test_resolve("let x = 1;").is_empty());
+    let diagnostics = test_resolve("fn(a) { 1 }");
+    assert!(!diagnostics[0].is_error() && diagnostics[0].severity() == "warning");
+}
+
+#[test]
+fn test_shadowed_bindings() {
+    test_diagnostics(
+        "let x = 1; let f = fn(x) { x }; f(x);",
+        &[(
+            DiagnosticKind::ShadowedBinding,
+            "1:23: shadowed binding: x (previously defined at 1:5)",
+        )],
+    );
+    test_diagnostics(
+        "let f = fn(a) { match (a) { a => a } };",
+        &[(
+            DiagnosticKind::ShadowedBinding,
+            "1:29: shadowed binding: a (previously defined at 1:12)",
+        )],
+    );
+}
+
+#[test]
+fn test_unreachable_code() {
+    test_diagnostics(
+        "let f = fn() {\n  return 1;\n  puts(2);\n  puts(3);\n};",
+        &[(
+            DiagnosticKind::UnreachableCode,
+            "3:3: unreachable code after return",
+        )],
+    );
+    test_diagnostics(
+        "if (true) { throw 1; let y = 2; }",
+        &[(
+            DiagnosticKind::UnreachableCode,
+            "1:22: unreachable code after throw",
+        )],
+    );
+}
+
+type Uses<'a> = Vec<(&'a str, Option<LexicalAddress>)>;
+
+fn test_addresses(input: &str) -> Vec<(String, Option<LexicalAddress>)> {
+    let mut l = Lexer::new(input);
+    let mut p = Parser::new(&mut l);
+    let mut program = p.parse_program().unwrap();
+    assert!(
+        p.get_errors().is_empty(),
+        "parser errors: {:?}",
+        p.get_errors()
+    );
+    resolve_addresses(&mut program);
+
+    let mut uses = Vec::new();
+    modify_program(&mut program, &mut |exp: &mut Expression| {
+        if let Expression::Identifier(ident) = exp {
+            uses.push((ident.value.clone(), ident.address));
+        }
+    });
+    uses
+}
+
+#[test]
+fn test_lexical_addresses() {
+    let at = |depth, slot| Some(LexicalAddress { depth, slot });
+    let tests: Vec<(&str, Uses)> = vec![
+        ("let x = 1; x", vec![("x", None)]),
+        (
+            "let f = fn(x) { fn(y) { x + y } };",
+            vec![("x", at(1, 0)), ("y", at(0, 0))],
+        ),
+        (
+            "let f = fn(a, b) { let c = a; c + b + g };",
+            vec![
+                ("a", at(0, 0)),
+                ("c", at(0, 2)),
+                ("b", at(0, 1)),
+                ("g", None),
+            ],
+        ),
+        // `let`s in `if` blocks live in the function's environment
+        (
+            "fn(a) { if (a) { let b = 1; } b }",
+            vec![("a", at(0, 0)), ("b", at(0, 1))],
+        ),
+        // nested functions may refer to later `let`s, the body itself may not
+        (
+            "fn() { let f = fn() { g() }; x; let x = 1; let g = fn() { 1 }; }",
+            vec![("g", at(1, 2)), ("x", None)],
+        ),
+        (
+            "fn(a) { match (a) { [a, b] => a + b } }",
+            vec![("a", at(0, 0)), ("a", at(0, 0)), ("b", at(0, 1))],
+        ),
+        (
+            "fn(a) { try { a } catch (e) { e + a } }",
+            vec![("a", at(0, 0)), ("e", at(0, 0)), ("a", at(1, 0))],
+        ),
+        ("fn(a, b = a) { b }", vec![("a", at(0, 0)), ("b", at(0, 1))]),
+    ];
+    for (input, expected) in tests.iter() {
+        let uses = test_addresses(input);
+        let uses: Uses = uses.iter().map(|(n, a)| (n.as_str(), *a)).collect();
+        assert!(
+            uses == *expected,
+            "wrong addresses for {}. want={:?}, got={:?}",
+            input,
+            expected,
+            uses
+        );
+    }
+}
@@ -0,0 +1,207 @@
+extern crate waiir;
+use waiir::code::*;
+use waiir::compiler::*;
+use waiir::lexer::*;
+use waiir::object::*;
+use waiir::parser::*;
+use waiir::serialize::*;
+use waiir::vm::*;
+
+fn test_compile(input: &str) -> Bytecode {
+    let mut l = Lexer::new(input);
+    let mut p = Parser::new(&mut l);
+    let program = p.parse_program().unwrap();
+    Compiler::new().compile(&program).unwrap()
+}
+
+// Replaces the payload of a serialized program and fixes up its header, so
+// only the validation of the payload itself can reject it.
+fn with_payload(data: &[u8], payload: &[u8]) -> Vec<u8> {
+    let mut out = data[..14].to_vec();
+    out[6..10].copy_from_slice(&crc32(payload).to_be_bytes());
+    out[10..14].copy_from_slice(&(payload.len() as u32).to_be_bytes());
+    out.extend_from_slice(payload);
+    out
+}
+
+fn test_load_error(data: &[u8], expected: &str) {
+    match deserialize(data) {
+        Ok(_) => panic!("expected error {}", expected),
+        Err(err) => assert!(
+            err.contains(expected),
+            "wrong error. got={}, want={}",
+            err,
+            expected
+        ),
+    }
+}
+
+#[test]
+fn test_round_trip() {
+    let input = r#"
+let add = fn(a, b = 2, ...rest) { a + b + len(rest) };
+let counter = fn(n) { fn() { n } };
+let name = "monkey";
+try { throw name } catch (e) { add(len(e.message), 1, 3, 4) + counter(-7)() }
+"#;
+    let bytecode = test_compile(input);
+    let data = serialize(&bytecode).unwrap();
+    assert!(&data[0..4] == MAGIC, "wrong magic. got={:?}", &data[0..4]);
+
+    let loaded = deserialize(&data).unwrap();
+    assert!(
+        loaded.globals == bytecode.globals,
+        "wrong globals. got={:?}",
+        loaded.globals
+    );
+    assert!(
+        disassemble(&loaded.main.instructions) == disassemble(&bytecode.main.instructions),
+        "instructions differ"
+    );
+    assert!(
+        loaded.main.spans == bytecode.main.spans,
+        "spans differ. got={:?}",
+        loaded.main.spans
+    );
+    assert!(
+        loaded.main.functions[0].defaults == [false, true] && loaded.main.functions[0].rest,
+        "wrong parameters. got={:?}",
+        loaded.main.functions[0]
+    );
+
+    match Vm::new().run(&loaded) {
+        Some(Value::Integer(value)) => assert!(value == 2, "wrong value. got={}", value),
+        result => panic!("wrong result. got={:?}", result),
+    }
+}
+
+#[test]
+fn test_spans() {
+    let bytecode = test_compile("let x = 1;\nlet y = x +\n  true;");
+    let add = bytecode
+        .main
+        .instructions
+        .iter()
+        .position(|byte| *byte == Opcode::Add as u8)
+        .unwrap();
+    let span = bytecode.main.span_at(add).unwrap();
+    assert!(
+        span.line == 2 && span.column == 11,
+        "wrong span for Add. got={}",
+        span
+    );
+    let span = bytecode.main.span_at(0).unwrap();
+    assert!(
+        span.line == 1 && span.column == 9,
+        "wrong span. got={}",
+        span
+    );
+}
+
+#[test]
+fn test_rejects_bad_files() {
+    let data = serialize(&test_compile("let f = fn(x) { x * 2 }; f(21)")).unwrap();
+
+    test_load_error(b"", "bad magic number");
+    test_load_error(b"#!monkey script", "bad magic number");
+
+    let mut other_version = data.clone();
+    other_version[5] = 9;
+    test_load_error(
+        &other_version,
+        "unsupported bytecode version 9 (expected 1)",
+    );
+
+    let mut flipped = data.clone();
+    let last = flipped.len() - 1;
+    flipped[last] ^= 1;
+    test_load_error(&flipped, "checksum mismatch");
+
+    test_load_error(&data[..data.len() - 3], "wrong payload length");
+
+    let payload = &data[14..];
+    test_load_error(
+        &with_payload(&data, &payload[..payload.len() - 3]),
+        "unexpected end of file",
+    );
+
+    let mut extra = payload.to_vec();
+    extra.push(0);
+    test_load_error(&with_payload(&data, &extra), "trailing data");
+}
+
+#[test]
+fn test_rejects_invalid_code() {
+    let data = serialize(&test_compile("let x = 1; x")).unwrap();
+    let payload = data[14..].to_vec();
+    // the main function's code follows the global names and its tables
+    let code = payload
+        .windows(4)
+        .position(|w| w == [Opcode::Constant as u8, 0, 0, Opcode::SetGlobal as u8])
+        .unwrap();
+
+    let mut bad_opcode = payload.clone();
+    bad_opcode[code] = 200;
+    test_load_error(&with_payload(&data, &bad_opcode), "unknown opcode 200");
+
+    let mut bad_constant = payload.clone();
+    bad_constant[code + 2] = 7;
+    test_load_error(
+        &with_payload(&data, &bad_constant),
+        "refers to constant 7 of 1",
+    );
+
+    let mut bad_global = payload.clone();
+    bad_global[code + 5] = 3;
+    test_load_error(&with_payload(&data, &bad_global), "refers to global 3 of 1");
+
+    let mut runs_off = payload.clone();
+    let end = code + 9;
+    runs_off[end] = Opcode::Pop as u8;
+    test_load_error(&with_payload(&data, &runs_off), "code can run past its end");
+}
+
+#[test]
+fn test_underflowing_code() {
+    let data = serialize(&test_compile("let x = 1; x")).unwrap();
+    let payload = data[14..].to_vec();
+    let code = payload
+        .windows(4)
+        .position(|w| w == [Opcode::Constant as u8, 0, 0, Opcode::SetGlobal as u8])
+        .unwrap();
+
+    // well-formed instructions that pop values nothing pushed
+    let mut pops = payload.clone();
+    pops[code..code + 3].copy_from_slice(&[
+        Opcode::Add as u8,
+        Opcode::Pop as u8,
+        Opcode::Pop as u8,
+    ]);
+    let mut calls = payload.clone();
+    calls[code..code + 6].copy_from_slice(&[Opcode::Call as u8, 3, 0, 0, 0, Opcode::Pop as u8]);
+
+    for payload in [pops, calls].iter() {
+        let loaded = deserialize(&with_payload(&data, payload)).unwrap();
+        match Vm::new().run(&loaded) {
+            Some(Value::Error(err)) => assert!(
+                err.message == "stack underflow",
+                "wrong message. got={}",
+                err.message
+            ),
+            result => panic!("expected an error. got={:?}", result),
+        }
+    }
+}
+
+#[test]
+fn test_unserializable_constants() {
+    let bytecode = test_compile("quote(1 + 2)");
+    match serialize(&bytecode) {
+        Ok(_) => panic!("quote serialized"),
+        Err(err) => assert!(
+            err == "cannot serialize constant of type QUOTE",
+            "wrong error. got={}",
+            err
+        ),
+    }
+}
@@ -0,0 +1,193 @@
+extern crate waiir;
+use waiir::lexer::*;
+use waiir::parser::*;
+use waiir::typechecker::*;
+
+fn test_check(input: &str) -> (Type, Vec<TypeError>) {
+    let mut l = Lexer::new(input);
+    let mut p = Parser::new(&mut l);
+    let program = p.parse_program().unwrap();
+    assert!(
+        p.get_errors().is_empty(),
+        "parser errors: {:?}",
+        p.get_errors()
+    );
+
+    let mut checker = TypeChecker::new();
+    let ty = checker.check_program(&program);
+    (ty, checker.take_errors())
+}
+
+#[test]
+fn test_infer_types() {
+    let tests = [
+        ("5", "int"),
+        ("true == false", "bool"),
+        (r#""a" + "b""#, "string"),
+        ("-1 * 2 < 3", "bool"),
+        ("[1, 2, 3]", "[int]"),
+        (r#"{"a": 1}"#, "{string: int}"),
+        (r#"{"a": 1, "b": true}"#, "{string: any}"),
+        ("[1, 2][0]", "int"),
+        ("fn(a, b) { a + b * 2 }", "fn(t0, t1) -> int"),
+        ("fn(a: string) { a }", "fn(string) -> string"),
+        ("fn(a) -> bool { return a == 1; }", "fn(t0) -> bool"),
+        ("let add = fn(a, b) { a * b }; add(1, 2)", "int"),
+        ("if (true) { 1 } else { 2 }", "int"),
+        ("if (true) { 1 } else { \"a\" }", "any"),
+        ("if (true) { 1 }", "any"),
+        ("let x = 5; x", "int"),
+        ("undefined_name", "any"),
+        (
+            "let fact = fn(n) { if (n < 2) { return 1; } n * fact(n - 1) }; fact",
+            "fn(int) -> int",
+        ),
+        ("let [a, b] = [1, 2]; a", "int"),
+        ("match (1) { x => x + 1 }", "int"),
+        ("try { 1 } catch (e) { 2 }", "int"),
+        ("len([1])", "int"),
+    ];
+    for tt in tests.iter() {
+        let (ty, errors) = test_check(tt.0);
+        assert!(
+            errors.is_empty(),
+            "unexpected errors for {}: {:?}",
+            tt.0,
+            errors
+        );
+        assert!(
+            ty.to_string() == tt.1,
+            "type wrong for {}. want={}, got={}",
+            tt.0,
+            tt.1,
+            ty
+        );
+    }
+}
+
+#[test]
+fn test_let_polymorphism() {
+    let (ty, errors) = test_check("let id = fn(x) { x }; id(1); id(true)");
+    assert!(errors.is_empty(), "unexpected errors: {:?}", errors);
+    assert!(ty == Type::Bool, "type wrong. got={}", ty);
+
+    let (ty, _) = test_check("let id = fn(x) { x }; id");
+    match ty {
+        Type::Function(params, ret) => assert!(params[0] == *ret, "got={:?}", ret),
+        other => panic!("type is not a function. got={}", other),
+    }
+}
+
+#[test]
+fn test_type_errors() {
+    let tests = [
+        ("1 + true", "1:3: type mismatch: int + bool"),
+        (r#""a" - "b""#, "1:5: unknown operator: string - string"),
+        ("-true", "1:1: unknown operator: -bool"),
+        ("1 == \"a\"", "1:3: type mismatch: int == string"),
+        (
+            "let x: int = true;",
+            "1:1: type mismatch in let x: expected int, got bool",
+        ),
+        (
+            "let f = fn(a: int) { a }; f(\"a\")",
+            "1:28: argument 1 of f: expected int, got string",
+        ),
+        (
+            "fn(a) -> string { return 1; }",
+            "1:19: return type mismatch: expected string, got int",
+        ),
+        (
+            "fn() -> int { true }",
+            "1:1: return type mismatch: expected int, got bool",
+        ),
+        ("5(1)", "1:2: not a function: int"),
+        ("true[0]", "1:5: index operator not supported: bool"),
+        ("[1][\"a\"]", "1:4: array index: expected int, got string"),
+        (
+            "let add = fn(a: int, b: int) { a + b * 2 };\nadd(1, true)",
+            "2:4: argument 2 of add: expected int, got bool",
+        ),
+        // what is not known yet shows as any
+        (
+            "let f = fn(a: int) { a }; f([])",
+            "1:28: argument 1 of f: expected int, got [any]",
+        ),
+        (
+            "fn(a: int = true) { a }",
+            "1:4: default value of parameter a: expected int, got bool",
+        ),
+    ];
+    for tt in tests.iter() {
+        let (_, errors) = test_check(tt.0);
+        assert!(
+            !errors.is_empty() && errors[0].to_string() == tt.1,
+            "wrong type error for {}. want={}, got={:?}",
+            tt.0,
+            tt.1,
+            errors
+        );
+    }
+}
+
+#[test]
+fn test_gradual_typing() {
+    let tests = [
+        "let h = {\"a\": 1, \"b\": true}; h[\"b\"] + 1",
+        "let f = fn(x: any) { x }; f(1); f(true)",
+        "import \"m.mk\" as m; m.value + 1",
+        "let f = fn(g) { g(1) }; f(fn(x) { x })",
+        "fn(...xs) { len(xs) }(1, 2, 3)",
+        "try { throw \"oops\"; } catch (e) { e.message }",
+        // branches do not decide the types of each other
+        "let f = fn(n) { match (n) { 0 => \"zero\", _ => n } }; f(0) + 1",
+        "let f = fn(n, s) { if (n) { s } else { \"a\" } }; f(true, 1)",
+        // nor do defaults decide the type of their parameter
+        "let w = fn(a, b = \"x\") { b }; w(1, 2)",
+        // every let-bound value is polymorphic, not only function literals
+        "let f = fn(x) { x }; let g = f; g(1) + 1; g(\"a\") + \"b\"",
+        // an operator on a value not known yet fails at runtime, if at all
+        "try { let f = fn(a) { a + true }; f(1) } catch (e) { 0 }",
+        "let f = fn(a) { a - true }; f",
+        "let f = fn(a) { -a == [] }; f",
+        // unannotated parameters take anything, whatever is done with them
+        "let add = fn(a, b) { a + b * 2 }; try { add(1, true) } catch (e) { 0 }",
+        "let classify = fn(v) {
+            match (v) {
+                0 => \"zero\",
+                [first, ...rest] => rest,
+                {\"kind\": k} => k,
+                x if x > 10 => \"big\",
+                _ => \"other\"
+            }
+        };
+        classify([1, 2, 3]); classify({\"kind\": \"leaf\"}); classify(11)",
+    ];
+    for tt in tests.iter() {
+        let (_, errors) = test_check(tt);
+        assert!(
+            errors.is_empty(),
+            "unexpected errors for {}: {:?}",
+            tt,
+            errors
+        );
+    }
+}
+
+#[test]
+fn test_checker_keeps_bindings() {
+    let mut checker = TypeChecker::new();
+    for (input, want) in [("let x = 5;", "null"), ("x + 1", "int")].iter() {
+        let mut l = Lexer::new(input);
+        let mut p = Parser::new(&mut l);
+        let program = p.parse_program().unwrap();
+        let ty = checker.check_program(&program);
+        assert!(
+            ty.to_string() == *want,
+            "type wrong. want={}, got={}",
+            want,
+            ty
+        );
+    }
+    assert!(checker.errors().is_empty(), "got={:?}", checker.errors());
+}
@@ -0,0 +1,152 @@
+extern crate waiir;
+use std::rc::*;
+use waiir::ast::*;
+use waiir::compiler::*;
+use waiir::evaluator::{ExecutionLimits, InterruptHandle};
+use waiir::lexer::*;
+use waiir::object::*;
+use waiir::parser::*;
+use waiir::resolver::*;
+use waiir::vm::*;
+
+fn test_eval(input: &str) -> Value {
+    let mut l = Lexer::new(input);
+    let mut p = Parser::new(&mut l);
+    let mut program = p.parse_program().unwrap();
+    resolve_addresses(&mut program);
+
+    let bytecode = Compiler::new().compile(&program).unwrap();
+    Vm::new().run(&bytecode).unwrap()
+}
+
+// The compiler does its own scoping, so lexical addresses make no difference.
+fn test_eval_unresolved(input: &str) -> Value {
+    let mut l = Lexer::new(input);
+    let mut p = Parser::new(&mut l);
+    let program = p.parse_program().unwrap();
+
+    let bytecode = Compiler::new().compile(&program).unwrap();
+    Vm::new().run(&bytecode).unwrap()
+}
+
+fn test_compile(input: &str) -> Bytecode {
+    let mut l = Lexer::new(input);
+    let mut p = Parser::new(&mut l);
+    let mut program = p.parse_program().unwrap();
+    resolve_addresses(&mut program);
+
+    Compiler::new().compile(&program).unwrap()
+}
+
+mod behaviour;
+use behaviour::*;
+
+#[test]
+fn test_closure_object() {
+    let evaluated = test_eval("fn(x) { x + 2; };");
+    let closure = match &evaluated {
+        Value::Closure(closure) => closure,
+        _ => panic!("object is not a closure. got={:?}", evaluated),
+    };
+
+    assert!(
+        closure.function.parameters == ["x"],
+        "closure has wrong parameters. parameters={:?}",
+        closure.function.parameters
+    );
+    assert!(
+        evaluated.inspect() == "fn(x) {\n(x + 2)\n}",
+        "wrong inspect. got={}",
+        evaluated.inspect()
+    );
+}
+
+#[test]
+fn test_host_function_named_arguments() {
+    let mut compiler = Compiler::new();
+    let mut vm = Vm::new();
+    vm.set_global(
+        compiler.define_global("connect"),
+        Value::Builtin(Rc::new(Builtin::new("connect", |args, named| {
+            let port = match named.get("port") {
+                Some(Value::Integer(port)) => *port,
+                _ => 0,
+            };
+            Value::Integer(args.len() as i64 * 100_000 + named.len() as i64 * 10_000 + port)
+        }))),
+    );
+    let mut l = Lexer::new("connect(1, port: 5432, retries: 3)");
+    let mut p = Parser::new(&mut l);
+    let program = p.parse_program().unwrap();
+    let bytecode = compiler.compile(&program).unwrap();
+
+    test_integer_object(vm.run(&bytecode).unwrap(), 125432);
+}
+
+#[test]
+fn test_globals_persist_between_runs() {
+    let mut compiler = Compiler::new();
+    let mut vm = Vm::new();
+    let lines = [
+        ("let counter = fn(n) { fn() { n } };", None),
+        ("let c = counter(41);", None),
+        ("c() + 1", Some(42)),
+    ];
+    for (input, expected) in lines.iter() {
+        let mut l = Lexer::new(input);
+        let mut p = Parser::new(&mut l);
+        let program = p.parse_program().unwrap();
+        let bytecode = compiler.compile(&program).unwrap();
+        let evaluated = vm.run(&bytecode);
+        match expected {
+            Some(expected) => test_integer_object(evaluated.unwrap(), *expected),
+            _ => assert!(
+                evaluated.is_none(),
+                "expected no value. got={:?}",
+                evaluated
+            ),
+        }
+    }
+}
+
+// Run with `cargo test --release --test vm_test -- --ignored --nocapture`.
+#[test]
+#[ignore]
+fn bench_fib() {
+    let input = "
+let fib = fn(n) { if (n < 2) { n } else { fib(n - 1) + fib(n - 2) } };
+fib(25);
+";
+    let start = std::time::Instant::now();
+    let evaluated = test_eval(input);
+    let elapsed = start.elapsed();
+    test_integer_object(evaluated, 75025);
+    eprintln!("fib(25) took {:?}", elapsed);
+}
+
+#[test]
+fn test_interrupt_handle() {
+    let interrupt = InterruptHandle::new();
+    let mut vm = Vm::new();
+    vm.set_limits(ExecutionLimits {
+        interrupt: Some(interrupt.clone()),
+        ..ExecutionLimits::default()
+    });
+    let raise = interrupt.clone();
+    let raiser = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        raise.interrupt();
+    });
+    // runs for ages unless interrupted; `catch` and `finally` do not see it
+    let input = "let f = fn(n) { if (n == 0) { 0 } else { f(n - 1) + f(n - 1) } }; try { f(40) } catch (e) { 1 } finally { 2 }";
+    match vm.run(&test_compile(input)).unwrap() {
+        Value::Error(err) => {
+            assert_eq!(err.kind, INTERRUPT_ERROR);
+            assert_eq!(err.message, "interrupted");
+        }
+        evaluated => panic!("object is not Error. got={:?}", evaluated),
+    }
+    raiser.join().unwrap();
+    interrupt.reset();
+    test_integer_object(vm.run(&test_compile("1 + 1")).unwrap(), 2);
+}